@@ -1,8 +1,95 @@
 use cmt_concurrent::CartesianMerkleTree as ConcurrentCMT;
+use cmt_concurrent::{verify_all_par, verify_proofs_par, ShardedCMT, SnapshotTree};
 use cmt_core::CartesianMerkleTree as SequentialCMT;
-use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use cmt_core::{calculate_merkle_hash, calculate_merkle_hash_into, ProofVerifier, Sha256Hasher};
+use criterion::measurement::{Measurement, ValueFormatter};
+use criterion::{
+    black_box, criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput,
+};
 use rayon::iter::IntoParallelRefIterator;
 use rayon::prelude::*;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Counts heap allocations so benches can report allocation churn.
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+/// Criterion measurement that counts heap allocations instead of time, so
+/// allocation churn is reported (and compared across runs) like a timing.
+struct Allocations;
+
+impl Measurement for Allocations {
+    type Intermediate = usize;
+    type Value = usize;
+
+    fn start(&self) -> usize {
+        ALLOCATIONS.load(Ordering::Relaxed)
+    }
+
+    fn end(&self, start: usize) -> usize {
+        ALLOCATIONS.load(Ordering::Relaxed) - start
+    }
+
+    fn add(&self, a: &usize, b: &usize) -> usize {
+        a + b
+    }
+
+    fn zero(&self) -> usize {
+        0
+    }
+
+    fn to_f64(&self, value: &usize) -> f64 {
+        *value as f64
+    }
+
+    fn formatter(&self) -> &dyn ValueFormatter {
+        &AllocationFormatter
+    }
+}
+
+struct AllocationFormatter;
+
+impl ValueFormatter for AllocationFormatter {
+    fn scale_values(&self, _typical: f64, _values: &mut [f64]) -> &'static str {
+        "allocs"
+    }
+
+    fn scale_throughputs(
+        &self,
+        _typical: f64,
+        throughput: &Throughput,
+        values: &mut [f64],
+    ) -> &'static str {
+        let (n, unit) = match *throughput {
+            Throughput::Elements(n) => (n, "allocs/elem"),
+            Throughput::Bytes(n) | Throughput::BytesDecimal(n) => (n, "allocs/byte"),
+        };
+        for value in values {
+            *value /= n as f64;
+        }
+        unit
+    }
+
+    fn scale_for_machines(&self, _values: &mut [f64]) -> &'static str {
+        "allocs"
+    }
+}
 
 fn generate_key(i: usize) -> [u8; 32] {
     let mut key = [0u8; 32];
@@ -32,6 +119,150 @@ fn bench_insert(c: &mut Criterion) {
         });
     });
 
+    group.bench_function("Concurrent insert_many", |b| {
+        let pairs: Vec<_> = (0..100000).map(|i| (generate_key(i), vec![0u8])).collect();
+        b.iter_batched(
+            || pairs.clone(),
+            |pairs| {
+                let cmt = ConcurrentCMT::new();
+                cmt.insert_many(pairs);
+                cmt
+            },
+            BatchSize::LargeInput,
+        );
+    });
+
+    // overwrite-heavy: every key already present, rewritten with the bytes
+    // it holds (no hash changes) or with new ones (the path is rehashed)
+    let keys: Vec<_> = (0..100000).map(generate_key).collect();
+    let existing = ConcurrentCMT::new();
+    keys.par_iter()
+        .for_each(|key| existing.insert(*key, vec![0u8]));
+    group.bench_function("Concurrent overwrite same value", |b| {
+        b.iter(|| {
+            keys.par_iter()
+                .for_each(|key| existing.insert(*key, vec![0u8]));
+        });
+    });
+    group.bench_function("Concurrent overwrite new value", |b| {
+        let mut byte = 0u8;
+        b.iter(|| {
+            byte = byte.wrapping_add(1);
+            keys.par_iter()
+                .for_each(|key| existing.insert(*key, vec![byte]));
+        });
+    });
+
+    group.bench_function("Concurrent par_build", |b| {
+        let pairs: Vec<_> = (0..100000).map(|i| (generate_key(i), vec![0u8])).collect();
+        b.iter(|| black_box(ConcurrentCMT::par_build(&pairs)));
+    });
+
+    group.finish();
+}
+
+fn bench_bulk_insert_allocations(c: &mut Criterion<Allocations>) {
+    let mut group = c.benchmark_group("CMT Allocations");
+
+    // rehashing a 32-node path, as one insert does: a fresh serialization
+    // buffer per node, against one scratch buffer for the whole path
+    const PATH: usize = 32;
+    let path: Vec<_> = (0..PATH)
+        .map(|i| (generate_key(i), [i as u8; 32]))
+        .collect();
+    group.throughput(Throughput::Elements(PATH as u64));
+    group.bench_function("calculate_merkle_hash", |b| {
+        b.iter(|| {
+            let mut acc = [0u8; 32];
+            for (key, value_hash) in &path {
+                acc = calculate_merkle_hash::<Sha256Hasher, _>(key, value_hash, &acc, &[0u8; 32]);
+            }
+            black_box(acc)
+        });
+    });
+    group.bench_function("calculate_merkle_hash_into", |b| {
+        b.iter(|| {
+            let mut buf = Vec::new();
+            let mut acc = [0u8; 32];
+            for (key, value_hash) in &path {
+                acc = calculate_merkle_hash_into::<Sha256Hasher, _>(
+                    &mut buf, key, value_hash, &acc, &[0u8; 32],
+                );
+            }
+            black_box(acc)
+        });
+    });
+
+    let keys: Vec<_> = (0..10000).map(generate_key).collect();
+    group.throughput(Throughput::Elements(keys.len() as u64));
+    group.bench_function("Sequential bulk insert", |b| {
+        b.iter(|| {
+            let mut cmt = SequentialCMT::new();
+            for key in &keys {
+                cmt.insert(*key, vec![0u8]);
+            }
+            black_box(cmt)
+        });
+    });
+
+    group.finish();
+}
+
+fn bench_from_sorted(c: &mut Criterion) {
+    let mut group = c.benchmark_group("CMT Bulk Load");
+    let pairs: Vec<_> = (0..100000)
+        .map(|i| (generate_key(i), vec![i as u8]))
+        .collect();
+
+    group.bench_function("Insert loop", |b| {
+        b.iter(|| {
+            let mut cmt = SequentialCMT::new();
+            for (key, value) in &pairs {
+                cmt.insert(*key, value.clone());
+            }
+            black_box(cmt)
+        });
+    });
+
+    group.bench_function("from_sorted", |b| {
+        b.iter(|| black_box(SequentialCMT::from_sorted(pairs.clone())));
+    });
+
+    group.finish();
+}
+
+/// Every insert gets a higher priority than anything already in the tree,
+/// so it becomes the new root and splits the old tree along its search
+/// path. Keys arrive in scrambled order, keeping that path O(log n); the
+/// time per insert should stay roughly flat as the tree grows.
+fn bench_split_heavy_insert(c: &mut Criterion) {
+    let mut group = c.benchmark_group("CMT Split-Heavy Insert");
+
+    for n in [1_000usize, 10_000, 100_000] {
+        // scrambled order in the leading bytes, priority in the trailing ones
+        let keys: Vec<_> = (0..n as u64)
+            .map(|i| {
+                let mut key = [0u8; 32];
+                key[0..8].copy_from_slice(&i.wrapping_mul(0x9e37_79b9_7f4a_7c15).to_be_bytes());
+                key[24..32].copy_from_slice(&i.to_be_bytes());
+                key
+            })
+            .collect();
+
+        group.throughput(Throughput::Elements(n as u64));
+        group.bench_with_input(BenchmarkId::new("Sequential", n), &keys, |b, keys| {
+            b.iter(|| {
+                let mut cmt = SequentialCMT::with_priority_fn(|k| {
+                    u64::from_be_bytes(k[24..32].try_into().unwrap()) as i128
+                });
+                for key in keys {
+                    cmt.insert(*key, vec![0u8]);
+                }
+                black_box(cmt)
+            });
+        });
+    }
+
     group.finish();
 }
 
@@ -69,5 +300,329 @@ fn bench_generate_proof(c: &mut Criterion) {
     group.finish();
 }
 
-criterion_group!(benches, bench_insert, bench_generate_proof);
-criterion_main!(benches);
+/// Two million-node trees a few entries apart: the hash-pruned `diff`
+/// against merging both in-order iterators.
+fn bench_diff(c: &mut Criterion) {
+    let mut group = c.benchmark_group("CMT Diff");
+    let old = SequentialCMT::from_sorted(
+        (0..1_000_000)
+            .map(|i| (generate_key(i), vec![i as u8]))
+            .collect(),
+    );
+    let mut new = old.clone();
+    for i in (0..1_000_000).step_by(100_000) {
+        new.insert(generate_key(i), b"changed".to_vec());
+    }
+    new.remove(&generate_key(123_456));
+    new.insert(generate_key(2_000_000), vec![]);
+    old.root_hash();
+    new.root_hash();
+
+    group.bench_function("Hash-pruned", |b| b.iter(|| black_box(old.diff(&new))));
+
+    group.bench_function("Naive merge", |b| {
+        b.iter(|| {
+            let (mut a, mut b) = (old.iter().peekable(), new.iter().peekable());
+            let mut differing = 0;
+            while let (Some((ka, va)), Some((kb, vb))) = (a.peek(), b.peek()) {
+                match ka.cmp(kb) {
+                    std::cmp::Ordering::Less => drop(a.next()),
+                    std::cmp::Ordering::Greater => drop(b.next()),
+                    std::cmp::Ordering::Equal => {
+                        differing += usize::from(va != vb);
+                        a.next();
+                        b.next();
+                        continue;
+                    }
+                }
+                differing += 1;
+            }
+            black_box(differing + a.count() + b.count())
+        });
+    });
+
+    group.finish();
+}
+
+fn bench_verify_proof(c: &mut Criterion) {
+    let mut group = c.benchmark_group("CMT Verify Proof");
+
+    let mut cmt = SequentialCMT::new();
+    for i in 0..100000 {
+        cmt.insert(generate_key(i), vec![i as u8]);
+    }
+    let root = cmt.root_hash().unwrap();
+    let proofs: Vec<_> = (0..100000)
+        .map(generate_key)
+        .map(|key| (cmt.generate_proof(&key), key))
+        .collect();
+
+    group.bench_function("Sequential", |b| {
+        b.iter(|| {
+            for (proof, key) in &proofs {
+                black_box(SequentialCMT::verify_proof(proof.clone(), *key, root));
+            }
+        });
+    });
+
+    let mut verifier = ProofVerifier::new();
+    group.bench_function("Reused verifier", |b| {
+        b.iter(|| {
+            for (proof, key) in &proofs {
+                black_box(verifier.verify(proof, key, &root));
+            }
+        });
+    });
+
+    group.bench_function("Parallel", |b| {
+        b.iter(|| black_box(verify_proofs_par(&proofs, &root)));
+    });
+
+    group.bench_function("Parallel All", |b| {
+        b.iter(|| black_box(verify_all_par(&proofs, &root)));
+    });
+
+    group.finish();
+}
+
+fn bench_verify_allocations(c: &mut Criterion<Allocations>) {
+    let mut group = c.benchmark_group("CMT Allocations");
+
+    let mut cmt = SequentialCMT::new();
+    for i in 0..10000 {
+        cmt.insert(generate_key(i), vec![i as u8]);
+    }
+    let root = cmt.root_hash().unwrap();
+    let proofs: Vec<_> = (0..10000)
+        .map(generate_key)
+        .map(|key| (cmt.generate_proof(&key), key))
+        .collect();
+    group.throughput(Throughput::Elements(proofs.len() as u64));
+
+    group.bench_function("verify_proof", |b| {
+        b.iter(|| {
+            for (proof, key) in &proofs {
+                black_box(SequentialCMT::verify_proof(proof.clone(), *key, root));
+            }
+        });
+    });
+
+    // a warm ProofVerifier allocates nothing, which criterion can't record;
+    // cmt-core's tests assert that instead
+
+    group.finish();
+}
+
+/// Setup for benches with a concurrent writer: each call hands out 100 keys
+/// no earlier iteration used, so the writer inserts new nodes every time
+/// rather than overwriting last iteration's. The previous batch is removed
+/// here, outside the timing, so the tree keeps its size across the run.
+fn fresh_writes<'a>(remove: impl Fn(&[u8; 32]) + 'a) -> impl FnMut() -> Vec<[u8; 32]> + 'a {
+    let mut next = 1 << 32;
+    let mut last: Vec<[u8; 32]> = Vec::new();
+    move || {
+        for key in &last {
+            remove(key);
+        }
+        last = (next..next + 100).map(generate_key).collect();
+        next += 100;
+        last.clone()
+    }
+}
+
+fn bench_contains_key_contention(c: &mut Criterion) {
+    let mut group = c.benchmark_group("CMT contains_key Contention");
+
+    let cmt = ConcurrentCMT::new();
+    let snapshots = SnapshotTree::new();
+    for i in 0..100000 {
+        cmt.insert(generate_key(i), vec![i as u8]);
+        snapshots.insert(generate_key(i), vec![i as u8]);
+    }
+    let keys: Vec<_> = (0..100000).map(generate_key).collect();
+
+    // every reader thread checks its share of `keys`
+    fn read_all(threads: usize, keys: &[[u8; 32]], contains: &(dyn Fn(&[u8; 32]) -> bool + Sync)) {
+        std::thread::scope(|s| {
+            for t in 0..threads {
+                s.spawn(move || {
+                    for key in keys.iter().skip(t).step_by(threads) {
+                        black_box(contains(key));
+                    }
+                });
+            }
+        });
+    }
+
+    for threads in [2usize, 4, 8] {
+        group.bench_function(format!("RwLock read, {threads} readers"), |b| {
+            b.iter(|| read_all(threads, &keys, &|k| cmt.contains_key(k)));
+        });
+        group.bench_function(format!("ArcSwap snapshot, {threads} readers"), |b| {
+            b.iter(|| read_all(threads, &keys, &|k| snapshots.contains_key(k)));
+        });
+    }
+
+    group.bench_function("RwLock read, 4 readers + writer", |b| {
+        b.iter_batched(
+            fresh_writes(|k| drop(cmt.remove(k))),
+            |writes| {
+                std::thread::scope(|s| {
+                    s.spawn(|| {
+                        for key in writes {
+                            cmt.insert(key, vec![0u8]);
+                        }
+                    });
+                    read_all(4, &keys, &|k| cmt.contains_key(k));
+                });
+            },
+            BatchSize::PerIteration,
+        );
+    });
+
+    group.bench_function("ArcSwap snapshot, 4 readers + writer", |b| {
+        b.iter_batched(
+            fresh_writes(|k| drop(snapshots.remove(k))),
+            |writes| {
+                std::thread::scope(|s| {
+                    s.spawn(|| {
+                        for key in writes {
+                            snapshots.insert(key, vec![0u8]);
+                        }
+                    });
+                    read_all(4, &keys, &|k| snapshots.contains_key(k));
+                });
+            },
+            BatchSize::PerIteration,
+        );
+    });
+
+    group.finish();
+}
+
+/// Write throughput of the single-lock tree against a 16-way sharded one.
+/// Keys get spread leading bytes so they land across every shard.
+fn bench_sharded_writes(c: &mut Criterion) {
+    let mut group = c.benchmark_group("CMT Sharded Writes");
+    let keys: Vec<_> = (0..100000usize)
+        .map(|i| {
+            let mut key = generate_key(i);
+            key[..2].copy_from_slice(&(i as u16).wrapping_mul(40503).to_be_bytes());
+            key
+        })
+        .collect();
+    group.throughput(Throughput::Elements(keys.len() as u64));
+
+    for threads in [8usize, 16] {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .unwrap();
+        group.bench_with_input(
+            BenchmarkId::new("Single lock", threads),
+            &keys,
+            |b, keys| {
+                b.iter(|| {
+                    let cmt = ConcurrentCMT::new();
+                    pool.install(|| keys.par_iter().for_each(|key| cmt.insert(*key, vec![0u8])));
+                    cmt
+                });
+            },
+        );
+        group.bench_with_input(BenchmarkId::new("16 shards", threads), &keys, |b, keys| {
+            b.iter(|| {
+                let cmt = ShardedCMT::new(4);
+                pool.install(|| keys.par_iter().for_each(|key| cmt.insert(*key, vec![0u8])));
+                cmt
+            });
+        });
+    }
+
+    group.finish();
+}
+
+/// Proof generation while a writer keeps inserting: the `RwLock` tree makes
+/// readers and the writer wait for each other, the snapshot tree lets
+/// readers proceed on the version they loaded.
+fn bench_proofs_under_writes(c: &mut Criterion) {
+    let mut group = c.benchmark_group("CMT Proofs Under Writes");
+    let keys: Vec<_> = (0..100000).map(generate_key).collect();
+
+    let locked = ConcurrentCMT::new();
+    let swapped = SnapshotTree::new();
+    for key in &keys {
+        locked.insert(*key, vec![0u8]);
+        swapped.insert(*key, vec![0u8]);
+    }
+    let proof_keys: Vec<_> = keys.iter().step_by(25).copied().collect();
+    group.throughput(Throughput::Elements(proof_keys.len() as u64));
+
+    group.bench_function("RwLock", |b| {
+        b.iter_batched(
+            fresh_writes(|k| drop(locked.remove(k))),
+            |writes| {
+                std::thread::scope(|s| {
+                    s.spawn(|| {
+                        for key in writes {
+                            locked.insert(key, vec![0u8]);
+                        }
+                    });
+                    for t in 0..4 {
+                        let (locked, proof_keys) = (&locked, &proof_keys);
+                        s.spawn(move || {
+                            for key in proof_keys.iter().skip(t).step_by(4) {
+                                black_box(locked.generate_proof(key));
+                            }
+                        });
+                    }
+                });
+            },
+            BatchSize::PerIteration,
+        );
+    });
+
+    group.bench_function("ArcSwap snapshot", |b| {
+        b.iter_batched(
+            fresh_writes(|k| drop(swapped.remove(k))),
+            |writes| {
+                std::thread::scope(|s| {
+                    s.spawn(|| {
+                        for key in writes {
+                            swapped.insert(key, vec![0u8]);
+                        }
+                    });
+                    for t in 0..4 {
+                        let (swapped, proof_keys) = (&swapped, &proof_keys);
+                        s.spawn(move || {
+                            for key in proof_keys.iter().skip(t).step_by(4) {
+                                black_box(swapped.generate_proof(key));
+                            }
+                        });
+                    }
+                });
+            },
+            BatchSize::PerIteration,
+        );
+    });
+
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_insert,
+    bench_from_sorted,
+    bench_split_heavy_insert,
+    bench_generate_proof,
+    bench_diff,
+    bench_verify_proof,
+    bench_contains_key_contention,
+    bench_sharded_writes,
+    bench_proofs_under_writes
+);
+criterion_group!(
+    name = allocations;
+    config = Criterion::default().with_measurement(Allocations).without_plots();
+    targets = bench_bulk_insert_allocations, bench_verify_allocations
+);
+criterion_main!(benches, allocations);
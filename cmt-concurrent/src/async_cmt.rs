@@ -0,0 +1,76 @@
+//! module for the async wrapper, behind the `async` feature
+//!
+//! `AsyncCMT` is a `cmt_core` tree behind a `tokio::sync::RwLock`. Waiting
+//! for the lock yields to the runtime instead of blocking a worker thread,
+//! and every operation finishes its tree work without awaiting while it
+//! holds the lock, so no guard is ever held across an `.await` in caller
+//! code. Proofs are built under a brief read lock and returned owned.
+use crate::{Hash, Key, Proof, Value};
+use cmt_core::CartesianMerkleTree;
+use tokio::sync::RwLock;
+
+/// Async-friendly tree for tokio services; see the module docs.
+#[derive(Default)]
+pub struct AsyncCMT {
+    inner: RwLock<CartesianMerkleTree>,
+}
+
+impl AsyncCMT {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Wraps an existing tree, e.g. one bulk-loaded with `from_sorted`.
+    pub fn from_tree(tree: CartesianMerkleTree) -> Self {
+        Self {
+            inner: RwLock::new(tree),
+        }
+    }
+
+    pub async fn insert(&self, key: Key, value: Value) {
+        self.inner.write().await.insert(key, value);
+    }
+
+    pub async fn remove(&self, key: &Key) -> Option<Value> {
+        self.inner.write().await.remove(key)
+    }
+
+    /// Clone of the value stored under `key`.
+    pub async fn get(&self, key: &Key) -> Option<Value> {
+        self.inner.read().await.get(key).cloned()
+    }
+
+    pub async fn contains_key(&self, key: &Key) -> bool {
+        self.inner.read().await.contains_key(key)
+    }
+
+    pub async fn len(&self) -> usize {
+        self.inner.read().await.len()
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.inner.read().await.is_empty()
+    }
+
+    /// Hash of the root node, or `None` for an empty tree.
+    pub async fn root_hash(&self) -> Option<Hash> {
+        self.inner.read().await.root_hash()
+    }
+
+    pub async fn generate_proof(&self, key: &Key) -> Proof {
+        self.inner.read().await.generate_proof(key)
+    }
+
+    /// Value and proof for `key` from one read of the tree, so both come
+    /// from the same version, together with that version's root hash.
+    pub async fn get_with_proof(&self, key: &Key) -> (Option<Value>, Proof, Option<Hash>) {
+        let tree = self.inner.read().await;
+        let (value, proof) = tree.get_with_proof(key);
+        (value.cloned(), proof, tree.root_hash())
+    }
+
+    /// Unwraps the tree once no other task holds the wrapper.
+    pub fn into_inner(self) -> CartesianMerkleTree {
+        self.inner.into_inner()
+    }
+}
@@ -0,0 +1,89 @@
+//! module for read-only cursors over the nodes of the locked tree
+//!
+//! `read` takes the tree's read lock and returns a `TreeReadGuard`, whose
+//! `root_node` hands out `NodeRef`s borrowed from the guard. Writers wait
+//! until the guard is dropped, so every node reached through it belongs to
+//! the same version of the tree. Don't write to the tree from a thread that
+//! holds a guard; the write would wait on the guard forever.
+use crate::{CartesianMerkleTreeWith, Hash, Hasher, Key, Priority, Subtree, TreeNode};
+use parking_lot::RwLockReadGuard;
+
+/// The tree's read lock, held until dropped; see the module docs.
+pub struct TreeReadGuard<'a, V> {
+    root: RwLockReadGuard<'a, Subtree<V>>,
+}
+
+impl<V> TreeReadGuard<'_, V> {
+    /// Cursor at the root node, or `None` for an empty tree.
+    pub fn root_node(&self) -> Option<NodeRef<'_, V>> {
+        self.root.as_deref().map(|node| NodeRef { node })
+    }
+
+    /// Hash of the root, or `None` if the tree is empty.
+    pub fn root_hash(&self) -> Option<Hash> {
+        self.root.as_ref().map(|n| n.hash)
+    }
+}
+
+/// Read-only handle to a node, borrowed from a `TreeReadGuard`.
+pub struct NodeRef<'a, V> {
+    node: &'a TreeNode<V>,
+}
+
+// not derived, which would require `V: Clone`
+impl<V> Clone for NodeRef<'_, V> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+impl<V> Copy for NodeRef<'_, V> {}
+
+impl<'a, V> NodeRef<'a, V> {
+    pub fn key(&self) -> &'a Key {
+        &self.node.key
+    }
+
+    pub fn priority(&self) -> Priority {
+        self.node.priority
+    }
+
+    pub fn value(&self) -> &'a V {
+        &self.node.value
+    }
+
+    pub fn value_hash(&self) -> &'a Hash {
+        &self.node.value_hash
+    }
+
+    /// Merkle hash of the subtree rooted here.
+    pub fn hash(&self) -> &'a Hash {
+        &self.node.hash
+    }
+
+    pub fn left(&self) -> Option<NodeRef<'a, V>> {
+        self.node.left.as_deref().map(|node| NodeRef { node })
+    }
+
+    pub fn right(&self) -> Option<NodeRef<'a, V>> {
+        self.node.right.as_deref().map(|node| NodeRef { node })
+    }
+}
+
+impl<V> std::fmt::Debug for NodeRef<'_, V> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NodeRef")
+            .field("key", &self.node.key)
+            .field("priority", &self.node.priority)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<V, H: Hasher> CartesianMerkleTreeWith<V, H> {
+    /// Takes the read lock for a node-by-node traversal; see the module
+    /// docs.
+    pub fn read(&self) -> TreeReadGuard<'_, V> {
+        TreeReadGuard {
+            root: self.root.read(),
+        }
+    }
+}
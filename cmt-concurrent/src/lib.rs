@@ -1,322 +1,1862 @@
-use crate::utils::calculate_merkle_hash;
+use crate::utils::{calculate_merkle_hash_into, hash_value};
+use cmt_core::outranks;
 use parking_lot::RwLock;
+use rayon::prelude::*;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
+#[cfg(feature = "async")]
+mod async_cmt;
+mod cursor;
+mod pinned;
+mod sharded;
+mod snapshot;
 mod utils;
 
+#[cfg(feature = "async")]
+pub use async_cmt::AsyncCMT;
+// Proofs share cmt-core's type, and with it the documented wire format
+// (`Proof::to_bytes` / `Proof::from_bytes`); the empty-hash sentinel and
+// its encoding are cmt-core's too, so both crates hash nodes identically.
+pub use cmt_core::{
+    empty_hash, hash_bytes, is_empty_subtree_hash, key_from_slice, Hasher, KeyLenError, PriorityFn,
+    Proof, ProofDecodeError, ProofVerifier, Sha256Hasher, TruncatedDigest, VerifyError, DIGEST_LEN,
+    EMPTY_ROOT,
+};
+pub use cursor::{NodeRef, TreeReadGuard};
+pub use pinned::PinnedView;
+pub use sharded::{ShardedCMT, ShardedCMTWith, ShardedProof, MAX_SHARD_BITS};
+pub use snapshot::{SnapshotTree, SnapshotTreeWith, TreeSnapshot};
+
 pub type Key = [u8; 32];
 pub type Priority = i128;
-pub type Hash = Vec<u8>;
+pub type Hash = [u8; DIGEST_LEN];
 pub type Value = Vec<u8>;
 
-pub trait Hasher {
-    fn hash(data: &[u8]) -> Hash;
-}
-
-#[derive(Debug, Clone)]
-pub struct TreeNode {
+/// A node of the concurrent tree. Cloning, dropping and debug-printing a
+/// node never recurse into its subtrees, so a node at the top of a
+/// degenerate spine is as safe to handle as a leaf.
+pub struct TreeNode<V = Value> {
     pub key: Key,
     pub priority: Priority,
-    pub value: Value,
+    pub value: V,
+    /// Hash of `value`, cached so rotations don't rehash the value.
+    pub value_hash: Hash,
     pub hash: Hash,
-    pub left: Option<Box<TreeNode>>,
-    pub right: Option<Box<TreeNode>>,
+    pub left: Option<Box<TreeNode<V>>>,
+    pub right: Option<Box<TreeNode<V>>>,
+}
+
+/// Deep copy of the subtree rooted here, made with an explicit stack.
+impl<V: Clone> Clone for TreeNode<V> {
+    fn clone(&self) -> Self {
+        let mut copies: Vec<Box<TreeNode<V>>> = Vec::new();
+        let mut stack = vec![(self, false)];
+        while let Some((n, children_done)) = stack.pop() {
+            if !children_done {
+                stack.push((n, true));
+                stack.extend(n.left.as_deref().map(|c| (c, false)));
+                stack.extend(n.right.as_deref().map(|c| (c, false)));
+                continue;
+            }
+            // the right subtree is copied first, so the left one is on top
+            let left = n.left.as_ref().and_then(|_| copies.pop());
+            let right = n.right.as_ref().and_then(|_| copies.pop());
+            copies.push(Box::new(TreeNode {
+                key: n.key,
+                priority: n.priority,
+                value: n.value.clone(),
+                value_hash: n.value_hash,
+                hash: n.hash,
+                left,
+                right,
+            }));
+        }
+        *copies.pop().expect("the root is copied last")
+    }
+}
+
+/// Tears the subtree down with an explicit stack; the default recursive drop
+/// of the boxed children could overflow on a degenerate tree.
+impl<V> Drop for TreeNode<V> {
+    fn drop(&mut self) {
+        let mut stack: Vec<Box<TreeNode<V>>> = self.left.take().into_iter().collect();
+        stack.extend(self.right.take());
+        while let Some(mut n) = stack.pop() {
+            stack.extend(n.left.take());
+            stack.extend(n.right.take());
+        }
+    }
 }
 
-impl PartialEq for TreeNode {
+/// Prints the node's own fields, and its children by key only.
+impl<V: std::fmt::Debug> std::fmt::Debug for TreeNode<V> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TreeNode")
+            .field("key", &self.key)
+            .field("priority", &self.priority)
+            .field("value", &self.value)
+            .field("value_hash", &self.value_hash)
+            .field("hash", &self.hash)
+            .field("left", &self.left.as_ref().map(|n| n.key))
+            .field("right", &self.right.as_ref().map(|n| n.key))
+            .finish()
+    }
+}
+
+impl<V> PartialEq for TreeNode<V> {
     fn eq(&self, other: &Self) -> bool {
         self.key == other.key
     }
 }
-impl Eq for TreeNode {}
+impl<V> Eq for TreeNode<V> {}
+
+/// An owned, possibly empty subtree.
+type Subtree<V> = Option<Box<TreeNode<V>>>;
+/// Nodes detached along a search path, root first, each with the side the
+/// path continued on.
+type Path<V> = Vec<(Box<TreeNode<V>>, std::cmp::Ordering)>;
+
+/// Concurrent tree of `V` values hashing with `H`; see
+/// `CartesianMerkleTree` for the SHA-256 default. Values are committed to
+/// through their byte representation (`V: AsRef<[u8]>`).
+pub struct CartesianMerkleTreeWith<V, H: Hasher> {
+    root: RwLock<Option<Box<TreeNode<V>>>>,
+    size: AtomicUsize,
+    priority_fn: PriorityFn,
+    hasher: PhantomData<fn() -> H>,
+}
+
+pub type CartesianMerkleTree<V = Value> = CartesianMerkleTreeWith<V, Sha256Hasher>;
+
+impl<V, H> Default for CartesianMerkleTreeWith<V, H>
+where
+    V: AsRef<[u8]> + Clone + Send + Sync,
+    H: Hasher,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Compact summary taken under a brief read lock: entry count, the start of
+/// the root hash, and height. Nodes are never printed.
+impl<V, H: Hasher> std::fmt::Debug for CartesianMerkleTreeWith<V, H> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (len, root, height) = {
+            let root = self.root.read();
+            let mut height = 0;
+            let mut stack: Vec<(&TreeNode<V>, usize)> =
+                root.as_deref().map(|n| (n, 1)).into_iter().collect();
+            while let Some((n, level)) = stack.pop() {
+                height = height.max(level);
+                for child in [n.left.as_deref(), n.right.as_deref()]
+                    .into_iter()
+                    .flatten()
+                {
+                    stack.push((child, level + 1));
+                }
+            }
+            (
+                self.size.load(Ordering::Acquire),
+                root.as_ref().map(|n| n.hash),
+                height,
+            )
+        };
+        f.debug_struct("CartesianMerkleTree")
+            .field("len", &len)
+            .field("root", &root.as_ref().map(ShortHash))
+            .field("height", &height)
+            .finish()
+    }
+}
+
+/// Debug-prints the first bytes of a hash in hex.
+struct ShortHash<'a>(&'a Hash);
+
+impl std::fmt::Debug for ShortHash<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0[..8].iter().try_for_each(|b| write!(f, "{b:02x}"))?;
+        f.write_str("..")
+    }
+}
 
-pub struct CartesianMerkleTree {
-    root: RwLock<Option<Box<TreeNode>>>,
+/// Same as `snapshot_clone`: an O(n) deep copy of the current tree, taken
+/// under the read lock, with its own fresh lock.
+impl<V, H> Clone for CartesianMerkleTreeWith<V, H>
+where
+    V: AsRef<[u8]> + Clone + Send + Sync,
+    H: Hasher,
+{
+    fn clone(&self) -> Self {
+        self.snapshot_clone()
+    }
 }
 
-impl CartesianMerkleTree {
+impl<V, H> CartesianMerkleTreeWith<V, H>
+where
+    V: AsRef<[u8]> + Clone + Send + Sync,
+    H: Hasher,
+{
     pub fn new() -> Self {
+        Self::with_priority_fn(find_priority::<H>)
+    }
+
+    /// Empty tree deriving priorities with `priority_fn` instead of the key
+    /// hash.
+    pub fn with_priority_fn<F>(priority_fn: F) -> Self
+    where
+        F: Fn(&Key) -> Priority + Send + Sync + 'static,
+    {
         Self {
             root: RwLock::new(None),
+            size: AtomicUsize::new(0),
+            priority_fn: Arc::new(priority_fn),
+            hasher: PhantomData,
         }
     }
 
+    /// Builds a tree from `pairs` without taking the lock per key, in O(n)
+    /// expected time. The pairs are sorted and split into one chunk per
+    /// worker thread; each chunk becomes a treap in a single monotonic-stack
+    /// pass, hashing every node once, and neighbouring chunks are then
+    /// joined by priority along their facing spines, rehashing only the
+    /// nodes whose children change there. Nothing recurses. The result
+    /// matches inserting the pairs one by one; for a repeated key the last
+    /// value wins.
+    pub fn par_build(pairs: &[(Key, V)]) -> Self {
+        let tree = Self::new();
+        let mut entries: Vec<(Key, V)> = pairs.to_vec();
+        // stable, so a repeated key keeps its input order for the dedup
+        entries.par_sort_by_key(|(k, _)| *k);
+        entries.reverse();
+        entries.dedup_by(|a, b| a.0 == b.0);
+        entries.reverse();
+
+        let chunk = entries.len().div_ceil(rayon::current_num_threads()).max(1);
+        let priority_fn = &tree.priority_fn;
+        let root = entries
+            .par_chunks(chunk)
+            .map(|chunk| Self::build_chunk(chunk, priority_fn))
+            // chunks come back in key order, so each join sees disjoint,
+            // ordered key ranges
+            .reduce(
+                || None,
+                |lower, upper| Self::join(lower, upper, &mut Vec::new()),
+            );
+        *tree.root.write() = root;
+        tree.size.store(entries.len(), Ordering::Release);
+        tree
+    }
+
+    /// Builds a treap from entries sorted by key, keeping the right spine
+    /// on a stack. A node is hashed when it leaves the stack, once both of
+    /// its children are final.
+    fn build_chunk(entries: &[(Key, V)], priority_fn: &PriorityFn) -> Subtree<V> {
+        let mut buf = Vec::new();
+        let mut spine: Vec<Box<TreeNode<V>>> = Vec::new();
+        for (key, value) in entries {
+            let priority = priority_fn(key);
+            let mut last: Subtree<V> = None;
+            while spine
+                .last()
+                .is_some_and(|top| outranks(priority, key, top.priority, &top.key))
+            {
+                let mut top = spine.pop().unwrap();
+                top.right = last.take();
+                Self::refresh(&mut top, &mut buf);
+                last = Some(top);
+            }
+            spine.push(Box::new(TreeNode {
+                key: *key,
+                priority,
+                value: value.clone(),
+                value_hash: hash_value::<H>(value.as_ref()),
+                hash: empty_hash(),
+                left: last,
+                right: None,
+            }));
+        }
+
+        let mut last = None;
+        while let Some(mut top) = spine.pop() {
+            top.right = last.take();
+            Self::refresh(&mut top, &mut buf);
+            last = Some(top);
+        }
+        last
+    }
+
+    /// Joins two treaps where every key in `lower` is below every key in
+    /// `upper`, walking down `lower`'s right spine and `upper`'s left spine
+    /// and rehashing the nodes along that walk.
+    fn join(mut lower: Subtree<V>, mut upper: Subtree<V>, buf: &mut Vec<u8>) -> Subtree<V> {
+        let mut path: Path<V> = Vec::new();
+        loop {
+            match (lower, upper) {
+                (Some(mut l), Some(u)) if outranks(l.priority, &l.key, u.priority, &u.key) => {
+                    lower = l.right.take();
+                    upper = Some(u);
+                    path.push((l, std::cmp::Ordering::Greater));
+                }
+                (l, Some(mut u)) => {
+                    upper = u.left.take();
+                    lower = l;
+                    path.push((u, std::cmp::Ordering::Less));
+                }
+                (rest, None) => return Self::reattach(path, rest, buf),
+            }
+        }
+    }
+
+    /// Deep-copies the current tree under the read lock into a fresh,
+    /// independent tree with its own lock.
+    pub fn snapshot_clone(&self) -> Self {
+        let root = self.root.read();
+        Self {
+            root: RwLock::new(root.clone()),
+            // read under the lock so the count matches the copied root
+            size: AtomicUsize::new(self.size.load(Ordering::Acquire)),
+            priority_fn: self.priority_fn.clone(),
+            hasher: PhantomData,
+        }
+    }
+
+    /// Copies the current version into a read-only view, so many proofs
+    /// can be taken against one root while writes continue. Holds the read
+    /// lock only for the O(n) copy; see `PinnedView`.
+    pub fn pinned(&self) -> PinnedView<V> {
+        let root = self.root.read();
+        PinnedView {
+            root: root.clone(),
+            // read under the lock so the count matches the copied root
+            len: self.size.load(Ordering::Acquire),
+        }
+    }
+
+    /// Hash of the root node, or `None` for an empty tree. Takes the read
+    /// lock just long enough to clone the hash out.
+    pub fn root_hash(&self) -> Option<Hash> {
+        self.root.read().as_ref().map(|n| n.hash)
+    }
+
+    /// `root_hash`, with an empty tree committing to `EMPTY_ROOT`.
+    pub fn committed_root(&self) -> Hash {
+        self.root_hash().unwrap_or(EMPTY_ROOT)
+    }
+
+    /// Number of keys in the tree, read without taking the lock.
+    pub fn len(&self) -> usize {
+        self.size.load(Ordering::Acquire)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Removes every entry under the write lock.
+    pub fn clear(&self) {
+        let mut root = self.root.write();
+        *root = None;
+        self.size.store(0, Ordering::Release);
+    }
+
+    /// Membership check under the read lock. Writers change nodes in
+    /// place, so a reader can't skip the lock without risking a node being
+    /// freed under it; where many threads check membership at once,
+    /// `SnapshotTree::contains_key` loads its root without locking.
     pub fn contains_key(&self, key: &Key) -> bool {
-        let cur = self.root.read();
-        let mut cur = cur.as_ref();
+        let root = self.root.read();
+        Self::lookup(root.as_deref(), key)
+    }
+
+    fn lookup(mut cur: Option<&TreeNode<V>>, key: &Key) -> bool {
         while let Some(n) = cur {
             if &n.key == key {
                 return true;
             } else if key < &n.key {
-                cur = n.left.as_ref();
+                cur = n.left.as_deref();
             } else {
-                cur = n.right.as_ref();
+                cur = n.right.as_deref();
             }
         }
         false
     }
 
-    pub fn insert(&self, key: Key, value: Value) {
-        let priority = find_priority(&key);
+    /// Inserts or overwrites `key`. Overwriting with the bytes the key
+    /// already holds changes no hash, so it skips rehashing the path.
+    pub fn insert(&self, key: Key, value: V) {
+        let mut root = self.root.write();
+        let priority = (self.priority_fn)(&key);
+        self.insert_locked(&mut root, key, value, priority, &mut Vec::new());
+    }
+
+    /// Inserts using a caller-supplied priority instead of the one derived
+    /// by the tree's priority function; see
+    /// `cmt_core::CartesianMerkleTree::insert_with_priority`.
+    pub fn insert_with_priority(&self, key: Key, value: V, priority: Priority) {
         let mut root = self.root.write();
-        *root = Self::insert_recursive(root.take(), key, value, priority);
+        self.insert_locked(&mut root, key, value, priority, &mut Vec::new());
     }
 
-    fn insert_recursive(
-        node: Option<Box<TreeNode>>,
+    /// Applies every insert under a single write-lock acquisition, so a
+    /// batch pays for the lock once
+    /// instead of per key. Later pairs win over earlier ones with the same
+    /// key, as with repeated `insert` calls.
+    pub fn insert_many(&self, pairs: Vec<(Key, V)>) {
+        let mut root = self.root.write();
+        let buf = &mut Vec::new();
+        for (key, value) in pairs {
+            let priority = (self.priority_fn)(&key);
+            self.insert_locked(&mut root, key, value, priority, buf);
+        }
+    }
+
+    /// Inserts into `root`, which the caller holds under the write lock.
+    fn insert_locked(
+        &self,
+        root: &mut Subtree<V>,
         key: Key,
-        value: Value,
+        value: V,
         priority: Priority,
-    ) -> Option<Box<TreeNode>> {
-        let mut current_node = match node {
-            Some(n) => n,
-            None => {
-                let hash = calculate_merkle_hash(&key, &Vec::new(), &Vec::new());
-                return Some(Box::new(TreeNode {
+        buf: &mut Vec<u8>,
+    ) {
+        let value_hash = hash_value::<H>(value.as_ref());
+
+        // detach the search path, stopping where the new node belongs
+        let mut path: Path<V> = Vec::new();
+        let mut cur = root.take();
+        while let Some(mut n) = cur {
+            let dir = key.cmp(&n.key);
+            if outranks(priority, &key, n.priority, &n.key) || dir == std::cmp::Ordering::Equal {
+                cur = Some(n);
+                break;
+            }
+            cur = match dir {
+                std::cmp::Ordering::Less => n.left.take(),
+                _ => n.right.take(),
+            };
+            path.push((n, dir));
+        }
+
+        let subtree = match cur {
+            // same key, and the new priority doesn't lift it: overwrite
+            Some(mut n) if n.key == key && priority <= n.priority => {
+                n.value = value;
+                if n.value_hash == value_hash {
+                    // values are committed by their hash, so rewriting the
+                    // same bytes leaves every hash on the path as it was
+                    *root = Self::restore(path, n);
+                    return;
+                }
+                n.value_hash = value_hash;
+                Self::refresh(&mut n, buf);
+                n
+            }
+            rest => {
+                let mut replaced = false;
+                let (left, right) = Self::split(rest, &key, &mut replaced, buf);
+                let mut n = Box::new(TreeNode {
                     key,
                     priority,
+                    value_hash,
                     value,
-                    hash,
-                    left: None,
-                    right: None,
-                }));
+                    hash: empty_hash(),
+                    left,
+                    right,
+                });
+                Self::refresh(&mut n, buf);
+                if !replaced {
+                    self.size.fetch_add(1, Ordering::Release);
+                }
+                n
             }
         };
+        *root = Self::reattach(path, Some(subtree), buf);
+    }
 
-        if priority > current_node.priority {
-            let hash = calculate_merkle_hash(&key, &Vec::new(), &Vec::new());
-            let mut new_node = Box::new(TreeNode {
-                key,
-                priority,
-                value,
-                hash,
-                left: None,
-                right: None,
-            });
-            Self::split(
-                &mut current_node,
-                &new_node.key,
-                &mut new_node.left,
-                &mut new_node.right,
-            );
-            // recompute hash for new_node
-            let (left_hash, right_hash) = rayon::join(
-                || {
-                    new_node
-                        .left
-                        .as_ref()
-                        .map(|n| n.hash.clone())
-                        .unwrap_or_default()
-                },
-                || {
-                    new_node
-                        .right
-                        .as_ref()
-                        .map(|n| n.hash.clone())
-                        .unwrap_or_default()
-                },
-            );
-            new_node.hash = calculate_merkle_hash(&new_node.key, &left_hash, &right_hash);
-            return Some(new_node);
+    /// Hangs an unchanged `node` back under the detached `path`, leaving
+    /// every hash as it was.
+    fn restore(mut path: Path<V>, node: Box<TreeNode<V>>) -> Subtree<V> {
+        let mut subtree = node;
+        while let Some((mut n, dir)) = path.pop() {
+            match dir {
+                std::cmp::Ordering::Less => n.left = Some(subtree),
+                _ => n.right = Some(subtree),
+            }
+            subtree = n;
         }
+        Some(subtree)
+    }
 
-        if key < current_node.key {
-            current_node.left =
-                Self::insert_recursive(current_node.left.take(), key, value, priority);
-        } else if key > current_node.key {
-            current_node.right =
-                Self::insert_recursive(current_node.right.take(), key, value, priority);
-        } else {
-            current_node.value = value;
-        }
-
-        let (left_hash, right_hash) = rayon::join(
-            || {
-                current_node
-                    .left
-                    .as_ref()
-                    .map(|n| n.hash.clone())
-                    .unwrap_or_default()
-            },
-            || {
-                current_node
-                    .right
-                    .as_ref()
-                    .map(|n| n.hash.clone())
-                    .unwrap_or_default()
-            },
-        );
-        current_node.hash = calculate_merkle_hash(&current_node.key, &left_hash, &right_hash);
-
-        Some(current_node)
+    /// Hangs `subtree` back under the detached `path`, rehashing every
+    /// ancestor on the way up.
+    fn reattach(mut path: Path<V>, mut subtree: Subtree<V>, buf: &mut Vec<u8>) -> Subtree<V> {
+        while let Some((mut n, dir)) = path.pop() {
+            match dir {
+                std::cmp::Ordering::Less => n.left = subtree,
+                _ => n.right = subtree,
+            }
+            Self::refresh(&mut n, buf);
+            subtree = Some(n);
+        }
+        subtree
     }
 
+    /// Splits `node` into the subtrees holding keys below and above `key`,
+    /// moving nodes rather than copying them and rehashing every node whose
+    /// children change. A node already holding `key` is dropped, and
+    /// `found` is set so the caller can tell an overwrite from an insert.
+    /// Walks with explicit stacks, so a skewed tree can't overflow.
     fn split(
-        node: &mut Box<TreeNode>,
+        node: Subtree<V>,
         key: &Key,
-        left: &mut Option<Box<TreeNode>>,
-        right: &mut Option<Box<TreeNode>>,
-    ) {
-        if node.key < *key {
-            *left = Some(node.clone());
-            if let Some(right_child) = node.right.as_mut() {
-                Self::split(right_child, key, left, right);
-            }
-        } else {
-            *right = Some(node.clone());
-            if let Some(left_child) = node.left.as_mut() {
-                Self::split(left_child, key, left, right);
+        found: &mut bool,
+        buf: &mut Vec<u8>,
+    ) -> (Subtree<V>, Subtree<V>) {
+        // nodes bound for the left result, each missing its right child, and
+        // for the right result, each missing its left child
+        let mut lower: Vec<Box<TreeNode<V>>> = Vec::new();
+        let mut upper: Vec<Box<TreeNode<V>>> = Vec::new();
+        let mut cur = node;
+        let (mut left, mut right) = (None, None);
+        while let Some(mut n) = cur {
+            match n.key.cmp(key) {
+                std::cmp::Ordering::Less => {
+                    cur = n.right.take();
+                    lower.push(n);
+                }
+                std::cmp::Ordering::Greater => {
+                    cur = n.left.take();
+                    upper.push(n);
+                }
+                std::cmp::Ordering::Equal => {
+                    *found = true;
+                    (left, right) = (n.left.take(), n.right.take());
+                    break;
+                }
             }
         }
+
+        while let Some(mut n) = lower.pop() {
+            n.right = left;
+            Self::refresh(&mut n, buf);
+            left = Some(n);
+        }
+        while let Some(mut n) = upper.pop() {
+            n.left = right;
+            Self::refresh(&mut n, buf);
+            right = Some(n);
+        }
+        (left, right)
+    }
+
+    /// Recomputes `node`'s hash from its children.
+    fn refresh(node: &mut TreeNode<V>, buf: &mut Vec<u8>) {
+        let empty = empty_hash();
+        let left_hash = node.left.as_ref().map_or(&empty, |n| &n.hash);
+        let right_hash = node.right.as_ref().map_or(&empty, |n| &n.hash);
+        node.hash = calculate_merkle_hash_into::<H, _>(
+            buf,
+            &node.key,
+            &node.value_hash,
+            left_hash,
+            right_hash,
+        );
     }
-    pub fn remove(&self, key: &Key) {
+
+    /// Removes `key`, returning its value if it was present. The lookup and
+    /// removal happen under one write lock, so when several threads race to
+    /// remove the same key exactly one of them gets `Some`.
+    pub fn remove(&self, key: &Key) -> Option<V> {
         let mut root = self.root.write();
-        *root = Self::remove_recursive(root.take(), key);
+        self.remove_locked(&mut root, key, &mut Vec::new())
     }
 
-    fn remove_recursive(node: Option<Box<TreeNode>>, key: &Key) -> Option<Box<TreeNode>> {
-        if let Some(mut current_node) = node {
-            if *key < current_node.key {
-                current_node.left = Self::remove_recursive(current_node.left.take(), key);
-            } else if *key > current_node.key {
-                current_node.right = Self::remove_recursive(current_node.right.take(), key);
+    /// Removes every key in `keys` under a single write-lock acquisition,
+    /// returning each key's value (or `None` if absent) in the same order.
+    pub fn remove_many(&self, keys: &[Key]) -> Vec<Option<V>> {
+        let mut root = self.root.write();
+        let buf = &mut Vec::new();
+        keys.iter()
+            .map(|key| self.remove_locked(&mut root, key, buf))
+            .collect()
+    }
+
+    /// Removes from `root`, which the caller holds under the write lock.
+    fn remove_locked(&self, root: &mut Subtree<V>, key: &Key, buf: &mut Vec<u8>) -> Option<V> {
+        let mut path: Path<V> = Vec::new();
+        let mut cur = root.take();
+        let found = loop {
+            let Some(mut n) = cur else {
+                break None;
+            };
+            let dir = key.cmp(&n.key);
+            if dir == std::cmp::Ordering::Equal {
+                break Some(n);
+            }
+            cur = match dir {
+                std::cmp::Ordering::Less => n.left.take(),
+                _ => n.right.take(),
+            };
+            path.push((n, dir));
+        };
+
+        match found {
+            Some(n) => {
+                let (subtree, value) = Self::heapify(n, buf);
+                *root = Self::reattach(path, subtree, buf);
+                self.size.fetch_sub(1, Ordering::Release);
+                Some(value)
+            }
+            None => {
+                // nothing changed below the path, so no hash is stale
+                let mut subtree = None;
+                while let Some((mut n, dir)) = path.pop() {
+                    match dir {
+                        std::cmp::Ordering::Less => n.left = subtree,
+                        _ => n.right = subtree,
+                    }
+                    subtree = Some(n);
+                }
+                *root = subtree;
+                None
+            }
+        }
+    }
+
+    /// Rotates `node` down until it is a leaf and unlinks it, lifting the
+    /// higher-priority child at each step. Returns the subtree that takes
+    /// its place and the unlinked node's value.
+    fn heapify(mut node: Box<TreeNode<V>>, buf: &mut Vec<u8>) -> (Subtree<V>, V) {
+        let mut lifted: Path<V> = Vec::new();
+        while node.left.is_some() || node.right.is_some() {
+            // lift the higher-ranked child, or the only one
+            let lift_left = match (&node.left, &node.right) {
+                (Some(l), Some(r)) => outranks(l.priority, &l.key, r.priority, &r.key),
+                (left, _) => left.is_some(),
+            };
+
+            // after the rotation `node` hangs off the lifted child; detach it
+            // again and keep sinking
+            if lift_left {
+                let mut top = utils::rotate_right::<H, V>(node, buf);
+                node = top.right.take().unwrap();
+                lifted.push((top, std::cmp::Ordering::Greater));
             } else {
-                // Node found, set priority to -inf and heapify down
-                current_node.priority = i128::MIN;
-                return Self::heapify(current_node);
+                let mut top = utils::rotate_left::<H, V>(node, buf);
+                node = top.left.take().unwrap();
+                lifted.push((top, std::cmp::Ordering::Less));
             }
-            // Update hash
-            let (left_hash, right_hash) = rayon::join(
-                || {
-                    current_node
-                        .left
-                        .as_ref()
-                        .map(|n| n.hash.clone())
-                        .unwrap_or_default()
-                },
-                || {
-                    current_node
-                        .right
-                        .as_ref()
-                        .map(|n| n.hash.clone())
-                        .unwrap_or_default()
-                },
-            );
-            current_node.hash = calculate_merkle_hash(&current_node.key, &left_hash, &right_hash);
-            return Some(current_node);
+        }
+        // nodes tear their subtrees down in `Drop`, so the value can't be
+        // moved out of the now childless node; copy it instead
+        (Self::reattach(lifted, None, buf), node.value.clone())
+    }
+
+    pub fn generate_proof(&self, key: &Key) -> Proof {
+        proof_for(self.root.read().as_deref(), key)
+    }
+
+    /// Clone of the value stored under `key`, under the read lock.
+    pub fn get(&self, key: &Key) -> Option<V> {
+        let root = self.root.read();
+        let mut cur = root.as_deref();
+        while let Some(n) = cur {
+            cur = match key.cmp(&n.key) {
+                std::cmp::Ordering::Equal => return Some(n.value.clone()),
+                std::cmp::Ordering::Less => n.left.as_deref(),
+                std::cmp::Ordering::Greater => n.right.as_deref(),
+            };
         }
         None
     }
 
-    fn heapify(node: Box<TreeNode>) -> Option<Box<TreeNode>> {
-        if node.left.is_none() && node.right.is_none() {
-            // Leaf node, remove it
-            return None;
+    /// Clone of the entry with the smallest key, under the read lock. Unlike
+    /// `BTreeMap::first_key_value` (and the sequential tree's) it returns
+    /// owned values, since a borrow can't outlive the lock guard.
+    ///
+    /// ```
+    /// use cmt_concurrent::CartesianMerkleTree;
+    /// use std::collections::BTreeMap;
+    ///
+    /// let cmt = CartesianMerkleTree::new();
+    /// let mut map = BTreeMap::new();
+    /// for i in [7u8, 3, 9] {
+    ///     cmt.insert([i; 32], vec![i]);
+    ///     map.insert([i; 32], vec![i]);
+    /// }
+    /// let owned = |(k, v): (&[u8; 32], &Vec<u8>)| (*k, v.clone());
+    /// assert_eq!(cmt.first_key_value(), map.first_key_value().map(owned));
+    /// ```
+    pub fn first_key_value(&self) -> Option<(Key, V)> {
+        let root = self.root.read();
+        let mut n = root.as_deref()?;
+        while let Some(l) = n.left.as_deref() {
+            n = l;
+        }
+        Some((n.key, n.value.clone()))
+    }
+
+    /// Clone of the entry with the largest key, under the read lock; owned
+    /// for the same reason as `first_key_value`.
+    ///
+    /// ```
+    /// use cmt_concurrent::CartesianMerkleTree;
+    /// use std::collections::BTreeMap;
+    ///
+    /// let cmt = CartesianMerkleTree::new();
+    /// let mut map = BTreeMap::new();
+    /// for i in [7u8, 3, 9] {
+    ///     cmt.insert([i; 32], vec![i]);
+    ///     map.insert([i; 32], vec![i]);
+    /// }
+    /// let owned = |(k, v): (&[u8; 32], &Vec<u8>)| (*k, v.clone());
+    /// assert_eq!(cmt.last_key_value(), map.last_key_value().map(owned));
+    /// ```
+    pub fn last_key_value(&self) -> Option<(Key, V)> {
+        let root = self.root.read();
+        let mut n = root.as_deref()?;
+        while let Some(r) = n.right.as_deref() {
+            n = r;
         }
+        Some((n.key, n.value.clone()))
+    }
+
+    /// `get` and `generate_proof` from one walk under one read lock, so the
+    /// value and proof always come from the same version of the tree.
+    pub fn get_cloned_with_proof(&self, key: &Key) -> (Option<V>, Proof) {
+        let root = self.root.read();
+        let (value, proof) = lookup_with_proof(root.as_deref(), key);
+        (value.cloned(), proof)
+    }
+
+    /// `generate_proof` together with the root hash the proof folds to,
+    /// both read under one read lock.
+    pub(crate) fn proof_with_root(&self, key: &Key) -> (Proof, Option<Hash>) {
+        let root = self.root.read();
+        (
+            proof_for(root.as_deref(), key),
+            root.as_ref().map(|n| n.hash),
+        )
+    }
+
+    /// Checks that `key` maps to `value` under `root_hash`: the proof must
+    /// be a membership proof for `key` whose committed value hash matches.
+    pub fn verify_value(proof: Proof, key: Key, value: &V, root_hash: Hash) -> bool {
+        proof.existence
+            && proof.value_hash == hash_value::<H>(value.as_ref())
+            && CartesianMerkleTreeWith::<Value, H>::verify_proof(proof, key, root_hash)
+    }
+}
 
-        let left_priority = node.left.as_ref().map_or(i128::MIN, |n| n.priority);
-        let right_priority = node.right.as_ref().map_or(i128::MIN, |n| n.priority);
+/// Proof verification only looks at hashes, so it lives on the byte-valued
+/// tree; it accepts proofs from trees of any value type.
+impl<H: Hasher> CartesianMerkleTreeWith<Value, H> {
+    pub fn verify_proof(proof: Proof, key: Key, root_hash: Hash) -> bool {
+        Self::verify_proof_detailed(proof, key, root_hash).is_ok()
+    }
 
-        if left_priority > right_priority {
-            let mut new_node = utils::rotate_right(node);
-            new_node.right = Self::heapify(new_node.right.take().unwrap());
-            Some(new_node)
+    /// Same check as `verify_proof`, reporting why a proof was rejected.
+    /// Proofs share cmt-core's format, and so does the check.
+    pub fn verify_proof_detailed(
+        proof: Proof,
+        key: Key,
+        root_hash: Hash,
+    ) -> Result<(), VerifyError> {
+        cmt_core::CartesianMerkleTreeWith::<H>::verify_proof_detailed(proof, key, root_hash)
+    }
+
+    /// Strict variant of `verify_proof` that also enforces the structural
+    /// rules of the documented algorithm; see
+    /// `cmt_core::CartesianMerkleTree::verify_strict`.
+    pub fn verify_strict(proof: Proof, key: Key, root_hash: Hash) -> bool {
+        cmt_core::CartesianMerkleTreeWith::<H>::verify_strict(proof, key, root_hash)
+    }
+}
+
+/// Checks each `(proof, key)` pair against `root_hash` on the rayon pool,
+/// for trees using the default hasher. Results are in input order.
+pub fn verify_proofs_par(proofs: &[(Proof, Key)], root_hash: &Hash) -> Vec<bool> {
+    proofs
+        .par_iter()
+        .map_init(ProofVerifier::new, |verifier, (proof, key)| {
+            verifier.verify(proof, key, root_hash)
+        })
+        .collect()
+}
+
+/// Whether every pair in `proofs` verifies against `root_hash`. Stops
+/// handing out work at the first failure.
+pub fn verify_all_par(proofs: &[(Proof, Key)], root_hash: &Hash) -> bool {
+    proofs
+        .par_iter()
+        .map_init(ProofVerifier::new, |verifier, (proof, key)| {
+            verifier.verify(proof, key, root_hash)
+        })
+        .all(|ok| ok)
+}
+
+/// Child hashes of `node` in canonical order.
+fn sorted_children<V>(node: &TreeNode<V>) -> [Hash; 2] {
+    let hash = |c: &Option<Box<TreeNode<V>>>| c.as_ref().map(|x| x.hash).unwrap_or_else(empty_hash);
+    let (left, right) = (hash(&node.left), hash(&node.right));
+    if left <= right {
+        [left, right]
+    } else {
+        [right, left]
+    }
+}
+
+/// Membership or non-existence proof for `key` in the tree at `root`.
+fn proof_for<V>(root: Option<&TreeNode<V>>, key: &Key) -> Proof {
+    lookup_with_proof(root, key).1
+}
+
+/// `key`'s value, if present, and its proof, from one walk down from `root`.
+fn lookup_with_proof<'a, V>(root: Option<&'a TreeNode<V>>, key: &Key) -> (Option<&'a V>, Proof) {
+    let mut prefix: Vec<(Key, Hash, Hash)> = Vec::new();
+    let mut cur = root;
+    let mut last: Option<&TreeNode<V>> = None;
+    let mut existence = false;
+
+    while let Some(n) = cur {
+        last = Some(n);
+        if &n.key == key {
+            existence = true;
+            break;
+        }
+        let (next, sibling) = if key < &n.key {
+            (n.left.as_deref(), n.right.as_deref())
         } else {
-            let mut new_node = utils::rotate_left(node);
-            new_node.left = Self::heapify(new_node.left.take().unwrap());
-            Some(new_node)
+            (n.right.as_deref(), n.left.as_deref())
+        };
+        if next.is_none() {
+            // non-existence: the last traversed node is the witness
+            break;
         }
+        // push (parent.e.k, parent.e.vh, sibling.mh)
+        prefix.push((
+            n.key,
+            n.value_hash,
+            sibling.map(|x| x.hash).unwrap_or_else(empty_hash),
+        ));
+        cur = next;
     }
+    // prefix is folded from the witness up to the root
+    prefix.reverse();
 
-    pub fn generate_proof(&self, key: &Key) -> Proof {
-        let mut prefix: Vec<(Key, Hash)> = Vec::new();
-        let cur = self.root.read();
-        let mut cur = cur.as_ref();
-        let mut last: Option<&TreeNode> = None;
-        let mut existence = false;
+    let (left_h, right_h) = match last {
+        Some(n) => (
+            n.left.as_ref().map(|x| x.hash).unwrap_or_else(empty_hash),
+            n.right.as_ref().map(|x| x.hash).unwrap_or_else(empty_hash),
+        ),
+        None => (empty_hash(), empty_hash()),
+    };
+    let non_ex_key = if existence { None } else { last.map(|n| n.key) };
 
-        while let Some(n) = cur {
-            if &n.key == key {
-                existence = true;
-                last = Some(n);
-                break;
+    // canonical order from the paper: suffix[0] < suffix[1]
+    let suffix = if left_h <= right_h {
+        [left_h, right_h]
+    } else {
+        [right_h, left_h]
+    };
+
+    // a witness with one child opens it, to show which side is empty
+    let child = match last {
+        Some(n) if !existence => match (n.left.as_deref(), n.right.as_deref()) {
+            (Some(c), None) | (None, Some(c)) => Some((c.key, c.value_hash, sorted_children(c))),
+            _ => None,
+        },
+        _ => None,
+    };
+
+    let proof = Proof {
+        prefix,
+        suffix,
+        value_hash: last.map(|n| n.value_hash).unwrap_or_else(empty_hash),
+        existence,
+        nonexistence_key: non_ex_key,
+        nonexistence_child: child,
+    };
+    (last.filter(|_| existence).map(|n| &n.value), proof)
+}
+
+fn find_priority<H: Hasher>(key: &Key) -> Priority {
+    let digest = H::hash(key.as_ref());
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(&digest[..16]);
+    i128::from_be_bytes(bytes) as i128
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    fn calculate_merkle_hash(key: &Key, value_hash: &Hash, left: &Hash, right: &Hash) -> Hash {
+        cmt_core::calculate_merkle_hash::<Sha256Hasher, _>(key, value_hash, left, right)
+    }
+
+    fn key(i: usize) -> Key {
+        let mut key = [0u8; 32];
+        key[0..8].copy_from_slice(&i.to_be_bytes());
+        key
+    }
+
+    #[test]
+    fn contains_key_under_concurrent_writes() {
+        let keys: Vec<Key> = (0..1500).map(key).collect();
+        let (stable, churn) = keys.split_at(500);
+        let (stable, churn) = (stable.to_vec(), churn.to_vec());
+
+        let cmt = Arc::new(CartesianMerkleTree::new());
+        for k in &stable {
+            cmt.insert(*k, vec![0u8]);
+        }
+
+        let writer = {
+            let cmt = Arc::clone(&cmt);
+            let churn = churn.clone();
+            thread::spawn(move || {
+                for k in &churn {
+                    cmt.insert(*k, vec![0u8]);
+                }
+                for k in &churn[..500] {
+                    cmt.remove(k);
+                }
+            })
+        };
+
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let cmt = Arc::clone(&cmt);
+                let stable = stable.clone();
+                thread::spawn(move || {
+                    for _ in 0..20 {
+                        for k in &stable {
+                            assert!(cmt.contains_key(k));
+                        }
+                        assert!(!cmt.contains_key(&key(5000)));
+                    }
+                })
+            })
+            .collect();
+
+        writer.join().unwrap();
+        for r in readers {
+            r.join().unwrap();
+        }
+
+        for k in &churn[..500] {
+            assert!(!cmt.contains_key(k));
+        }
+        assert_eq!(cmt.len(), 1000);
+        for k in &churn[500..] {
+            assert!(cmt.contains_key(k));
+        }
+    }
+
+    fn root_hash_of(cmt: &CartesianMerkleTree) -> Hash {
+        cmt.root_hash().unwrap_or_else(empty_hash)
+    }
+
+    #[test]
+    fn insert_with_priority_matches_the_core_tree() {
+        // a right spine under derived priorities would be unlikely; pin it
+        let cmt = CartesianMerkleTree::<Value>::new();
+        let mut core = cmt_core::CartesianMerkleTree::new();
+        for i in 0..50 {
+            let priority = -(i as Priority);
+            cmt.insert_with_priority(key(i), vec![i as u8], priority);
+            core.insert_with_priority(key(i), vec![i as u8], priority);
+        }
+        assert_eq!(core.height(), 50);
+        assert_eq!(cmt.root_hash(), core.root_hash());
+
+        // derived and manual priorities mix in one tree
+        for i in 50..100 {
+            cmt.insert(key(i), vec![]);
+            core.insert(key(i), vec![]);
+        }
+        cmt.insert_with_priority(key(7), vec![1], Priority::MAX);
+        core.insert_with_priority(key(7), vec![1], Priority::MAX);
+        assert_eq!(cmt.len(), 100);
+        assert_eq!(cmt.root.read().as_ref().unwrap().key, key(7));
+        assert_eq!(cmt.root_hash(), core.root_hash());
+    }
+
+    #[test]
+    fn same_value_overwrites_skip_rehashing() {
+        static HASHES: AtomicUsize = AtomicUsize::new(0);
+        struct Counting;
+        impl Hasher for Counting {
+            const ALGO_ID: u8 = cmt_core::HASH_ALGO_SHA256;
+            fn hash(data: &[u8]) -> Hash {
+                HASHES.fetch_add(1, Ordering::Relaxed);
+                Sha256Hasher::hash(data)
             }
-            // push (parent.e.k, parent.mh)
-            prefix.push((n.key.clone(), n.hash.clone()));
-            if key < &n.key {
-                cur = n.left.as_ref();
+        }
+
+        let cmt = CartesianMerkleTreeWith::<Value, Counting>::new();
+        for i in 0..200 {
+            cmt.insert(key(i), vec![i as u8]);
+        }
+        let root = cmt.root_hash();
+
+        // one hash for the priority and one for the value, nothing else
+        HASHES.store(0, Ordering::Relaxed);
+        cmt.insert(key(77), vec![77]);
+        assert_eq!(HASHES.load(Ordering::Relaxed), 2);
+        assert_eq!(cmt.root_hash(), root);
+        assert_eq!(cmt.len(), 200);
+
+        // a new value is committed, so the path is rehashed
+        HASHES.store(0, Ordering::Relaxed);
+        cmt.insert(key(77), vec![0xff]);
+        assert!(HASHES.load(Ordering::Relaxed) > 2);
+        assert_ne!(cmt.root_hash(), root);
+        assert_eq!(cmt.get(&key(77)), Some(vec![0xff]));
+        cmt.insert(key(77), vec![77]);
+        assert_eq!(cmt.root_hash(), root);
+    }
+
+    #[test]
+    fn debug_is_a_summary() {
+        let cmt = CartesianMerkleTree::new();
+        assert_eq!(
+            format!("{cmt:?}"),
+            "CartesianMerkleTree { len: 0, root: None, height: 0 }"
+        );
+        for i in 0..1000 {
+            cmt.insert(key(i), vec![0; 64]);
+        }
+        let debug = format!("{cmt:?}");
+        let root = cmt.root_hash().unwrap();
+        let prefix: String = root[..8].iter().map(|b| format!("{b:02x}")).collect();
+        assert!(debug.starts_with("CartesianMerkleTree { len: 1000, root: Some("));
+        assert!(debug.contains(&prefix));
+        assert!(debug.len() < 100);
+    }
+
+    #[test]
+    fn priority_ties_do_not_depend_on_order() {
+        let coarse = |k: &Key| Priority::from(k[7] % 4);
+        let keys: Vec<Key> = (0..500).map(key).collect();
+        let forward = CartesianMerkleTree::<Value>::with_priority_fn(coarse);
+        let backward = CartesianMerkleTree::<Value>::with_priority_fn(coarse);
+        for (f, b) in keys.iter().zip(keys.iter().rev()) {
+            forward.insert(*f, vec![]);
+            backward.insert(*b, vec![]);
+        }
+        for k in keys.iter().step_by(3) {
+            forward.remove(k);
+            backward.remove(k);
+        }
+        assert_eq!(forward.root_hash(), backward.root_hash());
+    }
+
+    #[test]
+    fn clone_matches_and_diverges() {
+        let cmt = CartesianMerkleTree::new();
+        for i in 0..100 {
+            cmt.insert(key(i), vec![i as u8]);
+        }
+        let copy = cmt.clone();
+        assert_eq!(copy.root_hash(), cmt.root_hash());
+
+        copy.insert(key(500), vec![]);
+        cmt.remove(&key(0));
+        assert_ne!(copy.root_hash(), cmt.root_hash());
+        assert!(copy.contains_key(&key(0)) && !cmt.contains_key(&key(500)));
+        assert_eq!((cmt.len(), copy.len()), (99, 101));
+    }
+
+    #[test]
+    fn pinned_view_proofs_outlive_writes() {
+        let cmt = Arc::new(CartesianMerkleTree::new());
+        for i in 0..500 {
+            cmt.insert(key(i), vec![i as u8]);
+        }
+        let view = cmt.pinned();
+        let root = view.root_hash().unwrap();
+        assert_eq!(cmt.root_hash(), Some(root));
+
+        let writer = {
+            let cmt = Arc::clone(&cmt);
+            thread::spawn(move || {
+                for i in 0..500 {
+                    cmt.remove(&key(i));
+                    cmt.insert(key(i + 500), vec![]);
+                }
+            })
+        };
+        for i in (0..1000).step_by(3) {
+            let k = key(i);
+            let proof = view.generate_proof(&k);
+            assert_eq!(proof.existence, i < 500);
+            assert!(CartesianMerkleTree::verify_proof(proof, k, root));
+        }
+        writer.join().unwrap();
+
+        assert_ne!(cmt.root_hash(), Some(root));
+        assert_eq!(view.root_hash(), Some(root));
+        assert_eq!((view.len(), view.get(&key(9))), (500, Some(&vec![9])));
+        assert!(CartesianMerkleTree::verify_proof(
+            view.generate_proof(&key(9)),
+            key(9),
+            root
+        ));
+        assert!(CartesianMerkleTree::<Value>::new().pinned().is_empty());
+    }
+
+    #[test]
+    fn get_with_proof_agrees_with_get() {
+        let cmt = CartesianMerkleTree::new();
+        for i in 0..200 {
+            cmt.insert(key(i), vec![i as u8; 3]);
+        }
+        let root = cmt.root_hash().unwrap();
+        for i in (0..300).step_by(7) {
+            let k = key(i);
+            let (value, proof) = cmt.get_cloned_with_proof(&k);
+            assert_eq!(value, cmt.get(&k));
+            assert_eq!(proof, cmt.generate_proof(&k));
+            match value {
+                Some(v) => {
+                    assert_eq!(v, vec![i as u8; 3]);
+                    assert!(CartesianMerkleTree::verify_value(proof, k, &v, root));
+                }
+                None => {
+                    assert!(i >= 200);
+                    assert!(CartesianMerkleTree::verify_proof(proof, k, root));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn batch_mutations_match_single_ones() {
+        let single = CartesianMerkleTree::new();
+        let batch = CartesianMerkleTree::new();
+        let pairs: Vec<(Key, Value)> = (0..300).map(|i| (key(i % 250), vec![i as u8])).collect();
+        for (k, v) in pairs.clone() {
+            single.insert(k, v);
+        }
+        batch.insert_many(pairs);
+        assert_eq!(batch.len(), 250);
+        assert_eq!(batch.root_hash(), single.root_hash());
+        // key 10 was last written by i = 260
+        assert_eq!(batch.get(&key(10)), Some(vec![4]));
+
+        let doomed: Vec<Key> = (200..260).map(key).collect();
+        let removed = batch.remove_many(&doomed);
+        for k in &doomed {
+            single.remove(k);
+        }
+        assert_eq!(removed.iter().flatten().count(), 50);
+        assert_eq!(removed[0], Some(vec![200]));
+        assert_eq!(removed[55], None);
+        assert_eq!(batch.len(), 200);
+        assert_eq!(batch.root_hash(), single.root_hash());
+    }
+
+    #[test]
+    fn contains_key_is_exact_under_concurrent_writes() {
+        use std::sync::atomic::AtomicBool;
+
+        // even keys stay put while a writer inserts and removes the odd ones
+        // around them, reshaping the paths readers walk
+        let locked = CartesianMerkleTree::<Value>::new();
+        let snapshot = SnapshotTree::new();
+        for i in (0..1000).step_by(2) {
+            locked.insert(key(i), vec![0]);
+            snapshot.insert(key(i), vec![0]);
+        }
+        let done = AtomicBool::new(false);
+        thread::scope(|s| {
+            s.spawn(|| {
+                for round in 0..10 {
+                    for i in (1..1000).step_by(2) {
+                        if round % 2 == 0 {
+                            locked.insert(key(i), vec![]);
+                            snapshot.insert(key(i), vec![]);
+                        } else {
+                            locked.remove(&key(i));
+                            snapshot.remove(&key(i));
+                        }
+                    }
+                }
+                done.store(true, Ordering::Release);
+            });
+            for _ in 0..2 {
+                s.spawn(|| {
+                    let mut checks = 0;
+                    while !done.load(Ordering::Acquire) || checks == 0 {
+                        for i in (0..1000).step_by(2) {
+                            assert!(locked.contains_key(&key(i)));
+                            assert!(snapshot.contains_key(&key(i)));
+                            assert!(!locked.contains_key(&key(i + 1000)));
+                            assert!(!snapshot.contains_key(&key(i + 1000)));
+                        }
+                        checks += 1;
+                    }
+                });
+            }
+        });
+        assert_eq!((locked.len(), snapshot.len()), (500, 500));
+        assert!(!locked.contains_key(&key(1)) && !snapshot.contains_key(&key(1)));
+    }
+
+    #[test]
+    fn sharded_proofs_select_their_shard() {
+        // spread keys over the leading bytes so every shard gets some
+        let spread = |i: usize| {
+            let mut k = key(i);
+            k[..2].copy_from_slice(&((i as u16).wrapping_mul(40503)).to_be_bytes());
+            k
+        };
+        let cmt = Arc::new(ShardedCMT::new(4));
+        thread::scope(|s| {
+            for t in 0..8 {
+                let cmt = Arc::clone(&cmt);
+                s.spawn(move || {
+                    for i in (t..800).step_by(8) {
+                        cmt.insert(spread(i), vec![i as u8]);
+                    }
+                });
+            }
+        });
+        assert_eq!((cmt.len(), cmt.shard_count()), (800, 16));
+        assert!((0..16).all(|i| !cmt.shard(i).is_empty()));
+
+        let root = cmt.root_hash();
+        for i in (0..1000).step_by(9) {
+            let k = spread(i);
+            let proof = cmt.generate_proof(&k);
+            assert_eq!(proof.shard as usize, cmt.shard_of(&k));
+            assert_eq!(proof.proof.existence, i < 800);
+            assert!(proof.verify(&k, &root));
+
+            // moved to another shard, the path no longer folds to the root
+            let mut moved = proof.clone();
+            moved.shard ^= 1;
+            let mut other = k;
+            other[0] ^= 0x10;
+            assert!(!moved.verify(&other, &root));
+        }
+
+        // a writer in the proven key's own shard can't split the proof from
+        // the shard root it carries
+        let busy = ShardedCMT::<Value>::new(1);
+        for i in 0..200 {
+            busy.insert(key(i), vec![0]);
+        }
+        thread::scope(|s| {
+            s.spawn(|| {
+                for round in 1..50u8 {
+                    for i in 0..200 {
+                        busy.insert(key(i), vec![round]);
+                    }
+                }
+            });
+            for i in (0..2000).map(|i| i % 250) {
+                let k = key(i);
+                let proof = busy.generate_proof(&k);
+                assert_eq!(proof.shard, 0);
+                assert!(CartesianMerkleTree::verify_proof(
+                    proof.proof.clone(),
+                    k,
+                    proof.shard_root
+                ));
+            }
+        });
+        let quiet = busy.root_hash();
+        assert!(busy.generate_proof(&key(7)).verify(&key(7), &quiet));
+
+        let before = root;
+        cmt.insert(spread(5000), vec![]);
+        assert_ne!(cmt.root_hash(), before);
+
+        // an empty shard can only prove absence
+        let sparse = ShardedCMT::<Value>::new(2);
+        sparse.insert([0xff; 32], vec![1]);
+        let absent = [0u8; 32];
+        let proof = sparse.generate_proof(&absent);
+        assert_eq!(proof.shard_root, empty_hash());
+        assert!(proof.verify(&absent, &sparse.root_hash()));
+        let member = sparse.generate_proof(&[0xff; 32]);
+        assert!(member.verify(&[0xff; 32], &sparse.root_hash()));
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn async_tree_serves_concurrent_tasks() {
+        let cmt = Arc::new(AsyncCMT::new());
+        let writers: Vec<_> = (0..4)
+            .map(|t| {
+                let cmt = Arc::clone(&cmt);
+                tokio::spawn(async move {
+                    for i in (t..400).step_by(4) {
+                        cmt.insert(key(i), vec![i as u8]).await;
+                        // reads interleave with other tasks' writes
+                        assert_eq!(cmt.get(&key(i)).await, Some(vec![i as u8]));
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.await.unwrap();
+        }
+        assert_eq!(cmt.len().await, 400);
+
+        let root = cmt.root_hash().await.unwrap();
+        let (value, proof, at) = cmt.get_with_proof(&key(7)).await;
+        assert_eq!(at, Some(root));
+        assert!(CartesianMerkleTree::verify_value(
+            proof,
+            key(7),
+            &value.unwrap(),
+            root
+        ));
+        assert!(CartesianMerkleTree::verify_proof(
+            cmt.generate_proof(&key(999)).await,
+            key(999),
+            root
+        ));
+        assert_eq!(cmt.remove(&key(7)).await, Some(vec![7]));
+        assert_ne!(cmt.root_hash().await, Some(root));
+    }
+
+    #[test]
+    fn snapshot_clone_is_independent() {
+        let keys: Vec<Key> = (0..200).map(key).collect();
+        let cmt = CartesianMerkleTree::new();
+        for k in &keys[..100] {
+            cmt.insert(*k, vec![1u8]);
+        }
+        let before = root_hash_of(&cmt);
+
+        let fork = cmt.snapshot_clone();
+        assert_eq!(root_hash_of(&fork), before);
+
+        for k in &keys[100..] {
+            fork.insert(*k, vec![2u8]);
+        }
+        for k in &keys[..50] {
+            fork.remove(k);
+        }
+
+        assert_eq!(root_hash_of(&cmt), before);
+        assert_ne!(root_hash_of(&fork), before);
+        assert_eq!(cmt.len(), 100);
+        assert_eq!(fork.len(), 150);
+        for k in &keys[..100] {
+            assert!(cmt.contains_key(k));
+        }
+        for k in &keys[100..] {
+            assert!(!cmt.contains_key(k));
+            assert!(fork.contains_key(k));
+        }
+    }
+
+    #[test]
+    fn proofs_bind_values() {
+        let keys: Vec<Key> = (0..50).map(key).collect();
+        let cmt = CartesianMerkleTree::new();
+        for k in &keys {
+            cmt.insert(*k, k[..8].to_vec());
+        }
+        let root = root_hash_of(&cmt);
+        let k = keys[17];
+        let proof = cmt.generate_proof(&k);
+        assert!(CartesianMerkleTree::verify_value(
+            cmt.generate_proof(&k),
+            k,
+            &k[..8].to_vec(),
+            root
+        ));
+        assert!(!CartesianMerkleTree::verify_value(
+            cmt.generate_proof(&k),
+            k,
+            &b"forged".to_vec(),
+            root
+        ));
+
+        cmt.insert(k, b"forged".to_vec());
+        assert_ne!(root_hash_of(&cmt), root);
+        assert!(!CartesianMerkleTree::verify_proof(
+            proof,
+            k,
+            root_hash_of(&cmt)
+        ));
+    }
+
+    #[test]
+    fn inserts_that_split_keep_the_tree_consistent() {
+        fn check(n: &TreeNode, inorder: &mut Vec<Key>) {
+            let child =
+                |c: &Option<Box<TreeNode>>| c.as_ref().map(|c| c.hash).unwrap_or_else(empty_hash);
+            if let Some(l) = n.left.as_deref() {
+                assert!(l.priority <= n.priority);
+                check(l, inorder);
+            }
+            inorder.push(n.key);
+            if let Some(r) = n.right.as_deref() {
+                assert!(r.priority <= n.priority);
+                check(r, inorder);
+            }
+            assert_eq!(
+                n.hash,
+                calculate_merkle_hash(&n.key, &n.value_hash, &child(&n.left), &child(&n.right))
+            );
+        }
+
+        let cmt = CartesianMerkleTree::new();
+        for i in 0..1000 {
+            cmt.insert(key(i), vec![0u8]);
+        }
+        assert_eq!(cmt.len(), 1000);
+
+        let mut inorder = Vec::new();
+        check(cmt.root.read().as_deref().unwrap(), &mut inorder);
+        let expected: Vec<Key> = (0..1000).map(key).collect();
+        assert_eq!(inorder, expected);
+    }
+
+    #[test]
+    fn parallel_verification_matches_one_by_one() {
+        let cmt = CartesianMerkleTree::new();
+        for i in 0..500 {
+            cmt.insert(key(2 * i), vec![i as u8]);
+        }
+        let root = cmt.root_hash().unwrap();
+        let mut proofs: Vec<(Proof, Key)> = (0..1000)
+            .map(|i| (cmt.generate_proof(&key(i)), key(i)))
+            .collect();
+        assert!(verify_all_par(&proofs, &root));
+        assert!(verify_proofs_par(&proofs, &root).iter().all(|ok| *ok));
+
+        // a proof checked against another key, and one with a bad sibling
+        proofs[10].1 = key(12);
+        proofs[301].0.suffix = [[9; 32], [9; 32]];
+        let results = verify_proofs_par(&proofs, &root);
+        for (i, (ok, (proof, key))) in results.iter().zip(&proofs).enumerate() {
+            assert_eq!(
+                *ok,
+                CartesianMerkleTree::verify_proof(proof.clone(), *key, root),
+                "proof {i}"
+            );
+        }
+        assert!(!results[10] && !results[301]);
+        assert_eq!(results.iter().filter(|ok| !**ok).count(), 2);
+        assert!(!verify_all_par(&proofs, &root));
+        assert!(verify_all_par(&[], &root));
+    }
+
+    #[test]
+    fn removing_above_a_minimum_priority_only_child() {
+        let cmt = CartesianMerkleTree::<Value>::with_priority_fn(|k: &Key| {
+            if *k == key(5) {
+                0
             } else {
-                cur = n.right.as_ref();
+                i128::MIN
             }
+        });
+        cmt.insert(key(5), vec![1]);
+        cmt.insert(key(3), vec![2]);
+        cmt.remove(&key(5));
+        assert_eq!(cmt.len(), 1);
+        assert!(cmt.contains_key(&key(3)));
+        let proof = cmt.generate_proof(&key(3));
+        assert!(CartesianMerkleTree::verify_proof(
+            proof,
+            key(3),
+            cmt.root_hash().unwrap()
+        ));
+    }
+
+    #[test]
+    fn witnessless_nonexistence_proofs_are_rejected() {
+        // what an empty tree hands out, which only proves the empty tree
+        let tree = CartesianMerkleTree::<Value>::new();
+        let empty = tree.generate_proof(&key(1));
+        assert!(!empty.existence && empty.nonexistence_key.is_none());
+        assert_eq!(tree.committed_root(), EMPTY_ROOT);
+        assert!(CartesianMerkleTree::verify_proof(
+            empty.clone(),
+            key(1),
+            EMPTY_ROOT
+        ));
+        assert!(CartesianMerkleTree::verify_strict(
+            empty.clone(),
+            key(1),
+            EMPTY_ROOT
+        ));
+        assert!(!CartesianMerkleTree::verify_strict(
+            empty.clone(),
+            key(1),
+            [1; 32]
+        ));
+        assert_eq!(
+            CartesianMerkleTree::verify_proof_detailed(empty, key(1), [1; 32]),
+            Err(VerifyError::EmptyProof)
+        );
+
+        let cmt = CartesianMerkleTree::new();
+        for i in 0..20 {
+            cmt.insert(key(2 * i), vec![0u8]);
         }
+        let root = cmt.root_hash().unwrap();
+        let mut stripped = cmt.generate_proof(&key(7));
+        stripped.nonexistence_key = None;
+        assert!(!CartesianMerkleTree::verify_proof(
+            stripped.clone(),
+            key(7),
+            root
+        ));
+        assert_eq!(
+            CartesianMerkleTree::verify_proof_detailed(stripped.clone(), key(7), root),
+            Err(VerifyError::MissingNonExistenceKey)
+        );
+        assert!(!CartesianMerkleTree::verify_proof(
+            stripped,
+            key(7),
+            EMPTY_ROOT
+        ));
+    }
 
-        let (left_h, right_h, non_ex_key) = if existence {
-            let ln = last
-                .unwrap()
-                .left
-                .as_ref()
-                .map(|x| x.hash.clone())
-                .unwrap_or_default();
-            let rn = last
-                .unwrap()
-                .right
-                .as_ref()
-                .map(|x| x.hash.clone())
-                .unwrap_or_default();
-            (ln, rn, None)
-        } else {
-            // non-existence: use the last traversed node as witness key
-            let witness = prefix.last().map(|(k, _)| k.clone());
-            let (ln, rn) = match cur {
-                Some(n) => (
-                    n.left.as_ref().map(|x| x.hash.clone()).unwrap_or_default(),
-                    n.right.as_ref().map(|x| x.hash.clone()).unwrap_or_default(),
-                ),
-                None => (Vec::new(), Vec::new()),
+    #[test]
+    fn nonexistence_proofs_pin_the_gap() {
+        let keys: Vec<Key> = (1..=50).map(|i| key(10 * i)).collect();
+        let cmt = CartesianMerkleTree::new();
+        for k in &keys {
+            cmt.insert(*k, vec![0u8]);
+        }
+        let root = cmt.root_hash().unwrap();
+
+        // the witnesses come out as cmt-core builds them, below the minimum,
+        // above the maximum and in an interior gap alike
+        let core: cmt_core::CartesianMerkleTree = keys.iter().map(|k| (*k, vec![0u8])).collect();
+        assert_eq!(core.root_hash(), Some(root));
+
+        let cases = [key(5), key(1000), key(255)];
+        for absent in cases {
+            assert_eq!(cmt.generate_proof(&absent), core.generate_proof(&absent));
+            assert!(CartesianMerkleTree::verify_strict(
+                cmt.generate_proof(&absent),
+                absent,
+                root
+            ));
+            for other in cases.iter().chain(&[key(250)]).filter(|k| **k != absent) {
+                let proof = cmt.generate_proof(&absent);
+                assert_eq!(
+                    CartesianMerkleTree::verify_proof_detailed(proof, *other, root),
+                    Err(VerifyError::KeyNotInGap)
+                );
+            }
+        }
+        for i in 0..=50 {
+            let absent = key(10 * i + 5);
+            let proof = cmt.generate_proof(&absent);
+            assert!(CartesianMerkleTree::verify_proof(proof, absent, root));
+        }
+    }
+
+    #[test]
+    fn racing_removes_succeed_exactly_once() {
+        let cmt = Arc::new(CartesianMerkleTree::new());
+        for i in 0..64 {
+            cmt.insert(key(i), vec![i as u8]);
+        }
+
+        let removers: Vec<_> = (0..8)
+            .map(|_| {
+                let cmt = Arc::clone(&cmt);
+                thread::spawn(move || cmt.remove(&key(17)))
+            })
+            .collect();
+        let results: Vec<Option<Value>> = removers.into_iter().map(|h| h.join().unwrap()).collect();
+
+        assert_eq!(results.iter().filter(|r| r.is_some()).count(), 1);
+        assert!(results.contains(&Some(vec![17])));
+        assert!(!cmt.contains_key(&key(17)));
+        assert_eq!(cmt.len(), 63);
+    }
+
+    #[test]
+    fn stores_structured_values() {
+        #[derive(Debug, Clone, PartialEq)]
+        struct Account {
+            encoded: [u8; 16],
+        }
+        impl AsRef<[u8]> for Account {
+            fn as_ref(&self) -> &[u8] {
+                &self.encoded
+            }
+        }
+        let account = |balance: u64| {
+            let mut encoded = [0u8; 16];
+            encoded[8..].copy_from_slice(&balance.to_be_bytes());
+            Account { encoded }
+        };
+
+        let cmt = CartesianMerkleTree::new();
+        for i in 0..32 {
+            cmt.insert(key(i), account(i as u64 * 100));
+        }
+        let root = cmt.root_hash().unwrap();
+        assert!(CartesianMerkleTree::verify_value(
+            cmt.generate_proof(&key(5)),
+            key(5),
+            &account(500),
+            root
+        ));
+        assert!(!CartesianMerkleTree::verify_value(
+            cmt.generate_proof(&key(5)),
+            key(5),
+            &account(501),
+            root
+        ));
+        assert_eq!(cmt.remove(&key(5)), Some(account(500)));
+    }
+
+    #[test]
+    fn clear_resets_root_and_len() {
+        let cmt = CartesianMerkleTree::new();
+        for i in 0..20 {
+            cmt.insert(key(i), vec![0u8]);
+        }
+        cmt.clear();
+        assert_eq!(cmt.root_hash(), None);
+        assert_eq!(cmt.len(), 0);
+        assert!(!cmt.contains_key(&key(3)));
+    }
+
+    #[test]
+    fn proofs_round_trip_through_bytes() {
+        // xorshift, so the workload is random but reproducible
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        let cmt = CartesianMerkleTree::new();
+        for _ in 0..300 {
+            cmt.insert(key((next() % 1000) as usize), vec![next() as u8]);
+        }
+        let root = cmt.root_hash().unwrap();
+        for _ in 0..300 {
+            let k = key((next() % 1000) as usize);
+            let proof = cmt.generate_proof(&k);
+            let decoded = Proof::from_bytes(&proof.to_bytes()).unwrap();
+            assert_eq!(decoded, proof);
+            assert!(CartesianMerkleTree::verify_proof(decoded, k, root));
+        }
+
+        // arbitrary field contents, including empty hashes
+        fn hash(next: &mut impl FnMut() -> u64) -> Hash {
+            if next().is_multiple_of(4) {
+                empty_hash()
+            } else {
+                std::array::from_fn(|_| next() as u8)
+            }
+        }
+        for _ in 0..300 {
+            let prefix = (0..next() % 5)
+                .map(|i| (key(i as usize), hash(&mut next), hash(&mut next)))
+                .collect();
+            let proof = Proof {
+                prefix,
+                suffix: [hash(&mut next), hash(&mut next)],
+                value_hash: hash(&mut next),
+                existence: next() % 2 == 0,
+                nonexistence_key: (next() % 2 == 0).then(|| key(7)),
+                nonexistence_child: (next() % 2 == 0)
+                    .then(|| (key(9), hash(&mut next), [hash(&mut next), hash(&mut next)])),
             };
-            (ln, rn, witness)
+            let bytes = proof.to_bytes();
+            assert_eq!(Proof::from_bytes(&bytes).unwrap(), proof);
+            assert!(Proof::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        }
+    }
+
+    #[test]
+    fn par_build_matches_sequential_inserts() {
+        let mut pairs: Vec<(Key, Value)> = (0..5000).map(|i| (key(i), vec![i as u8])).collect();
+        // a repeated key, which sequential insertion resolves to the last value
+        pairs.push((key(42), vec![0xaa]));
+
+        let sequential = CartesianMerkleTree::new();
+        for (k, v) in &pairs {
+            sequential.insert(*k, v.clone());
+        }
+        // out of order, but the duplicate still comes after the original
+        pairs[..5000].reverse();
+
+        let built = CartesianMerkleTree::par_build(&pairs);
+        assert_eq!(built.root_hash(), sequential.root_hash());
+        // chunk joins land wherever the thread count puts them
+        for threads in [1, 3, 7] {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap();
+            let chunked = pool.install(|| CartesianMerkleTree::par_build(&pairs));
+            assert_eq!(chunked.root_hash(), sequential.root_hash());
+        }
+        assert_eq!(built.len(), 5000);
+        assert!(CartesianMerkleTree::verify_value(
+            built.generate_proof(&key(42)),
+            key(42),
+            &vec![0xaa],
+            built.root_hash().unwrap()
+        ));
+        assert!(CartesianMerkleTree::<Value>::par_build(&[]).is_empty());
+    }
+
+    #[test]
+    fn snapshot_tree_matches_locked_tree() {
+        // xorshift, so the workload is random but reproducible
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
         };
 
-        let suffix = [left_h, right_h];
+        let locked = CartesianMerkleTree::new();
+        let swapped = SnapshotTree::new();
+        for _ in 0..3000 {
+            let k = key((next() % 500) as usize);
+            if next() % 3 == 0 {
+                assert_eq!(swapped.remove(&k), locked.remove(&k));
+            } else {
+                let v = vec![next() as u8];
+                locked.insert(k, v.clone());
+                swapped.insert(k, v);
+            }
+            assert_eq!(swapped.root_hash(), locked.root_hash());
+        }
+        assert_eq!(swapped.len(), locked.len());
 
-        Proof {
-            prefix,
-            suffix,
-            existence,
-            nonexistence_key: non_ex_key,
+        let root = swapped.root_hash().unwrap();
+        for i in 0..500 {
+            let proof = swapped.generate_proof(&key(i));
+            assert_eq!(proof, locked.generate_proof(&key(i)));
+            assert!(CartesianMerkleTree::verify_proof(proof, key(i), root));
         }
+
+        swapped.clear();
+        assert!(swapped.is_empty());
+        assert_eq!(swapped.root_hash(), None);
     }
-    pub fn verify_proof(proof: Proof, key: Key, root_hash: Hash) -> bool
-where {
-        let mut acc = Vec::new();
-        if proof.existence {
-            acc = calculate_merkle_hash(&key, proof.suffix[0].as_ref(), proof.suffix[1].as_ref());
-        } else {
-            acc = calculate_merkle_hash(
-                &proof.nonexistence_key.unwrap(),
-                proof.suffix[0].as_ref(),
-                proof.suffix[1].as_ref(),
-            )
+
+    #[test]
+    fn snapshots_stay_pinned_under_writes() {
+        let cmt = Arc::new(SnapshotTree::new());
+        for i in 0..1000 {
+            cmt.insert(key(i), vec![i as u8]);
         }
+        let pinned = cmt.snapshot();
+        let pinned_root = pinned.root_hash().unwrap();
 
-        for (k, mh) in proof.prefix {
-            acc = calculate_merkle_hash(&k, &acc, &mh)
+        let writer = {
+            let cmt = Arc::clone(&cmt);
+            thread::spawn(move || {
+                for i in 0..1000 {
+                    cmt.remove(&key(i));
+                    cmt.insert(key(i + 1000), vec![0xff]);
+                }
+            })
+        };
+        // every fresh snapshot is internally consistent mid-churn
+        for i in 0..500 {
+            let snapshot = cmt.snapshot();
+            let root = snapshot.root_hash().unwrap();
+            let k = key(i * 4);
+            assert!(CartesianMerkleTree::verify_proof(
+                snapshot.generate_proof(&k),
+                k,
+                root
+            ));
         }
+        writer.join().unwrap();
 
-        acc == root_hash
+        assert_eq!(pinned.root_hash(), Some(pinned_root));
+        assert_eq!(pinned.len(), 1000);
+        assert_eq!(pinned.get(&key(7)), Some(&vec![7]));
+        assert!(CartesianMerkleTree::verify_value(
+            pinned.generate_proof(&key(7)),
+            key(7),
+            &vec![7],
+            pinned_root
+        ));
+        assert!(!cmt.contains_key(&key(7)));
+        assert_eq!(cmt.len(), 1000);
     }
-}
 
-pub struct Proof {
-    pub prefix: Vec<(Key, Hash)>,
-    pub suffix: [Hash; 2],
-    pub existence: bool,
-    pub nonexistence_key: Option<Key>,
-}
+    #[test]
+    fn degenerate_tree_copies_without_recursing() {
+        // priority grows with the key, so ascending inserts leave a single
+        // left spine
+        const N: usize = 200_000;
+        let by_key = |k: &Key| u64::from_be_bytes(k[..8].try_into().unwrap()) as Priority;
+        let cmt = CartesianMerkleTree::<Value>::with_priority_fn(by_key);
+        for i in 0..N {
+            cmt.insert(key(i), vec![]);
+        }
+        let root = root_hash_of(&cmt);
 
-fn find_priority(key: &Key) -> Priority {
-    use sha2::{Digest, Sha256};
-    let digest = Sha256::digest(key.as_ref());
-    let mut bytes = [0u8; 16];
-    bytes.copy_from_slice(&digest[..16]);
-    i128::from_be_bytes(bytes) as i128
+        let fork = cmt.snapshot_clone();
+        let pinned = cmt.pinned();
+        assert_eq!(root_hash_of(&fork), root);
+        assert_eq!(pinned.root_hash(), Some(root));
+        assert_eq!(cmt.remove(&key(0)), Some(vec![]));
+        drop(fork);
+        drop(pinned);
+
+        let spine = cmt.root.read().as_deref().unwrap().clone();
+        assert_eq!(spine.key, key(N - 1));
+        assert!(format!("{spine:?}").len() < 1000);
+    }
+
+    #[test]
+    fn read_guard_cursor_walks_the_tree() {
+        let cmt = CartesianMerkleTree::<Value>::new();
+        assert!(cmt.read().root_node().is_none());
+        for i in 0..200 {
+            cmt.insert(key(i), vec![i as u8]);
+        }
+
+        let guard = cmt.read();
+        let root = guard.root_node().unwrap();
+        assert_eq!(Some(*root.hash()), guard.root_hash());
+
+        let mut keys = Vec::new();
+        let mut stack = vec![root];
+        while let Some(n) = stack.pop() {
+            let child = |c: Option<NodeRef<Value>>| c.map_or_else(empty_hash, |c| *c.hash());
+            assert_eq!(
+                *n.hash(),
+                calculate_merkle_hash(n.key(), n.value_hash(), &child(n.left()), &child(n.right()))
+            );
+            assert_eq!(n.value(), &vec![n.key()[7]]);
+            keys.push(*n.key());
+            stack.extend(n.left().into_iter().chain(n.right()));
+        }
+        drop(guard);
+        keys.sort();
+        assert_eq!(keys, (0..200).map(key).collect::<Vec<_>>());
+        assert_eq!(
+            cmt.root_hash(),
+            Some(*cmt.read().root_node().unwrap().hash())
+        );
+    }
 }
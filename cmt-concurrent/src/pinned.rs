@@ -0,0 +1,45 @@
+//! module for read-only views pinned to one version of the locked tree
+use crate::{proof_for, Hash, Key, Proof, Subtree};
+
+/// A private copy of a `CartesianMerkleTree` as it was when `pinned` was
+/// called. Reads never touch the live tree's lock, and writes to the live
+/// tree don't affect it, so its root hash and proofs always agree.
+///
+/// Pinning copies the whole tree, O(n), under one read lock. When pinned
+/// reads are frequent, `SnapshotTree` pins a version in O(1) instead.
+pub struct PinnedView<V> {
+    pub(crate) root: Subtree<V>,
+    pub(crate) len: usize,
+}
+
+impl<V> PinnedView<V> {
+    /// Hash of the pinned root, or `None` if the tree was empty.
+    pub fn root_hash(&self) -> Option<Hash> {
+        self.root.as_ref().map(|n| n.hash)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, key: &Key) -> Option<&V> {
+        let mut cur = self.root.as_deref();
+        while let Some(n) = cur {
+            cur = match key.cmp(&n.key) {
+                std::cmp::Ordering::Equal => return Some(&n.value),
+                std::cmp::Ordering::Less => n.left.as_deref(),
+                std::cmp::Ordering::Greater => n.right.as_deref(),
+            };
+        }
+        None
+    }
+
+    /// Proof for `key` against `root_hash`.
+    pub fn generate_proof(&self, key: &Key) -> Proof {
+        proof_for(self.root.as_deref(), key)
+    }
+}
@@ -0,0 +1,222 @@
+//! module for the sharded concurrent CMT
+//!
+//! `ShardedCMTWith` splits the key space into `2^bits` shards by the top
+//! `bits` bits of the key, each an independent locked tree. Writers to
+//! different shards never wait for each other, so write throughput scales
+//! with the number of shards that are busy at once. Keys should be spread
+//! evenly over their leading bits (hashes are); a key space clustered
+//! under one prefix falls into one shard and gains nothing.
+//!
+//! The combined root is a binary Merkle tree over the shard roots in shard
+//! order, an empty shard counting as the empty hash. A `ShardedProof` is
+//! therefore two proofs: the usual CMT proof inside the key's shard, and a
+//! shard-selection path from that shard's root up to the combined root.
+//! The path fixes the shard's position, so a proof can't be replayed
+//! against a different shard.
+use crate::{empty_hash, CartesianMerkleTreeWith, Hash, Hasher, Key, Proof, Sha256Hasher, Value};
+
+/// Domain tag for inner nodes of the shard tree, so they can never be
+/// mistaken for CMT node hashes.
+const SHARD_NODE_TAG: &[u8] = b"cmt:shard-node";
+
+/// Most shard bits supported, i.e. at most 65536 shards.
+pub const MAX_SHARD_BITS: u32 = 16;
+
+/// Concurrent tree split into independently locked shards; see the module
+/// docs.
+pub struct ShardedCMTWith<V, H: Hasher> {
+    shards: Box<[CartesianMerkleTreeWith<V, H>]>,
+    bits: u32,
+}
+
+pub type ShardedCMT<V = Value> = ShardedCMTWith<V, Sha256Hasher>;
+
+/// Proof for a key of a sharded tree against its combined root.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShardedProof {
+    /// Index of the shard owning the key.
+    pub shard: u32,
+    /// Root of that shard; the empty hash if it holds nothing.
+    pub shard_root: Hash,
+    /// Sibling hashes from the shard's leaf up to the combined root.
+    pub shard_path: Vec<Hash>,
+    /// Membership or non-existence proof inside the shard.
+    pub proof: Proof,
+}
+
+fn shard_of(key: &Key, bits: u32) -> usize {
+    match bits {
+        0 => 0,
+        _ => (u16::from_be_bytes([key[0], key[1]]) >> (16 - bits)) as usize,
+    }
+}
+
+fn shard_node<H: Hasher>(left: &Hash, right: &Hash) -> Hash {
+    let mut buf = Vec::with_capacity(SHARD_NODE_TAG.len() + 2 * left.len());
+    buf.extend_from_slice(SHARD_NODE_TAG);
+    buf.extend_from_slice(left);
+    buf.extend_from_slice(right);
+    H::hash(&buf)
+}
+
+impl<V, H> ShardedCMTWith<V, H>
+where
+    V: AsRef<[u8]> + Clone + Send + Sync,
+    H: Hasher,
+{
+    /// Empty tree with `2^bits` shards.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bits` exceeds `MAX_SHARD_BITS`.
+    pub fn new(bits: u32) -> Self {
+        assert!(
+            bits <= MAX_SHARD_BITS,
+            "at most {MAX_SHARD_BITS} shard bits"
+        );
+        Self {
+            shards: (0..1usize << bits)
+                .map(|_| CartesianMerkleTreeWith::new())
+                .collect(),
+            bits,
+        }
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Index of the shard that owns `key`.
+    pub fn shard_of(&self, key: &Key) -> usize {
+        shard_of(key, self.bits)
+    }
+
+    /// The shard at `index`, e.g. to read its root or len directly.
+    pub fn shard(&self, index: usize) -> &CartesianMerkleTreeWith<V, H> {
+        &self.shards[index]
+    }
+
+    fn owner(&self, key: &Key) -> &CartesianMerkleTreeWith<V, H> {
+        &self.shards[self.shard_of(key)]
+    }
+
+    /// Inserts under the owning shard's write lock only.
+    pub fn insert(&self, key: Key, value: V) {
+        self.owner(&key).insert(key, value);
+    }
+
+    pub fn remove(&self, key: &Key) -> Option<V> {
+        self.owner(key).remove(key)
+    }
+
+    pub fn get(&self, key: &Key) -> Option<V> {
+        self.owner(key).get(key)
+    }
+
+    pub fn contains_key(&self, key: &Key) -> bool {
+        self.owner(key).contains_key(key)
+    }
+
+    /// Sum of the shard sizes. Shards are read one at a time, so under
+    /// concurrent writes this is only a snapshot of each.
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|s| s.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(|s| s.is_empty())
+    }
+
+    /// Combined root over every shard root; see the module docs. Shard
+    /// roots are read one at a time, so it matches proofs taken while no
+    /// writer is active.
+    pub fn root_hash(&self) -> Hash {
+        let mut level: Vec<Hash> = self.shard_roots();
+        while level.len() > 1 {
+            level = level
+                .chunks(2)
+                .map(|pair| shard_node::<H>(&pair[0], &pair[1]))
+                .collect();
+        }
+        level[0]
+    }
+
+    fn shard_roots(&self) -> Vec<Hash> {
+        self.shards
+            .iter()
+            .map(|s| s.root_hash().unwrap_or_else(empty_hash))
+            .collect()
+    }
+
+    /// Proof for `key` against `root_hash`. The in-shard proof and
+    /// `shard_root` are read under the shard's one read lock, so they always
+    /// agree. The other shards' roots in `shard_path` are read one lock at a
+    /// time afterwards, a best-effort snapshot: as with `root_hash`, the
+    /// whole proof only matches a combined root read while no writer is
+    /// active.
+    pub fn generate_proof(&self, key: &Key) -> ShardedProof {
+        let shard = self.shard_of(key);
+        let (proof, root) = self.shards[shard].proof_with_root(key);
+        let shard_root = root.unwrap_or_else(empty_hash);
+        let mut level = self.shard_roots();
+        level[shard] = shard_root;
+
+        let mut shard_path = Vec::with_capacity(self.bits as usize);
+        let mut index = shard;
+        while level.len() > 1 {
+            shard_path.push(level[index ^ 1]);
+            level = level
+                .chunks(2)
+                .map(|pair| shard_node::<H>(&pair[0], &pair[1]))
+                .collect();
+            index /= 2;
+        }
+        ShardedProof {
+            shard: shard as u32,
+            shard_root,
+            shard_path,
+            proof,
+        }
+    }
+}
+
+impl ShardedProof {
+    /// Checks the proof for `key` against a SHA-256 sharded tree's
+    /// `root_hash`.
+    pub fn verify(&self, key: &Key, root_hash: &Hash) -> bool {
+        self.verify_with::<Sha256Hasher>(key, root_hash)
+    }
+
+    /// Same as `verify`, for trees built with hasher `H`. The shard count
+    /// is read off the path length, and the key must fall in the claimed
+    /// shard.
+    pub fn verify_with<H: Hasher>(&self, key: &Key, root_hash: &Hash) -> bool {
+        let bits = self.shard_path.len() as u32;
+        if bits > MAX_SHARD_BITS || shard_of(key, bits) != self.shard as usize {
+            return false;
+        }
+
+        let in_shard = if self.shard_root == empty_hash() {
+            // an empty shard holds nothing, which is all a proof can claim
+            !self.proof.existence
+        } else {
+            CartesianMerkleTreeWith::<Value, H>::verify_proof(
+                self.proof.clone(),
+                *key,
+                self.shard_root,
+            )
+        };
+
+        let mut hash = self.shard_root;
+        let mut index = self.shard;
+        for sibling in &self.shard_path {
+            hash = if index & 1 == 0 {
+                shard_node::<H>(&hash, sibling)
+            } else {
+                shard_node::<H>(sibling, &hash)
+            };
+            index /= 2;
+        }
+        in_shard && hash == *root_hash
+    }
+}
@@ -0,0 +1,175 @@
+//! module for the snapshot-read variant of the concurrent CMT
+//!
+//! `SnapshotTreeWith` keeps its root behind an `ArcSwap` instead of a lock.
+//! Nodes are immutable and shared between versions: a write copies the
+//! nodes on the paths it changes, links them to the untouched subtrees, and
+//! publishes the new root in one atomic swap. Readers load the current root
+//! without locking, so a long proof traversal never holds up a writer, and
+//! every read through a `TreeSnapshot` sees one consistent version.
+//!
+//! The nodes and their path-copying operations are `cmt_core`'s, the same
+//! ones behind `PersistentCMT`.
+use crate::{find_priority, Hash, Hasher, Key, Priority, PriorityFn, Proof, Sha256Hasher, Value};
+use arc_swap::ArcSwap;
+use cmt_core::persistent::{self, Link, Node};
+use parking_lot::Mutex;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+/// One published state of the tree.
+struct Version<V> {
+    root: Link<V>,
+    len: usize,
+}
+
+/// Releases nodes with an explicit stack; the default recursive drop could
+/// overflow on a degenerate tree.
+impl<V> Drop for Version<V> {
+    fn drop(&mut self) {
+        persistent::release(self.root.take());
+    }
+}
+
+/// Concurrent tree whose readers never take a lock; see the module docs.
+/// Writers are serialized among themselves, but never wait for readers.
+pub struct SnapshotTreeWith<V, H: Hasher> {
+    current: ArcSwap<Version<V>>,
+    writer: Mutex<()>,
+    priority_fn: PriorityFn,
+    hasher: PhantomData<fn() -> H>,
+}
+
+pub type SnapshotTree<V = Value> = SnapshotTreeWith<V, Sha256Hasher>;
+
+/// A pinned version of a `SnapshotTreeWith`. Later writes to the tree don't
+/// affect it, so repeated reads (e.g. a root hash and proofs against it)
+/// always agree.
+pub struct TreeSnapshot<V, H: Hasher = Sha256Hasher> {
+    version: Arc<Version<V>>,
+    hasher: PhantomData<fn() -> H>,
+}
+
+impl<V, H> Default for SnapshotTreeWith<V, H>
+where
+    V: AsRef<[u8]> + Clone + Send + Sync,
+    H: Hasher,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<V, H> SnapshotTreeWith<V, H>
+where
+    V: AsRef<[u8]> + Clone + Send + Sync,
+    H: Hasher,
+{
+    pub fn new() -> Self {
+        Self::with_priority_fn(find_priority::<H>)
+    }
+
+    /// Empty tree deriving priorities with `priority_fn` instead of the key
+    /// hash.
+    pub fn with_priority_fn<F>(priority_fn: F) -> Self
+    where
+        F: Fn(&Key) -> Priority + Send + Sync + 'static,
+    {
+        Self {
+            current: ArcSwap::from_pointee(Version { root: None, len: 0 }),
+            writer: Mutex::new(()),
+            priority_fn: Arc::new(priority_fn),
+            hasher: PhantomData,
+        }
+    }
+
+    /// Pins the current version for repeated reads.
+    pub fn snapshot(&self) -> TreeSnapshot<V, H> {
+        TreeSnapshot {
+            version: self.current.load_full(),
+            hasher: PhantomData,
+        }
+    }
+
+    /// Hash of the root node, or `None` for an empty tree.
+    pub fn root_hash(&self) -> Option<Hash> {
+        self.current.load().root.as_ref().map(|n| *n.hash())
+    }
+
+    pub fn len(&self) -> usize {
+        self.current.load().len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains_key(&self, key: &Key) -> bool {
+        persistent::lookup(self.current.load().root.as_deref(), key).is_some()
+    }
+
+    /// Proof for `key` against the version current when the call starts.
+    /// Pair it with a root hash from the same `snapshot` when the two must
+    /// agree under concurrent writes.
+    pub fn generate_proof(&self, key: &Key) -> Proof {
+        self.snapshot().generate_proof(key)
+    }
+
+    /// Removes every entry.
+    pub fn clear(&self) {
+        let _writer = self.writer.lock();
+        self.current.store(Arc::new(Version { root: None, len: 0 }));
+    }
+
+    pub fn insert(&self, key: Key, value: V) {
+        let priority = (self.priority_fn)(&key);
+        let _writer = self.writer.lock();
+        let current = self.current.load_full();
+        let (root, added) = persistent::insert::<V, H>(&current.root, key, value, priority);
+        self.current.store(Arc::new(Version {
+            root,
+            len: current.len + added as usize,
+        }));
+    }
+
+    /// Removes `key`, returning its value if it was present.
+    pub fn remove(&self, key: &Key) -> Option<V> {
+        let _writer = self.writer.lock();
+        let current = self.current.load_full();
+        let (root, found) = persistent::remove::<V, H>(&current.root, key)?;
+        let value = found.value().clone();
+        self.current.store(Arc::new(Version {
+            root,
+            len: current.len - 1,
+        }));
+        Some(value)
+    }
+}
+
+impl<V, H: Hasher> TreeSnapshot<V, H> {
+    /// Hash of the pinned root, or `None` if the tree was empty.
+    pub fn root_hash(&self) -> Option<Hash> {
+        self.version.root.as_ref().map(|n| *n.hash())
+    }
+
+    pub fn len(&self) -> usize {
+        self.version.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains_key(&self, key: &Key) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub fn get(&self, key: &Key) -> Option<&V> {
+        persistent::lookup(self.version.root.as_deref(), key).map(Node::value)
+    }
+
+    /// Membership or non-existence proof for `key` against `root_hash`;
+    /// the same construction as `CartesianMerkleTreeWith::generate_proof`.
+    pub fn generate_proof(&self, key: &Key) -> Proof {
+        persistent::generate_proof(self.version.root.as_deref(), key)
+    }
+}
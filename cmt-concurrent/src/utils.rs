@@ -1,66 +1,110 @@
 //! module to store the utility functions of CMT
-use crate::{Hash, TreeNode};
-use sha2::{Digest, Sha256};
+use crate::{empty_hash, hash_bytes, is_empty_subtree_hash, Hash, Hasher, TreeNode};
 
-pub fn calculate_merkle_hash<K: AsRef<[u8]>>(
+/// Hash a node commits to for its value, cached on the node as
+/// `value_hash`.
+pub fn hash_value<H: Hasher>(value: &[u8]) -> Hash {
+    H::hash(value)
+}
+
+/// Domain tag for nodes whose children are both empty.
+pub const LEAF_TAG: u8 = 0x00;
+/// Domain tag for nodes with at least one child.
+pub const INTERNAL_TAG: u8 = 0x01;
+
+/// `H(tag || key || value_hash || min(left, right) || max(left, right))`,
+/// with every field after the tag prefixed by its length so that no two
+/// distinct inputs serialize to the same bytes. The tag keeps a leaf hash
+/// from ever being read as an internal node hash, or vice versa.
+///
+/// Serializes into a caller-provided scratch buffer, so the insert, remove
+/// and rotation paths reuse one allocation across every hash they
+/// recompute.
+pub fn calculate_merkle_hash_into<H: Hasher, K: AsRef<[u8]>>(
+    buf: &mut Vec<u8>,
     key: &K,
+    value_hash: &Hash,
     left_child_hash: &Hash,
     right_child_hash: &Hash,
 ) -> Hash {
-    let mut buf = Vec::new();
-    buf.extend_from_slice(key.as_ref());
-    if left_child_hash < right_child_hash {
-        buf.extend_from_slice(left_child_hash);
-        buf.extend_from_slice(right_child_hash);
+    let (lo, hi) = if left_child_hash < right_child_hash {
+        (left_child_hash, right_child_hash)
+    } else {
+        (right_child_hash, left_child_hash)
+    };
+    let tag = if is_empty_subtree_hash(lo) && is_empty_subtree_hash(hi) {
+        LEAF_TAG
     } else {
-        buf.extend_from_slice(right_child_hash);
-        buf.extend_from_slice(left_child_hash);
+        INTERNAL_TAG
+    };
+    buf.clear();
+    buf.push(tag);
+    for field in [
+        key.as_ref(),
+        hash_bytes(value_hash),
+        hash_bytes(lo),
+        hash_bytes(hi),
+    ] {
+        buf.extend_from_slice(&(field.len() as u64).to_be_bytes());
+        buf.extend_from_slice(field);
     }
-    let mut hasher = Sha256::new();
-    hasher.update(&buf);
-    hasher.finalize().to_vec()
+    H::hash(buf)
 }
 
-pub fn rotate_left(mut x: Box<TreeNode>) -> Box<TreeNode> {
+/// Lifts `x`'s right child into its place, rehashing both nodes.
+///
+/// # Panics
+///
+/// If `x` has no right child; callers check first.
+pub fn rotate_left<H: Hasher, V>(mut x: Box<TreeNode<V>>, buf: &mut Vec<u8>) -> Box<TreeNode<V>> {
     let mut y = x.right.take().expect("rotate_left requires right child");
 
     // move y.left into x.right
     x.right = y.left.take();
 
     // recompute x.hash
-    let left_hash = x.left.as_ref().map(|n| n.hash.clone()).unwrap_or_default();
-    let right_hash = x.right.as_ref().map(|n| n.hash.clone()).unwrap_or_default();
-    x.hash = calculate_merkle_hash(&x.key, &left_hash, &right_hash);
+    let left_hash = x.left.as_ref().map(|n| n.hash).unwrap_or_else(empty_hash);
+    let right_hash = x.right.as_ref().map(|n| n.hash).unwrap_or_else(empty_hash);
+    x.hash =
+        calculate_merkle_hash_into::<H, _>(buf, &x.key, &x.value_hash, &left_hash, &right_hash);
 
     // put x as left child of y
     y.left = Some(x);
 
     // recompute y.hash
-    let left_hash = y.left.as_ref().map(|n| n.hash.clone()).unwrap_or_default();
-    let right_hash = y.right.as_ref().map(|n| n.hash.clone()).unwrap_or_default();
-    y.hash = calculate_merkle_hash(&y.key, &left_hash, &right_hash);
+    let left_hash = y.left.as_ref().map(|n| n.hash).unwrap_or_else(empty_hash);
+    let right_hash = y.right.as_ref().map(|n| n.hash).unwrap_or_else(empty_hash);
+    y.hash =
+        calculate_merkle_hash_into::<H, _>(buf, &y.key, &y.value_hash, &left_hash, &right_hash);
 
     y
 }
 
-pub fn rotate_right(mut y: Box<TreeNode>) -> Box<TreeNode> {
+/// Lifts `y`'s left child into its place, rehashing both nodes.
+///
+/// # Panics
+///
+/// If `y` has no left child; callers check first.
+pub fn rotate_right<H: Hasher, V>(mut y: Box<TreeNode<V>>, buf: &mut Vec<u8>) -> Box<TreeNode<V>> {
     let mut x = y.left.take().expect("rotate_right requires left child");
 
     // move x.right into y.left
     y.left = x.right.take();
 
     // recompute y.hash
-    let left_hash = y.left.as_ref().map(|n| n.hash.clone()).unwrap_or_default();
-    let right_hash = y.right.as_ref().map(|n| n.hash.clone()).unwrap_or_default();
-    y.hash = calculate_merkle_hash(&y.key, &left_hash, &right_hash);
+    let left_hash = y.left.as_ref().map(|n| n.hash).unwrap_or_else(empty_hash);
+    let right_hash = y.right.as_ref().map(|n| n.hash).unwrap_or_else(empty_hash);
+    y.hash =
+        calculate_merkle_hash_into::<H, _>(buf, &y.key, &y.value_hash, &left_hash, &right_hash);
 
     // put y as right child of x
     x.right = Some(y);
 
     // recompute x.hash
-    let left_hash = x.left.as_ref().map(|n| n.hash.clone()).unwrap_or_default();
-    let right_hash = x.right.as_ref().map(|n| n.hash.clone()).unwrap_or_default();
-    x.hash = calculate_merkle_hash(&x.key, &left_hash, &right_hash);
+    let left_hash = x.left.as_ref().map(|n| n.hash).unwrap_or_else(empty_hash);
+    let right_hash = x.right.as_ref().map(|n| n.hash).unwrap_or_else(empty_hash);
+    x.hash =
+        calculate_merkle_hash_into::<H, _>(buf, &x.key, &x.value_hash, &left_hash, &right_hash);
 
     x
 }
@@ -74,6 +118,9 @@ pub fn rotate_right(mut y: Box<TreeNode>) -> Box<TreeNode> {
 // • nonExistenceKey is used when e does not exist in the tree, and helps verify that e is absent.
 // The initial value of acc is computed as:
 // acc = H((existence?e.k : nonExistenceKey) ∥ proof.suffix[0] ∥ proof.suffix[1])
+// (this implementation also mixes in the hash of each node's value right
+// after its key, so proofs bind keys to values, and prepends a leaf/internal
+// domain tag)
 // ensuring that proof.suffix[0] < proof.suffix[1].
 // Then, acc is iteratively updated using values from prefix:
 // (
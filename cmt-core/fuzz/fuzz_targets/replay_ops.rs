@@ -0,0 +1,21 @@
+//! Replays arbitrary operation streams against a `BTreeMap` reference.
+#![no_main]
+
+use cmt_core::{CartesianMerkleTree, Op};
+use libfuzzer_sys::fuzz_target;
+use std::collections::BTreeMap;
+
+fuzz_target!(|ops: Vec<Op>| {
+    let mut cmt = CartesianMerkleTree::new();
+    let mut reference = BTreeMap::new();
+    for op in ops {
+        let expected = match &op {
+            Op::Insert(k, v) => reference.insert(*k, v.clone()),
+            Op::Remove(k) => reference.remove(k),
+            Op::Get(k) => reference.get(k).cloned(),
+        };
+        assert_eq!(cmt.apply(op), expected);
+    }
+    cmt.validate().unwrap();
+    assert!(cmt.iter().eq(reference.iter()));
+});
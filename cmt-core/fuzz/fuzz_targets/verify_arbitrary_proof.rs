@@ -0,0 +1,12 @@
+//! Feeds structurally arbitrary proofs straight into the verifiers.
+#![no_main]
+
+use cmt_core::{CartesianMerkleTree, Hash, Key, Proof};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|input: (Proof, Key, Hash)| {
+    let (proof, key, root) = input;
+    let strict = CartesianMerkleTree::verify_strict(proof.clone(), key, root.clone());
+    let lax = CartesianMerkleTree::verify_proof(proof, key, root);
+    assert!(!strict || lax);
+});
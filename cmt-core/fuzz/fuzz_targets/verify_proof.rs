@@ -0,0 +1,26 @@
+//! Feeds arbitrary bytes through `Proof::from_bytes` and the verifiers.
+//!
+//! Input layout: 32-byte key | 32-byte claimed root | encoded proof.
+#![no_main]
+
+use cmt_core::{key_from_slice, CartesianMerkleTree, Proof};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 64 {
+        return;
+    }
+    let (key, rest) = data.split_at(32);
+    let (root, bytes) = rest.split_at(32);
+    let key = key_from_slice(key).unwrap();
+
+    let Ok(proof) = Proof::from_bytes(bytes) else {
+        return;
+    };
+    assert_eq!(Proof::from_bytes(&proof.to_bytes()).as_ref(), Ok(&proof));
+
+    let strict = CartesianMerkleTree::verify_strict(proof.clone(), key, root.to_vec());
+    let lax = CartesianMerkleTree::verify_proof(proof, key, root.to_vec());
+    // anything the strict verifier accepts must also pass the plain one
+    assert!(!strict || lax);
+});
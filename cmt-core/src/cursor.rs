@@ -0,0 +1,68 @@
+//! module for read-only cursors over the nodes of a CMT
+//!
+//! `root_node` hands out a `NodeRef` to the root, from which callers can
+//! walk the tree by `left` and `right` and read each node's fields. The
+//! cursor borrows the tree, so nothing can change under it, and it exposes
+//! no way to mutate a node or take its children out.
+use crate::{CartesianMerkleTreeWith, Hash, Hasher, Key, Priority, TreeNode, Value};
+
+/// Read-only handle to a node of a `CartesianMerkleTree`.
+#[derive(Clone, Copy)]
+pub struct NodeRef<'a> {
+    node: &'a TreeNode,
+}
+
+impl<'a> NodeRef<'a> {
+    pub fn key(&self) -> &'a Key {
+        &self.node.key
+    }
+
+    pub fn priority(&self) -> Priority {
+        self.node.priority
+    }
+
+    pub fn value(&self) -> &'a Value {
+        &self.node.value
+    }
+
+    pub fn value_hash(&self) -> &'a Hash {
+        &self.node.value_hash
+    }
+
+    /// Merkle hash of the subtree rooted here.
+    pub fn hash(&self) -> &'a Hash {
+        self.node.hash()
+    }
+
+    /// Number of nodes in the subtree rooted here, including this one.
+    pub fn subtree_size(&self) -> usize {
+        self.node.subtree_size
+    }
+
+    pub fn left(&self) -> Option<NodeRef<'a>> {
+        self.node.left.as_deref().map(|node| NodeRef { node })
+    }
+
+    pub fn right(&self) -> Option<NodeRef<'a>> {
+        self.node.right.as_deref().map(|node| NodeRef { node })
+    }
+}
+
+impl core::fmt::Debug for NodeRef<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("NodeRef")
+            .field("key", &self.node.key)
+            .field("priority", &self.node.priority)
+            .field("subtree_size", &self.node.subtree_size)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<H: Hasher> CartesianMerkleTreeWith<H> {
+    /// Cursor at the root node, or `None` for an empty tree. Stale hashes
+    /// are settled first, so `hash` is set on every node reached from it.
+    pub fn root_node(&self) -> Option<NodeRef<'_>> {
+        self.settle();
+        self.root.as_deref().map(|node| NodeRef { node })
+    }
+}
@@ -0,0 +1,98 @@
+//! module for Graphviz DOT export and text dumps, debugging aids for
+//! looking at tree shape
+use crate::{CartesianMerkleTreeWith, Hasher, TreeNode, Visitor};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+/// Bytes of the key and hash shown in node labels.
+const LABEL_BYTES: usize = 4;
+
+/// Visitor that writes one DOT statement per node and edge.
+struct DotWriter {
+    out: String,
+}
+
+fn id(node: &TreeNode) -> String {
+    format!("n{}", hex::encode(node.key))
+}
+
+impl Visitor for DotWriter {
+    fn visit_pre(&mut self, node: &TreeNode) {
+        let _ = writeln!(
+            self.out,
+            "    {} [label=\"key {}..\\nprio {}\\nhash {}..\"];",
+            id(node),
+            hex::encode(&node.key[..LABEL_BYTES]),
+            node.priority,
+            hex::encode(&node.hash()[..LABEL_BYTES]),
+        );
+        // a lone child gets an invisible sibling so it is drawn on its side
+        let children = [(&node.left, "L"), (&node.right, "R")];
+        let lone = node.left.is_some() != node.right.is_some();
+        for (child, side) in children {
+            match child {
+                Some(c) => {
+                    let _ = writeln!(
+                        self.out,
+                        "    {} -> {} [label=\"{side}\"];",
+                        id(node),
+                        id(c)
+                    );
+                }
+                None if lone => {
+                    let _ = writeln!(
+                        self.out,
+                        "    {0}{side} [style=invis];\n    {0} -> {0}{side} [style=invis];",
+                        id(node)
+                    );
+                }
+                None => {}
+            }
+        }
+    }
+}
+
+impl<H: Hasher> CartesianMerkleTreeWith<H> {
+    /// Graphviz DOT rendering of the tree, e.g. for `dot -Tpng`. Each node
+    /// shows the start of its key, its priority and the start of its hash;
+    /// edges are labelled `L` and `R`.
+    pub fn to_dot(&self) -> String {
+        let mut writer = DotWriter {
+            out: String::from("digraph cmt {\n    node [shape=box, fontname=\"monospace\"];\n"),
+        };
+        self.walk(&mut writer);
+        writer.out.push_str("}\n");
+        writer.out
+    }
+
+    /// Full indented dump of the tree, one node per line in pre-order,
+    /// children indented under their parent and marked `L` or `R`. Unlike
+    /// `Debug`, which only summarizes, this is O(n) in output size.
+    pub fn debug_tree(&self) -> String {
+        self.settle();
+        let mut out = String::new();
+        let mut stack: Vec<(&TreeNode, usize, &str)> = self
+            .root
+            .as_deref()
+            .map(|n| (n, 0, "root"))
+            .into_iter()
+            .collect();
+        while let Some((n, depth, side)) = stack.pop() {
+            let _ = writeln!(
+                out,
+                "{:indent$}{side} key {}.. prio {} hash {}.. size {}",
+                "",
+                hex::encode(&n.key[..LABEL_BYTES]),
+                n.priority,
+                hex::encode(&n.hash()[..LABEL_BYTES]),
+                n.subtree_size,
+                indent = 2 * depth,
+            );
+            stack.extend(n.right.as_deref().map(|r| (r, depth + 1, "R")));
+            stack.extend(n.left.as_deref().map(|l| (l, depth + 1, "L")));
+        }
+        out
+    }
+}
@@ -0,0 +1,318 @@
+//! module for the binary wire format of CMT proofs
+//!
+//! Layout (all integers little-endian):
+//!
+//! ```text
+//! header   : version u8 | hash algorithm u8
+//! flags    : u8  (bit 0 = existence, bit 1 = nonexistence key present,
+//!                 bit 2 = witness child opening present)
+//! witness  : [u8; 32]          (only if flags bit 1 is set)
+//! child    : key [u8; 32] | 3 x (len u8 | hash bytes)
+//!                              (only if flags bit 2 is set: value hash,
+//!                               then the two sorted child hashes)
+//! value    : len u8 | value hash bytes
+//! suffix   : 2 x (len u8 | hash bytes)
+//! prefix   : count u32 | count x (key [u8; 32] | len u8 | value hash bytes
+//!                                  | len u8 | hash bytes)
+//! ```
+//!
+//! A hash length is 0 for the empty hash and 32 otherwise. The hash
+//! algorithm byte is the `Hasher::ALGO_ID` of the tree the proof came from.
+//! `to_bytes` and `from_bytes` assume SHA-256; proofs from trees with other
+//! hashers go through `to_bytes_with` and `from_bytes_with`, and decoding
+//! rejects a header naming a different hasher than the one expected.
+//!
+//! Multi-proofs share the header and then list their items in pre-order:
+//!
+//! ```text
+//! count    : u32
+//! items    : count x (tag u8 | body)
+//!            tag 0 = subtree: len u8 | hash bytes
+//!            tag 1 = node:    key [u8; 32] | len u8 | value hash bytes
+//! ```
+use crate::{
+    empty_hash, hash_bytes, Hash, Hasher, Key, MultiProof, MultiProofItem, Proof, Sha256Hasher,
+    DIGEST_LEN,
+};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// Current version of the proof wire format.
+pub const PROOF_FORMAT_VERSION: u8 = 3;
+/// Hash algorithm identifier for SHA-256 node hashes. Ids from `0x80` up
+/// are left to application-defined hashers.
+pub const HASH_ALGO_SHA256: u8 = 1;
+/// Hash algorithm identifier for Keccak-256 node hashes.
+pub const HASH_ALGO_KECCAK256: u8 = 2;
+/// Hash algorithm identifier for BLAKE2s-256 node hashes.
+pub const HASH_ALGO_BLAKE2S256: u8 = 3;
+
+const FLAG_EXISTENCE: u8 = 0b01;
+const FLAG_WITNESS: u8 = 0b10;
+const FLAG_CHILD: u8 = 0b100;
+
+const ITEM_SUBTREE: u8 = 0;
+const ITEM_NODE: u8 = 1;
+
+/// Fixed header every encoded proof starts with, laid out so C verifiers can
+/// read it directly.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProofHeader {
+    pub version: u8,
+    pub hash_algo: u8,
+}
+
+impl ProofHeader {
+    /// Header of proofs from SHA-256 trees.
+    pub const CURRENT: ProofHeader = ProofHeader::of::<Sha256Hasher>();
+
+    /// Header of proofs from trees hashing with `H`.
+    pub const fn of<H: Hasher>() -> ProofHeader {
+        ProofHeader {
+            version: PROOF_FORMAT_VERSION,
+            hash_algo: H::ALGO_ID,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProofDecodeError {
+    UnsupportedVersion(u8),
+    /// The header names a different hasher than the one expected.
+    UnsupportedHashAlgorithm(u8),
+    InvalidFlags(u8),
+    /// A hash was neither empty nor `DIGEST_LEN` bytes long.
+    InvalidHashLength(u8),
+    Truncated,
+    TrailingBytes(usize),
+}
+
+impl fmt::Display for ProofDecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnsupportedVersion(v) => write!(f, "unsupported proof format version {v}"),
+            Self::UnsupportedHashAlgorithm(a) => write!(f, "unsupported hash algorithm id {a}"),
+            Self::InvalidFlags(flags) => write!(f, "invalid proof flags {flags:#04x}"),
+            Self::InvalidHashLength(len) => write!(f, "invalid hash length {len}"),
+            Self::Truncated => write!(f, "proof bytes ended unexpectedly"),
+            Self::TrailingBytes(n) => write!(f, "{n} trailing bytes after proof"),
+        }
+    }
+}
+
+impl core::error::Error for ProofDecodeError {}
+
+impl Proof {
+    /// Encodes a proof from a SHA-256 tree.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.to_bytes_with::<Sha256Hasher>()
+    }
+
+    /// Encodes a proof from a tree hashing with `H`.
+    pub fn to_bytes_with<H: Hasher>(&self) -> Vec<u8> {
+        let header = ProofHeader::of::<H>();
+        let mut out = vec![header.version, header.hash_algo];
+
+        let mut flags = 0;
+        if self.existence {
+            flags |= FLAG_EXISTENCE;
+        }
+        if self.nonexistence_key.is_some() {
+            flags |= FLAG_WITNESS;
+        }
+        if self.nonexistence_child.is_some() {
+            flags |= FLAG_CHILD;
+        }
+        out.push(flags);
+        if let Some(witness) = &self.nonexistence_key {
+            out.extend_from_slice(witness);
+        }
+        if let Some((k, vh, [l, r])) = &self.nonexistence_child {
+            out.extend_from_slice(k);
+            for h in [vh, l, r] {
+                write_hash(&mut out, h);
+            }
+        }
+
+        write_hash(&mut out, &self.value_hash);
+        for h in &self.suffix {
+            write_hash(&mut out, h);
+        }
+
+        out.extend_from_slice(&(self.prefix.len() as u32).to_le_bytes());
+        for (k, vh, h) in &self.prefix {
+            out.extend_from_slice(k);
+            write_hash(&mut out, vh);
+            write_hash(&mut out, h);
+        }
+        out
+    }
+
+    /// Decodes a proof from a SHA-256 tree.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Proof, ProofDecodeError> {
+        Self::from_bytes_with::<Sha256Hasher>(bytes)
+    }
+
+    /// Decodes a proof from a tree hashing with `H`.
+    pub fn from_bytes_with<H: Hasher>(bytes: &[u8]) -> Result<Proof, ProofDecodeError> {
+        let mut r = Reader { bytes };
+        r.header::<H>()?;
+
+        let flags = r.u8()?;
+        if flags & !(FLAG_EXISTENCE | FLAG_WITNESS | FLAG_CHILD) != 0 {
+            return Err(ProofDecodeError::InvalidFlags(flags));
+        }
+        let nonexistence_key = if flags & FLAG_WITNESS != 0 {
+            Some(r.key()?)
+        } else {
+            None
+        };
+        let nonexistence_child = if flags & FLAG_CHILD != 0 {
+            let k = r.key()?;
+            Some((k, r.hash()?, [r.hash()?, r.hash()?]))
+        } else {
+            None
+        };
+
+        let value_hash = r.hash()?;
+        let suffix = [r.hash()?, r.hash()?];
+
+        let count = u32::from_le_bytes(r.take(4)?.try_into().unwrap()) as usize;
+        // every entry is at least a key and two length bytes
+        if count > r.bytes.len() / 34 {
+            return Err(ProofDecodeError::Truncated);
+        }
+        let mut prefix = Vec::with_capacity(count);
+        for _ in 0..count {
+            let k = r.key()?;
+            let vh = r.hash()?;
+            prefix.push((k, vh, r.hash()?));
+        }
+
+        if !r.bytes.is_empty() {
+            return Err(ProofDecodeError::TrailingBytes(r.bytes.len()));
+        }
+
+        Ok(Proof {
+            prefix,
+            suffix,
+            value_hash,
+            existence: flags & FLAG_EXISTENCE != 0,
+            nonexistence_key,
+            nonexistence_child,
+        })
+    }
+}
+
+impl MultiProof {
+    /// Encodes a multi-proof from a SHA-256 tree.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.to_bytes_with::<Sha256Hasher>()
+    }
+
+    /// Encodes a multi-proof from a tree hashing with `H`.
+    pub fn to_bytes_with<H: Hasher>(&self) -> Vec<u8> {
+        let header = ProofHeader::of::<H>();
+        let mut out = vec![header.version, header.hash_algo];
+        out.extend_from_slice(&(self.items.len() as u32).to_le_bytes());
+        for item in &self.items {
+            match item {
+                MultiProofItem::Subtree(h) => {
+                    out.push(ITEM_SUBTREE);
+                    write_hash(&mut out, h);
+                }
+                MultiProofItem::Node { key, value_hash } => {
+                    out.push(ITEM_NODE);
+                    out.extend_from_slice(key);
+                    write_hash(&mut out, value_hash);
+                }
+            }
+        }
+        out
+    }
+
+    /// Decodes a multi-proof from a SHA-256 tree; an unknown item tag is
+    /// reported as `InvalidFlags`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<MultiProof, ProofDecodeError> {
+        Self::from_bytes_with::<Sha256Hasher>(bytes)
+    }
+
+    /// Decodes a multi-proof from a tree hashing with `H`.
+    pub fn from_bytes_with<H: Hasher>(bytes: &[u8]) -> Result<MultiProof, ProofDecodeError> {
+        let mut r = Reader { bytes };
+        r.header::<H>()?;
+
+        let count = u32::from_le_bytes(r.take(4)?.try_into().unwrap()) as usize;
+        // every item is at least a tag and a length byte
+        if count > r.bytes.len() / 2 {
+            return Err(ProofDecodeError::Truncated);
+        }
+        let mut items = Vec::with_capacity(count);
+        for _ in 0..count {
+            items.push(match r.u8()? {
+                ITEM_SUBTREE => MultiProofItem::Subtree(r.hash()?),
+                ITEM_NODE => MultiProofItem::Node {
+                    key: r.key()?,
+                    value_hash: r.hash()?,
+                },
+                tag => return Err(ProofDecodeError::InvalidFlags(tag)),
+            });
+        }
+
+        if !r.bytes.is_empty() {
+            return Err(ProofDecodeError::TrailingBytes(r.bytes.len()));
+        }
+        Ok(MultiProof { items })
+    }
+}
+
+fn write_hash(out: &mut Vec<u8>, h: &Hash) {
+    let bytes = hash_bytes(h);
+    out.push(bytes.len() as u8);
+    out.extend_from_slice(bytes);
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], ProofDecodeError> {
+        if self.bytes.len() < n {
+            return Err(ProofDecodeError::Truncated);
+        }
+        let (head, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(head)
+    }
+
+    fn header<H: Hasher>(&mut self) -> Result<(), ProofDecodeError> {
+        let version = self.u8()?;
+        if version != PROOF_FORMAT_VERSION {
+            return Err(ProofDecodeError::UnsupportedVersion(version));
+        }
+        let hash_algo = self.u8()?;
+        if hash_algo != H::ALGO_ID {
+            return Err(ProofDecodeError::UnsupportedHashAlgorithm(hash_algo));
+        }
+        Ok(())
+    }
+
+    fn u8(&mut self) -> Result<u8, ProofDecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn key(&mut self) -> Result<Key, ProofDecodeError> {
+        Ok(self.take(32)?.try_into().unwrap())
+    }
+
+    fn hash(&mut self) -> Result<Hash, ProofDecodeError> {
+        match self.u8()? {
+            0 => Ok(empty_hash()),
+            len if len as usize == DIGEST_LEN => Ok(self.take(DIGEST_LEN)?.try_into().unwrap()),
+            len => Err(ProofDecodeError::InvalidHashLength(len)),
+        }
+    }
+}
@@ -0,0 +1,193 @@
+//! module for in-place access to a single CMT entry
+use crate::{CartesianMerkleTreeWith, Hasher, Key, Sha256Hasher, TreeNode, Value};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+use core::ops::{Deref, DerefMut};
+
+/// Mutable handle to a value in a `CartesianMerkleTree`.
+///
+/// Node hashes commit to values, so the path from the root down to the
+/// entry is detached from the tree while the handle is alive, found once and
+/// hung back when the handle is dropped; only a value that actually changed
+/// marks that path for rehashing. Leaking the handle (e.g. with
+/// `mem::forget`) leaks the detached nodes and leaves the tree empty.
+pub struct ValueMut<'a, H: Hasher = Sha256Hasher> {
+    tree: &'a mut CartesianMerkleTreeWith<H>,
+    key: Key,
+    /// The search path for `key`, root first, ending with its node unless
+    /// the key is still vacant.
+    path: Vec<(Box<TreeNode>, Ordering)>,
+    value: Value,
+    slot: Slot,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Slot {
+    Vacant,
+    Occupied,
+    Inserted,
+}
+
+impl<'a, H: Hasher> ValueMut<'a, H> {
+    /// Takes over a path from `detach_path` that ends at `key`'s node.
+    pub(crate) fn occupied(
+        tree: &'a mut CartesianMerkleTreeWith<H>,
+        key: Key,
+        mut path: Vec<(Box<TreeNode>, Ordering)>,
+    ) -> Self {
+        let value = path
+            .last_mut()
+            .map(|(n, _)| core::mem::take(&mut n.value))
+            .unwrap_or_default();
+        Self {
+            tree,
+            key,
+            path,
+            value,
+            slot: Slot::Occupied,
+        }
+    }
+
+    pub fn key(&self) -> &Key {
+        &self.key
+    }
+}
+
+impl<H: Hasher> Deref for ValueMut<'_, H> {
+    type Target = Value;
+
+    fn deref(&self) -> &Value {
+        &self.value
+    }
+}
+
+impl<H: Hasher> DerefMut for ValueMut<'_, H> {
+    fn deref_mut(&mut self) -> &mut Value {
+        &mut self.value
+    }
+}
+
+impl<H: Hasher> Drop for ValueMut<'_, H> {
+    fn drop(&mut self) {
+        let path = core::mem::take(&mut self.path);
+        match self.slot {
+            Slot::Vacant => self.tree.restore_path(path),
+            slot => {
+                let value = core::mem::take(&mut self.value);
+                self.tree.put_back(path, value, slot == Slot::Inserted);
+            }
+        }
+    }
+}
+
+/// A view into a single key of a `CartesianMerkleTree`, obtained from
+/// `CartesianMerkleTree::entry`.
+pub enum Entry<'a, H: Hasher = Sha256Hasher> {
+    Occupied(OccupiedEntry<'a, H>),
+    Vacant(VacantEntry<'a, H>),
+}
+
+/// An entry whose key is present in the tree.
+pub struct OccupiedEntry<'a, H: Hasher = Sha256Hasher> {
+    value: ValueMut<'a, H>,
+}
+
+/// An entry whose key is absent from the tree. It holds the detached
+/// search path for the key, so `insert` doesn't walk the tree again.
+pub struct VacantEntry<'a, H: Hasher = Sha256Hasher> {
+    guard: ValueMut<'a, H>,
+}
+
+impl<'a, H: Hasher> Entry<'a, H> {
+    pub(crate) fn new(tree: &'a mut CartesianMerkleTreeWith<H>, key: Key) -> Self {
+        let (path, found) = tree.detach_path(&key);
+        if found {
+            Entry::Occupied(OccupiedEntry {
+                value: ValueMut::occupied(tree, key, path),
+            })
+        } else {
+            let guard = ValueMut {
+                tree,
+                key,
+                path,
+                value: Value::new(),
+                slot: Slot::Vacant,
+            };
+            Entry::Vacant(VacantEntry { guard })
+        }
+    }
+
+    pub fn key(&self) -> &Key {
+        match self {
+            Entry::Occupied(e) => e.key(),
+            Entry::Vacant(e) => e.key(),
+        }
+    }
+
+    /// Inserts `default` if the entry is vacant, and returns the value.
+    pub fn or_insert(self, default: Value) -> ValueMut<'a, H> {
+        match self {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => e.insert(default),
+        }
+    }
+
+    /// Like `or_insert`, but only builds the value when it is needed.
+    pub fn or_insert_with<F: FnOnce() -> Value>(self, default: F) -> ValueMut<'a, H> {
+        match self {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => e.insert(default()),
+        }
+    }
+
+    pub fn or_default(self) -> ValueMut<'a, H> {
+        self.or_insert_with(Value::default)
+    }
+
+    /// Applies `f` to the value if the entry is occupied.
+    pub fn and_modify<F: FnOnce(&mut Value)>(self, f: F) -> Self {
+        match self {
+            Entry::Occupied(mut e) => {
+                f(e.get_mut());
+                Entry::Occupied(e)
+            }
+            vacant => vacant,
+        }
+    }
+}
+
+impl<'a, H: Hasher> OccupiedEntry<'a, H> {
+    pub fn key(&self) -> &Key {
+        self.value.key()
+    }
+
+    pub fn get(&self) -> &Value {
+        &self.value
+    }
+
+    /// The path to the root is marked for rehashing once the entry is dropped.
+    pub fn get_mut(&mut self) -> &mut Value {
+        &mut self.value
+    }
+
+    pub fn into_mut(self) -> ValueMut<'a, H> {
+        self.value
+    }
+}
+
+impl<'a, H: Hasher> VacantEntry<'a, H> {
+    pub fn key(&self) -> &Key {
+        &self.guard.key
+    }
+
+    /// Inserts `value` under the entry's key.
+    pub fn insert(self, value: Value) -> ValueMut<'a, H> {
+        let mut guard = self.guard;
+        let path = core::mem::take(&mut guard.path);
+        guard.path = guard.tree.insert_detached(path, guard.key, Value::new());
+        guard.value = value;
+        guard.slot = Slot::Inserted;
+        guard
+    }
+}
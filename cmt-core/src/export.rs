@@ -0,0 +1,219 @@
+//! module for exporting proofs of every key with shared path nodes
+//!
+//! Independent proofs repeat the hashes of shared ancestors once per key.
+//! The export instead stores every node once, with a reference to its
+//! parent, and proofs are rebuilt by walking those references. Layout (all
+//! integers little-endian):
+//!
+//! ```text
+//! header : magic b"CMTX" | version u8 | node count u32
+//! node   : key [u8; 32] | parent u32 (u32::MAX for the root)
+//!          | side u8 (0 = left child, 1 = right child, 2 = root)
+//!          | value hash (len u8 | bytes)
+//!          | left child hash (len u8 | bytes)
+//!          | right child hash (len u8 | bytes)
+//! ```
+//!
+//! Nodes are written in pre-order, so a parent always precedes its children.
+//! A hash length is 0 for the empty hash and 32 otherwise.
+use crate::{
+    empty_hash, hash_bytes, utils, Hash, Hasher, Key, Proof, Sha256Hasher, TreeNode, DIGEST_LEN,
+};
+use std::io::{self, Read, Write};
+
+const MAGIC: &[u8; 4] = b"CMTX";
+const EXPORT_VERSION: u8 = 2;
+const NO_PARENT: u32 = u32::MAX;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Side {
+    Left,
+    Right,
+    Root,
+}
+
+struct ExportedNode {
+    key: Key,
+    parent: u32,
+    side: Side,
+    value_hash: Hash,
+    left: Hash,
+    right: Hash,
+}
+
+impl ExportedNode {
+    fn hash<H: Hasher>(&self) -> Hash {
+        utils::calculate_merkle_hash::<H, _>(&self.key, &self.value_hash, &self.left, &self.right)
+    }
+}
+
+pub(crate) fn write_all<W: Write>(root: Option<&TreeNode>, mut writer: W) -> io::Result<()> {
+    let count = root.map_or(0, |n| n.subtree_size);
+    writer.write_all(MAGIC)?;
+    writer.write_all(&[EXPORT_VERSION])?;
+    writer.write_all(&(count as u32).to_le_bytes())?;
+
+    let mut next_index: u32 = 0;
+    let mut stack: Vec<(&TreeNode, u32, Side)> = root
+        .map(|n| (n, NO_PARENT, Side::Root))
+        .into_iter()
+        .collect();
+    while let Some((n, parent, side)) = stack.pop() {
+        let index = next_index;
+        next_index += 1;
+
+        writer.write_all(&n.key)?;
+        writer.write_all(&parent.to_le_bytes())?;
+        writer.write_all(&[side as u8])?;
+        write_hash(&mut writer, &n.value_hash)?;
+        for child in [&n.left, &n.right] {
+            write_hash(
+                &mut writer,
+                &child.as_ref().map_or_else(empty_hash, |c| *c.hash()),
+            )?;
+        }
+
+        if let Some(right) = n.right.as_deref() {
+            stack.push((right, index, Side::Right));
+        }
+        if let Some(left) = n.left.as_deref() {
+            stack.push((left, index, Side::Left));
+        }
+    }
+    Ok(())
+}
+
+/// Proofs read back from `CartesianMerkleTree::export_all_proofs`.
+pub struct ProofExport {
+    nodes: Vec<ExportedNode>,
+}
+
+impl ProofExport {
+    pub fn read_from<R: Read>(mut reader: R) -> io::Result<ProofExport> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC || read_u8(&mut reader)? != EXPORT_VERSION {
+            return Err(invalid("not a CMT proof export"));
+        }
+        let count = read_u32(&mut reader)? as usize;
+
+        let mut nodes: Vec<ExportedNode> = Vec::new();
+        for index in 0..count {
+            let mut key = [0u8; 32];
+            reader.read_exact(&mut key)?;
+            let parent = read_u32(&mut reader)?;
+            let side = match read_u8(&mut reader)? {
+                0 => Side::Left,
+                1 => Side::Right,
+                2 => Side::Root,
+                _ => return Err(invalid("invalid node side")),
+            };
+            // pre-order: parents come first, and only the first node is the root
+            let is_root = index == 0;
+            if is_root != (side == Side::Root) || (!is_root && parent as usize >= index) {
+                return Err(invalid("invalid parent reference"));
+            }
+            let value_hash = read_hash(&mut reader)?;
+            let left = read_hash(&mut reader)?;
+            let right = read_hash(&mut reader)?;
+            nodes.push(ExportedNode {
+                key,
+                parent,
+                side,
+                value_hash,
+                left,
+                right,
+            });
+        }
+        Ok(ProofExport { nodes })
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Root hash of the exported tree, recomputed from the root record.
+    pub fn root_hash(&self) -> Hash {
+        self.root_hash_with::<Sha256Hasher>()
+    }
+
+    /// Same as `root_hash`, for exports of trees built with hasher `H`.
+    pub fn root_hash_with<H: Hasher>(&self) -> Hash {
+        self.nodes
+            .first()
+            .map(ExportedNode::hash::<H>)
+            .unwrap_or_else(empty_hash)
+    }
+
+    /// Rebuilds the membership proof of every exported key.
+    pub fn proofs(&self) -> impl Iterator<Item = (Key, Proof)> + '_ {
+        (0..self.nodes.len()).map(move |i| (self.nodes[i].key, self.proof(i)))
+    }
+
+    fn proof(&self, index: usize) -> Proof {
+        let node = &self.nodes[index];
+        let suffix = if node.left <= node.right {
+            [node.left, node.right]
+        } else {
+            [node.right, node.left]
+        };
+
+        let mut prefix = Vec::new();
+        let mut child = node;
+        while child.side != Side::Root {
+            let parent = &self.nodes[child.parent as usize];
+            // push (parent.e.k, parent.e.vh, sibling.mh)
+            let sibling = match child.side {
+                Side::Left => parent.right,
+                _ => parent.left,
+            };
+            prefix.push((parent.key, parent.value_hash, sibling));
+            child = parent;
+        }
+
+        Proof {
+            prefix,
+            suffix,
+            value_hash: node.value_hash,
+            existence: true,
+            nonexistence_key: None,
+            nonexistence_child: None,
+        }
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn read_u8<R: Read>(reader: &mut R) -> io::Result<u8> {
+    let mut b = [0u8; 1];
+    reader.read_exact(&mut b)?;
+    Ok(b[0])
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut b = [0u8; 4];
+    reader.read_exact(&mut b)?;
+    Ok(u32::from_le_bytes(b))
+}
+
+fn write_hash<W: Write>(writer: &mut W, hash: &Hash) -> io::Result<()> {
+    let bytes = hash_bytes(hash);
+    writer.write_all(&[bytes.len() as u8])?;
+    writer.write_all(bytes)
+}
+
+fn read_hash<R: Read>(reader: &mut R) -> io::Result<Hash> {
+    let mut hash = empty_hash();
+    match read_u8(reader)? as usize {
+        0 => {}
+        DIGEST_LEN => reader.read_exact(&mut hash)?,
+        _ => return Err(invalid("invalid hash length")),
+    }
+    Ok(hash)
+}
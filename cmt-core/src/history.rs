@@ -0,0 +1,53 @@
+//! module for the opt-in audit trail of root hashes
+//!
+//! A tree built with `with_history` appends one `(OpSummary, root hash)`
+//! record per mutation, so a log can later show that after operation `N`
+//! the root was `X`. Calls that leave the tree as it was, such as clearing
+//! an empty tree or storing the bytes a key already holds, add no record. Each record settles the tree's hashes, so mutations
+//! cost a rehash of their path up front instead of on the next read, and
+//! the log grows by one record per mutation; trees record nothing by
+//! default.
+use crate::{empty_hash, CartesianMerkleTreeWith, Hash, Hasher, Key};
+use alloc::vec::Vec;
+
+/// The mutation behind a history record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpSummary {
+    /// `insert`, `insert_with_priority` or a vacant `entry`, new key or
+    /// overwrite.
+    Insert(Key),
+    /// A `remove` (or `pop_min`/`pop_max`) that found its key.
+    Remove(Key),
+    /// A value changed in place through `get_mut` or `entry`.
+    Update(Key),
+    /// `clear` or `drain`.
+    Clear,
+    Merge,
+    Compact,
+    Rebalance,
+}
+
+impl<H: Hasher> CartesianMerkleTreeWith<H> {
+    /// Turns on the audit trail; see the module docs. The history starts
+    /// empty, so enable it on a fresh tree to log every root it has had.
+    pub fn with_history(mut self) -> Self {
+        self.history.get_or_insert_with(Vec::new);
+        self
+    }
+
+    /// Every mutation since `with_history`, oldest first, with the root
+    /// hash it left (the empty hash for an empty tree). Empty when the
+    /// trail is off. The halves from `split_at` start without one.
+    pub fn history(&self) -> &[(OpSummary, Hash)] {
+        self.history.as_deref().unwrap_or_default()
+    }
+
+    pub(crate) fn record(&mut self, op: OpSummary) {
+        if self.history.is_some() {
+            let root = self.root_hash().unwrap_or_else(empty_hash);
+            if let Some(history) = &mut self.history {
+                history.push((op, root));
+            }
+        }
+    }
+}
@@ -0,0 +1,210 @@
+//! module for the iterators over a CMT
+use crate::{empty_hash, utils, Hash, Key, Proof, TreeNode, Value};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::ops::{Bound, RangeBounds};
+
+enum Stage {
+    Descend,
+    Yield,
+    Ascend,
+}
+
+/// Iterator returned by `CartesianMerkleTree::proofs`.
+///
+/// The traversal stack always holds every ancestor of the node being
+/// visited, which is exactly the path a membership proof needs.
+pub struct Proofs<'a> {
+    stack: Vec<(&'a TreeNode, Stage)>,
+}
+
+impl<'a> Proofs<'a> {
+    pub(crate) fn new(root: Option<&'a TreeNode>) -> Self {
+        Self {
+            stack: root.map(|n| (n, Stage::Descend)).into_iter().collect(),
+        }
+    }
+
+    fn proof_for(&self, node: &TreeNode) -> Proof {
+        let mut prefix: Vec<(Key, Hash, Hash)> = Vec::with_capacity(self.stack.len());
+        let mut child = node;
+        for (ancestor, _) in self.stack.iter().rev() {
+            let sibling = if child.key < ancestor.key {
+                ancestor.right.as_ref()
+            } else {
+                ancestor.left.as_ref()
+            };
+            // push (parent.e.k, parent.e.vh, sibling.mh)
+            prefix.push((
+                ancestor.key,
+                ancestor.value_hash,
+                sibling.map_or_else(empty_hash, |x| *x.hash()),
+            ));
+            child = ancestor;
+        }
+
+        Proof {
+            prefix,
+            suffix: utils::proof_suffix(Some(node)),
+            value_hash: node.value_hash,
+            existence: true,
+            nonexistence_key: None,
+            nonexistence_child: None,
+        }
+    }
+}
+
+impl Iterator for Proofs<'_> {
+    type Item = (Key, Proof);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((n, stage)) = self.stack.pop() {
+            match stage {
+                Stage::Descend => {
+                    self.stack.push((n, Stage::Yield));
+                    if let Some(left) = n.left.as_deref() {
+                        self.stack.push((left, Stage::Descend));
+                    }
+                }
+                Stage::Yield => {
+                    // only ancestors remain on the stack at this point
+                    let proof = self.proof_for(n);
+                    self.stack.push((n, Stage::Ascend));
+                    if let Some(right) = n.right.as_deref() {
+                        self.stack.push((right, Stage::Descend));
+                    }
+                    return Some((n.key, proof));
+                }
+                Stage::Ascend => {}
+            }
+        }
+        None
+    }
+}
+
+/// Iterator returned by `CartesianMerkleTree::iter`.
+///
+/// Keeps the left spine of the unvisited part of the tree on an explicit
+/// stack, so memory is bounded by the tree height rather than the call stack.
+pub struct Iter<'a> {
+    stack: Vec<&'a TreeNode>,
+}
+
+impl<'a> Iter<'a> {
+    pub(crate) fn new(root: Option<&'a TreeNode>) -> Self {
+        let mut iter = Self { stack: Vec::new() };
+        iter.push_left_spine(root);
+        iter
+    }
+
+    fn push_left_spine(&mut self, mut node: Option<&'a TreeNode>) {
+        while let Some(n) = node {
+            self.stack.push(n);
+            node = n.left.as_deref();
+        }
+    }
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a Key, &'a Value);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let n = self.stack.pop()?;
+        self.push_left_spine(n.right.as_deref());
+        Some((&n.key, &n.value))
+    }
+}
+
+/// Iterator returned by `CartesianMerkleTree::range`.
+///
+/// Like `Iter`, but the initial descent skips every subtree lying wholly
+/// below the start bound, and iteration stops at the first key past the
+/// end bound.
+pub struct Range<'a> {
+    stack: Vec<&'a TreeNode>,
+    end: Bound<Key>,
+}
+
+impl<'a> Range<'a> {
+    pub(crate) fn new<R: RangeBounds<Key>>(root: Option<&'a TreeNode>, range: R) -> Self {
+        let mut iter = Self {
+            stack: Vec::new(),
+            end: range.end_bound().cloned(),
+        };
+        // keep only the nodes at or after the start bound on the stack
+        let mut node = root;
+        while let Some(n) = node {
+            let after_start = match range.start_bound() {
+                Bound::Included(lo) => n.key >= *lo,
+                Bound::Excluded(lo) => n.key > *lo,
+                Bound::Unbounded => true,
+            };
+            if after_start {
+                iter.stack.push(n);
+                node = n.left.as_deref();
+            } else {
+                node = n.right.as_deref();
+            }
+        }
+        iter
+    }
+
+    fn push_left_spine(&mut self, mut node: Option<&'a TreeNode>) {
+        while let Some(n) = node {
+            self.stack.push(n);
+            node = n.left.as_deref();
+        }
+    }
+}
+
+impl<'a> Iterator for Range<'a> {
+    type Item = (&'a Key, &'a Value);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let n = self.stack.pop()?;
+        let before_end = match &self.end {
+            Bound::Included(hi) => n.key <= *hi,
+            Bound::Excluded(hi) => n.key < *hi,
+            Bound::Unbounded => true,
+        };
+        if !before_end {
+            self.stack.clear();
+            return None;
+        }
+        self.push_left_spine(n.right.as_deref());
+        Some((&n.key, &n.value))
+    }
+}
+
+/// Consuming iterator returned by `CartesianMerkleTree::into_iter`.
+///
+/// Nodes are unboxed and dropped as they are yielded, so keys and values
+/// are moved out without cloning.
+pub struct IntoIter {
+    stack: Vec<TreeNode>,
+}
+
+impl IntoIter {
+    pub(crate) fn new(root: Option<Box<TreeNode>>) -> Self {
+        let mut iter = Self { stack: Vec::new() };
+        iter.push_left_spine(root);
+        iter
+    }
+
+    fn push_left_spine(&mut self, mut node: Option<Box<TreeNode>>) {
+        while let Some(mut n) = node {
+            node = n.left.take();
+            self.stack.push(*n);
+        }
+    }
+}
+
+impl Iterator for IntoIter {
+    type Item = (Key, Value);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut n = self.stack.pop()?;
+        self.push_left_spine(n.right.take());
+        Some((n.key, core::mem::take(&mut n.value)))
+    }
+}
@@ -1,26 +1,256 @@
-use crate::utils::calculate_merkle_hash;
+#![cfg_attr(not(feature = "std"), no_std)]
 
+extern crate alloc;
+
+use crate::utils::hash_value;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+use core::marker::PhantomData;
+use core::ops::{Bound, RangeBounds};
+use digest::typenum::{IsGreaterOrEqual, True, U32};
+#[cfg(feature = "std")]
+use std::io;
+
+mod cursor;
+mod dot;
+mod encoding;
+mod entry;
+#[cfg(feature = "std")]
+mod export;
+mod history;
+mod iter;
+mod multiproof;
+mod ops;
+#[doc(hidden)]
+pub mod persistent;
+mod rangeproof;
+mod replica;
+#[cfg(feature = "serde")]
+mod serialize;
+mod store;
+#[cfg(feature = "std")]
+mod stream;
+mod typed;
 mod utils;
+mod validate;
+mod verifier;
+mod visitor;
+
+pub use cursor::NodeRef;
+pub use encoding::{
+    ProofDecodeError, ProofHeader, HASH_ALGO_BLAKE2S256, HASH_ALGO_KECCAK256, HASH_ALGO_SHA256,
+    PROOF_FORMAT_VERSION,
+};
+pub use entry::{Entry, OccupiedEntry, VacantEntry, ValueMut};
+#[cfg(feature = "std")]
+pub use export::ProofExport;
+pub use history::OpSummary;
+pub use iter::{IntoIter, Iter, Proofs, Range};
+pub use multiproof::{MultiProof, MultiProofItem};
+pub use ops::Op;
+pub use persistent::{
+    ConsistencyProof, PersistentCMT, PersistentCMTWith, VersionHistory, VersionHistoryWith,
+};
+pub use rangeproof::{RangeProof, SuccessorProof};
+pub use replica::{first_divergence, roots_agree, TreeDiff};
+pub use store::{MemoryStore, NodeId, NodeStore, StoredCMT, StoredCMTWith, StoredNode};
+pub use typed::{KeyEncode, TypedCMT, TypedCMTWith, U64CMT};
+pub use utils::{
+    calculate_merkle_hash, calculate_merkle_hash_into, combine_roots, rotate_left, rotate_right,
+    RotateError,
+};
+pub use validate::{ValidationError, Violation};
+pub use verifier::{ProofVerifier, ProofVerifierWith};
+pub use visitor::Visitor;
 
 pub type Key = [u8; 32];
 pub type Priority = i128;
-pub type Hash = Vec<u8>;
+pub type Hash = [u8; DIGEST_LEN];
 pub type Value = Vec<u8>;
 
-pub trait Hasher {
+/// Error returned by `key_from_slice` when the input isn't exactly 32 bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyLenError {
+    pub len: usize,
+}
+
+impl core::fmt::Display for KeyLenError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "key must be 32 bytes, got {}", self.len)
+    }
+}
+
+impl core::error::Error for KeyLenError {}
+
+/// Converts external bytes into a `Key`, rejecting any other length.
+pub fn key_from_slice(bytes: &[u8]) -> Result<Key, KeyLenError> {
+    bytes
+        .try_into()
+        .map_err(|_| KeyLenError { len: bytes.len() })
+}
+
+/// Length in bytes of a node hash (SHA-256 digest).
+pub const DIGEST_LEN: usize = 32;
+
+/// The canonical hash of a missing child. A leaf is hashed with this value
+/// for both children, and it sorts before every real digest.
+pub const fn empty_hash() -> Hash {
+    [0; DIGEST_LEN]
+}
+
+pub fn is_empty_subtree_hash(hash: &Hash) -> bool {
+    *hash == empty_hash()
+}
+
+/// The root hash an empty tree commits to: the empty sentinel, which no
+/// node hash can equal. `root_hash` reports an empty tree as `None` and
+/// `committed_root` as `EMPTY_ROOT`. An empty tree's proof for any key is
+/// a non-existence proof with no path, no witness and only empty hashes;
+/// it verifies against `EMPTY_ROOT` and no other root, and every other
+/// proof is rejected against `EMPTY_ROOT`.
+pub const EMPTY_ROOT: Hash = empty_hash();
+
+/// Bytes `hash` contributes to node hashing and the wire formats. The empty
+/// sentinel contributes none, so in length-prefixed encodings it stays
+/// distinct from every real digest.
+pub fn hash_bytes(hash: &Hash) -> &[u8] {
+    if is_empty_subtree_hash(hash) {
+        &[]
+    } else {
+        hash
+    }
+}
+
+/// Hash function used for node hashes, value hashes and priorities.
+/// Digests are fixed at `DIGEST_LEN` bytes by the `Hash` type, whatever the
+/// hasher; wider digests are truncated to fit (see `TruncatedDigest`). The
+/// `'static` bound lets the default priority function be stored on the tree.
+pub trait Hasher: 'static {
+    /// Identifier encoded proofs carry in their header, so a decoder
+    /// expecting another hasher rejects them; see the `HASH_ALGO_*`
+    /// constants.
+    const ALGO_ID: u8;
+
     fn hash(data: &[u8]) -> Hash;
 }
 
-#[derive(Debug, Clone)]
+/// The default `Hasher`, backed by SHA-256.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Sha256Hasher;
+
+impl Hasher for Sha256Hasher {
+    const ALGO_ID: u8 = HASH_ALGO_SHA256;
+
+    fn hash(data: &[u8]) -> Hash {
+        use sha2::{Digest, Sha256};
+        Sha256::digest(data).into()
+    }
+}
+
+/// `Hasher` for any RustCrypto `Digest` with at least `DIGEST_LEN` bytes of
+/// output, keeping the first `DIGEST_LEN` bytes of each digest.
+///
+/// The width of `Hash` is fixed, so this does not widen node hashes: with
+/// SHA-512 or BLAKE2b every digest is cut to 32 bytes, and the tree gets
+/// 128-bit collision resistance whatever the digest offers. Digests shorter
+/// than `DIGEST_LEN` bytes are rejected at compile time. For 32-byte digests
+/// such as `sha3::Keccak256` or `blake2::Blake2s256` nothing is cut, and
+/// `TruncatedDigest<sha2::Sha256, HASH_ALGO_SHA256>` hashes exactly like
+/// `Sha256Hasher`.
+///
+/// `ALGO_ID` is the `Hasher::ALGO_ID` written into encoded proofs, e.g.
+/// `TruncatedDigest<sha3::Keccak256, HASH_ALGO_KECCAK256>`.
+pub struct TruncatedDigest<D, const ALGO_ID: u8>(PhantomData<fn() -> D>);
+
+impl<D, const ALGO_ID: u8> Hasher for TruncatedDigest<D, ALGO_ID>
+where
+    D: digest::Digest + 'static,
+    D::OutputSize: IsGreaterOrEqual<U32, Output = True>,
+{
+    const ALGO_ID: u8 = ALGO_ID;
+
+    fn hash(data: &[u8]) -> Hash {
+        let mut out = [0; DIGEST_LEN];
+        out.copy_from_slice(&D::digest(data)[..DIGEST_LEN]);
+        out
+    }
+}
+
+/// Cache for a node's hash, emptied when a mutation leaves it stale. With
+/// `std` it is a `OnceLock`, so trees can be shared across threads;
+/// without it, a `OnceCell`.
+#[cfg(feature = "std")]
+pub(crate) type HashCell = std::sync::OnceLock<Hash>;
+#[cfg(not(feature = "std"))]
+pub(crate) type HashCell = core::cell::OnceCell<Hash>;
+
+/// A node of a `CartesianMerkleTree`. Cloning, dropping and debug-printing
+/// a node never recurse into its subtrees, so a node at the top of a
+/// degenerate spine is as safe to handle as a leaf.
 pub struct TreeNode {
     pub key: Key,
     pub priority: Priority,
     pub value: Value,
-    pub hash: Hash,
+    /// Hash of `value`, cached so rotations don't rehash the value.
+    pub value_hash: Hash,
+    /// Merkle hash of the subtree rooted here, or unset while a mutation
+    /// has left it stale. Read it through `hash`.
+    pub(crate) hash: HashCell,
+    /// Number of nodes in the subtree rooted here, including this one.
+    pub subtree_size: usize,
     pub left: Option<Box<TreeNode>>,
     pub right: Option<Box<TreeNode>>,
 }
 
+impl TreeNode {
+    /// Merkle hash of the subtree rooted here. The tree settles every stale
+    /// hash before handing nodes out, so this is always set there.
+    pub fn hash(&self) -> &Hash {
+        self.hash
+            .get()
+            .expect("node hash read before the tree was settled")
+    }
+}
+
+/// Deep copy of the subtree rooted here, made with an explicit stack.
+impl Clone for TreeNode {
+    fn clone(&self) -> Self {
+        *clone_subtree(self)
+    }
+}
+
+/// Tears the subtree down with an explicit stack; the default recursive drop
+/// of the boxed children could overflow on a degenerate tree.
+impl Drop for TreeNode {
+    fn drop(&mut self) {
+        let mut stack: Vec<Box<TreeNode>> = self.left.take().into_iter().collect();
+        stack.extend(self.right.take());
+        while let Some(mut n) = stack.pop() {
+            stack.extend(n.left.take());
+            stack.extend(n.right.take());
+        }
+    }
+}
+
+/// Prints the node's own fields, and its children by key only.
+impl core::fmt::Debug for TreeNode {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("TreeNode")
+            .field("key", &self.key)
+            .field("priority", &self.priority)
+            .field("value", &self.value)
+            .field("value_hash", &self.value_hash)
+            .field("hash", &self.hash.get())
+            .field("subtree_size", &self.subtree_size)
+            .field("left", &self.left.as_ref().map(|n| n.key))
+            .field("right", &self.right.as_ref().map(|n| n.key))
+            .finish()
+    }
+}
+
 impl PartialEq for TreeNode {
     fn eq(&self, other: &Self) -> bool {
         self.key == other.key
@@ -28,270 +258,4676 @@ impl PartialEq for TreeNode {
 }
 impl Eq for TreeNode {}
 
-pub struct CartesianMerkleTree {
+/// Derives a node's treap priority from its key. It must be deterministic:
+/// the same key always gets the same priority, so a given key set always
+/// produces the same shape and root hash, in any insertion order. Equal
+/// priorities are allowed; the smaller key goes above.
+pub type PriorityFn = Arc<dyn Fn(&Key) -> Priority + Send + Sync>;
+
+/// A Cartesian Merkle Tree hashing with `H`. Most code uses the
+/// SHA-256 `CartesianMerkleTree` alias.
+pub struct CartesianMerkleTreeWith<H: Hasher> {
     root: Option<Box<TreeNode>>,
+    size: usize,
+    priority_fn: PriorityFn,
+    /// Audit trail, `None` unless `with_history` turned it on.
+    history: Option<Vec<(OpSummary, Hash)>>,
+    hasher: PhantomData<fn() -> H>,
+}
+
+pub type CartesianMerkleTree = CartesianMerkleTreeWith<Sha256Hasher>;
+
+impl<H: Hasher> Default for CartesianMerkleTreeWith<H> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Deep copy: every node is duplicated, so this is O(n) in time and memory
+/// and the copy is fully independent of the original. For cheap snapshots
+/// that share structure between versions, see `PersistentCMT`.
+impl<H: Hasher> Clone for CartesianMerkleTreeWith<H> {
+    fn clone(&self) -> Self {
+        Self {
+            root: self.root.as_deref().map(clone_subtree),
+            size: self.size,
+            priority_fn: self.priority_fn.clone(),
+            history: self.history.clone(),
+            hasher: PhantomData,
+        }
+    }
+}
+
+/// Compact summary: entry count, the start of the root hash, and height.
+/// Use `debug_tree` or `to_dot` to see every node.
+impl<H: Hasher> core::fmt::Debug for CartesianMerkleTreeWith<H> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let root = self.root_hash();
+        f.debug_struct("CartesianMerkleTree")
+            .field("len", &self.size)
+            .field("root", &root.as_ref().map(ShortHash))
+            .field("height", &self.height())
+            .finish()
+    }
+}
+
+/// Debug-prints the first bytes of a hash in hex.
+struct ShortHash<'a>(&'a Hash);
+
+impl core::fmt::Debug for ShortHash<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}..", hex::encode(&self.0[..8]))
+    }
+}
+
+/// Trees are equal when they hold the same entries. Shape is ignored, so
+/// trees whose priorities differ (custom priority functions, or
+/// `insert_with_priority`) can be equal with different root hashes.
+/// Compares the entries in key order, O(n).
+impl<H: Hasher> PartialEq for CartesianMerkleTreeWith<H> {
+    fn eq(&self, other: &Self) -> bool {
+        self.size == other.size && self.iter().eq(other.iter())
+    }
+}
+impl<H: Hasher> Eq for CartesianMerkleTreeWith<H> {}
+
+/// Copies a subtree with an explicit stack, as `TreeNode`'s `Drop` tears one
+/// down, so a degenerate tree can't overflow the call stack.
+fn clone_subtree(root: &TreeNode) -> Box<TreeNode> {
+    let mut copies: Vec<Box<TreeNode>> = Vec::new();
+    let mut stack = vec![(root, false)];
+    while let Some((n, children_done)) = stack.pop() {
+        if !children_done {
+            stack.push((n, true));
+            stack.extend(n.left.as_deref().map(|c| (c, false)));
+            stack.extend(n.right.as_deref().map(|c| (c, false)));
+            continue;
+        }
+        // the right subtree is copied first, so the left one is on top
+        let left = n.left.as_ref().and_then(|_| copies.pop());
+        let right = n.right.as_ref().and_then(|_| copies.pop());
+        copies.push(Box::new(TreeNode {
+            key: n.key,
+            priority: n.priority,
+            value: n.value.clone(),
+            value_hash: n.value_hash,
+            hash: n.hash.clone(),
+            subtree_size: n.subtree_size,
+            left,
+            right,
+        }));
+    }
+    copies.pop().expect("the root is copied last")
 }
 
-impl CartesianMerkleTree {
+impl<H: Hasher> CartesianMerkleTreeWith<H> {
+    /// Empty tree deriving priorities from the first 16 bytes of `H(key)`.
     pub fn new() -> Self {
-        Self { root: None }
+        Self::with_priority_fn(find_priority::<H>)
+    }
+
+    /// Empty tree deriving priorities with `priority_fn` instead of the key
+    /// hash, e.g. to keep shapes stable across a hasher change.
+    pub fn with_priority_fn<F>(priority_fn: F) -> Self
+    where
+        F: Fn(&Key) -> Priority + Send + Sync + 'static,
+    {
+        Self {
+            root: None,
+            size: 0,
+            priority_fn: Arc::new(priority_fn),
+            history: None,
+            hasher: PhantomData,
+        }
+    }
+
+    /// Hash of the root node, or `None` for an empty tree. This is the
+    /// commitment proofs are verified against.
+    pub fn root_hash(&self) -> Option<Hash> {
+        self.settle();
+        self.root.as_ref().map(|n| *n.hash())
+    }
+
+    /// `root_hash`, with an empty tree committing to `EMPTY_ROOT`.
+    pub fn committed_root(&self) -> Hash {
+        self.root_hash().unwrap_or(EMPTY_ROOT)
+    }
+
+    /// Computes every hash left stale by mutations. Mutations only unset
+    /// the hashes on the paths they touch, so repeated changes to one
+    /// subtree are hashed once, here, when a hash is next read. A node with
+    /// a hash set has a fully hashed subtree, so clean subtrees are skipped.
+    pub(crate) fn settle(&self) {
+        let Some(root) = self.root.as_deref() else {
+            return;
+        };
+        if root.hash.get().is_some() {
+            return;
+        }
+
+        let mut buf = Vec::new();
+        let mut stack = vec![(root, false)];
+        while let Some((n, children_done)) = stack.pop() {
+            if children_done {
+                let left_hash = n.left.as_deref().map_or(&EMPTY_HASH, TreeNode::hash);
+                let right_hash = n.right.as_deref().map_or(&EMPTY_HASH, TreeNode::hash);
+                let hash = calculate_merkle_hash_into::<H, _>(
+                    &mut buf,
+                    &n.key,
+                    &n.value_hash,
+                    left_hash,
+                    right_hash,
+                );
+                // a concurrent reader may have settled it first; the value
+                // is the same either way
+                let _ = n.hash.set(hash);
+                continue;
+            }
+            stack.push((n, true));
+            for child in [n.left.as_deref(), n.right.as_deref()]
+                .into_iter()
+                .flatten()
+            {
+                if child.hash.get().is_none() {
+                    stack.push((child, false));
+                }
+            }
+        }
+    }
+
+    /// Number of keys in the tree.
+    pub fn len(&self) -> usize {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    /// Removes every entry, keeping the tree's priority function.
+    pub fn clear(&mut self) {
+        if self.root.is_none() {
+            return;
+        }
+        self.root = None;
+        self.size = 0;
+        self.record(OpSummary::Clear);
     }
 
     pub fn contains_key(&self, key: &Key) -> bool {
         let mut cur = self.root.as_ref();
         while let Some(n) = cur {
-            if &n.key == key {
-                return true;
-            } else if key < &n.key {
-                cur = n.left.as_ref();
-            } else {
-                cur = n.right.as_ref();
+            match key.cmp(&n.key) {
+                Ordering::Equal => return true,
+                Ordering::Less => cur = n.left.as_ref(),
+                Ordering::Greater => cur = n.right.as_ref(),
             }
         }
         false
     }
 
-    pub fn insert(&mut self, key: Key, value: Value) {
-        let priority = find_priority(&key);
-        self.root = Self::insert_recursive(self.root.take(), key, value, priority);
+    pub fn get(&self, key: &Key) -> Option<&Value> {
+        let mut cur = self.root.as_ref();
+        while let Some(n) = cur {
+            match key.cmp(&n.key) {
+                Ordering::Equal => return Some(&n.value),
+                Ordering::Less => cur = n.left.as_ref(),
+                Ordering::Greater => cur = n.right.as_ref(),
+            }
+        }
+        None
     }
 
-    fn insert_recursive(
-        node: Option<Box<TreeNode>>,
+    /// Merkle hash of the subtree rooted at `key`'s node, or `None` if the
+    /// key is absent. For the root's key this is the root hash.
+    pub fn subtree_hash(&self, key: &Key) -> Option<Hash> {
+        self.settle();
+        let mut cur = self.root.as_deref();
+        while let Some(n) = cur {
+            cur = match key.cmp(&n.key) {
+                Ordering::Equal => return Some(*n.hash()),
+                Ordering::Less => n.left.as_deref(),
+                Ordering::Greater => n.right.as_deref(),
+            };
+        }
+        None
+    }
+
+    /// Mutable access to the value stored under `key`.
+    ///
+    /// Node hashes commit to values, so the value is handed out through a
+    /// guard that holds the path down to it and marks that path stale once
+    /// it is dropped, if the value changed.
+    pub fn get_mut(&mut self, key: &Key) -> Option<ValueMut<'_, H>> {
+        let (path, found) = self.detach_path(key);
+        if !found {
+            self.restore_path(path);
+            return None;
+        }
+        Some(ValueMut::occupied(self, *key, path))
+    }
+
+    /// Detaches the search path for `key`, root first, ending with `key`'s
+    /// own node (children still attached) when it is `found`. The tree is
+    /// left without its root until the path is put back.
+    pub(crate) fn detach_path(&mut self, key: &Key) -> (Vec<(Box<TreeNode>, Ordering)>, bool) {
+        let mut path = Vec::new();
+        let mut cur = self.root.take();
+        while let Some(mut n) = cur {
+            let dir = key.cmp(&n.key);
+            cur = match dir {
+                Ordering::Equal => {
+                    path.push((n, dir));
+                    return (path, true);
+                }
+                Ordering::Less => n.left.take(),
+                Ordering::Greater => n.right.take(),
+            };
+            path.push((n, dir));
+        }
+        (path, false)
+    }
+
+    /// Puts a path from `detach_path` back unchanged, keeping every cached
+    /// hash.
+    pub(crate) fn restore_path(&mut self, mut path: Vec<(Box<TreeNode>, Ordering)>) {
+        let mut subtree = None;
+        while let Some((mut n, dir)) = path.pop() {
+            match dir {
+                Ordering::Less => n.left = subtree,
+                Ordering::Greater => n.right = subtree,
+                Ordering::Equal => {}
+            }
+            subtree = Some(n);
+        }
+        self.root = subtree;
+    }
+
+    /// Inserts the absent `key` into the tree whose search path for it is
+    /// `path`, detached by `detach_path`. Only the part of the path below
+    /// the new node's place is reattached and split; the returned path runs
+    /// down to the new node, whose ancestors are left for `put_back` to
+    /// refresh.
+    pub(crate) fn insert_detached(
+        &mut self,
+        mut path: Vec<(Box<TreeNode>, Ordering)>,
         key: Key,
         value: Value,
-        priority: Priority,
-    ) -> Option<Box<TreeNode>> {
-        let mut current_node = match node {
-            Some(n) => n,
-            None => {
-                let hash = calculate_merkle_hash(&key, &Vec::new(), &Vec::new());
-                return Some(Box::new(TreeNode {
-                    key,
-                    priority,
-                    value,
-                    hash,
-                    left: None,
-                    right: None,
-                }));
-            }
-        };
+    ) -> Vec<(Box<TreeNode>, Ordering)> {
+        let priority = (self.priority_fn)(&key);
+        let at = path
+            .iter()
+            .position(|(n, _)| outranks(priority, &key, n.priority, &n.key))
+            .unwrap_or(path.len());
+        self.restore_path(path.split_off(at));
+        let mut replaced = None;
+        let (left, right) = Self::split(self.root.take(), &key, &mut replaced);
+        let mut n = Box::new(TreeNode {
+            key,
+            priority,
+            value_hash: hash_value::<H>(&value),
+            value,
+            hash: HashCell::new(),
+            subtree_size: 1,
+            left,
+            right,
+        });
+        Self::refresh(&mut n);
+        self.size += 1;
+        path.push((n, Ordering::Equal));
+        path
+    }
 
-        if priority > current_node.priority {
-            let hash = calculate_merkle_hash(&key, &Vec::new(), &Vec::new());
-            let mut new_node = Box::new(TreeNode {
-                key,
-                priority,
-                value,
-                hash,
-                left: None,
-                right: None,
-            });
-            Self::split(
-                &mut current_node,
-                &new_node.key,
-                &mut new_node.left,
-                &mut new_node.right,
-            );
-            // recompute hash for new_node
-            let left_hash = new_node
-                .left
-                .as_ref()
-                .map(|n| n.hash.clone())
-                .unwrap_or_default();
-            let right_hash = new_node
-                .right
-                .as_ref()
-                .map(|n| n.hash.clone())
-                .unwrap_or_default();
-            new_node.hash = calculate_merkle_hash(&new_node.key, &left_hash, &right_hash);
-            return Some(new_node);
-        }
-
-        if key < current_node.key {
-            current_node.left =
-                Self::insert_recursive(current_node.left.take(), key, value, priority);
-        } else if key > current_node.key {
-            current_node.right =
-                Self::insert_recursive(current_node.right.take(), key, value, priority);
+    /// Puts a path from `detach_path` or `insert_detached` back with `value`
+    /// in its last node. The path is marked stale only if the value changed
+    /// or the node was just `inserted`, and that change goes on the history.
+    pub(crate) fn put_back(
+        &mut self,
+        mut path: Vec<(Box<TreeNode>, Ordering)>,
+        value: Value,
+        inserted: bool,
+    ) {
+        let Some((mut n, _)) = path.pop() else {
+            return;
+        };
+        let value_hash = hash_value::<H>(&value);
+        let changed = n.value_hash != value_hash;
+        n.value_hash = value_hash;
+        n.value = value;
+        let key = n.key;
+        if changed || inserted {
+            Self::refresh(&mut n);
+            self.root = Self::reattach(path, Some(n));
         } else {
-            current_node.value = value;
+            path.push((n, Ordering::Equal));
+            self.restore_path(path);
         }
+        if inserted {
+            self.record(OpSummary::Insert(key));
+        } else if changed {
+            self.record(OpSummary::Update(key));
+        }
+    }
 
-        let left_hash = current_node
-            .left
-            .as_ref()
-            .map(|n| n.hash.clone())
-            .unwrap_or_default();
-        let right_hash = current_node
-            .right
-            .as_ref()
-            .map(|n| n.hash.clone())
-            .unwrap_or_default();
-        current_node.hash = calculate_merkle_hash(&current_node.key, &left_hash, &right_hash);
+    /// Gets the entry for `key` for in-place insertion or modification.
+    pub fn entry(&mut self, key: Key) -> Entry<'_, H> {
+        Entry::new(self, key)
+    }
 
-        Some(current_node)
+    /// Number of nodes on the longest root-to-leaf path (0 when empty).
+    /// Walks with an explicit stack, so a degenerate tree can't overflow.
+    pub fn height(&self) -> usize {
+        let mut height = 0;
+        let mut stack: Vec<(&TreeNode, usize)> =
+            self.root.as_deref().map(|n| (n, 1)).into_iter().collect();
+        while let Some((n, level)) = stack.pop() {
+            height = height.max(level);
+            for child in [n.left.as_deref(), n.right.as_deref()]
+                .into_iter()
+                .flatten()
+            {
+                stack.push((child, level + 1));
+            }
+        }
+        height
     }
 
-    fn split(
-        node: &mut Box<TreeNode>,
-        key: &Key,
-        left: &mut Option<Box<TreeNode>>,
-        right: &mut Option<Box<TreeNode>>,
-    ) {
-        if node.key < *key {
-            *left = Some(node.clone());
-            if let Some(right_child) = node.right.as_mut() {
-                Self::split(right_child, key, left, right);
+    /// Distribution of the node priorities, to check that the priority
+    /// function spreads them well for this key set. Walks every node and
+    /// sorts the priorities, O(n log n); diagnostic use only.
+    pub fn priority_stats(&self) -> PriorityStats {
+        let mut priorities: Vec<Priority> = Vec::with_capacity(self.size);
+        let mut stack: Vec<&TreeNode> = self.root.as_deref().into_iter().collect();
+        while let Some(n) = stack.pop() {
+            priorities.push(n.priority);
+            stack.extend(n.left.as_deref());
+            stack.extend(n.right.as_deref());
+        }
+        priorities.sort_unstable();
+
+        let mut buckets = [0; PRIORITY_BUCKETS];
+        for p in &priorities {
+            // flip the sign bit so `Priority::MIN` lands in bucket 0
+            let offset = (*p as u128) ^ (1 << 127);
+            buckets[(offset >> (128 - PRIORITY_BUCKETS.ilog2())) as usize] += 1;
+        }
+        PriorityStats {
+            min: priorities.first().copied(),
+            max: priorities.last().copied(),
+            collisions: priorities.windows(2).filter(|w| w[0] == w[1]).count(),
+            buckets,
+        }
+    }
+
+    /// Depth statistics over every node, for spotting skewed shapes.
+    pub fn stats(&self) -> TreeStats {
+        let (mut total, mut max_depth) = (0, 0);
+        let mut stack: Vec<(&TreeNode, usize)> =
+            self.root.as_deref().map(|n| (n, 0)).into_iter().collect();
+        while let Some((n, depth)) = stack.pop() {
+            total += depth;
+            max_depth = max_depth.max(depth);
+            for child in [n.left.as_deref(), n.right.as_deref()]
+                .into_iter()
+                .flatten()
+            {
+                stack.push((child, depth + 1));
             }
-        } else {
-            *right = Some(node.clone());
-            if let Some(left_child) = node.left.as_mut() {
-                Self::split(left_child, key, left, right);
+        }
+        TreeStats {
+            max_depth,
+            average_depth: if self.size == 0 {
+                0.0
+            } else {
+                total as f64 / self.size as f64
+            },
+        }
+    }
+
+    /// 0-based depth of `key` (the root is at depth 0), or `None` if the key
+    /// is absent. This is also the length of the key's proof prefix.
+    pub fn depth_of(&self, key: &Key) -> Option<usize> {
+        let mut depth = 0;
+        let mut cur = self.root.as_ref();
+        while let Some(n) = cur {
+            match key.cmp(&n.key) {
+                Ordering::Equal => return Some(depth),
+                Ordering::Less => cur = n.left.as_ref(),
+                Ordering::Greater => cur = n.right.as_ref(),
             }
+            depth += 1;
         }
+        None
+    }
+
+    /// Counts the keys falling in `range` in O(log n), using the subtree
+    /// sizes instead of visiting the keys themselves.
+    pub fn count_range<R: RangeBounds<Key>>(&self, range: R) -> usize {
+        let upper = match range.end_bound() {
+            Bound::Included(hi) => self.count_below(hi, true),
+            Bound::Excluded(hi) => self.count_below(hi, false),
+            Bound::Unbounded => utils::subtree_size(&self.root),
+        };
+        let lower = match range.start_bound() {
+            Bound::Included(lo) => self.count_below(lo, false),
+            Bound::Excluded(lo) => self.count_below(lo, true),
+            Bound::Unbounded => 0,
+        };
+        upper.saturating_sub(lower)
     }
-    pub fn remove(&mut self, key: &Key) {
-        self.root = Self::remove_recursive(self.root.take(), key);
+
+    /// Number of keys strictly less than `key`, whether or not `key` is
+    /// present. For a present key this is its 0-based position in key order.
+    pub fn rank(&self, key: &Key) -> usize {
+        self.count_below(key, false)
     }
 
-    fn remove_recursive(node: Option<Box<TreeNode>>, key: &Key) -> Option<Box<TreeNode>> {
-        if let Some(mut current_node) = node {
-            if *key < current_node.key {
-                current_node.left = Self::remove_recursive(current_node.left.take(), key);
-            } else if *key > current_node.key {
-                current_node.right = Self::remove_recursive(current_node.right.take(), key);
-            } else {
-                // Node found, set priority to -inf and heapify down
-                current_node.priority = i128::MIN;
-                return Self::heapify(current_node);
-            }
-            // Update hash
-            let left_hash = current_node
-                .left
-                .as_ref()
-                .map(|n| n.hash.clone())
-                .unwrap_or_default();
-            let right_hash = current_node
-                .right
-                .as_ref()
-                .map(|n| n.hash.clone())
-                .unwrap_or_default();
-            current_node.hash = calculate_merkle_hash(&current_node.key, &left_hash, &right_hash);
-            return Some(current_node);
+    /// The `n`-th smallest entry (0-based), found in O(height) by steering
+    /// with the subtree sizes.
+    pub fn select(&self, mut n: usize) -> Option<(&Key, &Value)> {
+        let mut cur = self.root.as_deref();
+        while let Some(node) = cur {
+            let left = utils::subtree_size(&node.left);
+            match n.cmp(&left) {
+                Ordering::Less => cur = node.left.as_deref(),
+                Ordering::Equal => return Some((&node.key, &node.value)),
+                Ordering::Greater => {
+                    n -= left + 1;
+                    cur = node.right.as_deref();
+                }
+            }
         }
         None
     }
 
-    fn heapify(node: Box<TreeNode>) -> Option<Box<TreeNode>> {
-        if node.left.is_none() && node.right.is_none() {
-            // Leaf node, remove it
-            return None;
+    /// Number of keys strictly less than `key` (or `<=` when `inclusive`).
+    fn count_below(&self, key: &Key, inclusive: bool) -> usize {
+        let mut count = 0;
+        let mut cur = self.root.as_ref();
+        while let Some(n) = cur {
+            let below = match n.key.cmp(key) {
+                Ordering::Less => true,
+                Ordering::Equal => inclusive,
+                Ordering::Greater => false,
+            };
+            if below {
+                count += 1 + utils::subtree_size(&n.left);
+                cur = n.right.as_ref();
+            } else {
+                cur = n.left.as_ref();
+            }
         }
+        count
+    }
 
-        let left_priority = node.left.as_ref().map_or(i128::MIN, |n| n.priority);
-        let right_priority = node.right.as_ref().map_or(i128::MIN, |n| n.priority);
+    /// Lazily yields every key/value pair in ascending key order.
+    pub fn iter(&self) -> Iter<'_> {
+        Iter::new(self.root.as_deref())
+    }
 
-        if left_priority > right_priority {
-            let mut new_node = utils::rotate_right(node);
-            new_node.right = Self::heapify(new_node.right.take().unwrap());
-            Some(new_node)
-        } else {
-            let mut new_node = utils::rotate_left(node);
-            new_node.left = Self::heapify(new_node.left.take().unwrap());
-            Some(new_node)
+    /// Empties the tree and yields its entries in ascending key order,
+    /// moving them out without cloning. The tree is empty as soon as this
+    /// returns, so dropping the iterator early just drops the rest.
+    pub fn drain(&mut self) -> IntoIter {
+        self.size = 0;
+        let root = self.root.take();
+        if root.is_some() {
+            self.record(OpSummary::Clear);
         }
+        IntoIter::new(root)
     }
 
-    pub fn generate_proof(&self, key: &Key) -> Proof {
-        let mut prefix: Vec<(Key, Hash)> = Vec::new();
-        let mut cur = self.root.as_ref();
-        let mut last: Option<&TreeNode> = None;
-        let mut existence = false;
+    /// Lazily yields the entries whose keys fall in `range`, in ascending
+    /// key order, skipping subtrees that lie outside it. Bounds behave as in
+    /// `BTreeMap::range`.
+    pub fn range<R: RangeBounds<Key>>(&self, range: R) -> Range<'_> {
+        Range::new(self.root.as_deref(), range)
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &Key> {
+        self.iter().map(|(k, _)| k)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &Value> {
+        self.iter().map(|(_, v)| v)
+    }
+
+    /// Smallest key in the tree, found by walking the left spine.
+    pub fn min_key(&self) -> Option<&Key> {
+        self.first().map(|(k, _)| k)
+    }
+
+    /// Largest key in the tree, found by walking the right spine.
+    pub fn max_key(&self) -> Option<&Key> {
+        self.last().map(|(k, _)| k)
+    }
 
+    /// Entry with the smallest key, in O(height).
+    pub fn first(&self) -> Option<(&Key, &Value)> {
+        let mut n = self.root.as_deref()?;
+        while let Some(l) = n.left.as_deref() {
+            n = l;
+        }
+        Some((&n.key, &n.value))
+    }
+
+    /// Entry with the largest key, in O(height).
+    pub fn last(&self) -> Option<(&Key, &Value)> {
+        let mut n = self.root.as_deref()?;
+        while let Some(r) = n.right.as_deref() {
+            n = r;
+        }
+        Some((&n.key, &n.value))
+    }
+
+    /// Entry with the smallest key, borrowed, as
+    /// `BTreeMap::first_key_value` returns it; the same as `first`.
+    ///
+    /// ```
+    /// use cmt_core::CartesianMerkleTree;
+    /// use std::collections::BTreeMap;
+    ///
+    /// let mut cmt = CartesianMerkleTree::new();
+    /// let mut map = BTreeMap::new();
+    /// assert_eq!(cmt.first_key_value(), map.first_key_value());
+    /// for i in [7u8, 3, 9] {
+    ///     cmt.insert([i; 32], vec![i]);
+    ///     map.insert([i; 32], vec![i]);
+    /// }
+    /// assert_eq!(cmt.first_key_value(), map.first_key_value());
+    /// assert_eq!(cmt.first_key_value(), Some((&[3; 32], &vec![3])));
+    /// ```
+    pub fn first_key_value(&self) -> Option<(&Key, &Value)> {
+        self.first()
+    }
+
+    /// Entry with the largest key, borrowed, as `BTreeMap::last_key_value`
+    /// returns it; the same as `last`.
+    ///
+    /// ```
+    /// use cmt_core::CartesianMerkleTree;
+    /// use std::collections::BTreeMap;
+    ///
+    /// let mut cmt = CartesianMerkleTree::new();
+    /// let mut map = BTreeMap::new();
+    /// assert_eq!(cmt.last_key_value(), map.last_key_value());
+    /// for i in [7u8, 3, 9] {
+    ///     cmt.insert([i; 32], vec![i]);
+    ///     map.insert([i; 32], vec![i]);
+    /// }
+    /// assert_eq!(cmt.last_key_value(), map.last_key_value());
+    /// assert_eq!(cmt.last_key_value(), Some((&[9; 32], &vec![9])));
+    /// ```
+    pub fn last_key_value(&self) -> Option<(&Key, &Value)> {
+        self.last()
+    }
+
+    /// Removes and returns the entry with the smallest key, in O(height).
+    pub fn pop_min(&mut self) -> Option<(Key, Value)> {
+        let key = *self.first()?.0;
+        self.remove(&key).map(|value| (key, value))
+    }
+
+    /// Removes and returns the entry with the largest key, in O(height).
+    pub fn pop_max(&mut self) -> Option<(Key, Value)> {
+        let key = *self.last()?.0;
+        self.remove(&key).map(|value| (key, value))
+    }
+
+    /// Entry with the largest key strictly below `key`, whether or not `key`
+    /// itself is present. The answer is the last node where the descent
+    /// turned right.
+    pub fn predecessor(&self, key: &Key) -> Option<(&Key, &Value)> {
+        let mut best = None;
+        let mut cur = self.root.as_deref();
         while let Some(n) = cur {
-            if &n.key == key {
-                existence = true;
-                last = Some(n);
-                break;
+            if n.key < *key {
+                best = Some(n);
+                cur = n.right.as_deref();
+            } else {
+                cur = n.left.as_deref();
             }
-            // push (parent.e.k, parent.mh)
-            prefix.push((n.key.clone(), n.hash.clone()));
-            if key < &n.key {
-                cur = n.left.as_ref();
+        }
+        best.map(|n| (&n.key, &n.value))
+    }
+
+    /// Entry with the smallest key strictly above `key`, whether or not
+    /// `key` itself is present. The answer is the last node where the
+    /// descent turned left.
+    pub fn successor(&self, key: &Key) -> Option<(&Key, &Value)> {
+        let mut best = None;
+        let mut cur = self.root.as_deref();
+        while let Some(n) = cur {
+            if n.key > *key {
+                best = Some(n);
+                cur = n.left.as_deref();
             } else {
-                cur = n.right.as_ref();
+                cur = n.right.as_deref();
             }
         }
+        best.map(|n| (&n.key, &n.value))
+    }
 
-        let (left_h, right_h, non_ex_key) = if existence {
-            let ln = last
-                .unwrap()
-                .left
-                .as_ref()
-                .map(|x| x.hash.clone())
-                .unwrap_or_default();
-            let rn = last
-                .unwrap()
-                .right
-                .as_ref()
-                .map(|x| x.hash.clone())
-                .unwrap_or_default();
-            (ln, rn, None)
-        } else {
-            // non-existence: use the last traversed node as witness key
-            let witness = prefix.last().map(|(k, _)| k.clone());
-            let (ln, rn) = match cur {
-                Some(n) => (
-                    n.left.as_ref().map(|x| x.hash.clone()).unwrap_or_default(),
-                    n.right.as_ref().map(|x| x.hash.clone()).unwrap_or_default(),
-                ),
-                None => (Vec::new(), Vec::new()),
-            };
-            (ln, rn, witness)
-        };
+    /// Lazily yields every key with its membership proof, in key order.
+    /// Proofs are built from the traversal's own descent stack, so this is
+    /// much cheaper than calling `generate_proof` once per key.
+    pub fn proofs(&self) -> Proofs<'_> {
+        self.settle();
+        Proofs::new(self.root.as_deref())
+    }
 
-        let suffix = [left_h, right_h];
+    /// Writes the proofs of every key to `writer`, storing each node once
+    /// and having proofs reference shared ancestors. Read them back with
+    /// `ProofExport::read_from`.
+    #[cfg(feature = "std")]
+    pub fn export_all_proofs<W: io::Write>(&self, writer: W) -> io::Result<()> {
+        self.settle();
+        export::write_all(self.root.as_deref(), writer)
+    }
 
-        Proof {
-            prefix,
-            suffix,
-            existence,
-            nonexistence_key: non_ex_key,
+    /// Rebuilds the tree from its current entries, re-deriving every
+    /// priority with the tree's priority function. After heavy churn this
+    /// yields a fresh, densely allocated tree with the canonical shape for
+    /// its key set.
+    pub fn compact(&mut self) {
+        let entries = self.take_entries();
+        let priority_fn = &self.priority_fn;
+        self.root =
+            Self::build_sorted(entries.into_iter().map(|(k, v, _)| (k, v, priority_fn(&k))));
+        self.record(OpSummary::Compact);
+    }
+
+    /// Rebuilds the tree into a perfectly balanced shape, `height()` at most
+    /// `log2(len) + 1`, for when its priorities have left it skewed: a poor
+    /// priority function, colliding priorities, or caller-supplied ones
+    /// from `insert_with_priority`. Runs in O(n).
+    ///
+    /// The tree's own priorities are handed out again by depth, highest at
+    /// the root, so they stay spread over the same range: a later insert
+    /// still lands as high as its priority puts it rather than always
+    /// sinking below the rebuilt nodes. Entries are unchanged; the root
+    /// hash commits to the shape and changes with it.
+    ///
+    /// Nodes no longer carry the priority their key derives, so the shape
+    /// now depends on the tree's history and not just its key set: two trees
+    /// with the same entries can stop sharing a root hash, and merging the
+    /// tree into another takes `merge`'s slower path. Later inserts still derive their priorities, which
+    /// over time pulls the tree back toward the priority function's shape,
+    /// skew included. `compact` restores the canonical shape.
+    pub fn rebalance(&mut self) {
+        let entries = self.take_entries();
+        // depth of each in-order position when the middle entry of every
+        // range is its root
+        let mut depths = vec![0; entries.len()];
+        let mut widths: Vec<usize> = Vec::new();
+        let mut ranges = vec![(0, entries.len(), 0)];
+        while let Some((lo, hi, depth)) = ranges.pop() {
+            if lo < hi {
+                let mid = lo + (hi - lo) / 2;
+                depths[mid] = depth;
+                if widths.len() <= depth {
+                    widths.push(0);
+                }
+                widths[depth] += 1;
+                ranges.push((lo, mid, depth + 1));
+                ranges.push((mid + 1, hi, depth + 1));
+            }
+        }
+
+        // deal the priorities out level by level, highest first; each level
+        // stays strictly below the one above, so ties can't break the heap,
+        // and above a floor that leaves one value per level below it, so
+        // priorities bunched at `Priority::MIN` don't run out of room
+        let mut priorities: Vec<Priority> = entries.iter().map(|(_, _, p)| *p).collect();
+        priorities.sort_unstable_by(|a, b| b.cmp(a));
+        let mut dealt = priorities.into_iter();
+        let mut cap = Priority::MAX;
+        let mut by_level = Vec::with_capacity(widths.len());
+        let levels = widths.len();
+        for (depth, width) in widths.into_iter().enumerate() {
+            let floor = Priority::MIN + (levels - 1 - depth) as Priority;
+            let row: Vec<Priority> = dealt
+                .by_ref()
+                .take(width)
+                .map(|p| p.clamp(floor, cap))
+                .collect();
+            cap = row.last().map_or(cap, |p| p.saturating_sub(1));
+            by_level.push(row.into_iter());
         }
+
+        self.root =
+            Self::build_sorted(entries.into_iter().zip(depths).map(|((k, v, _), depth)| {
+                let priority = by_level[depth].next().expect("one priority per node");
+                (k, v, priority)
+            }));
+        self.record(OpSummary::Rebalance);
     }
-    pub fn verify_proof(proof: Proof, key: Key, root_hash: Hash) -> bool
-where {
-        let mut acc = Vec::new();
-        if proof.existence {
-            acc = calculate_merkle_hash(&key, proof.suffix[0].as_ref(), proof.suffix[1].as_ref());
-        } else {
-            acc = calculate_merkle_hash(
-                &proof.nonexistence_key.unwrap(),
-                proof.suffix[0].as_ref(),
-                proof.suffix[1].as_ref(),
-            )
+
+    /// Whether every node under `root` carries the priority this tree's
+    /// priority function gives its key.
+    fn derives_priorities(&self, root: Option<&TreeNode>) -> bool {
+        let mut stack: Vec<&TreeNode> = root.into_iter().collect();
+        while let Some(n) = stack.pop() {
+            if n.priority != (self.priority_fn)(&n.key) {
+                return false;
+            }
+            stack.extend(n.left.as_deref());
+            stack.extend(n.right.as_deref());
         }
+        true
+    }
 
-        for (k, mh) in proof.prefix {
-            acc = calculate_merkle_hash(&k, &acc, &mh)
+    /// Empties the tree into its entries in key order, with their
+    /// priorities, without recursion.
+    fn take_entries(&mut self) -> Vec<(Key, Value, Priority)> {
+        let mut entries = Vec::with_capacity(utils::subtree_size(&self.root));
+        let mut stack: Vec<Box<TreeNode>> = Vec::new();
+        let mut cur = self.root.take();
+        loop {
+            while let Some(mut n) = cur {
+                cur = n.left.take();
+                stack.push(n);
+            }
+            match stack.pop() {
+                Some(mut n) => {
+                    cur = n.right.take();
+                    entries.push((n.key, core::mem::take(&mut n.value), n.priority));
+                }
+                None => return entries,
+            }
         }
+    }
 
-        acc == root_hash
+    /// Bulk-loads a tree in O(n), hashing every node at most once, instead of
+    /// inserting the pairs one by one. The result is identical to inserting
+    /// them. Input that isn't sorted by key is sorted first; when a key
+    /// repeats, its last value wins, as with repeated inserts.
+    pub fn from_sorted(mut pairs: Vec<(Key, Value)>) -> Self {
+        if !pairs.windows(2).all(|w| w[0].0 < w[1].0) {
+            // stable, so equal keys keep their input order for the dedup
+            pairs.sort_by_key(|(k, _)| *k);
+            pairs.reverse();
+            pairs.dedup_by(|a, b| a.0 == b.0);
+            pairs.reverse();
+        }
+        let mut tree = Self::new();
+        tree.size = pairs.len();
+        let priority_fn = &tree.priority_fn;
+        tree.root = Self::build_sorted(pairs.into_iter().map(|(k, v)| (k, v, priority_fn(&k))));
+        tree
     }
-}
 
-pub struct Proof {
-    pub prefix: Vec<(Key, Hash)>,
-    pub suffix: [Hash; 2],
-    pub existence: bool,
-    pub nonexistence_key: Option<Key>,
-}
+    /// Builds a treap from entries sorted by key, with their priorities, in
+    /// a single O(n) pass, keeping the right spine on a stack.
+    fn build_sorted(
+        entries: impl IntoIterator<Item = (Key, Value, Priority)>,
+    ) -> Option<Box<TreeNode>> {
+        let finish = |n: &mut Box<TreeNode>| {
+            n.subtree_size = 1 + utils::subtree_size(&n.left) + utils::subtree_size(&n.right);
+        };
 
-fn find_priority(key: &Key) -> Priority {
-    use sha2::{Digest, Sha256};
-    let digest = Sha256::digest(key.as_ref());
-    let mut bytes = [0u8; 16];
-    bytes.copy_from_slice(&digest[..16]);
-    i128::from_be_bytes(bytes) as i128
+        let mut spine: Vec<Box<TreeNode>> = Vec::new();
+        for (key, value, priority) in entries {
+            let mut last: Option<Box<TreeNode>> = None;
+            while spine.last().is_some_and(|top| top.priority < priority) {
+                let mut top = spine.pop().unwrap();
+                top.right = last.take();
+                finish(&mut top);
+                last = Some(top);
+            }
+            spine.push(Box::new(TreeNode {
+                key,
+                priority,
+                value_hash: hash_value::<H>(&value),
+                value,
+                hash: HashCell::new(),
+                subtree_size: 1,
+                left: last,
+                right: None,
+            }));
+        }
+
+        let mut last = None;
+        while let Some(mut top) = spine.pop() {
+            top.right = last.take();
+            finish(&mut top);
+            last = Some(top);
+        }
+        last
+    }
+
+    /// Walks every node depth-first, calling the visitor's pre-, in- and
+    /// post-order hooks.
+    pub fn walk<V: Visitor + ?Sized>(&self, visitor: &mut V) {
+        self.settle();
+        visitor::walk(self.root.as_deref(), visitor);
+    }
+
+    pub fn insert(&mut self, key: Key, value: Value) {
+        let priority = (self.priority_fn)(&key);
+        self.insert_with_priority(key, value, priority);
+    }
+
+    /// Inserts using a caller-supplied priority instead of the one derived
+    /// by the tree's priority function, e.g. to import a tree built elsewhere
+    /// or to pin down a shape in a test.
+    ///
+    /// Manual and derived priorities can be mixed in one tree: every node is
+    /// placed by its own priority, so the heap order always holds, but the
+    /// shape and root hash then depend on the priorities given and not just
+    /// on the key set. Re-inserting a key keeps the higher of its old and
+    /// new priority.
+    pub fn insert_with_priority(&mut self, key: Key, value: Value, priority: Priority) {
+        // detach the search path, stopping where the new node belongs
+        let mut path: Vec<(Box<TreeNode>, Ordering)> = Vec::new();
+        let mut cur = self.root.take();
+        while let Some(mut n) = cur {
+            let dir = key.cmp(&n.key);
+            if outranks(priority, &key, n.priority, &n.key) || dir == Ordering::Equal {
+                cur = Some(n);
+                break;
+            }
+            cur = match dir {
+                Ordering::Less => n.left.take(),
+                _ => n.right.take(),
+            };
+            path.push((n, dir));
+        }
+
+        let subtree = match cur {
+            // the same bytes again: nothing to rehash or record
+            Some(n) if n.key == key && priority <= n.priority && n.value == value => {
+                path.push((n, Ordering::Equal));
+                self.restore_path(path);
+                return;
+            }
+            // same key, and the new priority doesn't lift it: overwrite
+            Some(mut n) if n.key == key && priority <= n.priority => {
+                n.value_hash = hash_value::<H>(&value);
+                n.value = value;
+                Self::refresh(&mut n);
+                n
+            }
+            rest => {
+                let mut replaced = None;
+                let (left, right) = Self::split(rest, &key, &mut replaced);
+                let mut n = Box::new(TreeNode {
+                    key,
+                    priority,
+                    value_hash: hash_value::<H>(&value),
+                    value,
+                    hash: HashCell::new(),
+                    subtree_size: 1,
+                    left,
+                    right,
+                });
+                Self::refresh(&mut n);
+                if replaced.is_none() {
+                    self.size += 1;
+                }
+                n
+            }
+        };
+        self.root = Self::reattach(path, Some(subtree));
+        self.record(OpSummary::Insert(key));
+    }
+
+    /// Hangs `subtree` back under the detached `path` (root first), fixing
+    /// up every ancestor's size and marking its hash stale on the way up.
+    fn reattach(
+        mut path: Vec<(Box<TreeNode>, Ordering)>,
+        mut subtree: Option<Box<TreeNode>>,
+    ) -> Option<Box<TreeNode>> {
+        while let Some((mut n, dir)) = path.pop() {
+            match dir {
+                Ordering::Less => n.left = subtree,
+                _ => n.right = subtree,
+            }
+            Self::refresh(&mut n);
+            subtree = Some(n);
+        }
+        subtree
+    }
+
+    /// Splits `node` into the subtrees holding keys below and above `key`,
+    /// moving nodes rather than copying them and marking every node whose
+    /// children change stale. A node already holding `key` is detached into
+    /// `found`, childless, so the caller can tell an overwrite from an
+    /// insert. Walks with explicit stacks, so a skewed tree can't overflow.
+    fn split(
+        node: Option<Box<TreeNode>>,
+        key: &Key,
+        found: &mut Option<Box<TreeNode>>,
+    ) -> (Option<Box<TreeNode>>, Option<Box<TreeNode>>) {
+        // nodes bound for the left result, each missing its right child, and
+        // for the right result, each missing its left child
+        let (mut lower, mut upper): (Vec<Box<TreeNode>>, Vec<Box<TreeNode>>) =
+            (Vec::new(), Vec::new());
+        let mut cur = node;
+        let (mut left, mut right) = (None, None);
+        while let Some(mut n) = cur {
+            match n.key.cmp(key) {
+                Ordering::Less => {
+                    cur = n.right.take();
+                    lower.push(n);
+                }
+                Ordering::Greater => {
+                    cur = n.left.take();
+                    upper.push(n);
+                }
+                Ordering::Equal => {
+                    (left, right) = (n.left.take(), n.right.take());
+                    *found = Some(n);
+                    break;
+                }
+            }
+        }
+
+        while let Some(mut n) = lower.pop() {
+            n.right = left;
+            Self::refresh(&mut n);
+            left = Some(n);
+        }
+        while let Some(mut n) = upper.pop() {
+            n.left = right;
+            Self::refresh(&mut n);
+            right = Some(n);
+        }
+        (left, right)
+    }
+
+    /// Marks `node`'s hash stale and recomputes its subtree size from its
+    /// children.
+    fn refresh(node: &mut TreeNode) {
+        node.hash.take();
+        node.subtree_size = 1 + utils::subtree_size(&node.left) + utils::subtree_size(&node.right);
+    }
+
+    /// Moves every entry of `other` into this tree; where both hold a key,
+    /// `other`'s value wins. The result is the same tree as inserting the
+    /// entries one by one.
+    ///
+    /// When `other`'s priorities come from this tree's priority function, as
+    /// with two trees from `new`, the two treaps are merged by splitting one
+    /// around the other's higher-priority roots, in O(m log(n / m)) for
+    /// sizes m <= n. Subtrees that come through whole keep their hashes;
+    /// disjoint key ranges are the cheapest case. Otherwise `other`'s
+    /// entries are inserted one at a time.
+    pub fn merge(&mut self, mut other: Self) {
+        if !self.derives_priorities(other.root.as_deref()) {
+            // one record for the whole merge, not one per insert
+            let history = self.history.take();
+            self.extend(other);
+            self.history = history;
+            self.record(OpSummary::Merge);
+            return;
+        }
+
+        enum Task {
+            /// Merge two subtrees; the second one's values win.
+            Union(Option<Box<TreeNode>>, Option<Box<TreeNode>>),
+            /// Give the node the last two results as its children.
+            Attach(Box<TreeNode>),
+        }
+        let mut results: Vec<Option<Box<TreeNode>>> = Vec::new();
+        let mut tasks = vec![Task::Union(self.root.take(), other.root.take())];
+        while let Some(task) = tasks.pop() {
+            let (top, left, right) = match task {
+                Task::Union(None, t) | Task::Union(t, None) => {
+                    results.push(t);
+                    continue;
+                }
+                Task::Attach(mut n) => {
+                    n.right = results.pop().flatten();
+                    n.left = results.pop().flatten();
+                    Self::refresh(&mut n);
+                    results.push(Some(n));
+                    continue;
+                }
+                Task::Union(Some(mut a), Some(mut b)) => {
+                    let mut dup = None;
+                    if outranks(b.priority, &b.key, a.priority, &a.key) {
+                        let (lo, hi) = Self::split(Some(a), &b.key, &mut dup);
+                        let (l, r) = (b.left.take(), b.right.take());
+                        (b, Task::Union(lo, l), Task::Union(hi, r))
+                    } else {
+                        let (lo, hi) = Self::split(Some(b), &a.key, &mut dup);
+                        if let Some(mut d) = dup {
+                            a.value = core::mem::take(&mut d.value);
+                            a.value_hash = d.value_hash;
+                        }
+                        let (l, r) = (a.left.take(), a.right.take());
+                        (a, Task::Union(l, lo), Task::Union(r, hi))
+                    }
+                }
+            };
+            tasks.push(Task::Attach(top));
+            tasks.push(right);
+            tasks.push(left);
+        }
+        self.root = results.pop().flatten();
+        self.size = utils::subtree_size(&self.root);
+        self.record(OpSummary::Merge);
+    }
+
+    /// Splits the tree into the entries with keys below `key` and those at
+    /// or above it, the inverse of `merge`. Both halves keep the priority
+    /// function, and only the O(log n) nodes along the split path are
+    /// rehashed.
+    pub fn split_at(mut self, key: &Key) -> (Self, Self) {
+        let mut at = None;
+        let (left, right) = Self::split(self.root.take(), key, &mut at);
+        let half = |root: Option<Box<TreeNode>>| Self {
+            size: utils::subtree_size(&root),
+            root,
+            priority_fn: self.priority_fn.clone(),
+            history: None,
+            hasher: PhantomData,
+        };
+        let (lower, mut upper) = (half(left), half(right));
+        if let Some(mut n) = at {
+            upper.insert_with_priority(n.key, core::mem::take(&mut n.value), n.priority);
+        }
+        (lower, upper)
+    }
+
+    /// Removes `key`, returning its value if it was present.
+    pub fn remove(&mut self, key: &Key) -> Option<Value> {
+        let mut path: Vec<(Box<TreeNode>, Ordering)> = Vec::new();
+        let mut cur = self.root.take();
+        let found = loop {
+            let Some(mut n) = cur else {
+                break None;
+            };
+            let dir = key.cmp(&n.key);
+            if dir == Ordering::Equal {
+                break Some(n);
+            }
+            cur = match dir {
+                Ordering::Less => n.left.take(),
+                _ => n.right.take(),
+            };
+            path.push((n, dir));
+        };
+
+        let Some(mut n) = found else {
+            // nothing changed below the path, so no hash is stale
+            self.restore_path(path);
+            return None;
+        };
+
+        let value = core::mem::take(&mut n.value);
+        let subtree = Self::heapify(&mut n);
+        self.root = Self::reattach(path, subtree);
+        self.size -= 1;
+        self.record(OpSummary::Remove(*key));
+        Some(value)
+    }
+
+    /// Removes `key` and returns its value together with a membership proof
+    /// against the pre-removal root and the root hash after the removal, so
+    /// a verifier can check the state transition. Returns `None` if the key
+    /// was not present.
+    pub fn remove_with_proof(&mut self, key: &Key) -> Option<(Value, Proof, Hash)> {
+        let proof = self.generate_proof(key);
+        if !proof.existence {
+            return None;
+        }
+
+        let removed = self.remove(key);
+        let new_root = self.root_hash().unwrap_or_else(empty_hash);
+
+        removed.map(|value| (value, proof, new_root))
+    }
+
+    /// Rotates `node` down until it has at most one child and unlinks it,
+    /// lifting the higher-priority child at each step. Returns the subtree
+    /// that takes its place. The rotations are done by hand on the children
+    /// already taken out, so there is no missing-child case to fail on.
+    fn heapify(node: &mut TreeNode) -> Option<Box<TreeNode>> {
+        let mut lifted: Vec<(Box<TreeNode>, Ordering)> = Vec::new();
+        loop {
+            // once a side is empty the other child takes `node`'s place
+            // whatever its priority, and `node` is left childless
+            let (l, r) = match (node.left.take(), node.right.take()) {
+                (Some(l), Some(r)) => (l, r),
+                (only, None) | (None, only) => return Self::reattach(lifted, only),
+            };
+            // lift the higher-ranked child; `node` sinks to the side it came
+            // from, taking over the lifted child's inner subtree
+            if outranks(l.priority, &l.key, r.priority, &r.key) {
+                let mut top = l;
+                node.left = top.right.take();
+                node.right = Some(r);
+                lifted.push((top, Ordering::Greater));
+            } else {
+                let mut top = r;
+                node.right = top.left.take();
+                node.left = Some(l);
+                lifted.push((top, Ordering::Less));
+            }
+        }
+    }
+
+    pub fn generate_proof(&self, key: &Key) -> Proof {
+        self.generate_proof_borrowed(key).into_owned()
+    }
+
+    /// Like `generate_proof`, but the proof borrows keys and hashes from the
+    /// tree instead of cloning them. Convert with `into_owned` when the
+    /// proof has to outlive the borrow, e.g. before serializing it.
+    pub fn generate_proof_borrowed(&self, key: &Key) -> BorrowedProof<'_> {
+        self.lookup_with_proof(key).1
+    }
+
+    /// The value under `key` together with a proof for it, from a single
+    /// walk down the tree. The proof commits to the value's hash, so
+    /// `verify_value` accepts exactly the value returned here; for a
+    /// missing key it is a non-existence proof.
+    pub fn get_with_proof(&self, key: &Key) -> (Option<&Value>, Proof) {
+        let (value, proof) = self.lookup_with_proof(key);
+        (value, proof.into_owned())
+    }
+
+    fn lookup_with_proof(&self, key: &Key) -> (Option<&Value>, BorrowedProof<'_>) {
+        self.settle();
+        let mut prefix: Vec<(&Key, &Hash, &Hash)> = Vec::new();
+        let mut cur = self.root.as_deref();
+        let mut last: Option<&TreeNode> = None;
+        let mut existence = false;
+
+        while let Some(n) = cur {
+            last = Some(n);
+            if &n.key == key {
+                existence = true;
+                break;
+            }
+            let (next, sibling) = if key < &n.key {
+                (n.left.as_deref(), n.right.as_deref())
+            } else {
+                (n.right.as_deref(), n.left.as_deref())
+            };
+            if next.is_none() {
+                // non-existence: the last traversed node is the witness
+                break;
+            }
+            // push (parent.e.k, parent.e.vh, sibling.mh)
+            prefix.push((
+                &n.key,
+                &n.value_hash,
+                sibling.map_or(&EMPTY_HASH, |x| x.hash()),
+            ));
+            cur = next;
+        }
+        // prefix is folded from the witness up to the root
+        prefix.reverse();
+
+        let (left_h, right_h) = match last {
+            Some(n) => (
+                n.left.as_ref().map_or(&EMPTY_HASH, |x| x.hash()),
+                n.right.as_ref().map_or(&EMPTY_HASH, |x| x.hash()),
+            ),
+            None => (&EMPTY_HASH, &EMPTY_HASH),
+        };
+        // canonical order from the paper: suffix[0] < suffix[1]
+        let suffix = if left_h <= right_h {
+            [left_h, right_h]
+        } else {
+            [right_h, left_h]
+        };
+        let non_ex_key = if existence {
+            None
+        } else {
+            last.map(|n| &n.key)
+        };
+        // a witness with one child opens it, to show which side is empty
+        let child = match last {
+            Some(n) if !existence => match (n.left.as_deref(), n.right.as_deref()) {
+                (Some(c), None) | (None, Some(c)) => {
+                    Some((&c.key, &c.value_hash, sorted_children(c)))
+                }
+                _ => None,
+            },
+            _ => None,
+        };
+
+        let proof = BorrowedProof {
+            prefix,
+            suffix,
+            value_hash: last.map_or(&EMPTY_HASH, |n| &n.value_hash),
+            existence,
+            nonexistence_key: non_ex_key,
+            nonexistence_child: child,
+        };
+        let value = last.filter(|_| existence).map(|n| &n.value);
+        (value, proof)
+    }
+
+    pub fn verify_proof(proof: Proof, key: Key, root_hash: Hash) -> bool {
+        Self::verify_proof_detailed(proof, key, root_hash).is_ok()
+    }
+
+    /// Same check as `verify_proof`, reporting why a proof was rejected.
+    pub fn verify_proof_detailed(
+        proof: Proof,
+        key: Key,
+        root_hash: Hash,
+    ) -> Result<(), VerifyError> {
+        Self::verify_into(&mut Vec::new(), &proof, &key, &root_hash)
+    }
+
+    /// `verify_proof_detailed`, serializing every node it hashes into
+    /// `buf`.
+    pub(crate) fn verify_into(
+        buf: &mut Vec<u8>,
+        proof: &Proof,
+        key: &Key,
+        root_hash: &Hash,
+    ) -> Result<(), VerifyError> {
+        let leaf_key = if proof.existence {
+            key
+        } else {
+            let Some(witness) = &proof.nonexistence_key else {
+                return match VerifyError::missing_witness(proof) {
+                    // the key is absent from the empty tree
+                    VerifyError::EmptyProof if *root_hash == EMPTY_ROOT => Ok(()),
+                    err => Err(err),
+                };
+            };
+            if !Self::proves_gap(buf, proof, key) {
+                return Err(VerifyError::KeyNotInGap);
+            }
+            witness
+        };
+
+        let mut acc = calculate_merkle_hash_into::<H, _>(
+            buf,
+            leaf_key,
+            &proof.value_hash,
+            &proof.suffix[0],
+            &proof.suffix[1],
+        );
+        for (k, vh, mh) in &proof.prefix {
+            acc = calculate_merkle_hash_into::<H, _>(buf, k, vh, &acc, mh)
+        }
+
+        if acc != *root_hash {
+            return Err(VerifyError::RootMismatch {
+                expected: *root_hash,
+                got: acc,
+            });
+        }
+        Ok(())
+    }
+
+    /// Checks the part of a non-existence proof the hash chain can't: the
+    /// search for `key` must take the same turns as the path to the
+    /// witness, and end at the witness on a side where it has no child.
+    /// Child hashes are stored sorted, so when the witness has one child
+    /// the proof opens it to show which side that child is on.
+    fn proves_gap(buf: &mut Vec<u8>, proof: &Proof, key: &Key) -> bool {
+        let Some(witness) = &proof.nonexistence_key else {
+            return false;
+        };
+        let opening = proof
+            .nonexistence_child
+            .as_ref()
+            .map(|(k, vh, [l, r])| (k, vh, [l, r]));
+        falls_in_gap::<H>(
+            buf,
+            key,
+            witness,
+            proof.prefix.iter().map(|(k, _, _)| k),
+            [&proof.suffix[0], &proof.suffix[1]],
+            opening,
+        )
+    }
+
+    /// Checks that `key` maps to `value` under `root_hash`: the proof must
+    /// be a membership proof for `key` whose committed value hash matches.
+    pub fn verify_value(proof: Proof, key: Key, value: &[u8], root_hash: Hash) -> bool {
+        proof.existence
+            && proof.value_hash == hash_value::<H>(value)
+            && Self::verify_proof(proof, key, root_hash)
+    }
+
+    /// Checks that `key` is committed to `value_hash` under `root_hash`
+    /// without seeing the value, for light clients that shouldn't learn
+    /// values they don't query. The commitment is `H(value)`, so a client
+    /// that later gets the value can open it, as `verify_value` does.
+    pub fn verify_commitment(proof: Proof, key: Key, value_hash: &Hash, root_hash: Hash) -> bool {
+        proof.existence
+            && proof.value_hash == *value_hash
+            && Self::verify_proof(proof, key, root_hash)
+    }
+
+    /// Strict variant of `verify_proof` that also enforces the structural
+    /// rules of the documented algorithm while folding: the suffix must be in
+    /// canonical order (`suffix[0] < suffix[1]`, or both empty), no value
+    /// hash may be the empty sentinel, the witness key must match the
+    /// `existence` flag, and no prefix hash may equal the running
+    /// accumulator (which would leave the fold order ambiguous).
+    /// Non-existence proofs get the same gap check as in `verify_proof`.
+    pub fn verify_strict(proof: Proof, key: Key, root_hash: Hash) -> bool {
+        let [left, right] = &proof.suffix;
+        if left > right || (left == right && !is_empty_subtree_hash(left)) {
+            return false;
+        }
+
+        let leaf_key = match (proof.existence, proof.nonexistence_key) {
+            (true, None) if proof.nonexistence_child.is_none() => key,
+            (false, Some(witness)) if Self::proves_gap(&mut Vec::new(), &proof, &key) => witness,
+            (false, None) => return proof.is_empty_tree_proof() && root_hash == EMPTY_ROOT,
+            _ => return false,
+        };
+
+        if is_empty_subtree_hash(&proof.value_hash) {
+            return false;
+        }
+        let mut acc = calculate_merkle_hash::<H, _>(&leaf_key, &proof.value_hash, left, right);
+        for (k, vh, mh) in &proof.prefix {
+            if is_empty_subtree_hash(vh) || *mh == acc {
+                return false;
+            }
+            acc = calculate_merkle_hash::<H, _>(k, vh, &acc, mh);
+        }
+
+        acc == root_hash
+    }
+}
+
+/// Shape summary returned by `CartesianMerkleTree::stats`. Depths are
+/// 0-based, as in `depth_of`; a balanced tree of `n` keys has an average
+/// depth around `log2(n)`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TreeStats {
+    pub max_depth: usize,
+    pub average_depth: f64,
+}
+
+/// Number of equal-width buckets `PriorityStats` splits the priority range
+/// into.
+pub const PRIORITY_BUCKETS: usize = 16;
+
+/// Priority distribution returned by `CartesianMerkleTree::priority_stats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PriorityStats {
+    /// Lowest and highest priority, `None` for an empty tree.
+    pub min: Option<Priority>,
+    pub max: Option<Priority>,
+    /// Nodes whose priority equals that of a node with a smaller key;
+    /// `len` minus the number of distinct priorities. Equal priorities
+    /// fall back to key order, which is how skew creeps in.
+    pub collisions: usize,
+    /// Node counts over `PRIORITY_BUCKETS` equal slices of the whole
+    /// priority range, lowest first. Hash-derived priorities should fill
+    /// them about evenly.
+    pub buckets: [usize; PRIORITY_BUCKETS],
+}
+
+/// Why `verify_proof_detailed` rejected a proof.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerifyError {
+    /// A non-existence proof with no path and no witness, as generated
+    /// from an empty tree, checked against a root other than `EMPTY_ROOT`.
+    EmptyProof,
+    /// A non-existence proof with a path but no witness key.
+    MissingNonExistenceKey,
+    /// The key doesn't fall in the gap next to the witness, so the proof
+    /// says nothing about it.
+    KeyNotInGap,
+    /// The proof folds to `got` instead of the `expected` root.
+    RootMismatch { expected: Hash, got: Hash },
+}
+
+impl VerifyError {
+    fn missing_witness(proof: &Proof) -> Self {
+        if proof.is_empty_tree_proof() {
+            Self::EmptyProof
+        } else {
+            Self::MissingNonExistenceKey
+        }
+    }
+}
+
+impl core::fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::EmptyProof => write!(f, "proof is empty"),
+            Self::MissingNonExistenceKey => write!(f, "non-existence proof has no witness key"),
+            Self::KeyNotInGap => write!(f, "key is not in the gap the proof covers"),
+            Self::RootMismatch { expected, got } => write!(
+                f,
+                "proof folds to root {} instead of {}",
+                hex::encode(got),
+                hex::encode(expected)
+            ),
+        }
+    }
+}
+
+impl core::error::Error for VerifyError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct Proof {
+    /// `(ancestor key, ancestor value hash, sibling hash)`, from the
+    /// witness up to the root.
+    pub prefix: Vec<(Key, Hash, Hash)>,
+    pub suffix: [Hash; 2],
+    /// Value hash of the proven entry, or of the witness for non-existence
+    /// proofs.
+    pub value_hash: Hash,
+    pub existence: bool,
+    pub nonexistence_key: Option<Key>,
+    /// For non-existence proofs whose witness has exactly one child: that
+    /// child's key, value hash and sorted child hashes.
+    pub nonexistence_child: Option<(Key, Hash, [Hash; 2])>,
+}
+
+impl Proof {
+    /// Whether this is the proof an empty tree hands out for every key:
+    /// non-existence, with no path, witness or child opening and only empty
+    /// hashes. It verifies against `EMPTY_ROOT` and no other root.
+    pub fn is_empty_tree_proof(&self) -> bool {
+        !self.existence
+            && self.nonexistence_key.is_none()
+            && self.nonexistence_child.is_none()
+            && only_empty_hashes(
+                &self.prefix,
+                &self.value_hash,
+                [&self.suffix[0], &self.suffix[1]],
+            )
+    }
+}
+
+/// Whether a proof with path `prefix` commits to nothing but empty hashes.
+fn only_empty_hashes<T>(prefix: &[T], value_hash: &Hash, suffix: [&Hash; 2]) -> bool {
+    prefix.is_empty()
+        && is_empty_subtree_hash(value_hash)
+        && suffix.into_iter().all(is_empty_subtree_hash)
+}
+
+static EMPTY_HASH: Hash = empty_hash();
+
+/// A proof whose keys and hashes point into the tree it was generated from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BorrowedProof<'a> {
+    pub prefix: Vec<(&'a Key, &'a Hash, &'a Hash)>,
+    pub suffix: [&'a Hash; 2],
+    pub value_hash: &'a Hash,
+    pub existence: bool,
+    pub nonexistence_key: Option<&'a Key>,
+    pub nonexistence_child: Option<(&'a Key, &'a Hash, [&'a Hash; 2])>,
+}
+
+impl BorrowedProof<'_> {
+    /// Same as `Proof::is_empty_tree_proof`.
+    pub fn is_empty_tree_proof(&self) -> bool {
+        !self.existence
+            && self.nonexistence_key.is_none()
+            && self.nonexistence_child.is_none()
+            && only_empty_hashes(&self.prefix, self.value_hash, self.suffix)
+    }
+
+    /// Same check as `CartesianMerkleTree::verify_proof`, without cloning
+    /// anything out of the proof.
+    pub fn verify(&self, key: &Key, root_hash: &Hash) -> bool {
+        self.verify_with::<Sha256Hasher>(key, root_hash)
+    }
+
+    /// Same as `verify`, for proofs from a tree built with hasher `H`.
+    pub fn verify_with<H: Hasher>(&self, key: &Key, root_hash: &Hash) -> bool {
+        let leaf_key = if self.existence {
+            key
+        } else {
+            match self.nonexistence_key {
+                Some(witness) => {
+                    let keys = self.prefix.iter().map(|(k, _, _)| *k);
+                    let child = self.nonexistence_child;
+                    if !falls_in_gap::<H>(&mut Vec::new(), key, witness, keys, self.suffix, child) {
+                        return false;
+                    }
+                    witness
+                }
+                None => return self.is_empty_tree_proof() && *root_hash == EMPTY_ROOT,
+            }
+        };
+        let mut acc = calculate_merkle_hash::<H, _>(
+            leaf_key,
+            self.value_hash,
+            self.suffix[0],
+            self.suffix[1],
+        );
+        for (k, vh, mh) in &self.prefix {
+            acc = calculate_merkle_hash::<H, _>(*k, vh, &acc, mh);
+        }
+        acc == *root_hash
+    }
+
+    pub fn into_owned(self) -> Proof {
+        Proof {
+            prefix: self
+                .prefix
+                .into_iter()
+                .map(|(k, vh, h)| (*k, *vh, *h))
+                .collect(),
+            suffix: [*self.suffix[0], *self.suffix[1]],
+            value_hash: *self.value_hash,
+            existence: self.existence,
+            nonexistence_key: self.nonexistence_key.copied(),
+            nonexistence_child: self
+                .nonexistence_child
+                .map(|(k, vh, [l, r])| (*k, *vh, [*l, *r])),
+        }
+    }
+}
+
+impl From<BorrowedProof<'_>> for Proof {
+    fn from(proof: BorrowedProof<'_>) -> Self {
+        proof.into_owned()
+    }
+}
+
+/// Child hashes of `node` in canonical order, borrowed from the tree.
+fn sorted_children(node: &TreeNode) -> [&Hash; 2] {
+    let left = node.left.as_ref().map_or(&EMPTY_HASH, |x| x.hash());
+    let right = node.right.as_ref().map_or(&EMPTY_HASH, |x| x.hash());
+    if left <= right {
+        [left, right]
+    } else {
+        [right, left]
+    }
+}
+
+/// Whether `key` lies in the gap a non-existence proof points at:
+/// `ancestors` are the witness's ancestors, `suffix` its child hashes and
+/// `child` the opening of its only child, if it has one. The opening is
+/// hashed through `buf`.
+fn falls_in_gap<'a, H: Hasher>(
+    buf: &mut Vec<u8>,
+    key: &Key,
+    witness: &Key,
+    ancestors: impl Iterator<Item = &'a Key>,
+    suffix: [&Hash; 2],
+    child: Option<(&Key, &Hash, [&Hash; 2])>,
+) -> bool {
+    if key == witness {
+        return false;
+    }
+    // at every ancestor, the search for `key` turns the same way as the
+    // path to the witness
+    for a in ancestors {
+        if key == a || (key < a) != (witness < a) {
+            return false;
+        }
+    }
+
+    let occupied = match (
+        is_empty_subtree_hash(suffix[0]),
+        is_empty_subtree_hash(suffix[1]),
+    ) {
+        (true, true) => None,
+        (true, false) => Some(suffix[1]),
+        (false, true) => Some(suffix[0]),
+        // the search would continue into one of the children
+        (false, false) => return false,
+    };
+    match (occupied, child) {
+        (None, None) => true,
+        (Some(hash), Some((child_key, child_vh, [l, r]))) => {
+            *hash == calculate_merkle_hash_into::<H, _>(buf, child_key, child_vh, l, r)
+                && (child_key < witness) != (key < witness)
+        }
+        _ => false,
+    }
+}
+
+/// Whether a node with `priority` and `key` belongs above one with
+/// `other_priority` and `other_key`. Equal priorities go to the smaller key,
+/// so every key set has exactly one valid shape, whatever the insertion
+/// order. Public for `cmt-concurrent`, whose trees must order nodes the same
+/// way; not part of the stable API.
+#[doc(hidden)]
+pub fn outranks(priority: Priority, key: &Key, other_priority: Priority, other_key: &Key) -> bool {
+    (priority, core::cmp::Reverse(key)) > (other_priority, core::cmp::Reverse(other_key))
+}
+
+fn find_priority<H: Hasher>(key: &Key) -> Priority {
+    let digest = H::hash(key.as_ref());
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(&digest[..16]);
+    i128::from_be_bytes(bytes) as i128
+}
+
+impl<H: Hasher> FromIterator<(Key, Value)> for CartesianMerkleTreeWith<H> {
+    fn from_iter<I: IntoIterator<Item = (Key, Value)>>(iter: I) -> Self {
+        let mut tree = Self::new();
+        tree.extend(iter);
+        tree
+    }
+}
+
+impl<H: Hasher> Extend<(Key, Value)> for CartesianMerkleTreeWith<H> {
+    fn extend<I: IntoIterator<Item = (Key, Value)>>(&mut self, iter: I) {
+        for (key, value) in iter {
+            self.insert(key, value);
+        }
+    }
+}
+
+impl<H: Hasher> IntoIterator for CartesianMerkleTreeWith<H> {
+    type Item = (Key, Value);
+    type IntoIter = IntoIter;
+
+    fn into_iter(mut self) -> IntoIter {
+        IntoIter::new(self.root.take())
+    }
+}
+
+impl<'a, H: Hasher> IntoIterator for &'a CartesianMerkleTreeWith<H> {
+    type Item = (&'a Key, &'a Value);
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn find_priority(key: &Key) -> Priority {
+        super::find_priority::<Sha256Hasher>(key)
+    }
+
+    fn hash_value(value: &[u8]) -> Hash {
+        utils::hash_value::<Sha256Hasher>(value)
+    }
+
+    fn calculate_merkle_hash(key: &Key, value_hash: &Hash, left: &Hash, right: &Hash) -> Hash {
+        utils::calculate_merkle_hash::<Sha256Hasher, _>(key, value_hash, left, right)
+    }
+
+    fn key(i: usize) -> Key {
+        let mut key = [0u8; 32];
+        key[0..8].copy_from_slice(&i.to_be_bytes());
+        key
+    }
+
+    /// Builds a tree of `key(0..n)`, each holding its index, inserted in a
+    /// shuffled order so inserts rotate as well as land at leaves. Returns
+    /// the keys in sorted order.
+    fn build(n: usize) -> (CartesianMerkleTree, Vec<Key>) {
+        let mut order: Vec<usize> = (0..n).collect();
+        // xorshift, so the shuffle is random but reproducible
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        for i in (1..n).rev() {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            order.swap(i, (state % (i as u64 + 1)) as usize);
+        }
+        let mut cmt = CartesianMerkleTree::new();
+        for i in order {
+            cmt.insert(key(i), i.to_be_bytes().to_vec());
+        }
+        (cmt, (0..n).map(key).collect())
+    }
+
+    fn root_hash_of(cmt: &CartesianMerkleTree) -> Hash {
+        cmt.root_hash().unwrap_or_else(empty_hash)
+    }
+
+    #[test]
+    fn verify_strict_rejects_misordered_suffix() {
+        let empty = empty_hash();
+        let vh = hash_value(b"v");
+        let left = calculate_merkle_hash(&key(1), &vh, &empty, &empty);
+        let right = calculate_merkle_hash(&key(3), &vh, &empty, &empty);
+        let root = calculate_merkle_hash(&key(2), &vh, &left, &right);
+
+        let (lo, hi) = if left < right {
+            (left, right)
+        } else {
+            (right, left)
+        };
+        let honest = || Proof {
+            prefix: Vec::new(),
+            suffix: [lo, hi],
+            value_hash: vh,
+            existence: true,
+            nonexistence_key: None,
+            nonexistence_child: None,
+        };
+        let crafted = || {
+            let mut proof = honest();
+            proof.suffix.swap(0, 1);
+            proof
+        };
+
+        assert!(CartesianMerkleTree::verify_strict(honest(), key(2), root));
+        // the final accumulator still matches, only the ordering is violated
+        assert!(CartesianMerkleTree::verify_proof(crafted(), key(2), root));
+        assert!(!CartesianMerkleTree::verify_strict(crafted(), key(2), root));
+    }
+
+    #[derive(Default)]
+    struct KeyOrders {
+        pre: Vec<Key>,
+        inorder: Vec<Key>,
+        post: Vec<Key>,
+    }
+
+    impl Visitor for KeyOrders {
+        fn visit_pre(&mut self, node: &TreeNode) {
+            self.pre.push(node.key);
+        }
+        fn visit_in(&mut self, node: &TreeNode) {
+            self.inorder.push(node.key);
+        }
+        fn visit_post(&mut self, node: &TreeNode) {
+            self.post.push(node.key);
+        }
+    }
+
+    fn collect_recursive(node: Option<&TreeNode>, orders: &mut KeyOrders) {
+        if let Some(n) = node {
+            orders.pre.push(n.key);
+            collect_recursive(n.left.as_deref(), orders);
+            orders.inorder.push(n.key);
+            collect_recursive(n.right.as_deref(), orders);
+            orders.post.push(n.key);
+        }
+    }
+
+    #[test]
+    fn walk_visits_pre_in_and_post_order() {
+        let (cmt, keys) = build(64);
+
+        let mut walked = KeyOrders::default();
+        cmt.walk(&mut walked);
+        let mut expected = KeyOrders::default();
+        collect_recursive(cmt.root.as_deref(), &mut expected);
+
+        assert_eq!(walked.inorder, keys);
+        assert_eq!(walked.pre, expected.pre);
+        assert_eq!(walked.post, expected.post);
+        assert_eq!(walked.pre[0], cmt.root.as_ref().unwrap().key);
+        assert_eq!(walked.post.last(), walked.pre.first());
+    }
+
+    #[test]
+    fn node_cursor_walks_the_whole_tree() {
+        let (mut cmt, keys) = build(100);
+        cmt.insert(keys[3], vec![9]); // leave stale hashes for root_node to settle
+        assert!(CartesianMerkleTree::new().root_node().is_none());
+
+        let root = cmt.root_node().unwrap();
+        assert_eq!(root.subtree_size(), 100);
+        assert_eq!(*root.hash(), root_hash_of(&cmt));
+
+        let mut inorder = Vec::new();
+        let mut stack = Vec::new();
+        let mut cur = Some(root);
+        while cur.is_some() || !stack.is_empty() {
+            while let Some(n) = cur {
+                stack.push(n);
+                cur = n.left();
+            }
+            let n = stack.pop().unwrap();
+            let child = |c: Option<NodeRef>| c.map_or_else(empty_hash, |c| *c.hash());
+            assert_eq!(
+                *n.hash(),
+                calculate_merkle_hash(n.key(), n.value_hash(), &child(n.left()), &child(n.right()))
+            );
+            assert_eq!(n.value(), cmt.get(n.key()).unwrap());
+            assert!(n.left().is_none_or(|l| l.priority() <= n.priority()));
+            inorder.push(*n.key());
+            cur = n.right();
+        }
+        assert_eq!(inorder, keys);
+    }
+
+    #[test]
+    fn remove_with_proof_proves_prior_membership() {
+        let (mut cmt, keys) = build(100);
+
+        for k in keys.iter().step_by(7) {
+            let before = root_hash_of(&cmt);
+            let (value, proof, after) = cmt.remove_with_proof(k).unwrap();
+
+            assert_eq!(value.len(), 8);
+            assert!(proof.existence);
+            assert!(CartesianMerkleTree::verify_proof(proof, *k, before));
+            assert_eq!(after, root_hash_of(&cmt));
+            assert!(!cmt.contains_key(k));
+        }
+
+        assert!(cmt.remove_with_proof(&keys[0]).is_none());
+        assert!(cmt.remove_with_proof(&key(1000)).is_none());
+    }
+
+    #[test]
+    fn count_range_matches_naive_count() {
+        let (cmt, keys) = build(200);
+        let count = |range: &dyn Fn(&Key) -> bool| keys.iter().filter(|k| range(k)).count();
+
+        let (lo, hi) = (keys[20], keys[150]);
+        assert_eq!(cmt.count_range(lo..hi), count(&|k| *k >= lo && *k < hi));
+        assert_eq!(cmt.count_range(lo..=hi), count(&|k| *k >= lo && *k <= hi));
+        assert_eq!(cmt.count_range(..hi), count(&|k| *k < hi));
+        assert_eq!(cmt.count_range(lo..), count(&|k| *k >= lo));
+        assert_eq!(
+            cmt.count_range((Bound::Excluded(lo), Bound::Excluded(hi))),
+            count(&|k| *k > lo && *k < hi)
+        );
+        assert_eq!(cmt.count_range(..), keys.len());
+        assert_eq!(cmt.count_range(hi..lo), 0);
+
+        // bounds that fall between stored keys
+        let (mut gap_lo, mut gap_hi) = (key(10), key(170));
+        gap_lo[31] = 1;
+        gap_hi[31] = 1;
+        assert_eq!(
+            cmt.count_range(gap_lo..gap_hi),
+            count(&|k| *k >= gap_lo && *k < gap_hi)
+        );
+    }
+
+    #[test]
+    fn proof_bytes_round_trip_and_reject_unknown_header() {
+        let (cmt, keys) = build(50);
+        for k in [keys[0], keys[25], key(1000)] {
+            let proof = cmt.generate_proof(&k);
+            let bytes = proof.to_bytes();
+            assert_eq!(bytes[0], PROOF_FORMAT_VERSION);
+            assert_eq!(bytes[1], HASH_ALGO_SHA256);
+            assert_eq!(Proof::from_bytes(&bytes), Ok(proof));
+        }
+
+        let mut bytes = cmt.generate_proof(&keys[10]).to_bytes();
+        bytes[0] = PROOF_FORMAT_VERSION + 1;
+        assert_eq!(
+            Proof::from_bytes(&bytes),
+            Err(ProofDecodeError::UnsupportedVersion(
+                PROOF_FORMAT_VERSION + 1
+            ))
+        );
+
+        bytes[0] = PROOF_FORMAT_VERSION;
+        bytes[1] = 0xff;
+        assert_eq!(
+            Proof::from_bytes(&bytes),
+            Err(ProofDecodeError::UnsupportedHashAlgorithm(0xff))
+        );
+
+        // the value hash length follows the header and flags
+        bytes[1] = HASH_ALGO_SHA256;
+        bytes[3] = 31;
+        assert_eq!(
+            Proof::from_bytes(&bytes),
+            Err(ProofDecodeError::InvalidHashLength(31))
+        );
+    }
+
+    #[test]
+    fn leaves_and_their_proofs_use_empty_hash() {
+        let (cmt, _) = build(30);
+        cmt.settle();
+
+        let mut leaves = Vec::new();
+        let mut stack: Vec<&TreeNode> = cmt.root.as_deref().into_iter().collect();
+        while let Some(n) = stack.pop() {
+            if n.left.is_none() && n.right.is_none() {
+                leaves.push(n);
+            }
+            stack.extend(n.left.as_deref());
+            stack.extend(n.right.as_deref());
+        }
+        assert!(!leaves.is_empty());
+
+        for leaf in leaves {
+            let empty = empty_hash();
+            assert_eq!(
+                *leaf.hash(),
+                calculate_merkle_hash(&leaf.key, &leaf.value_hash, &empty, &empty)
+            );
+
+            let proof = cmt.generate_proof(&leaf.key);
+            assert!(proof.suffix.iter().all(is_empty_subtree_hash));
+            assert_eq!(proof.suffix, [empty_hash(), empty_hash()]);
+        }
+    }
+
+    fn assert_heap_ordered(node: Option<&TreeNode>) {
+        if let Some(n) = node {
+            for child in [n.left.as_deref(), n.right.as_deref()]
+                .into_iter()
+                .flatten()
+            {
+                assert!(child.priority <= n.priority);
+            }
+            assert_heap_ordered(n.left.as_deref());
+            assert_heap_ordered(n.right.as_deref());
+        }
+    }
+
+    #[test]
+    fn insert_with_priority_builds_requested_shape() {
+        //        4
+        //      /   \
+        //     2     6
+        //    / \   / \
+        //   1   3 5   7
+        let mut cmt = CartesianMerkleTree::new();
+        for (k, priority) in [
+            (4, 100),
+            (2, 90),
+            (6, 80),
+            (1, 70),
+            (3, 60),
+            (5, 50),
+            (7, 40),
+        ] {
+            cmt.insert_with_priority(key(k), vec![k as u8], priority);
+        }
+
+        let root = cmt.root.as_deref().unwrap();
+        let (l, r) = (
+            root.left.as_deref().unwrap(),
+            root.right.as_deref().unwrap(),
+        );
+        assert_eq!(root.key, key(4));
+        assert_eq!((l.key, r.key), (key(2), key(6)));
+        assert_eq!(l.left.as_ref().unwrap().key, key(1));
+        assert_eq!(l.right.as_ref().unwrap().key, key(3));
+        assert_eq!(r.left.as_ref().unwrap().key, key(5));
+        assert_eq!(r.right.as_ref().unwrap().key, key(7));
+        assert_eq!(root.priority, 100);
+        assert_eq!(root.subtree_size, 7);
+        assert_heap_ordered(cmt.root.as_deref());
+
+        // priorities are taken as given, not derived from the key
+        assert_ne!(root.priority, find_priority(&key(4)));
+    }
+
+    #[test]
+    fn priority_fn_drives_shape_regardless_of_insert_order() {
+        // priority falls as the key grows, so the tree is a right spine
+        let by_key = |k: &Key| -(k[7] as Priority);
+        let mut ascending = CartesianMerkleTree::with_priority_fn(by_key);
+        let mut descending = CartesianMerkleTree::with_priority_fn(by_key);
+        for i in 0..8 {
+            ascending.insert(key(i), vec![i as u8]);
+            descending.insert(key(7 - i), vec![(7 - i) as u8]);
+        }
+
+        assert_eq!(ascending.root_hash(), descending.root_hash());
+        assert_eq!(ascending.root.as_ref().unwrap().key, key(0));
+        assert_eq!(ascending.depth_of(&key(7)), Some(7));
+        assert_heap_ordered(ascending.root.as_deref());
+
+        let root = ascending.root_hash().unwrap();
+        for k in [key(3), key(8)] {
+            let proof = ascending.generate_proof(&k);
+            assert!(CartesianMerkleTree::verify_proof(proof, k, root));
+        }
+
+        ascending.compact();
+        assert_eq!(ascending.root_hash(), descending.root_hash());
+    }
+
+    #[test]
+    fn combine_roots_is_order_independent_and_stable() {
+        let combine_roots = combine_roots::<Sha256Hasher>;
+        let roots: Vec<Hash> = (0..5)
+            .map(|i| calculate_merkle_hash(&key(i), &hash_value(&[]), &empty_hash(), &empty_hash()))
+            .collect();
+        let combined = combine_roots(&roots);
+
+        let mut reversed = roots.clone();
+        reversed.reverse();
+        assert_eq!(combine_roots(&reversed), combined);
+        let mut rotated = roots.clone();
+        rotated.rotate_left(2);
+        assert_eq!(combine_roots(&rotated), combined);
+        assert_eq!(combine_roots(&roots), combined);
+
+        assert_eq!(combined.len(), DIGEST_LEN);
+        assert_ne!(combine_roots(&roots[..4]), combined);
+        assert_ne!(combine_roots(&[]), combine_roots(&[empty_hash()]));
+
+        // SHA-256 commitments are unchanged by the hasher parameter
+        let fixed = [[1; DIGEST_LEN], [2; DIGEST_LEN], empty_hash()];
+        assert_eq!(
+            hex::encode(combine_roots(&fixed)),
+            "5ffbe0e7a78ea48eb9d15d5a5d4cfa8193fb7c148b5d9bc9655eeda5e89db2b6"
+        );
+        assert_eq!(
+            super::combine_roots::<TruncatedDigest<sha2::Sha256, HASH_ALGO_SHA256>>(&fixed),
+            combine_roots(&fixed)
+        );
+        // the same digest under another id commits differently
+        assert_ne!(
+            super::combine_roots::<TruncatedDigest<sha2::Sha256, 0xf3>>(&fixed),
+            combine_roots(&fixed)
+        );
+    }
+
+    #[test]
+    fn proofs_iterator_yields_verifying_proofs_in_order() {
+        let (cmt, keys) = build(100);
+        let root = root_hash_of(&cmt);
+
+        let mut yielded = Vec::new();
+        for (k, proof) in cmt.proofs() {
+            assert_eq!(proof, cmt.generate_proof(&k));
+            assert!(CartesianMerkleTree::verify_proof(proof, k, root));
+            yielded.push(k);
+        }
+        assert_eq!(yielded, keys);
+
+        assert_eq!(CartesianMerkleTree::new().proofs().count(), 0);
+    }
+
+    #[test]
+    fn depth_of_matches_proof_prefix_length() {
+        let (cmt, keys) = build(100);
+        let root_key = cmt.root.as_ref().unwrap().key;
+        assert_eq!(cmt.depth_of(&root_key), Some(0));
+
+        for k in &keys {
+            let depth = cmt.depth_of(k).unwrap();
+            assert_eq!(depth, cmt.generate_proof(k).prefix.len());
+        }
+        assert_eq!(cmt.depth_of(&key(1000)), None);
+    }
+
+    #[test]
+    fn exported_proofs_verify_against_root() {
+        let (cmt, keys) = build(80);
+        let root = root_hash_of(&cmt);
+
+        let mut file = Vec::new();
+        cmt.export_all_proofs(&mut file).unwrap();
+        let export = ProofExport::read_from(file.as_slice()).unwrap();
+        assert_eq!(export.len(), keys.len());
+        assert_eq!(export.root_hash(), root);
+
+        let mut seen = Vec::new();
+        for (k, proof) in export.proofs() {
+            assert_eq!(proof, cmt.generate_proof(&k));
+            assert!(CartesianMerkleTree::verify_proof(proof, k, root));
+            seen.push(k);
+        }
+        seen.sort();
+        assert_eq!(seen, keys);
+
+        // shared ancestors are stored once, so the export beats independent proofs
+        let independent: usize = keys
+            .iter()
+            .map(|k| cmt.generate_proof(k).to_bytes().len())
+            .sum();
+        assert!(file.len() < independent);
+
+        assert!(ProofExport::read_from(&file[..file.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn streamed_tree_round_trips() {
+        const N: usize = 1_000_000;
+        // FNV-1a in four lanes: every input byte reaches the digest, at a
+        // fraction of SHA-256's cost for a million nodes
+        struct Fnv;
+        impl Hasher for Fnv {
+            const ALGO_ID: u8 = 0xf0;
+            fn hash(data: &[u8]) -> Hash {
+                let mut out = empty_hash();
+                for (lane, chunk) in out.chunks_mut(8).enumerate() {
+                    let mut h = 0xcbf2_9ce4_8422_2325u64 ^ lane as u64;
+                    for b in data {
+                        h = (h ^ *b as u64).wrapping_mul(0x100_0000_01b3);
+                    }
+                    chunk.copy_from_slice(&h.to_be_bytes());
+                }
+                out
+            }
+        }
+        type Tree = CartesianMerkleTreeWith<Fnv>;
+        let cmt = Tree::from_sorted((0..N).map(|i| (key(i), vec![i as u8])).collect());
+        let root = cmt.root_hash().unwrap();
+
+        let mut file = Vec::new();
+        cmt.write_to(&mut file).unwrap();
+        let loaded = Tree::read_from(&mut file.as_slice()).unwrap();
+        assert_eq!(loaded.len(), N);
+        assert_eq!(loaded.root_hash().unwrap(), root);
+        assert_eq!(loaded.height(), cmt.height());
+        assert_eq!(loaded.get(&key(N / 2)), Some(&vec![(N / 2) as u8]));
+
+        let empty = Tree::new();
+        let mut file = Vec::new();
+        empty.write_to(&mut file).unwrap();
+        assert!(Tree::read_from(&mut file.as_slice()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn streamed_tree_rejects_corrupt_input() {
+        let (cmt, _) = build(50);
+        let mut file = Vec::new();
+        cmt.write_to(&mut file).unwrap();
+        assert!(CartesianMerkleTree::read_from(&mut &file[..file.len() - 1]).is_err());
+
+        // the root's key, first in pre-order, no longer sorts above its left subtree
+        let mut bad = file.clone();
+        bad[17..49].copy_from_slice(&[0u8; 32]);
+        let err = CartesianMerkleTree::read_from(&mut bad.as_slice()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // a higher priority for the second node puts it above the root
+        let mut bad = file.clone();
+        let second = 13 + 4 + u32::from_le_bytes(file[13..17].try_into().unwrap()) as usize;
+        bad[second + 4 + 32..second + 4 + 48].copy_from_slice(&i128::MAX.to_le_bytes());
+        let err = CartesianMerkleTree::read_from(&mut bad.as_slice()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    /// Checks BST order, heap order, subtree sizes and hashes of the whole
+    /// tree, settling it first.
+    fn assert_integrity(cmt: &CartesianMerkleTree) {
+        fn check(n: &TreeNode, inorder: &mut Vec<Key>) -> usize {
+            let mut size = 1;
+            if let Some(l) = n.left.as_deref() {
+                assert!(l.priority <= n.priority);
+                size += check(l, inorder);
+            }
+            inorder.push(n.key);
+            if let Some(r) = n.right.as_deref() {
+                assert!(r.priority <= n.priority);
+                size += check(r, inorder);
+            }
+            let child =
+                |c: &Option<Box<TreeNode>>| c.as_ref().map_or_else(empty_hash, |c| *c.hash());
+            assert_eq!(n.value_hash, hash_value(&n.value));
+            assert_eq!(
+                *n.hash(),
+                calculate_merkle_hash(&n.key, &n.value_hash, &child(&n.left), &child(&n.right))
+            );
+            assert_eq!(n.subtree_size, size);
+            size
+        }
+        cmt.settle();
+        let mut inorder = Vec::new();
+        if let Some(n) = cmt.root.as_deref() {
+            check(n, &mut inorder);
+        }
+        assert!(inorder.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(cmt.validate(), Ok(()));
+    }
+
+    #[test]
+    fn compact_preserves_contents_after_churn() {
+        let keys: Vec<Key> = (0..300).map(key).collect();
+        let mut cmt = CartesianMerkleTree::new();
+        for k in &keys[..200] {
+            cmt.insert(*k, k[..8].to_vec());
+        }
+        for k in keys[..200].iter().step_by(3) {
+            cmt.remove(k);
+        }
+        for k in &keys[200..] {
+            cmt.insert(*k, k[..8].to_vec());
+        }
+        let remaining: Vec<Key> = keys[..200]
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| i % 3 != 0)
+            .map(|(_, k)| *k)
+            .chain(keys[200..].iter().copied())
+            .collect();
+        let before = root_hash_of(&cmt);
+
+        cmt.compact();
+
+        assert_integrity(&cmt);
+        assert_eq!(cmt.root.as_ref().unwrap().subtree_size, remaining.len());
+        for k in &remaining {
+            assert!(cmt.contains_key(k));
+        }
+        // the treap shape only depends on the key set, so the root is unchanged
+        assert_eq!(root_hash_of(&cmt), before);
+
+        // and it matches a tree built from scratch over the same keys
+        let mut fresh = CartesianMerkleTree::new();
+        for k in &remaining {
+            fresh.insert(*k, k[..8].to_vec());
+        }
+        assert_eq!(root_hash_of(&fresh), root_hash_of(&cmt));
+    }
+
+    #[test]
+    fn rebalance_flattens_a_skewed_tree() {
+        const N: usize = 10_000;
+        // priority grows with the key, so ascending inserts build a spine
+        let by_key = |k: &Key| u64::from_be_bytes(k[..8].try_into().unwrap()) as Priority;
+        let mut cmt = CartesianMerkleTree::with_priority_fn(by_key);
+        for i in 0..N {
+            cmt.insert(key(i), vec![i as u8]);
+        }
+        assert_eq!(cmt.height(), N);
+        let before = cmt.clone();
+
+        cmt.rebalance();
+        assert_eq!(cmt.height(), N.ilog2() as usize + 1);
+        assert_eq!(cmt, before);
+        assert_integrity(&cmt);
+        assert_eq!(cmt.get(&key(1234)), Some(&vec![1234usize as u8]));
+
+        // the old priorities are dealt out again, so a later insert still
+        // climbs as far as its derived priority takes it
+        cmt.insert(key(N), vec![]);
+        assert_eq!(cmt.depth_of(&key(N)), Some(0));
+        cmt.insert_with_priority(key(N + 1), vec![], N as Priority / 2);
+        let depth = cmt.depth_of(&key(N + 1)).unwrap();
+        assert!(depth > 0 && depth < cmt.height() - 1, "depth {depth}");
+        assert!(cmt.contains_key(&key(1234)));
+
+        // later inserts and removals keep the treap valid
+        for i in N + 2..N + 100 {
+            cmt.insert(key(i), vec![]);
+        }
+        cmt.remove(&key(N / 2));
+        assert_eq!(cmt.len(), N + 99);
+        assert_integrity(&cmt);
+
+        // `compact` puts the derived priorities, and the skew, back
+        cmt.compact();
+        assert_eq!(cmt.height(), N + 99);
+
+        // manual priorities all at the bottom of the range still get one
+        // distinct value per level
+        let mut floor = CartesianMerkleTree::new();
+        for i in 0..100 {
+            floor.insert_with_priority(key(i), vec![], Priority::MIN);
+        }
+        floor.rebalance();
+        assert_eq!(floor.height(), 7);
+        floor.validate().unwrap();
+
+        let mut empty = CartesianMerkleTree::new();
+        empty.rebalance();
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn borrowed_proof_verifies_without_cloning() {
+        let (cmt, keys) = build(60);
+        let root = root_hash_of(&cmt);
+
+        for k in keys.iter().chain([&key(1000)]) {
+            let borrowed = cmt.generate_proof_borrowed(k);
+            assert!(borrowed.verify(k, &root));
+
+            // the borrowed keys point straight into the tree's nodes
+            if let Some(&(parent_key, _, _)) = borrowed.prefix.last() {
+                assert!(std::ptr::eq(parent_key, &cmt.root.as_ref().unwrap().key));
+            }
+
+            let owned: Proof = borrowed.into();
+            assert_eq!(owned, cmt.generate_proof(k));
+        }
+        assert!(!cmt
+            .generate_proof_borrowed(&keys[3])
+            .verify(&keys[4], &root));
+    }
+
+    #[test]
+    fn key_from_slice_checks_length() {
+        let bytes: Vec<u8> = (0..32).collect();
+        let k = key_from_slice(&bytes).unwrap();
+        assert_eq!(&k[..], &bytes[..]);
+
+        assert_eq!(key_from_slice(&bytes[..31]), Err(KeyLenError { len: 31 }));
+        assert_eq!(key_from_slice(&[0u8; 33]), Err(KeyLenError { len: 33 }));
+        assert_eq!(key_from_slice(&[]), Err(KeyLenError { len: 0 }));
+        assert_eq!(
+            KeyLenError { len: 31 }.to_string(),
+            "key must be 32 bytes, got 31"
+        );
+    }
+
+    #[test]
+    fn first_divergence_pinpoints_the_missing_key() {
+        let (a, keys) = build(120);
+        let (mut b, _) = build(120);
+        assert!(roots_agree(&[&a, &b]));
+        assert_eq!(first_divergence(&a, &b), None);
+
+        let missing = keys[57];
+        b.remove(&missing);
+        assert!(!roots_agree(&[&a, &b, &a]));
+        assert_eq!(first_divergence(&a, &b), Some(missing));
+        assert_eq!(first_divergence(&b, &a), Some(missing));
+
+        b.remove(&keys[20]);
+        assert_eq!(first_divergence(&a, &b), Some(keys[20]));
+
+        let empty = CartesianMerkleTree::new();
+        assert_eq!(first_divergence(&a, &empty), Some(keys[0]));
+        assert!(roots_agree::<Sha256Hasher>(&[]));
+    }
+
+    /// Diff by merging the two in-order iterators, visiting every entry.
+    fn naive_diff(old: &CartesianMerkleTree, new: &CartesianMerkleTree) -> TreeDiff {
+        let mut diff = TreeDiff::default();
+        let (mut a, mut b) = (old.iter().peekable(), new.iter().peekable());
+        loop {
+            match (a.peek(), b.peek()) {
+                (None, None) => return diff,
+                (Some((ka, _)), Some((kb, _))) if ka == kb => {
+                    let ((k, va), (_, vb)) = (a.next().unwrap(), b.next().unwrap());
+                    if va != vb {
+                        diff.changed.push((*k, va.clone(), vb.clone()));
+                    }
+                }
+                (Some((ka, _)), Some((kb, _))) if ka > kb => {
+                    let (k, v) = b.next().unwrap();
+                    diff.added.push((*k, v.clone()));
+                }
+                (Some(_), _) => {
+                    let (k, v) = a.next().unwrap();
+                    diff.removed.push((*k, v.clone()));
+                }
+                (None, Some(_)) => {
+                    let (k, v) = b.next().unwrap();
+                    diff.added.push((*k, v.clone()));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn typed_keys_encode_deterministically() {
+        assert_eq!(0x0102u16.encode(), [1, 2]);
+        assert_eq!((-1i32).encode(), [0x7f, 0xff, 0xff, 0xff]);
+        assert_eq!("ab".encode(), String::from("ab").encode());
+        assert_eq!(b"ab".encode(), b"ab"[..].encode());
+
+        let mut by_int = U64CMT::new();
+        for i in 0..200u64 {
+            by_int.insert(&i, i.to_be_bytes().to_vec());
+        }
+        assert_eq!(by_int.len(), 200);
+        assert_eq!(by_int.get(&7), Some(&7u64.to_be_bytes().to_vec()));
+        assert_eq!(by_int.remove(&7), Some(7u64.to_be_bytes().to_vec()));
+        assert!(!by_int.contains_key(&7));
+        let root = by_int.root_hash().unwrap();
+        assert!(U64CMT::verify_proof(by_int.generate_proof(&8), &8, root));
+        assert!(!U64CMT::verify_proof(by_int.generate_proof(&8), &9, root));
+        by_int.tree().validate().unwrap();
+
+        // insertion order doesn't matter, and the tree key is the padded
+        // encoding with its length last
+        let mut reversed = U64CMT::new();
+        for i in (0..200u64).rev().filter(|i| *i != 7) {
+            reversed.insert(&i, i.to_be_bytes().to_vec());
+        }
+        assert_eq!(reversed.root_hash(), Some(root));
+        let mut eight = [0; 32];
+        eight[7] = 8;
+        eight[31] = 8;
+        assert_eq!(U64CMT::tree_key(&8), eight);
+        assert!(reversed.tree().contains_key(&eight));
+
+        let mut by_name = TypedCMT::<str>::new();
+        by_name.insert("a key well past thirty-two bytes long", vec![1]);
+        by_name.insert("", vec![2]);
+        assert_eq!(by_name.get(""), Some(&vec![2]));
+        assert_eq!(
+            by_name.get("a key well past thirty-two bytes long"),
+            Some(&vec![1])
+        );
+        let root = by_name.root_hash().unwrap();
+        assert!(TypedCMT::<str>::verify_proof(
+            by_name.generate_proof(""),
+            "",
+            root
+        ));
+    }
+
+    #[test]
+    fn typed_keys_keep_their_order() {
+        // xorshift, so the keys are random but reproducible
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        let mut ints: Vec<i64> = (0..500)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                (state % 2_000_000) as i64 - 1_000_000
+            })
+            .collect();
+        let mut by_int = TypedCMT::<i64>::new();
+        for i in &ints {
+            by_int.insert(i, i.to_be_bytes().to_vec());
+        }
+        ints.sort_unstable();
+        ints.dedup();
+        let stored: Vec<i64> = by_int
+            .tree()
+            .values()
+            .map(|v| i64::from_be_bytes(v[..].try_into().unwrap()))
+            .collect();
+        assert_eq!(stored, ints);
+
+        // a range of tree keys is a range of integers
+        let (lo, hi) = (ints[100], ints[200]);
+        let in_range = by_int
+            .tree()
+            .range(TypedCMT::<i64>::tree_key(&lo)..=TypedCMT::<i64>::tree_key(&hi))
+            .count();
+        assert_eq!(in_range, 101);
+
+        // byte-wise for strings, shorter first, trailing zero bytes included
+        let names = ["", "\0", "a", "a\0", "ab", "b", "ba"];
+        let mut by_name = TypedCMT::<str>::new();
+        for name in names.iter().rev() {
+            by_name.insert(name, name.as_bytes().to_vec());
+        }
+        let stored: Vec<&[u8]> = by_name.tree().values().map(|v| &v[..]).collect();
+        let expected: Vec<&[u8]> = names.iter().map(|n| n.as_bytes()).collect();
+        assert_eq!(stored, expected);
+    }
+
+    #[test]
+    fn diff_matches_naive_merge() {
+        let (old, keys) = build(3000);
+        let mut new = old.clone();
+        assert!(old.diff(&new).is_empty());
+
+        for (i, k) in keys.iter().enumerate().step_by(97) {
+            match i % 3 {
+                0 => drop(new.remove(k)),
+                1 => new.insert(*k, b"changed".to_vec()),
+                // same value: not a change
+                _ => new.insert(*k, old.get(k).unwrap().clone()),
+            }
+        }
+        for i in 3000..3010 {
+            new.insert(key(i), vec![1]);
+        }
+
+        let diff = old.diff(&new);
+        assert_eq!(diff, naive_diff(&old, &new));
+        assert_eq!(diff.added.len(), 10);
+        assert_eq!(diff.removed.len(), 11);
+        assert_eq!(diff.changed.len(), 10);
+
+        // the reverse diff swaps added and removed and flips each change
+        let back = new.diff(&old);
+        assert_eq!(back.added, diff.removed);
+        assert_eq!(back.removed, diff.added);
+        assert_eq!(back, naive_diff(&new, &old));
+
+        let empty = CartesianMerkleTree::new();
+        assert_eq!(old.diff(&empty).removed.len(), old.len());
+        assert_eq!(empty.diff(&new).added.len(), new.len());
+    }
+
+    #[test]
+    fn get_and_get_mut_return_stored_values() {
+        let (mut cmt, keys) = build(50);
+        for k in &keys {
+            let value = cmt.get(k).unwrap();
+            assert_eq!(value.len(), 8);
+        }
+        assert_eq!(cmt.get(&key(1000)), None);
+
+        let root = root_hash_of(&cmt);
+        cmt.get_mut(&keys[7]).unwrap().push(42);
+        assert_eq!(cmt.get(&keys[7]).unwrap().last(), Some(&42));
+        // the guard marks the path stale once it is dropped
+        assert_ne!(root_hash_of(&cmt), root);
+        assert_integrity(&cmt);
+        assert!(cmt.get_mut(&key(1000)).is_none());
+    }
+
+    #[test]
+    fn len_counts_only_new_keys_and_real_removals() {
+        let (mut cmt, keys) = build(40);
+        assert_eq!(cmt.len(), 40);
+        assert!(!cmt.is_empty());
+
+        cmt.insert(keys[3], vec![9]);
+        assert_eq!(cmt.len(), 40);
+
+        cmt.remove(&keys[3]);
+        cmt.remove(&keys[3]);
+        cmt.remove(&key(1000));
+        assert_eq!(cmt.len(), 39);
+        cmt.remove_with_proof(&keys[4]);
+        assert_eq!(cmt.len(), 38);
+
+        for k in &keys {
+            cmt.remove(k);
+        }
+        assert_eq!(cmt.len(), 0);
+        assert!(cmt.is_empty());
+        assert!(CartesianMerkleTree::new().is_empty());
+    }
+
+    #[test]
+    fn iter_matches_btreemap_order() {
+        let (mut cmt, keys) = build(300);
+        for k in keys.iter().step_by(3) {
+            cmt.remove(k);
+        }
+        cmt.insert(keys[1], vec![7]);
+
+        // fill in reverse so the ordering comes from the map, not the input
+        let mut expected = std::collections::BTreeMap::new();
+        for k in keys.iter().rev() {
+            if let Some(v) = cmt.get(k) {
+                expected.insert(*k, v.clone());
+            }
+        }
+
+        let got: Vec<_> = cmt.iter().map(|(k, v)| (*k, v.clone())).collect();
+        let want: Vec<_> = expected.into_iter().collect();
+        assert_eq!(got, want);
+        assert!(cmt.keys().copied().eq(want.iter().map(|(k, _)| *k)));
+        assert!(cmt.values().eq(want.iter().map(|(_, v)| v)));
+        assert_eq!(CartesianMerkleTree::new().iter().next(), None);
+    }
+
+    #[test]
+    fn extremes_match_iteration_ends() {
+        let (mut cmt, keys) = build(100);
+        for k in [keys[0], keys[99], keys[1]] {
+            cmt.remove(&k);
+        }
+
+        let all: Vec<_> = cmt.iter().collect();
+        assert_eq!(cmt.first(), all.first().copied());
+        assert_eq!(cmt.last(), all.last().copied());
+        assert_eq!(cmt.min_key(), Some(&keys[2]));
+        assert_eq!(cmt.max_key(), Some(&keys[98]));
+
+        let empty = CartesianMerkleTree::new();
+        assert_eq!((empty.min_key(), empty.max_key()), (None, None));
+        assert_eq!((empty.first(), empty.last()), (None, None));
+    }
+
+    #[test]
+    fn neighbours_match_btreemap() {
+        use std::ops::Bound::{Excluded, Unbounded};
+
+        // even keys only, so every odd key falls in a gap
+        let (mut cmt, keys) = build(200);
+        for k in keys.iter().skip(1).step_by(2) {
+            cmt.remove(k);
+        }
+        let expected: std::collections::BTreeMap<Key, Value> =
+            cmt.iter().map(|(k, v)| (*k, v.clone())).collect();
+
+        let probes = keys
+            .iter()
+            .copied()
+            .chain([key(1000), [0u8; 32], [0xff; 32]]);
+        for k in probes {
+            let pred = expected.range((Unbounded, Excluded(k))).next_back();
+            let succ = expected.range((Excluded(k), Unbounded)).next();
+            assert_eq!(cmt.predecessor(&k), pred);
+            assert_eq!(cmt.successor(&k), succ);
+        }
+        assert_eq!(cmt.predecessor(&keys[0]), None);
+        assert_eq!(cmt.successor(&keys[198]), None);
+    }
+
+    #[test]
+    fn select_inverts_rank_under_churn() {
+        // xorshift, so the workload is random but reproducible
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        let mut cmt = CartesianMerkleTree::new();
+        let mut expected = std::collections::BTreeSet::new();
+        for _ in 0..2000 {
+            let k = key((next() % 500) as usize);
+            if next() % 3 == 0 {
+                cmt.remove(&k);
+                expected.remove(&k);
+            } else {
+                cmt.insert(k, vec![0]);
+                expected.insert(k);
+            }
+        }
+
+        for (i, k) in expected.iter().enumerate() {
+            assert_eq!(cmt.rank(k), i);
+            assert_eq!(cmt.select(cmt.rank(k)).map(|(k, _)| k), Some(k));
+        }
+        assert_eq!(cmt.select(expected.len()), None);
+        assert_eq!(cmt.rank(&[0xff; 32]), expected.len());
+    }
+
+    #[test]
+    fn replayed_ops_match_a_btreemap() {
+        // xorshift, so the workload is random but reproducible
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        let mut cmt = CartesianMerkleTree::new();
+        let mut reference = std::collections::BTreeMap::new();
+        for step in 0..10_000 {
+            // a small key space, so inserts overwrite and removes hit
+            let k = key((next() % 300) as usize);
+            let op = match next() % 4 {
+                0 => Op::Remove(k),
+                1 => Op::Get(k),
+                _ => Op::Insert(k, (next() as u16).to_be_bytes().to_vec()),
+            };
+            let expected = match &op {
+                Op::Insert(k, v) => reference.insert(*k, v.clone()),
+                Op::Remove(k) => reference.remove(k),
+                Op::Get(k) => reference.get(k).cloned(),
+            };
+            assert_eq!(cmt.apply(op.clone()), expected, "{op:?}");
+            if step % 1000 == 0 {
+                cmt.validate().unwrap();
+            }
+        }
+        cmt.validate().unwrap();
+        assert_eq!(cmt.len(), reference.len());
+        assert!(cmt.iter().eq(reference.iter()));
+    }
+
+    #[test]
+    fn history_records_every_mutation() {
+        let mut plain = CartesianMerkleTree::new();
+        plain.insert(key(1), vec![1]);
+        assert!(plain.history().is_empty());
+
+        let mut cmt = CartesianMerkleTree::new().with_history();
+        let mut mutations = 0;
+        // each step adds `added` records, the last one with the current root
+        let mut check = |cmt: &CartesianMerkleTree, added: usize| {
+            mutations += added;
+            assert_eq!(cmt.history().len(), mutations);
+            if added > 0 {
+                assert_eq!(cmt.history().last().unwrap().1, root_hash_of(cmt));
+            }
+        };
+
+        for i in 0..20 {
+            cmt.insert(key(i), vec![i as u8]);
+            check(&cmt, 1);
+        }
+        let first_root = cmt.history()[0].1;
+        cmt.insert(key(3), vec![0xff]);
+        check(&cmt, 1);
+        // writing the same bytes again changes nothing, by either route
+        cmt.insert(key(3), vec![0xff]);
+        check(&cmt, 0);
+        *cmt.entry(key(3)).or_default() = vec![0xff];
+        check(&cmt, 0);
+        assert!(cmt.remove(&key(4)).is_some());
+        check(&cmt, 1);
+        assert!(cmt.remove(&key(4)).is_none());
+        check(&cmt, 0);
+        cmt.get_mut(&key(5)).unwrap().push(1);
+        check(&cmt, 1);
+        // reading through the guard changes nothing
+        assert_eq!(cmt.get_mut(&key(6)).unwrap()[0], 6);
+        check(&cmt, 0);
+        // the insert and the write through the returned guard land as one
+        // record, once the guard hangs the path back
+        *cmt.entry(key(100)).or_insert(vec![7]) = vec![8];
+        check(&cmt, 1);
+        cmt.pop_min();
+        check(&cmt, 1);
+        let (other, _) = build(10);
+        cmt.merge(other);
+        check(&cmt, 1);
+        cmt.rebalance();
+        check(&cmt, 1);
+        cmt.clear();
+        check(&cmt, 1);
+        // nor does emptying a tree that is already empty
+        cmt.clear();
+        check(&cmt, 0);
+        assert_eq!(cmt.drain().count(), 0);
+        check(&cmt, 0);
+
+        let mut single = CartesianMerkleTree::new();
+        single.insert(key(0), vec![0]);
+        assert_eq!(first_root, root_hash_of(&single));
+        assert_eq!(cmt.history()[0].0, OpSummary::Insert(key(0)));
+        assert_eq!(cmt.history()[21].0, OpSummary::Remove(key(4)));
+        assert_eq!(cmt.history()[22].0, OpSummary::Update(key(5)));
+        assert_eq!(cmt.history()[23].0, OpSummary::Insert(key(100)));
+        assert_eq!(cmt.history()[24].0, OpSummary::Remove(key(0)));
+        assert_eq!(
+            cmt.history().last(),
+            Some(&(OpSummary::Clear, empty_hash()))
+        );
+        assert_eq!(cmt.clone().history(), cmt.history());
+    }
+
+    #[test]
+    fn height_and_stats_track_shape() {
+        let empty = CartesianMerkleTree::new();
+        assert_eq!(empty.height(), 0);
+        assert_eq!(empty.stats().average_depth, 0.0);
+
+        // descending priorities along ascending keys: one right spine
+        let mut spine = CartesianMerkleTree::new();
+        for i in 0..300 {
+            spine.insert_with_priority(key(i), vec![], -(i as Priority));
+        }
+        assert_eq!(spine.height(), 300);
+        let stats = spine.stats();
+        assert_eq!(stats.max_depth, 299);
+        assert_eq!(stats.average_depth, 299.0 / 2.0);
+
+        let (cmt, keys) = build(1000);
+        let stats = cmt.stats();
+        assert_eq!(cmt.height(), stats.max_depth + 1);
+        let total: usize = keys.iter().map(|k| cmt.depth_of(k).unwrap()).sum();
+        assert_eq!(stats.average_depth, total as f64 / 1000.0);
+        assert!(stats.average_depth < 3.0 * 1000f64.log2());
+    }
+
+    #[test]
+    fn priority_stats_count_collisions() {
+        let empty = CartesianMerkleTree::new();
+        let stats = empty.priority_stats();
+        assert_eq!((stats.min, stats.max, stats.collisions), (None, None, 0));
+        assert_eq!(stats.buckets, [0; PRIORITY_BUCKETS]);
+
+        let (cmt, _) = build(1600);
+        let stats = cmt.priority_stats();
+        assert_eq!(stats.collisions, 0);
+        assert_eq!(stats.buckets.iter().sum::<usize>(), 1600);
+        // 100 expected per bucket
+        assert!(stats.buckets.iter().all(|&b| (50..150).contains(&b)));
+
+        // three keys share one priority and two share another
+        let mut cmt = CartesianMerkleTree::new();
+        for (i, p) in [5, 5, 5, -7, -7, 0, Priority::MIN, Priority::MAX]
+            .into_iter()
+            .enumerate()
+        {
+            cmt.insert_with_priority(key(i), vec![], p);
+        }
+        let stats = cmt.priority_stats();
+        assert_eq!(stats.collisions, 3);
+        assert_eq!(stats.min, Some(Priority::MIN));
+        assert_eq!(stats.max, Some(Priority::MAX));
+        let mut expected = [0; PRIORITY_BUCKETS];
+        expected[0] = 1; // MIN
+        expected[PRIORITY_BUCKETS / 2 - 1] = 2; // -7
+        expected[PRIORITY_BUCKETS / 2] = 4; // 0 and 5
+        expected[PRIORITY_BUCKETS - 1] = 1; // MAX
+        assert_eq!(stats.buckets, expected);
+    }
+
+    #[test]
+    fn clear_empties_the_tree() {
+        let (mut cmt, keys) = build(50);
+        cmt.clear();
+        assert_eq!(cmt.root_hash(), None);
+        assert_eq!(cmt.len(), 0);
+        assert!(!cmt.contains_key(&keys[0]));
+
+        cmt.insert(keys[0], vec![1]);
+        assert_eq!(cmt.len(), 1);
+    }
+
+    #[test]
+    fn default_is_an_empty_tree() {
+        #[derive(Default)]
+        struct Index {
+            tree: CartesianMerkleTree,
+        }
+
+        let mut index = Index::default();
+        assert!(index.tree.is_empty());
+        index.tree.insert(key(1), vec![1]);
+        let taken = std::mem::take(&mut index.tree);
+        assert_eq!(taken.len(), 1);
+        assert_eq!(index.tree.root_hash(), None);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip_keeps_root_hash() {
+        let (mut cmt, keys) = build(200);
+        cmt.remove(&keys[7]);
+
+        let json = serde_json::to_string(&cmt).unwrap();
+        let loaded: CartesianMerkleTree = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded.root_hash(), cmt.root_hash());
+        assert_eq!(loaded.len(), cmt.len());
+        assert_integrity(&loaded);
+        assert_eq!(loaded.count_range(keys[10]..keys[20]), 10);
+
+        let unverified = CartesianMerkleTree::deserialize_tree(
+            &mut serde_json::Deserializer::from_str(&json),
+            false,
+        )
+        .unwrap();
+        assert_eq!(unverified.root_hash(), cmt.root_hash());
+
+        let empty = serde_json::to_string(&CartesianMerkleTree::new()).unwrap();
+        let empty: CartesianMerkleTree = serde_json::from_str(&empty).unwrap();
+        assert!(empty.is_empty());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_handles_a_degenerate_tree() {
+        // priority grows with the key, so ascending inserts build a spine
+        // deeper than any recursive (de)serializer could follow
+        const N: usize = 200_000;
+        let by_key = |k: &Key| u64::from_be_bytes(k[..8].try_into().unwrap()) as Priority;
+        let mut cmt = CartesianMerkleTree::with_priority_fn(by_key);
+        for i in 0..N {
+            cmt.insert(key(i), vec![]);
+        }
+        assert_eq!(cmt.height(), N);
+
+        let json = serde_json::to_string(&cmt).unwrap();
+        let loaded: CartesianMerkleTree = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded.root_hash(), cmt.root_hash());
+        assert_eq!(loaded.height(), N);
+        assert_eq!(loaded.len(), N);
+
+        // a record list cut short or run on is rejected, and the partial
+        // spine is torn down without recursing
+        let cut = json.rfind("},{").unwrap();
+        assert!(
+            serde_json::from_str::<CartesianMerkleTree>(&format!("{}}}]", &json[..cut])).is_err()
+        );
+        let extra = format!(
+            "{},{}]",
+            &json[..json.len() - 1],
+            &json[1..json.find("},{").unwrap() + 1]
+        );
+        assert!(serde_json::from_str::<CartesianMerkleTree>(&extra).is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_load_rejects_tampered_values() {
+        let (mut cmt, keys) = build(20);
+        let json = serde_json::to_string(&cmt).unwrap();
+        // swap the value without updating any hash
+        cmt.root.as_mut().unwrap().value = vec![0xff];
+        let tampered = serde_json::to_string(&cmt).unwrap();
+        assert_ne!(json, tampered);
+
+        assert!(serde_json::from_str::<CartesianMerkleTree>(&tampered).is_err());
+        let trusted = CartesianMerkleTree::deserialize_tree(
+            &mut serde_json::Deserializer::from_str(&tampered),
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            trusted.get(&cmt.root.as_ref().unwrap().key),
+            Some(&vec![0xff])
+        );
+        assert!(trusted.contains_key(&keys[3]));
+    }
+
+    #[test]
+    fn from_sorted_matches_repeated_inserts() {
+        let (reference, keys) = build(500);
+        let pairs: Vec<(Key, Value)> = reference.iter().map(|(k, v)| (*k, v.clone())).collect();
+        let loaded = CartesianMerkleTree::from_sorted(pairs.clone());
+        assert_eq!(loaded.root_hash(), reference.root_hash());
+        assert_eq!(loaded.len(), 500);
+        assert_integrity(&loaded);
+
+        // unsorted input with a duplicate: the later value wins
+        let mut shuffled: Vec<_> = pairs.iter().rev().cloned().collect();
+        shuffled.push((keys[3], vec![0xaa]));
+        let loaded = CartesianMerkleTree::from_sorted(shuffled);
+        assert_eq!(loaded.len(), 500);
+        assert_eq!(loaded.get(&keys[3]), Some(&vec![0xaa]));
+        assert_integrity(&loaded);
+    }
+
+    #[test]
+    fn old_versions_keep_proving_against_old_roots() {
+        // xorshift, so the workload is random but reproducible
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        let mut mutable = CartesianMerkleTree::new();
+        let mut versions = vec![PersistentCMT::new()];
+        for _ in 0..2000 {
+            let k = key((next() % 400) as usize);
+            let latest = versions.last().unwrap();
+            let version = if next() % 3 == 0 {
+                mutable.remove(&k);
+                latest.remove(&k)
+            } else {
+                let v = vec![next() as u8];
+                mutable.insert(k, v.clone());
+                latest.insert(k, v)
+            };
+            assert_eq!(version.version(), root_hash_of(&mutable));
+            assert_eq!(version.len(), mutable.len());
+            versions.push(version);
+        }
+        let roots: Vec<Hash> = versions.iter().map(|v| v.version()).collect();
+
+        // every key of an early version still proves against that root,
+        // however the later versions changed it
+        let old = &versions[500];
+        let latest = versions.last().unwrap();
+        let mut changed = 0;
+        for i in 0..400 {
+            let k = key(i);
+            let proof = old.generate_proof(&k);
+            assert!(CartesianMerkleTree::verify_proof(
+                proof.clone(),
+                k,
+                roots[500]
+            ));
+            if let Some(v) = old.get(&k) {
+                assert!(CartesianMerkleTree::verify_value(proof, k, v, roots[500]));
+                if latest.get(&k) != Some(v) {
+                    changed += 1;
+                }
+            }
+        }
+        assert!(changed > 0);
+        assert_eq!(old.version(), roots[500]);
+    }
+
+    #[test]
+    fn persistent_versions_share_untouched_subtrees() {
+        use std::collections::HashSet;
+        fn nodes(root: &persistent::Link) -> Vec<*const persistent::Node> {
+            let mut stack: Vec<&Arc<persistent::Node>> = root.iter().collect();
+            let mut out = Vec::new();
+            while let Some(n) = stack.pop() {
+                out.push(Arc::as_ptr(n));
+                stack.extend(n.left.iter().chain(n.right.iter()));
+            }
+            out
+        }
+
+        let mut tree = PersistentCMT::new();
+        for i in 0..10_000 {
+            tree = tree.insert(key(i), vec![i as u8]);
+        }
+        let mut reference = CartesianMerkleTree::new();
+        for i in 0..10_000 {
+            reference.insert(key(i), vec![i as u8]);
+        }
+        let height = reference.height();
+
+        let old: HashSet<_> = nodes(&tree.root).into_iter().collect();
+        for (i, next) in [
+            tree.insert(key(77_777), vec![1]),
+            tree.insert(key(5_000), vec![2]),
+            tree.remove(&key(1_234)),
+        ]
+        .iter()
+        .enumerate()
+        {
+            let fresh = nodes(&next.root)
+                .into_iter()
+                .filter(|n| !old.contains(n))
+                .count();
+            // a search path plus a split or merge path, never the whole tree
+            assert!(fresh <= 2 * height + 1, "version {i} copied {fresh} nodes");
+        }
+    }
+
+    #[test]
+    fn consistency_proofs_reject_removed_or_changed_keys() {
+        let mut history = VersionHistory::new();
+        let empty = history.latest().version();
+        for i in 0..200 {
+            history.insert(key(i), vec![i as u8]);
+        }
+        let old = history.latest().version();
+        for i in 200..400 {
+            history.insert(key(i), vec![i as u8]);
+        }
+        // rewriting a key with its current value changes nothing
+        let extended = history.insert(key(7), vec![7]);
+
+        let proof = history.consistency_proof(old, extended).unwrap();
+        assert_eq!(proof.entries.len(), 200);
+        assert!(proof.verify(&old, &extended));
+        assert!(history
+            .consistency_proof(empty, extended)
+            .unwrap()
+            .verify(&empty, &extended));
+        assert!(!proof.verify(&extended, &old));
+
+        // dropping an old entry no longer reproduces the old root
+        let mut short = proof.clone();
+        short.entries.remove(3);
+        short.proofs.remove(3);
+        assert!(!short.verify(&old, &extended));
+
+        let removed = history.remove(&key(42));
+        assert!(!history
+            .consistency_proof(old, removed)
+            .unwrap()
+            .verify(&old, &removed));
+        assert!(!proof.verify(&old, &removed));
+
+        history.insert(key(42), vec![42]);
+        let mutated = history.insert(key(9), vec![0xff]);
+        assert!(!history
+            .consistency_proof(old, mutated)
+            .unwrap()
+            .verify(&old, &mutated));
+
+        // putting the value back restores consistency
+        let restored = history.insert(key(9), vec![9]);
+        assert!(history
+            .consistency_proof(old, restored)
+            .unwrap()
+            .verify(&old, &restored));
+        assert!(history.consistency_proof(old, [1; 32]).is_none());
+    }
+
+    #[test]
+    fn multiproof_is_smaller_than_separate_proofs() {
+        let mut cmt = CartesianMerkleTree::new();
+        for i in 0..10_000 {
+            cmt.insert(key(i), vec![i as u8]);
+        }
+        let root = root_hash_of(&cmt);
+        let keys: Vec<Key> = (0..100).map(|i| key(i * 97)).collect();
+
+        let multi = cmt.generate_multiproof(&keys);
+        assert!(CartesianMerkleTree::verify_multiproof(&multi, &keys, root));
+        for (i, k) in keys.iter().enumerate() {
+            let value_hash = hash_value(&[(i * 97) as u8]);
+            assert_eq!(multi.value_hash(k), Some(&value_hash));
+        }
+
+        let multi_size = multi.to_bytes().len();
+        let separate_size: usize = keys
+            .iter()
+            .map(|k| cmt.generate_proof(k).to_bytes().len())
+            .sum();
+        assert!(
+            multi_size * 3 < separate_size * 2,
+            "multiproof {multi_size} bytes vs {separate_size} bytes of proofs"
+        );
+
+        let decoded = MultiProof::from_bytes(&multi.to_bytes()).unwrap();
+        assert_eq!(decoded, multi);
+        let mut bad_tag = multi.to_bytes();
+        bad_tag[6] = 7;
+        assert_eq!(
+            MultiProof::from_bytes(&bad_tag),
+            Err(ProofDecodeError::InvalidFlags(7))
+        );
+    }
+
+    #[test]
+    fn multiproof_rejects_uncovered_or_tampered_keys() {
+        let mut cmt = CartesianMerkleTree::new();
+        for i in 0..500 {
+            cmt.insert(key(i), vec![i as u8]);
+        }
+        let root = root_hash_of(&cmt);
+        let keys = [key(3), key(250), key(499)];
+        let multi = cmt.generate_multiproof(&keys);
+        assert!(CartesianMerkleTree::verify_multiproof(&multi, &keys, root));
+        assert!(CartesianMerkleTree::verify_multiproof(
+            &multi,
+            &keys[1..],
+            root
+        ));
+
+        // a key whose path isn't opened, or that isn't in the tree at all
+        assert!(!CartesianMerkleTree::verify_multiproof(
+            &multi,
+            &[key(100)],
+            root
+        ));
+        let absent = cmt.generate_multiproof(&[key(3), key(1000)]);
+        assert!(CartesianMerkleTree::verify_multiproof(
+            &absent,
+            &[key(3)],
+            root
+        ));
+        assert!(!CartesianMerkleTree::verify_multiproof(
+            &absent,
+            &[key(1000)],
+            root
+        ));
+
+        let mut tampered = multi.clone();
+        let MultiProofItem::Node { value_hash, .. } = tampered
+            .items
+            .iter_mut()
+            .find(|item| matches!(item, MultiProofItem::Node { key: k, .. } if *k == key(250)))
+            .unwrap()
+        else {
+            unreachable!()
+        };
+        value_hash[0] ^= 1;
+        assert!(!CartesianMerkleTree::verify_multiproof(
+            &tampered, &keys, root
+        ));
+
+        let mut truncated = multi.clone();
+        truncated.items.pop();
+        assert!(!CartesianMerkleTree::verify_multiproof(
+            &truncated, &keys, root
+        ));
+        assert!(!CartesianMerkleTree::verify_multiproof(
+            &MultiProof { items: Vec::new() },
+            &[],
+            root
+        ));
+    }
+
+    #[test]
+    fn range_proofs_cover_exactly_the_range() {
+        // even keys only, so there are gaps to prove empty
+        let mut cmt = CartesianMerkleTree::new();
+        for i in 0..300 {
+            cmt.insert(key(2 * i), vec![i as u8]);
+        }
+        let root = root_hash_of(&cmt);
+        let check = |start: usize, end: usize, expected: usize| {
+            let (start, end) = (key(start), key(end));
+            let proof = cmt.range_proof(&start, &end);
+            assert_eq!(proof.entries.len(), expected);
+            let listed: Vec<(Key, Value)> = cmt
+                .range(start..=end)
+                .map(|(k, v)| (*k, v.clone()))
+                .collect();
+            assert_eq!(proof.entries, listed);
+            assert!(CartesianMerkleTree::verify_range_proof(
+                &proof, &start, &end, root
+            ));
+            proof
+        };
+
+        let proof = check(100, 199, 50);
+        check(0, 1000, 300);
+        check(401, 401, 0); // a gap between two keys
+        check(700, 800, 0); // past the last key
+        check(300, 200, 0); // inverted, hence empty
+        let single = check(250, 250, 1);
+        assert_eq!(single.entries, vec![(key(250), vec![125])]);
+
+        // the proof doesn't stretch to a wider range, and a narrower range
+        // would need fewer entries
+        assert!(!CartesianMerkleTree::verify_range_proof(
+            &proof,
+            &key(90),
+            &key(199),
+            root
+        ));
+        assert!(!CartesianMerkleTree::verify_range_proof(
+            &proof,
+            &key(110),
+            &key(199),
+            root
+        ));
+        assert!(!CartesianMerkleTree::verify_range_proof(
+            &single,
+            &key(248),
+            &key(250),
+            root
+        ));
+
+        let mut empty = CartesianMerkleTree::new();
+        let none = empty.range_proof(&key(0), &key(10));
+        assert!(none.entries.is_empty());
+        assert!(CartesianMerkleTree::verify_range_proof(
+            &none,
+            &key(0),
+            &key(10),
+            empty_hash()
+        ));
+        empty.insert(key(5), vec![5]);
+        assert!(!CartesianMerkleTree::verify_range_proof(
+            &none,
+            &key(0),
+            &key(10),
+            root_hash_of(&empty)
+        ));
+    }
+
+    #[test]
+    fn range_entries_come_with_their_proof() {
+        let mut cmt = CartesianMerkleTree::new();
+        for i in 0..300 {
+            cmt.insert(key(2 * i), vec![i as u8]);
+        }
+        let root = root_hash_of(&cmt);
+        let verify = |entries: &[(Key, Value)], proof: &RangeProof, range| {
+            CartesianMerkleTree::verify_range_with_proof::<(Bound<Key>, Bound<Key>)>(
+                entries, proof, range, root,
+            )
+        };
+
+        let ranges = [
+            (Bound::Included(key(100)), Bound::Excluded(key(200))),
+            (Bound::Excluded(key(100)), Bound::Included(key(200))),
+            (Bound::Unbounded, Bound::Excluded(key(10))),
+            (Bound::Excluded(key(590)), Bound::Unbounded),
+            (Bound::Unbounded, Bound::Unbounded),
+            (Bound::Excluded(key(101)), Bound::Excluded(key(102))),
+            (Bound::Excluded([0xff; 32]), Bound::Unbounded),
+            (Bound::Unbounded, Bound::Excluded([0; 32])),
+        ];
+        for range in ranges {
+            let (entries, proof) = cmt.get_range_with_proof(range);
+            let listed: Vec<(Key, Value)> =
+                cmt.range(range).map(|(k, v)| (*k, v.clone())).collect();
+            assert_eq!(entries, listed);
+            assert!(proof.entries.is_empty());
+            assert!(verify(&entries, &proof, range), "{range:?}");
+        }
+
+        let range = (Bound::Included(key(100)), Bound::Excluded(key(200)));
+        let (entries, proof) = cmt.get_range_with_proof(range);
+        assert_eq!(entries.len(), 50);
+
+        let mut altered = entries.clone();
+        altered[7].1 = vec![0xff];
+        assert!(!verify(&altered, &proof, range));
+        let mut omitted = entries.clone();
+        omitted.remove(49);
+        assert!(!verify(&omitted, &proof, range));
+        // the last key is outside a half-open range but inside a closed one
+        let closed = (Bound::Included(key(100)), Bound::Included(key(200)));
+        assert!(!verify(&entries, &proof, closed));
+    }
+
+    #[test]
+    fn successor_proofs_show_the_next_key() {
+        // even keys only, so odd keys are absent
+        let mut cmt = CartesianMerkleTree::new();
+        for i in 1..200 {
+            cmt.insert(key(2 * i), vec![i as u8]);
+        }
+        let root = root_hash_of(&cmt);
+        let verify = |proof: &SuccessorProof, k: usize| {
+            CartesianMerkleTree::verify_successor_proof(proof, &key(k), root)
+        };
+
+        for k in [0, 2, 3, 100, 101, 396] {
+            let proof = cmt.successor_proof(&key(k));
+            assert!(verify(&proof, k), "key {k}");
+            assert_eq!(proof.present, k % 2 == 0 && k > 0);
+            assert_eq!(proof.successor, Some(key(k + 2 - k % 2)));
+            assert_eq!(proof.successor_value(), Some(&vec![(k / 2 + 1) as u8]));
+        }
+
+        // at or past the largest key there is no successor
+        for k in [398, 399, 1000] {
+            let proof = cmt.successor_proof(&key(k));
+            assert!(verify(&proof, k));
+            assert_eq!(proof.present, k == 398);
+            assert_eq!(proof.successor, None);
+            assert_eq!(proof.successor_value(), None);
+        }
+        let empty = CartesianMerkleTree::new();
+        let proof = empty.successor_proof(&key(1));
+        assert!(CartesianMerkleTree::verify_successor_proof(
+            &proof,
+            &key(1),
+            empty_hash()
+        ));
+
+        // claiming the key after the real successor
+        let mut skipped = cmt.successor_proof(&key(101));
+        skipped.successor = Some(key(104));
+        assert!(!verify(&skipped, 101));
+        skipped.range = cmt.range_proof(&key(101), &key(104));
+        assert!(!verify(&skipped, 101));
+        skipped.range.entries.retain(|(k, _)| *k != key(102));
+        assert!(!verify(&skipped, 101));
+
+        // claiming no successor, or the queried key's presence, falsely
+        let mut none = cmt.successor_proof(&key(101));
+        none.successor = None;
+        assert!(!verify(&none, 101));
+        let mut present = cmt.successor_proof(&key(101));
+        present.present = true;
+        assert!(!verify(&present, 101));
+
+        // a successor at or below the key is never valid
+        let mut backwards = cmt.successor_proof(&key(100));
+        backwards.successor = Some(key(100));
+        backwards.range = cmt.range_proof(&key(100), &key(100));
+        assert!(!verify(&backwards, 100));
+    }
+
+    #[test]
+    fn range_proofs_reject_missing_or_hidden_entries() {
+        let mut cmt = CartesianMerkleTree::new();
+        for i in 0..300 {
+            cmt.insert(key(i), vec![i as u8]);
+        }
+        let root = root_hash_of(&cmt);
+        let (start, end) = (key(40), key(60));
+        let proof = cmt.range_proof(&start, &end);
+        assert!(CartesianMerkleTree::verify_range_proof(
+            &proof, &start, &end, root
+        ));
+
+        let mut dropped = proof.clone();
+        dropped.entries.remove(5);
+        assert!(!CartesianMerkleTree::verify_range_proof(
+            &dropped, &start, &end, root
+        ));
+
+        let mut changed = proof.clone();
+        changed.entries[5].1 = vec![0xff];
+        assert!(!CartesianMerkleTree::verify_range_proof(
+            &changed, &start, &end, root
+        ));
+
+        // collapsing an opened subtree into its hash still folds to the
+        // root, but must not hide keys in the range
+        let items = &proof.tree.items;
+        for i in 0..items.len() {
+            let MultiProofItem::Node { key: k, .. } = items[i] else {
+                continue;
+            };
+            // find the end of this node's subtree in pre-order
+            let (mut open, mut j) = (1, i + 1);
+            while open > 0 {
+                open += match items[j] {
+                    MultiProofItem::Node { .. } => 1,
+                    MultiProofItem::Subtree(_) => -1,
+                };
+                j += 1;
+            }
+            let hides_range = items[i..j].iter().any(
+                |item| matches!(item, MultiProofItem::Node { key, .. } if (start..=end).contains(key)),
+            );
+            if !hides_range {
+                continue;
+            }
+            let mut hidden = proof.clone();
+            let hash = *find_node(&cmt, &k).unwrap().hash();
+            hidden
+                .tree
+                .items
+                .splice(i..j, [MultiProofItem::Subtree(hash)]);
+            hidden.entries.retain(|(key, _)| {
+                hidden
+                    .tree
+                    .items
+                    .iter()
+                    .any(|item| matches!(item, MultiProofItem::Node { key: k, .. } if k == key))
+            });
+            assert!(!CartesianMerkleTree::verify_range_proof(
+                &hidden, &start, &end, root
+            ));
+        }
+    }
+
+    fn find_node<'a>(cmt: &'a CartesianMerkleTree, key: &Key) -> Option<&'a TreeNode> {
+        let mut cur = cmt.root.as_deref();
+        while let Some(n) = cur {
+            cur = match key.cmp(&n.key) {
+                Ordering::Equal => return Some(n),
+                Ordering::Less => n.left.as_deref(),
+                Ordering::Greater => n.right.as_deref(),
+            };
+        }
+        None
+    }
+
+    #[test]
+    fn detailed_verification_says_why_a_proof_failed() {
+        let (cmt, keys) = build(50);
+        let root = root_hash_of(&cmt);
+        let member = keys[10];
+        let verify = |proof: Proof, key: Key, root: Hash| {
+            let detailed = CartesianMerkleTree::verify_proof_detailed(proof.clone(), key, root);
+            assert_eq!(
+                detailed.is_ok(),
+                CartesianMerkleTree::verify_proof(proof, key, root)
+            );
+            detailed
+        };
+
+        assert_eq!(verify(cmt.generate_proof(&member), member, root), Ok(()));
+        let absent = key(1000);
+        assert_eq!(verify(cmt.generate_proof(&absent), absent, root), Ok(()));
+
+        let wrong = [7; 32];
+        let Err(VerifyError::RootMismatch { expected, got }) =
+            verify(cmt.generate_proof(&member), member, wrong)
+        else {
+            panic!("expected a root mismatch");
+        };
+        assert_eq!((expected, got), (wrong, root));
+
+        let mut no_witness = cmt.generate_proof(&absent);
+        no_witness.nonexistence_key = None;
+        assert_eq!(
+            verify(no_witness, absent, root),
+            Err(VerifyError::MissingNonExistenceKey)
+        );
+        assert_eq!(
+            verify(cmt.generate_proof(&absent), keys[3], root),
+            Err(VerifyError::KeyNotInGap)
+        );
+
+        let empty = CartesianMerkleTree::new().generate_proof(&absent);
+        assert_eq!(verify(empty, absent, root), Err(VerifyError::EmptyProof));
+    }
+
+    #[test]
+    fn proof_verifier_agrees_with_verify_proof() {
+        let (cmt, keys) = build(200);
+        let root = root_hash_of(&cmt);
+        let mut verifier = ProofVerifier::new();
+
+        // members, and absent keys falling into every kind of gap
+        let probes = keys.iter().flat_map(|k| {
+            let mut above = *k;
+            above[31] = 1;
+            [*k, above]
+        });
+        for k in probes.chain([[0; 32], [0xff; 32]]) {
+            let proof = cmt.generate_proof(&k);
+            assert!(verifier.verify(&proof, &k, &root));
+
+            let mut tampered = proof.clone();
+            tampered.value_hash[0] ^= 1;
+            assert_eq!(
+                verifier.verify_detailed(&tampered, &k, &root),
+                CartesianMerkleTree::verify_proof_detailed(tampered, k, root)
+            );
+            assert!(!verifier.verify(&proof, &k, &[7; 32]));
+            let other = if k == keys[0] { keys[1] } else { keys[0] };
+            assert!(!verifier.verify(&proof, &other, &root));
+        }
+    }
+
+    /// Counts each thread's own allocations, so a test can check that a
+    /// path allocates nothing while other tests run alongside it.
+    struct CountingAlloc;
+
+    std::thread_local! {
+        static ALLOCATIONS: core::cell::Cell<usize> = const { core::cell::Cell::new(0) };
+    }
+
+    unsafe impl core::alloc::GlobalAlloc for CountingAlloc {
+        unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+            std::alloc::System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
+            std::alloc::System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static GLOBAL: CountingAlloc = CountingAlloc;
+
+    fn allocations() -> usize {
+        ALLOCATIONS.with(core::cell::Cell::get)
+    }
+
+    #[test]
+    fn warm_proof_verifier_allocates_nothing() {
+        let (cmt, keys) = build(500);
+        let root = root_hash_of(&cmt);
+        let proofs: Vec<_> = keys.iter().map(|k| (cmt.generate_proof(k), *k)).collect();
+        let mut verifier = ProofVerifier::new();
+        assert!(verifier.verify(&proofs[0].0, &proofs[0].1, &root));
+
+        let before = allocations();
+        let verified = proofs
+            .iter()
+            .filter(|(proof, key)| verifier.verify(proof, key, &root))
+            .count();
+        assert_eq!(allocations() - before, 0);
+        assert_eq!(verified, proofs.len());
+
+        // verify_proof pays for its own buffer on every call
+        let (proof, key) = proofs[1].clone();
+        let before = allocations();
+        assert!(CartesianMerkleTree::verify_proof(proof, key, root));
+        assert!(allocations() > before);
+    }
+
+    #[test]
+    fn empty_tree_proofs_verify_against_empty_root() {
+        let (mut cmt, keys) = build(20);
+        assert_eq!(cmt.committed_root(), root_hash_of(&cmt));
+        let member = cmt.generate_proof(&keys[0]);
+        assert!(!CartesianMerkleTree::verify_proof(
+            member, keys[0], EMPTY_ROOT
+        ));
+        for k in &keys {
+            cmt.remove(k);
+        }
+        assert_eq!(cmt.root_hash(), None);
+        assert_eq!(cmt.committed_root(), EMPTY_ROOT);
+
+        for k in [key(3), [0; 32], [0xff; 32]] {
+            let proof = cmt.generate_proof(&k);
+            assert!(proof.is_empty_tree_proof());
+            assert!(cmt.generate_proof_borrowed(&k).is_empty_tree_proof());
+            assert!(CartesianMerkleTree::verify_proof(
+                proof.clone(),
+                k,
+                EMPTY_ROOT
+            ));
+            assert!(CartesianMerkleTree::verify_strict(
+                proof.clone(),
+                k,
+                EMPTY_ROOT
+            ));
+            assert!(ProofVerifier::new().verify(&proof, &k, &EMPTY_ROOT));
+            assert!(cmt.generate_proof_borrowed(&k).verify(&k, &EMPTY_ROOT));
+            assert_eq!(
+                CartesianMerkleTree::verify_proof_detailed(proof.clone(), k, [1; 32]),
+                Err(VerifyError::EmptyProof)
+            );
+
+            // a path without a witness is still no proof
+            let mut pathed = proof;
+            pathed.prefix.push((key(1), hash_value(b"x"), empty_hash()));
+            assert!(!pathed.is_empty_tree_proof());
+            assert!(!CartesianMerkleTree::verify_proof(pathed, k, EMPTY_ROOT));
+        }
+    }
+
+    #[test]
+    fn get_with_proof_returns_the_committed_value() {
+        let (mut cmt, keys) = build(100);
+        cmt.insert(keys[7], b"seven".to_vec());
+        let root = root_hash_of(&cmt);
+
+        for k in &keys {
+            let (value, proof) = cmt.get_with_proof(k);
+            let value = value.unwrap().clone();
+            assert_eq!(Some(&value), cmt.get(k));
+            assert_eq!(proof, cmt.generate_proof(k));
+            assert!(CartesianMerkleTree::verify_value(
+                proof.clone(),
+                *k,
+                &value,
+                root
+            ));
+            assert!(!CartesianMerkleTree::verify_value(
+                proof, *k, b"other", root
+            ));
+        }
+
+        let absent = key(1000);
+        let (value, proof) = cmt.get_with_proof(&absent);
+        assert_eq!(value, None);
+        assert!(!proof.existence);
+        assert!(CartesianMerkleTree::verify_proof(proof, absent, root));
+    }
+
+    #[test]
+    fn validate_names_the_broken_node_and_invariant() {
+        let fresh = || {
+            let (cmt, _) = build(64);
+            assert_eq!(cmt.validate(), Ok(()));
+            cmt
+        };
+        let child_of_root = |cmt: &CartesianMerkleTree| -> Key {
+            cmt.root.as_ref().unwrap().left.as_ref().unwrap().key
+        };
+        let expect = |cmt: CartesianMerkleTree, key: Key, violation| {
+            assert_eq!(cmt.validate(), Err(ValidationError { key, violation }));
+        };
+
+        let mut cmt = fresh();
+        let root = cmt.root.as_mut().unwrap();
+        root.left.as_mut().unwrap().key = [0xff; 32];
+        let k = child_of_root(&cmt);
+        expect(cmt, k, Violation::KeyOrder);
+
+        let mut cmt = fresh();
+        let root = cmt.root.as_mut().unwrap();
+        root.left.as_mut().unwrap().priority = root.priority + 1;
+        let k = child_of_root(&cmt);
+        expect(cmt, k, Violation::HeapOrder);
+
+        let mut cmt = fresh();
+        let root = cmt.root.as_mut().unwrap();
+        root.value = b"tampered".to_vec();
+        let k = root.key;
+        expect(cmt, k, Violation::ValueHash);
+
+        let mut cmt = fresh();
+        let root = cmt.root.as_mut().unwrap();
+        root.subtree_size += 1;
+        let k = root.key;
+        expect(cmt, k, Violation::SubtreeSize);
+
+        let mut cmt = fresh();
+        let root = cmt.root.as_mut().unwrap();
+        let left = root.left.as_mut().unwrap();
+        left.hash = HashCell::from([3; 32]);
+        let k = child_of_root(&cmt);
+        // the parent is checked first and already disagrees with the child
+        let parent = cmt.root.as_ref().unwrap().key;
+        assert_ne!(parent, k);
+        expect(cmt, parent, Violation::NodeHash);
+    }
+
+    #[test]
+    fn rotations_hand_back_a_node_without_the_child_to_lift() {
+        let mut cmt = CartesianMerkleTree::new();
+        cmt.insert_with_priority(key(5), vec![5], 10);
+        cmt.insert_with_priority(key(3), vec![3], 5);
+        let root = cmt.root.take().unwrap();
+
+        let err = rotate_left(root).unwrap_err();
+        assert_eq!(err.node.key, key(5));
+        assert!(err.to_string().contains(&hex::encode(key(5))));
+        let top = rotate_right(err.node).unwrap();
+        assert_eq!(top.key, key(3));
+        assert_eq!(top.right.as_ref().unwrap().key, key(5));
+        assert_eq!(top.subtree_size, 2);
+        assert!(rotate_right(top).is_err());
+    }
+
+    #[test]
+    fn removing_above_a_minimum_priority_only_child() {
+        // the only child has the lowest possible priority; heapify must
+        // still lift it rather than the empty side
+        for (child, root) in [(key(3), key(5)), (key(7), key(5))] {
+            let mut cmt = CartesianMerkleTree::new();
+            cmt.insert_with_priority(root, vec![1], 0);
+            cmt.insert_with_priority(child, vec![2], i128::MIN);
+            assert_eq!(cmt.remove(&root), Some(vec![1]));
+            assert_eq!(cmt.len(), 1);
+            assert_eq!(cmt.get(&child), Some(&vec![2]));
+            assert_integrity(&cmt);
+        }
+    }
+
+    #[test]
+    fn dot_export_draws_every_node_and_edge() {
+        let empty = CartesianMerkleTree::new().to_dot();
+        assert!(empty.starts_with("digraph cmt {"));
+        assert!(!empty.contains("->"));
+
+        let (cmt, keys) = build(40);
+        let dot = cmt.to_dot();
+        assert!(dot.trim_end().ends_with('}'));
+        let visible_edges = dot
+            .lines()
+            .filter(|l| l.contains("->") && !l.contains("invis"))
+            .count();
+        assert_eq!(visible_edges, keys.len() - 1);
+        for k in &keys {
+            assert!(dot.contains(&format!(
+                "n{} [label=\"key {}..",
+                hex::encode(k),
+                hex::encode(&k[..4])
+            )));
+        }
+        let root = cmt.root.as_ref().unwrap();
+        assert!(dot.contains(&format!("prio {}\\n", root.priority)));
+        assert!(dot.contains(&format!("hash {}..", hex::encode(&root.hash()[..4]))));
+    }
+
+    #[test]
+    fn node_hashing_waits_until_a_hash_is_read() {
+        use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        // SHA-256, counting how often it runs
+        struct Counting;
+        impl Hasher for Counting {
+            const ALGO_ID: u8 = HASH_ALGO_SHA256;
+            fn hash(data: &[u8]) -> Hash {
+                CALLS.fetch_add(1, Relaxed);
+                Sha256Hasher::hash(data)
+            }
+        }
+        let calls = || CALLS.load(Relaxed);
+
+        const N: usize = 200;
+        let mut cmt = CartesianMerkleTreeWith::<Counting>::with_priority_fn(find_priority);
+        let mut reference = CartesianMerkleTree::new();
+        for i in 0..N {
+            cmt.insert(key(i), vec![i as u8]);
+            reference.insert(key(i), vec![i as u8]);
+        }
+        // only the value hashes so far; then every node once
+        assert_eq!(calls(), N);
+        assert_eq!(cmt.root_hash(), reference.root_hash());
+        assert_eq!(calls(), 2 * N);
+        cmt.root_hash();
+        assert_eq!(calls(), 2 * N);
+
+        // a batch of updates is hashed together, along its paths only
+        for i in 0..10 {
+            cmt.insert(key(i), vec![0xff]);
+            reference.insert(key(i), vec![0xff]);
+        }
+        let before = calls();
+        let proof = cmt.generate_proof(&key(3));
+        assert!(calls() - before < N);
+        let root = reference.root_hash().unwrap();
+        assert_eq!(cmt.root_hash(), Some(root));
+        assert!(CartesianMerkleTree::verify_value(
+            proof,
+            key(3),
+            &[0xff],
+            root
+        ));
+    }
+
+    #[test]
+    fn degenerate_tree_does_not_overflow() {
+        // priority grows with the key, so ascending inserts leave a single
+        // left spine a million nodes deep
+        const N: usize = 1_000_000;
+        // a cheap stand-in hasher: this test is about depth, not digests
+        struct Fold;
+        impl Hasher for Fold {
+            const ALGO_ID: u8 = 0xf1;
+            fn hash(data: &[u8]) -> Hash {
+                let mut out = empty_hash();
+                let n = data.len().min(DIGEST_LEN);
+                out[..n].copy_from_slice(&data[..n]);
+                out
+            }
+        }
+        let by_key = |k: &Key| u64::from_be_bytes(k[..8].try_into().unwrap()) as Priority;
+        let mut cmt = CartesianMerkleTreeWith::<Fold>::with_priority_fn(by_key);
+        for i in 1..=N {
+            cmt.insert(key(i), vec![]);
+        }
+        assert_eq!(cmt.height(), N);
+
+        // all of these walk the full spine
+        cmt.insert(key(0), vec![1]);
+        assert_eq!(cmt.depth_of(&key(0)), Some(N));
+        assert_eq!(cmt.remove(&key(1)), Some(vec![]));
+        assert_eq!(cmt.remove(&key(0)), Some(vec![1]));
+        assert_eq!(cmt.remove(&key(0)), None);
+        assert_eq!(cmt.len(), N - 1);
+        assert_eq!(cmt.first().map(|(k, _)| *k), Some(key(2)));
+
+        let copy = cmt.clone();
+        assert_eq!(copy.root_hash(), cmt.root_hash());
+        drop(copy);
+
+        // a node copied out, printed or dropped on its own doesn't recurse
+        let spine = cmt.root.as_deref().unwrap().clone();
+        assert_eq!(spine.subtree_size, N - 1);
+        assert!(format!("{spine:?}").len() < 1000);
+        drop(spine);
+    }
+
+    #[test]
+    fn range_matches_btreemap() {
+        use std::ops::Bound::{self, Excluded, Included, Unbounded};
+
+        let (mut cmt, keys) = build(150);
+        for k in keys.iter().step_by(4) {
+            cmt.remove(k);
+        }
+        let expected: std::collections::BTreeMap<Key, Value> =
+            cmt.iter().map(|(k, v)| (*k, v.clone())).collect();
+
+        let points = [keys[0], keys[3], keys[40], keys[41], keys[149], key(1000)];
+        let bounds = |k: Key| [Included(k), Excluded(k), Unbounded];
+        for lo in points {
+            for hi in points.into_iter().filter(|hi| *hi >= lo) {
+                for range in bounds(lo)
+                    .into_iter()
+                    .flat_map(|s| bounds(hi).map(|e| (s, e)))
+                {
+                    // BTreeMap panics on an empty range with equal excluded bounds
+                    if lo == hi && matches!(range, (Excluded(_), Excluded(_))) {
+                        continue;
+                    }
+                    let got: Vec<_> = cmt.range(range).collect();
+                    let want: Vec<_> = expected
+                        .range::<Key, (Bound<Key>, Bound<Key>)>(range)
+                        .collect();
+                    assert_eq!(got, want);
+                }
+            }
+        }
+        assert_eq!(cmt.range(..).count(), cmt.len());
+        assert_eq!(
+            cmt.range(keys[10]..keys[20]).count(),
+            cmt.count_range(keys[10]..keys[20])
+        );
+    }
+
+    #[test]
+    fn collect_extend_and_into_iter_round_trip() {
+        let (reference, _) = build(120);
+        let pairs: Vec<(Key, Value)> = reference.iter().map(|(k, v)| (*k, v.clone())).collect();
+
+        let mut cmt: CartesianMerkleTree = pairs[..80].iter().cloned().collect();
+        assert_eq!(cmt.len(), 80);
+        cmt.extend(pairs[80..].iter().cloned());
+        assert_eq!(cmt.len(), 120);
+        assert_eq!(root_hash_of(&cmt), root_hash_of(&reference));
+
+        let borrowed: Vec<_> = (&cmt).into_iter().map(|(k, v)| (*k, v.clone())).collect();
+        let owned: Vec<_> = cmt.into_iter().collect();
+        assert_eq!(owned, borrowed);
+        assert!(owned.windows(2).all(|w| w[0].0 < w[1].0));
+    }
+
+    #[test]
+    fn entry_inserts_once_then_modifies() {
+        let (mut cmt, keys) = build(50);
+        let before = root_hash_of(&cmt);
+
+        cmt.entry(keys[7])
+            .and_modify(|v| v.push(1))
+            .or_insert(vec![0]);
+        assert_eq!(cmt.get(&keys[7]).unwrap().last(), Some(&1));
+        assert_ne!(root_hash_of(&cmt), before);
+        assert_integrity(&cmt);
+        assert_eq!(cmt.len(), 50);
+
+        // lowest priority of all, so it lands as a leaf
+        let floor = keys.iter().map(find_priority).min().unwrap();
+        let fresh = (1000..)
+            .map(key)
+            .find(|k| find_priority(k) < floor)
+            .unwrap();
+        for _ in 0..3 {
+            cmt.entry(fresh).or_default().push(1);
+        }
+        assert_eq!(cmt.get(&fresh), Some(&vec![1, 1, 1]));
+        assert_eq!(cmt.len(), 51);
+        assert!(CartesianMerkleTree::verify_proof(
+            cmt.generate_proof(&fresh),
+            fresh,
+            root_hash_of(&cmt)
+        ));
+        assert!(matches!(cmt.entry(fresh), Entry::Occupied(_)));
+        assert!(matches!(cmt.entry(key(2000)), Entry::Vacant(_)));
+        // a vacant entry that is dropped hangs its path back untouched
+        assert_eq!(cmt.len(), 51);
+        assert_integrity(&cmt);
+
+        // highest priority of all, so the insert splits the whole tree
+        let ceiling = keys.iter().map(find_priority).max().unwrap();
+        let top = (1000..)
+            .map(key)
+            .find(|k| find_priority(k) > ceiling)
+            .unwrap();
+        let mut reference = cmt.clone();
+        reference.insert(top, vec![9]);
+        *cmt.entry(top).or_default() = vec![9];
+        assert_eq!(cmt.root.as_ref().map(|n| n.key), Some(top));
+        assert_eq!(root_hash_of(&cmt), root_hash_of(&reference));
+        assert_integrity(&cmt);
+    }
+
+    #[test]
+    fn remove_returns_the_value() {
+        let (mut cmt, keys) = build(30);
+        let before = root_hash_of(&cmt);
+        assert_eq!(cmt.remove(&key(1000)), None);
+        assert_eq!(root_hash_of(&cmt), before);
+        assert_eq!(cmt.len(), 30);
+
+        let expected = cmt.get(&keys[12]).cloned();
+        assert!(expected.is_some());
+        assert_eq!(cmt.remove(&keys[12]), expected);
+        assert_eq!(cmt.remove(&keys[12]), None);
+    }
+
+    #[test]
+    fn tampered_value_changes_root_and_fails_old_proof() {
+        let (mut cmt, keys) = build(40);
+        let root = root_hash_of(&cmt);
+        let k = keys[9];
+        let value = cmt.get(&k).unwrap().clone();
+        let proof = cmt.generate_proof(&k);
+
+        assert!(CartesianMerkleTree::verify_value(
+            proof.clone(),
+            k,
+            &value,
+            root
+        ));
+        assert!(!CartesianMerkleTree::verify_value(
+            proof.clone(),
+            k,
+            b"forged",
+            root
+        ));
+
+        // a prover swapping in another value can't keep the same root
+        let mut forged = proof.clone();
+        forged.value_hash = hash_value(b"forged");
+        assert!(!CartesianMerkleTree::verify_proof(forged, k, root));
+
+        cmt.insert(k, b"forged".to_vec());
+        let new_root = root_hash_of(&cmt);
+        assert_ne!(new_root, root);
+        assert!(!CartesianMerkleTree::verify_proof(proof, k, new_root));
+        let fresh = cmt.generate_proof(&k);
+        assert!(CartesianMerkleTree::verify_value(
+            fresh, k, b"forged", new_root
+        ));
+    }
+
+    #[test]
+    fn commitments_verify_without_the_value() {
+        let (cmt, keys) = build(40);
+        let root = root_hash_of(&cmt);
+        let k = keys[17];
+        let value = cmt.get(&k).unwrap().clone();
+        let commitment = hash_value(&value);
+        let proof = cmt.generate_proof(&k);
+
+        // commit-only: the verifier gets the value hash, not the value
+        assert!(CartesianMerkleTree::verify_commitment(
+            proof.clone(),
+            k,
+            &commitment,
+            root
+        ));
+        assert!(!CartesianMerkleTree::verify_commitment(
+            proof.clone(),
+            k,
+            &hash_value(b"other"),
+            root
+        ));
+        assert!(!CartesianMerkleTree::verify_commitment(
+            proof.clone(),
+            keys[18],
+            &commitment,
+            root
+        ));
+        // reveal: the same proof also checks the value itself
+        assert!(CartesianMerkleTree::verify_value(proof, k, &value, root));
+
+        // a non-existence witness's value hash commits nothing for the key
+        let absent = key(1000);
+        let proof = cmt.generate_proof(&absent);
+        assert!(!CartesianMerkleTree::verify_commitment(
+            proof.clone(),
+            absent,
+            &proof.value_hash,
+            root
+        ));
+    }
+
+    #[test]
+    fn leaf_and_internal_hashes_are_domain_separated() {
+        use sha2::{Digest, Sha256};
+        let framed = |tag: u8, fields: [&[u8]; 4]| {
+            let mut buf = vec![tag];
+            for f in fields {
+                buf.extend_from_slice(&(f.len() as u64).to_be_bytes());
+                buf.extend_from_slice(f);
+            }
+            Hash::from(Sha256::digest(&buf))
+        };
+
+        let (cmt, _) = build(40);
+        cmt.settle();
+        let mut stack: Vec<&TreeNode> = cmt.root.as_deref().into_iter().collect();
+        let (mut leaves, mut internals) = (0, 0);
+        while let Some(n) = stack.pop() {
+            let suffix = utils::proof_suffix(Some(n));
+            let fields = [
+                &n.key[..],
+                &n.value_hash,
+                hash_bytes(&suffix[0]),
+                hash_bytes(&suffix[1]),
+            ];
+            let is_leaf = n.left.is_none() && n.right.is_none();
+            let (tag, other) = if is_leaf {
+                leaves += 1;
+                (utils::LEAF_TAG, utils::INTERNAL_TAG)
+            } else {
+                internals += 1;
+                (utils::INTERNAL_TAG, utils::LEAF_TAG)
+            };
+            assert_eq!(*n.hash(), framed(tag, fields));
+            // the same preimage under the other tag is a different hash
+            assert_ne!(*n.hash(), framed(other, fields));
+            stack.extend(n.left.as_deref());
+            stack.extend(n.right.as_deref());
+        }
+        assert!(leaves > 0 && internals > 0);
+    }
+
+    #[test]
+    fn verify_proof_round_trips_membership_and_absence() {
+        let (cmt, keys) = build(80);
+        let root = root_hash_of(&cmt);
+
+        for k in &keys {
+            let proof = cmt.generate_proof(k);
+            assert!(proof.existence);
+            assert!(CartesianMerkleTree::verify_proof(proof, *k, root));
+        }
+        for i in 1000..1080 {
+            let absent = key(i);
+            let proof = cmt.generate_proof(&absent);
+            assert!(!proof.existence);
+            assert!(proof.nonexistence_key.is_some());
+            assert!(CartesianMerkleTree::verify_proof(proof, absent, root));
+        }
+
+        // a proof only verifies against the root it was generated from
+        let proof = cmt.generate_proof(&keys[0]);
+        assert!(!CartesianMerkleTree::verify_proof(
+            proof,
+            keys[0],
+            empty_hash()
+        ));
+        // an empty tree proves absence, and only against its own root
+        let empty = CartesianMerkleTree::new();
+        let proof = empty.generate_proof(&keys[0]);
+        assert!(!CartesianMerkleTree::verify_proof(
+            proof.clone(),
+            keys[0],
+            root
+        ));
+        assert!(CartesianMerkleTree::verify_proof(
+            proof, keys[0], EMPTY_ROOT
+        ));
+    }
+
+    #[test]
+    fn root_hash_tracks_the_root_node() {
+        let mut cmt = CartesianMerkleTree::new();
+        assert_eq!(cmt.root_hash(), None);
+
+        let k = key(1);
+        cmt.insert(k, vec![1]);
+        let root = cmt.root_hash().unwrap();
+        assert_eq!(root.len(), DIGEST_LEN);
+        assert!(CartesianMerkleTree::verify_proof(
+            cmt.generate_proof(&k),
+            k,
+            root
+        ));
+
+        cmt.remove(&k);
+        assert_eq!(cmt.root_hash(), None);
+    }
+
+    #[test]
+    fn inserts_that_split_keep_the_tree_consistent() {
+        // ascending key order, so most inserts land above existing nodes
+        let mut cmt = CartesianMerkleTree::new();
+        for i in 0..1000 {
+            cmt.insert(key(i), i.to_be_bytes().to_vec());
+        }
+        assert_integrity(&cmt);
+        assert_eq!(cmt.len(), 1000);
+        assert_eq!(utils::subtree_size(&cmt.root), 1000);
+        assert!(cmt.keys().copied().eq((0..1000).map(key)));
+
+        // same shape and hashes as a tree built without any split
+        let sorted: Vec<Key> = (0..1000).map(key).collect();
+        let mut reference = CartesianMerkleTree::new();
+        for k in &sorted {
+            let i = usize::from_be_bytes(k[..8].try_into().unwrap());
+            reference.insert(*k, i.to_be_bytes().to_vec());
+        }
+        assert_eq!(cmt.root_hash(), reference.root_hash());
+
+        // a caller-supplied priority above the key's current ancestors
+        // moves the key up instead of duplicating it
+        let top = cmt.root.as_ref().unwrap().priority;
+        cmt.insert_with_priority(key(500), vec![1], top + 1);
+        assert_integrity(&cmt);
+        assert_eq!(cmt.len(), 1000);
+        assert_eq!(cmt.root.as_ref().unwrap().key, key(500));
+        assert_eq!(cmt.get(&key(500)), Some(&vec![1]));
+    }
+
+    #[test]
+    fn nonexistence_proofs_pin_the_gap() {
+        let keys: Vec<Key> = (1..=50).map(|i| key(10 * i)).collect();
+        let mut cmt = CartesianMerkleTree::new();
+        for k in &keys {
+            cmt.insert(*k, k[..8].to_vec());
+        }
+        let root = cmt.root_hash().unwrap();
+
+        let below = key(5);
+        let above = key(1000);
+        let interior = key(255);
+        let cases = [below, above, interior];
+        for absent in cases {
+            let proof = cmt.generate_proof(&absent);
+            assert!(!proof.existence);
+            assert!(CartesianMerkleTree::verify_proof(
+                proof.clone(),
+                absent,
+                root
+            ));
+            assert!(CartesianMerkleTree::verify_strict(proof, absent, root));
+            assert!(cmt.generate_proof_borrowed(&absent).verify(&absent, &root));
+
+            // the same witness says nothing about keys outside its gap
+            for other in cases.iter().filter(|k| **k != absent) {
+                let proof = cmt.generate_proof(&absent);
+                assert!(!CartesianMerkleTree::verify_proof(
+                    proof.clone(),
+                    *other,
+                    root
+                ));
+                assert!(!CartesianMerkleTree::verify_strict(proof, *other, root));
+            }
+            // nor about members next to it
+            for member in [key(250), key(10), key(500)] {
+                let proof = cmt.generate_proof(&absent);
+                assert!(!CartesianMerkleTree::verify_proof(proof, member, root));
+            }
+        }
+
+        // every gap verifies, including witnesses with a single child
+        let mut one_child = 0;
+        for i in 0..=50 {
+            let absent = key(10 * i + 5);
+            let proof = cmt.generate_proof(&absent);
+            one_child += usize::from(proof.nonexistence_child.is_some());
+            assert!(CartesianMerkleTree::verify_strict(proof, absent, root));
+        }
+        assert!(one_child > 0);
+    }
+
+    #[test]
+    fn custom_hasher_is_used_throughout() {
+        // SHA-256 under a fixed prefix, standing in for e.g. Keccak-256
+        struct Prefixed;
+        impl Hasher for Prefixed {
+            const ALGO_ID: u8 = 0xf2;
+            fn hash(data: &[u8]) -> Hash {
+                use sha2::{Digest, Sha256};
+                Sha256::new()
+                    .chain_update(b"prefixed")
+                    .chain_update(data)
+                    .finalize()
+                    .into()
+            }
+        }
+
+        let keys: Vec<Key> = (0..100).map(key).collect();
+        let custom: CartesianMerkleTreeWith<Prefixed> =
+            keys.iter().map(|k| (*k, k[..8].to_vec())).collect();
+        let default: CartesianMerkleTree = keys.iter().map(|k| (*k, k[..8].to_vec())).collect();
+        let root = custom.root_hash().unwrap();
+        assert_ne!(Some(&root), default.root_hash().as_ref());
+
+        for k in keys.iter().chain([&key(1000)]) {
+            let proof = custom.generate_proof(k);
+            assert!(CartesianMerkleTreeWith::<Prefixed>::verify_strict(
+                proof.clone(),
+                *k,
+                root
+            ));
+            assert!(custom
+                .generate_proof_borrowed(k)
+                .verify_with::<Prefixed>(k, &root));
+            assert!(!CartesianMerkleTree::verify_proof(proof, *k, root));
+        }
+
+        let mut bytes = Vec::new();
+        custom.export_all_proofs(&mut bytes).unwrap();
+        let export = ProofExport::read_from(&bytes[..]).unwrap();
+        assert_eq!(export.root_hash_with::<Prefixed>(), root);
+
+        // priorities come from the hasher too, so the shapes differ
+        let top = |t: &[Key]| {
+            t.iter()
+                .max_by_key(|k| super::find_priority::<Prefixed>(k))
+                .copied()
+        };
+        assert_eq!(custom.root.as_ref().map(|n| n.key), top(&keys));
+    }
+
+    #[test]
+    fn clones_are_deep_and_independent() {
+        let (mut cmt, keys) = build(200);
+        // a mutation leaves hashes stale; the clone settles on its own
+        cmt.insert(keys[0], b"stale".to_vec());
+        let mut copy = cmt.clone();
+        assert_eq!(copy.root_hash(), cmt.root_hash());
+        assert_integrity(&copy);
+
+        let before = root_hash_of(&cmt);
+        copy.insert(key(1000), vec![1]);
+        copy.remove(&keys[1]);
+        assert_eq!(root_hash_of(&cmt), before);
+        assert_ne!(root_hash_of(&copy), before);
+        assert_eq!((cmt.len(), copy.len()), (200, 200));
+        assert!(cmt.get(&keys[1]).is_some() && copy.get(&keys[1]).is_none());
+    }
+
+    #[test]
+    fn debug_summarizes_and_debug_tree_dumps() {
+        assert_eq!(
+            format!("{:?}", CartesianMerkleTree::new()),
+            "CartesianMerkleTree { len: 0, root: None, height: 0 }"
+        );
+        let (cmt, keys) = build(500);
+        let root = hex::encode(&root_hash_of(&cmt)[..8]);
+        assert_eq!(
+            format!("{cmt:?}"),
+            format!(
+                "CartesianMerkleTree {{ len: 500, root: Some({root}..), height: {} }}",
+                cmt.height()
+            )
+        );
+
+        let dump = cmt.debug_tree();
+        assert_eq!(dump.lines().count(), keys.len());
+        let first = dump.lines().next().unwrap();
+        assert!(first.starts_with("root key "));
+        assert!(first.ends_with(" size 500"));
+        let deepest = dump.lines().map(|l| l.len() - l.trim_start().len()).max();
+        assert_eq!(deepest, Some(2 * (cmt.height() - 1)));
+        assert!(CartesianMerkleTree::new().debug_tree().is_empty());
+    }
+
+    #[test]
+    fn merge_matches_building_from_all_pairs() {
+        let entries = |range: core::ops::Range<usize>, tag: u8| -> Vec<(Key, Value)> {
+            range.map(|i| (key(i), vec![tag, i as u8])).collect()
+        };
+        let cases = [
+            (entries(0..300, 1), entries(300..500, 2)),
+            (entries(300..500, 1), entries(0..300, 2)),
+            (entries(0..400, 1), entries(200..600, 2)),
+            (entries(0..500, 1), entries(100..120, 2)),
+            (entries(0..50, 1), entries(0..600, 2)),
+            (entries(0..100, 1), Vec::new()),
+            (Vec::new(), entries(0..100, 2)),
+        ];
+        for (ours, theirs) in cases {
+            let mut merged: CartesianMerkleTree = ours.iter().cloned().collect();
+            merged.merge(theirs.iter().cloned().collect());
+            // later pairs win, as `theirs` does in the merge
+            let expected: CartesianMerkleTree = ours.into_iter().chain(theirs).collect();
+            assert_eq!(merged.len(), expected.len());
+            assert_eq!(merged.root_hash(), expected.root_hash());
+            assert_integrity(&merged);
+        }
+
+        // shapes built from other priorities are merged entry by entry
+        let (mut cmt, keys) = build(200);
+        let mut other = CartesianMerkleTree::with_priority_fn(|_| 0);
+        other.insert(keys[0], b"theirs".to_vec());
+        other.insert(key(1000), vec![]);
+        cmt.merge(other);
+        assert_eq!(cmt.len(), 201);
+        assert_eq!(cmt.get(&keys[0]), Some(&b"theirs".to_vec()));
+        assert_integrity(&cmt);
+    }
+
+    #[test]
+    fn split_at_then_merge_round_trips() {
+        let (cmt, keys) = build(300);
+        let root = cmt.root_hash();
+        for pivot in [keys[0], keys[1], keys[150], key(1000), [0u8; 32]] {
+            let (mut lower, upper) = cmt.clone().split_at(&pivot);
+            assert!(lower.keys().all(|k| *k < pivot));
+            assert!(upper.keys().all(|k| *k >= pivot));
+            assert_eq!(lower.len() + upper.len(), 300);
+            for half in [&lower, &upper] {
+                let expected: CartesianMerkleTree =
+                    half.iter().map(|(k, v)| (*k, v.clone())).collect();
+                assert_eq!(half.root_hash(), expected.root_hash());
+                assert_integrity(half);
+            }
+
+            lower.merge(upper);
+            assert_eq!(lower.root_hash(), root);
+        }
+    }
+
+    #[test]
+    fn pop_min_and_max_drain_in_order() {
+        let (mut cmt, keys) = build(100);
+        let mut expected = cmt.clone();
+        for k in &keys[..10] {
+            let (key, _) = cmt.pop_min().unwrap();
+            assert_eq!(key, *k);
+            expected.remove(k);
+            assert_eq!(cmt.root_hash(), expected.root_hash());
+        }
+        for k in keys[10..].iter().rev().take(10) {
+            assert_eq!(cmt.pop_max().map(|(key, _)| key), Some(*k));
+        }
+        assert_integrity(&cmt);
+
+        let mut drained = Vec::new();
+        while let Some((k, _)) = cmt.pop_min() {
+            drained.push(k);
+        }
+        assert_eq!(drained, keys[10..90]);
+        assert!(cmt.is_empty() && cmt.root_hash().is_none());
+        assert_eq!((cmt.pop_min(), cmt.pop_max()), (None, None));
+    }
+
+    #[test]
+    fn drain_empties_the_tree_in_order() {
+        let (mut cmt, _) = build(200);
+        let expected: std::collections::BTreeMap<Key, Value> =
+            cmt.iter().map(|(k, v)| (*k, v.clone())).collect();
+        assert!(cmt.drain().eq(expected.into_iter()));
+        assert!(cmt.is_empty() && cmt.root_hash().is_none());
+
+        let (mut cmt, keys) = build(200);
+        assert_eq!(cmt.drain().next().map(|(k, _)| k), Some(keys[0]));
+        assert!(cmt.is_empty() && cmt.iter().next().is_none());
+        cmt.insert(keys[5], vec![]);
+        assert_eq!(cmt.len(), 1);
+        assert_integrity(&cmt);
+    }
+
+    #[test]
+    fn stored_tree_matches_boxed_tree() {
+        let (cmt, keys) = build(300);
+        let mut stored = StoredCMT::default();
+        for (k, v) in cmt.iter() {
+            stored.insert(*k, v.clone()).unwrap();
+        }
+        // overwrites rewrite nodes in place
+        let mut cmt = cmt;
+        for k in keys.iter().step_by(7) {
+            stored.insert(*k, b"new".to_vec()).unwrap();
+            cmt.insert(*k, b"new".to_vec());
+        }
+        assert_eq!(stored.len(), 300);
+        assert_eq!(stored.store().len(), 300);
+        let root = cmt.root_hash().unwrap();
+        assert_eq!(stored.root_hash().unwrap(), Some(root));
+
+        for k in keys.iter().take(20).chain([&key(1000), &[0u8; 32]]) {
+            assert_eq!(stored.get(k).unwrap().as_ref(), cmt.get(k));
+            let proof = stored.generate_proof(k).unwrap();
+            assert_eq!(proof, cmt.generate_proof(k));
+            assert!(CartesianMerkleTree::verify_proof(proof, *k, root));
+        }
+
+        // the store alone is enough to pick the tree back up
+        let (id, len) = (stored.root_id(), stored.len());
+        let reopened = StoredCMT::open(stored.into_store(), id, len);
+        assert_eq!(reopened.root_hash().unwrap(), Some(root));
+        assert_eq!(StoredCMT::default().root_hash().unwrap(), None);
+    }
+
+    #[test]
+    fn stored_tree_removes_and_reuses_freed_nodes() {
+        let (mut cmt, keys) = build(300);
+        let mut stored = StoredCMT::default();
+        for (k, v) in cmt.iter() {
+            stored.insert(*k, v.clone()).unwrap();
+        }
+
+        for k in keys.iter().step_by(3) {
+            assert_eq!(stored.remove(k).unwrap(), cmt.remove(k));
+        }
+        assert_eq!(stored.remove(&key(1000)).unwrap(), None);
+        assert_eq!(stored.len(), 200);
+        assert_eq!(stored.store().len(), 200);
+        assert_eq!(stored.store().capacity(), 300);
+        let root = cmt.root_hash().unwrap();
+        assert_eq!(stored.root_hash().unwrap(), Some(root));
+        for k in keys.iter().take(10) {
+            assert_eq!(stored.generate_proof(k).unwrap(), cmt.generate_proof(k));
+        }
+
+        // new nodes fill the freed slots before the arena grows
+        for i in 300..400 {
+            stored.insert(key(i), vec![]).unwrap();
+            cmt.insert(key(i), vec![]);
+        }
+        assert_eq!(stored.store().capacity(), 300);
+        assert_eq!(stored.root_hash().unwrap(), cmt.root_hash());
+
+        for i in 0..400 {
+            assert_eq!(stored.remove(&key(i)).unwrap(), cmt.remove(&key(i)));
+        }
+        assert!(stored.is_empty() && stored.store().is_empty());
+        assert_eq!(stored.root_hash().unwrap(), None);
+    }
+
+    #[test]
+    fn stored_tree_reads_values_only_at_the_target() {
+        // counts the values read out of the arena
+        #[derive(Default)]
+        struct Counting {
+            inner: MemoryStore,
+            reads: core::cell::Cell<usize>,
+        }
+        impl NodeStore for Counting {
+            type Error = core::convert::Infallible;
+            fn get(&self, id: NodeId) -> Result<StoredNode, Self::Error> {
+                self.inner.get(id)
+            }
+            fn value(&self, id: NodeId) -> Result<Value, Self::Error> {
+                self.reads.set(self.reads.get() + 1);
+                self.inner.value(id)
+            }
+            fn put(&mut self, id: NodeId, node: StoredNode) -> Result<(), Self::Error> {
+                self.inner.put(id, node)
+            }
+            fn put_value(&mut self, id: NodeId, value: Value) -> Result<(), Self::Error> {
+                self.inner.put_value(id, value)
+            }
+            fn alloc(&mut self, node: StoredNode, value: Value) -> Result<NodeId, Self::Error> {
+                self.inner.alloc(node, value)
+            }
+        }
+
+        let (mut cmt, keys) = build(300);
+        let mut stored = StoredCMT::new(Counting::default());
+        for (k, v) in cmt.iter() {
+            stored.insert(*k, v.clone()).unwrap();
+        }
+        for k in keys.iter().step_by(5) {
+            stored.insert(*k, b"new".to_vec()).unwrap();
+            cmt.insert(*k, b"new".to_vec());
+        }
+        stored.generate_proof(&keys[0]).unwrap();
+        assert_eq!(stored.store().reads.get(), 0);
+
+        for k in &keys {
+            assert!(stored.get(k).unwrap().is_some());
+        }
+        assert_eq!(stored.get(&key(1000)).unwrap(), None);
+        assert_eq!(stored.store().reads.get(), keys.len());
+
+        for k in keys.iter().step_by(3) {
+            assert_eq!(stored.remove(k).unwrap(), cmt.remove(k));
+        }
+        assert_eq!(stored.store().reads.get(), keys.len() + 100);
+        assert_eq!(stored.root_hash().unwrap(), cmt.root_hash());
+    }
+
+    #[test]
+    fn insertion_order_does_not_change_the_root() {
+        // xorshift, so the shuffles are random but reproducible
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        let mut shuffled = |keys: &mut Vec<Key>| {
+            for i in (1..keys.len()).rev() {
+                keys.swap(i, (next() % (i as u64 + 1)) as usize);
+            }
+        };
+
+        let mut keys: Vec<Key> = (0..10_000).map(key).collect();
+        let sorted = CartesianMerkleTree::from_sorted(keys.iter().map(|k| (*k, vec![])).collect());
+        let root = sorted.root_hash();
+        for _ in 0..8 {
+            shuffled(&mut keys);
+            let cmt: CartesianMerkleTree = keys.iter().map(|k| (*k, vec![])).collect();
+            assert_eq!(cmt.root_hash(), root);
+        }
+
+        // a priority function with many ties, as truncation collisions
+        // would give, and removals mixed in
+        let coarse = |k: &Key| Priority::from(k[7] % 4);
+        let mut roots = std::collections::BTreeSet::new();
+        keys.truncate(2000);
+        for _ in 0..8 {
+            shuffled(&mut keys);
+            let mut cmt = CartesianMerkleTree::with_priority_fn(coarse);
+            for k in &keys {
+                cmt.insert(*k, vec![]);
+            }
+            for k in &keys {
+                if k[7] % 3 == 0 {
+                    cmt.remove(k);
+                }
+            }
+            assert!(cmt.validate().is_ok());
+            roots.insert(cmt.root_hash());
+        }
+        assert_eq!(roots.len(), 1);
+    }
+
+    #[test]
+    fn truncated_digests_can_hash() {
+        type Wide = TruncatedDigest<sha2::Sha512, 0xf3>;
+        let keys: Vec<Key> = (0..200).map(key).collect();
+        let pairs: Vec<(Key, Value)> = keys.iter().map(|k| (*k, k[..8].to_vec())).collect();
+        let sha: CartesianMerkleTreeWith<TruncatedDigest<sha2::Sha256, HASH_ALGO_SHA256>> =
+            pairs.iter().cloned().collect();
+        let default: CartesianMerkleTree = pairs.iter().cloned().collect();
+        assert_eq!(sha.root_hash(), default.root_hash());
+
+        let wide: CartesianMerkleTreeWith<Wide> = pairs.iter().cloned().collect();
+        {
+            use sha2::Digest;
+            let full = sha2::Sha512::digest(b"cmt");
+            assert_eq!(Wide::hash(b"cmt")[..], full[..DIGEST_LEN]);
+        }
+        let root = wide.root_hash().unwrap();
+        assert_ne!(Some(root), default.root_hash());
+        assert!(wide.validate().is_ok());
+        for k in &keys[..20] {
+            let proof = wide.generate_proof(k);
+            assert!(CartesianMerkleTreeWith::<Wide>::verify_proof(
+                proof.clone(),
+                *k,
+                root
+            ));
+            assert!(!CartesianMerkleTree::verify_proof(proof.clone(), *k, root));
+
+            // the header names the hasher, and decoding checks it
+            let bytes = proof.to_bytes_with::<Wide>();
+            assert_eq!(bytes[1], 0xf3);
+            assert_eq!(Proof::from_bytes_with::<Wide>(&bytes).as_ref(), Ok(&proof));
+            assert_eq!(
+                Proof::from_bytes(&bytes),
+                Err(ProofDecodeError::UnsupportedHashAlgorithm(0xf3))
+            );
+            assert_eq!(
+                Proof::from_bytes_with::<Wide>(&proof.to_bytes()),
+                Err(ProofDecodeError::UnsupportedHashAlgorithm(HASH_ALGO_SHA256))
+            );
+        }
+        let multi = wide.generate_multiproof(&keys[..5]);
+        let bytes = multi.to_bytes_with::<Wide>();
+        assert_eq!(MultiProof::from_bytes_with::<Wide>(&bytes), Ok(multi));
+        assert!(MultiProof::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn subtree_hash_matches_each_node() {
+        let (mut cmt, keys) = build(100);
+        cmt.insert(keys[3], b"stale".to_vec());
+        let root = cmt.root.as_ref().unwrap().key;
+        assert_eq!(cmt.subtree_hash(&root), cmt.root_hash());
+        for k in &keys {
+            assert_eq!(
+                cmt.subtree_hash(k),
+                Some(*find_node(&cmt, k).unwrap().hash())
+            );
+        }
+        assert_eq!(cmt.subtree_hash(&key(1000)), None);
+
+        // a leaf's subtree hash is its hash with two empty children
+        let leaf = keys
+            .iter()
+            .map(|k| find_node(&cmt, k).unwrap())
+            .find(|n| n.subtree_size == 1);
+        let leaf = leaf.unwrap();
+        assert_eq!(
+            cmt.subtree_hash(&leaf.key),
+            Some(calculate_merkle_hash(
+                &leaf.key,
+                &leaf.value_hash,
+                &empty_hash(),
+                &empty_hash()
+            ))
+        );
+    }
+
+    #[test]
+    fn equality_is_by_content() {
+        let (cmt, keys) = build(100);
+        let mut entries: Vec<(Key, Value)> = cmt.iter().map(|(k, v)| (*k, v.clone())).collect();
+        entries.reverse();
+        let reversed: CartesianMerkleTree = entries.into_iter().collect();
+        assert!(cmt == reversed);
+
+        // same entries, different shape
+        let mut flat = CartesianMerkleTree::new();
+        for (i, (k, v)) in cmt.iter().enumerate() {
+            flat.insert_with_priority(*k, v.clone(), i as Priority);
+        }
+        assert_ne!(flat.root_hash(), cmt.root_hash());
+        assert!(flat == cmt);
+
+        flat.insert(keys[0], vec![]);
+        assert!(flat != cmt);
+        flat.remove(&keys[0]);
+        assert!(flat != cmt);
+        assert!(CartesianMerkleTree::new() == CartesianMerkleTree::new());
+    }
 }
@@ -0,0 +1,109 @@
+//! module for multi-key membership proofs
+//!
+//! A `MultiProof` is the part of the tree covering the search paths of
+//! several keys: nodes on any of the paths are opened (key and value hash),
+//! and every subtree hanging off them is replaced by its hash. Paths share
+//! their top, so each node near the root is sent and hashed once instead of
+//! once per key.
+use crate::utils::calculate_merkle_hash_into;
+use crate::{empty_hash, CartesianMerkleTreeWith, Hash, Hasher, Key, TreeNode};
+use alloc::collections::BTreeSet;
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// One element of a `MultiProof`, in pre-order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum MultiProofItem {
+    /// An opened node; its two children follow as complete items.
+    Node { key: Key, value_hash: Hash },
+    /// A subtree given only by its hash (the empty hash for no child).
+    Subtree(Hash),
+}
+
+/// Membership proof for several keys of one tree, built by
+/// `generate_multiproof` and checked with `verify_multiproof`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct MultiProof {
+    /// The pruned tree in pre-order: each node, then its left and right
+    /// children.
+    pub items: Vec<MultiProofItem>,
+}
+
+impl MultiProof {
+    /// Value hash committed for `key`, if the proof opens it.
+    pub fn value_hash(&self, key: &Key) -> Option<&Hash> {
+        self.items.iter().find_map(|item| match item {
+            MultiProofItem::Node { key: k, value_hash } if k == key => Some(value_hash),
+            _ => None,
+        })
+    }
+}
+
+enum Pending<'a> {
+    Open(&'a TreeNode, &'a [Key]),
+    Hash(Hash),
+}
+
+/// A child is opened only if some requested key lies below it.
+fn child<'a>(node: Option<&'a TreeNode>, keys: &'a [Key]) -> Pending<'a> {
+    match node {
+        Some(n) if !keys.is_empty() => Pending::Open(n, keys),
+        n => Pending::Hash(n.map_or_else(empty_hash, |n| *n.hash())),
+    }
+}
+
+impl<H: Hasher> CartesianMerkleTreeWith<H> {
+    /// One proof covering every key in `keys`. Keys missing from the tree
+    /// still get their search path, but the proof won't verify for them.
+    pub fn generate_multiproof(&self, keys: &[Key]) -> MultiProof {
+        self.settle();
+        let mut keys = keys.to_vec();
+        keys.sort_unstable();
+        keys.dedup();
+
+        let mut items = Vec::new();
+        let mut stack = vec![child(self.root.as_deref(), &keys)];
+        while let Some(pending) = stack.pop() {
+            match pending {
+                Pending::Hash(h) => items.push(MultiProofItem::Subtree(h)),
+                Pending::Open(n, keys) => {
+                    items.push(MultiProofItem::Node {
+                        key: n.key,
+                        value_hash: n.value_hash,
+                    });
+                    let lower = keys.partition_point(|k| k < &n.key);
+                    let upper = keys.partition_point(|k| k <= &n.key);
+                    stack.push(child(n.right.as_deref(), &keys[upper..]));
+                    stack.push(child(n.left.as_deref(), &keys[..lower]));
+                }
+            }
+        }
+        MultiProof { items }
+    }
+
+    /// Rebuilds the root from the pruned tree in `proof` and checks it
+    /// against `root_hash`, and that every key in `keys` is opened in it.
+    pub fn verify_multiproof(proof: &MultiProof, keys: &[Key], root_hash: Hash) -> bool {
+        let buf = &mut Vec::new();
+        let mut hashes: Vec<Hash> = Vec::new();
+        let mut opened = BTreeSet::new();
+        // children come after their parent, so fold from the back
+        for item in proof.items.iter().rev() {
+            match item {
+                MultiProofItem::Subtree(h) => hashes.push(*h),
+                MultiProofItem::Node { key, value_hash } => {
+                    let (Some(left), Some(right)) = (hashes.pop(), hashes.pop()) else {
+                        return false;
+                    };
+                    hashes.push(calculate_merkle_hash_into::<H, _>(
+                        buf, key, value_hash, &left, &right,
+                    ));
+                    opened.insert(key);
+                }
+            }
+        }
+        hashes == [root_hash] && keys.iter().all(|k| opened.contains(k))
+    }
+}
@@ -0,0 +1,32 @@
+//! module for replaying operation streams, e.g. from a fuzzer
+//!
+//! A harness decodes a `Vec<Op>` (with the `arbitrary` feature it can derive
+//! one from raw fuzz input), applies each op to a tree and to a reference
+//! `BTreeMap`, and checks that the results agree and that `validate` passes.
+use crate::{CartesianMerkleTreeWith, Hasher, Key, Value};
+
+/// One tree operation, as `apply` runs it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum Op {
+    Insert(Key, Value),
+    Remove(Key),
+    Get(Key),
+}
+
+impl<H: Hasher> CartesianMerkleTreeWith<H> {
+    /// Runs `op` and returns what the same call on a `BTreeMap` returns:
+    /// the value replaced by an insert, the value removed, or a copy of the
+    /// value read.
+    pub fn apply(&mut self, op: Op) -> Option<Value> {
+        match op {
+            Op::Insert(key, value) => {
+                let old = self.get(&key).cloned();
+                self.insert(key, value);
+                old
+            }
+            Op::Remove(key) => self.remove(&key),
+            Op::Get(key) => self.get(&key).cloned(),
+        }
+    }
+}
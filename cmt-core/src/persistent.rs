@@ -0,0 +1,625 @@
+//! module for the persistent (immutable, versioned) CMT
+//!
+//! Every `insert` and `remove` on a `PersistentCMT` returns a new version
+//! and leaves the old one untouched. Nodes are immutable and reference
+//! counted, so a new version copies only the nodes on the paths the
+//! mutation changed (O(log n) of them) and shares every other subtree with
+//! its predecessor. Old versions stay fully usable: they can be read and
+//! can produce proofs against their own root.
+//!
+//! A `VersionHistory` records a sequence of versions by root hash and can
+//! prove that a later version is a consistent extension of an earlier one.
+//! Treaps have no append order, so "consistent" means the new version's
+//! key set is a superset of the old one's and no existing key maps to a
+//! different value; new keys may land anywhere.
+//!
+//! The node type and its path-copying operations are generic over the
+//! value, and also back `cmt-concurrent`'s `SnapshotTree`; they are public
+//! for that crate only and not part of the stable API.
+use crate::utils::{calculate_merkle_hash_into, hash_value};
+use crate::{
+    empty_hash, find_priority, outranks, CartesianMerkleTreeWith, Hash, Hasher, Key, Priority,
+    PriorityFn, Proof, Sha256Hasher, Value,
+};
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+use core::marker::PhantomData;
+
+/// Immutable node; its children may be shared with other versions.
+pub struct Node<V = Value> {
+    key: Key,
+    priority: Priority,
+    value: V,
+    value_hash: Hash,
+    hash: Hash,
+    pub(crate) left: Link<V>,
+    pub(crate) right: Link<V>,
+}
+
+pub type Link<V = Value> = Option<Arc<Node<V>>>;
+
+/// One version of a persistent tree hashing with `H`. Cloning a version is
+/// O(1) and shares all of its nodes.
+pub struct PersistentCMTWith<H: Hasher> {
+    pub(crate) root: Link,
+    len: usize,
+    priority_fn: PriorityFn,
+    hasher: PhantomData<fn() -> H>,
+}
+
+pub type PersistentCMT = PersistentCMTWith<Sha256Hasher>;
+
+impl<H: Hasher> Clone for PersistentCMTWith<H> {
+    fn clone(&self) -> Self {
+        Self {
+            root: self.root.clone(),
+            len: self.len,
+            priority_fn: self.priority_fn.clone(),
+            hasher: PhantomData,
+        }
+    }
+}
+
+impl<H: Hasher> Default for PersistentCMTWith<H> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Releases nodes with an explicit stack; the default recursive drop could
+/// overflow on a degenerate tree. Nodes still shared with another version
+/// are left to it.
+impl<H: Hasher> Drop for PersistentCMTWith<H> {
+    fn drop(&mut self) {
+        release(self.root.take());
+    }
+}
+
+impl<H: Hasher> PersistentCMTWith<H> {
+    /// Empty version deriving priorities from the first 16 bytes of `H(key)`.
+    pub fn new() -> Self {
+        Self::with_priority_fn(find_priority::<H>)
+    }
+
+    /// Empty version deriving priorities with `priority_fn`; later versions
+    /// inherit it.
+    pub fn with_priority_fn<F>(priority_fn: F) -> Self
+    where
+        F: Fn(&Key) -> Priority + Send + Sync + 'static,
+    {
+        Self {
+            root: None,
+            len: 0,
+            priority_fn: Arc::new(priority_fn),
+            hasher: PhantomData,
+        }
+    }
+
+    /// Root hash identifying this version; the empty hash for an empty tree.
+    /// Proofs from this version verify against it.
+    pub fn version(&self) -> Hash {
+        self.root.as_ref().map_or_else(empty_hash, |n| n.hash)
+    }
+
+    /// Hash of the root node, or `None` for an empty tree.
+    pub fn root_hash(&self) -> Option<Hash> {
+        self.root.as_ref().map(|n| n.hash)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn contains_key(&self, key: &Key) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub fn get(&self, key: &Key) -> Option<&Value> {
+        lookup(self.root.as_deref(), key).map(Node::value)
+    }
+
+    /// New version with `value` stored under `key`.
+    pub fn insert(&self, key: Key, value: Value) -> Self {
+        let priority = (self.priority_fn)(&key);
+        let (root, added) = insert::<_, H>(&self.root, key, value, priority);
+        self.with_root(root, self.len + added as usize)
+    }
+
+    /// New version without `key`; a version equal to this one if the key
+    /// is absent.
+    pub fn remove(&self, key: &Key) -> Self {
+        match remove::<_, H>(&self.root, key) {
+            Some((root, _)) => self.with_root(root, self.len - 1),
+            None => self.clone(),
+        }
+    }
+
+    fn with_root(&self, root: Link, len: usize) -> Self {
+        Self {
+            root,
+            len,
+            priority_fn: self.priority_fn.clone(),
+            hasher: PhantomData,
+        }
+    }
+
+    /// Membership or non-existence proof for `key` against `version()`;
+    /// the same construction as `CartesianMerkleTree::generate_proof`.
+    pub fn generate_proof(&self, key: &Key) -> Proof {
+        generate_proof(self.root.as_deref(), key)
+    }
+
+    /// Entries in key order, with their priorities.
+    fn entries(&self) -> Vec<(Key, Priority, Value)> {
+        let mut out = Vec::with_capacity(self.len);
+        let mut stack: Vec<&Node> = Vec::new();
+        let mut cur = self.root.as_deref();
+        loop {
+            while let Some(n) = cur {
+                stack.push(n);
+                cur = n.left.as_deref();
+            }
+            let Some(n) = stack.pop() else {
+                return out;
+            };
+            out.push((n.key, n.priority, n.value.clone()));
+            cur = n.right.as_deref();
+        }
+    }
+}
+
+/// Evidence that every entry committed by an old root is still present,
+/// with the same value, under a new root.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConsistencyProof {
+    /// Every entry of the old version in key order, with its priority, so
+    /// the verifier can rebuild the old tree and recompute its root.
+    pub entries: Vec<(Key, Priority, Value)>,
+    /// Membership proof of each entry against the new root, in the same
+    /// order as `entries`.
+    pub proofs: Vec<Proof>,
+}
+
+impl ConsistencyProof {
+    /// Checks that `new_root` consistently extends `old_root`.
+    pub fn verify(&self, old_root: &Hash, new_root: &Hash) -> bool {
+        self.verify_with::<Sha256Hasher>(old_root, new_root)
+    }
+
+    /// Same as `verify`, for trees built with hasher `H`.
+    pub fn verify_with<H: Hasher>(&self, old_root: &Hash, new_root: &Hash) -> bool {
+        if self.entries.len() != self.proofs.len()
+            || !self.entries.windows(2).all(|w| w[0].0 < w[1].0)
+        {
+            return false;
+        }
+
+        // the entries must be exactly what the old root commits to
+        let priorities: BTreeMap<Key, Priority> =
+            self.entries.iter().map(|(k, p, _)| (*k, *p)).collect();
+        let mut old = PersistentCMTWith::<H>::with_priority_fn(move |k| priorities[k]);
+        for (key, _, value) in &self.entries {
+            old = old.insert(*key, value.clone());
+        }
+        if old.version() != *old_root {
+            return false;
+        }
+
+        self.entries
+            .iter()
+            .zip(&self.proofs)
+            .all(|((key, _, value), proof)| {
+                CartesianMerkleTreeWith::<H>::verify_value(proof.clone(), *key, value, *new_root)
+            })
+    }
+}
+
+/// The versions a persistent tree went through, looked up by root hash.
+/// Versions share structure, so keeping them all costs O(log n) nodes per
+/// recorded mutation.
+pub struct VersionHistoryWith<H: Hasher> {
+    versions: Vec<PersistentCMTWith<H>>,
+    by_root: BTreeMap<Hash, usize>,
+}
+
+pub type VersionHistory = VersionHistoryWith<Sha256Hasher>;
+
+impl<H: Hasher> Default for VersionHistoryWith<H> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<H: Hasher> VersionHistoryWith<H> {
+    /// History starting from an empty tree.
+    pub fn new() -> Self {
+        Self::from_version(PersistentCMTWith::new())
+    }
+
+    /// History starting from `initial`.
+    pub fn from_version(initial: PersistentCMTWith<H>) -> Self {
+        let mut history = Self {
+            versions: Vec::new(),
+            by_root: BTreeMap::new(),
+        };
+        history.record(initial);
+        history
+    }
+
+    fn record(&mut self, version: PersistentCMTWith<H>) -> Hash {
+        let root = version.version();
+        // a root that comes back keeps its first index; the contents match
+        self.by_root.entry(root).or_insert(self.versions.len());
+        self.versions.push(version);
+        root
+    }
+
+    /// The most recent version.
+    pub fn latest(&self) -> &PersistentCMTWith<H> {
+        self.versions.last().expect("history is never empty")
+    }
+
+    /// The recorded version with root `root`, if any.
+    pub fn get(&self, root: &Hash) -> Option<&PersistentCMTWith<H>> {
+        self.by_root.get(root).map(|&i| &self.versions[i])
+    }
+
+    /// Number of recorded versions, including the initial one.
+    pub fn len(&self) -> usize {
+        self.versions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.versions.is_empty()
+    }
+
+    /// Records a new version with `value` stored under `key`; returns its
+    /// root.
+    pub fn insert(&mut self, key: Key, value: Value) -> Hash {
+        let next = self.latest().insert(key, value);
+        self.record(next)
+    }
+
+    /// Records a new version without `key`; returns its root.
+    pub fn remove(&mut self, key: &Key) -> Hash {
+        let next = self.latest().remove(key);
+        self.record(next)
+    }
+
+    /// Consistency proof from `old_root` to `new_root`, or `None` if either
+    /// root isn't in the history. A proof is produced for any two recorded
+    /// versions; it only verifies if the new one keeps every old entry.
+    pub fn consistency_proof(&self, old_root: Hash, new_root: Hash) -> Option<ConsistencyProof> {
+        let old = self.get(&old_root)?;
+        let new = self.get(&new_root)?;
+        let entries = old.entries();
+        let proofs = entries
+            .iter()
+            .map(|(key, _, _)| new.generate_proof(key))
+            .collect();
+        Some(ConsistencyProof { entries, proofs })
+    }
+}
+
+impl<V> Node<V> {
+    pub fn key(&self) -> &Key {
+        &self.key
+    }
+
+    pub fn value(&self) -> &V {
+        &self.value
+    }
+
+    /// Merkle hash of the subtree rooted here.
+    pub fn hash(&self) -> &Hash {
+        &self.hash
+    }
+
+    /// Hashes the node from its children and freezes it.
+    fn finish<H: Hasher>(mut self, buf: &mut Vec<u8>) -> Arc<Self> {
+        let hash = |c: &Link<V>| c.as_ref().map_or_else(empty_hash, |n| n.hash);
+        self.hash = calculate_merkle_hash_into::<H, _>(
+            buf,
+            &self.key,
+            &self.value_hash,
+            &hash(&self.left),
+            &hash(&self.right),
+        );
+        Arc::new(self)
+    }
+
+    /// Copy of the node with new children, sharing everything else.
+    fn with_children<H: Hasher>(
+        &self,
+        left: Link<V>,
+        right: Link<V>,
+        buf: &mut Vec<u8>,
+    ) -> Arc<Self>
+    where
+        V: Clone,
+    {
+        Node {
+            key: self.key,
+            priority: self.priority,
+            value: self.value.clone(),
+            value_hash: self.value_hash,
+            hash: empty_hash(),
+            left,
+            right,
+        }
+        .finish::<H>(buf)
+    }
+}
+
+/// Releases the nodes under `root` with an explicit stack; the default
+/// recursive drop could overflow on a degenerate tree. Nodes still shared
+/// with another version are left to it.
+pub fn release<V>(root: Link<V>) {
+    let mut stack: Vec<Arc<Node<V>>> = root.into_iter().collect();
+    while let Some(n) = stack.pop() {
+        if let Some(mut n) = Arc::into_inner(n) {
+            stack.extend(n.left.take());
+            stack.extend(n.right.take());
+        }
+    }
+}
+
+/// The node holding `key` under `cur`, if any.
+pub fn lookup<'a, V>(mut cur: Option<&'a Node<V>>, key: &Key) -> Option<&'a Node<V>> {
+    while let Some(n) = cur {
+        cur = match key.cmp(&n.key) {
+            Ordering::Equal => return Some(n),
+            Ordering::Less => n.left.as_deref(),
+            Ordering::Greater => n.right.as_deref(),
+        };
+    }
+    None
+}
+
+/// Root of a new version of `root` with `value` stored under `key`, and
+/// whether the key is new to it. Only the nodes on the changed paths are
+/// copied.
+pub fn insert<V: AsRef<[u8]> + Clone, H: Hasher>(
+    root: &Link<V>,
+    key: Key,
+    value: V,
+    priority: Priority,
+) -> (Link<V>, bool) {
+    let buf = &mut Vec::new();
+
+    // walk down to where the new node belongs, remembering the path
+    let mut path: Vec<(&Arc<Node<V>>, Ordering)> = Vec::new();
+    let mut cur = root.as_ref();
+    while let Some(n) = cur {
+        let dir = key.cmp(&n.key);
+        if outranks(priority, &key, n.priority, &n.key) || dir == Ordering::Equal {
+            break;
+        }
+        cur = match dir {
+            Ordering::Less => n.left.as_ref(),
+            _ => n.right.as_ref(),
+        };
+        path.push((n, dir));
+    }
+
+    let mut added = false;
+    let subtree = match cur {
+        // same key, and the new priority doesn't lift it: overwrite
+        Some(n) if n.key == key && priority <= n.priority => Node {
+            key,
+            priority: n.priority,
+            value_hash: hash_value::<H>(value.as_ref()),
+            value,
+            hash: empty_hash(),
+            left: n.left.clone(),
+            right: n.right.clone(),
+        }
+        .finish::<H>(buf),
+        rest => {
+            let mut replaced = false;
+            let (left, right) = split::<V, H>(rest, &key, &mut replaced, buf);
+            added = !replaced;
+            Node {
+                key,
+                priority,
+                value_hash: hash_value::<H>(value.as_ref()),
+                value,
+                hash: empty_hash(),
+                left,
+                right,
+            }
+            .finish::<H>(buf)
+        }
+    };
+    (rebuild_path::<V, H>(path, Some(subtree), buf), added)
+}
+
+/// Root of a new version of `root` without `key`, and the removed node, or
+/// `None` if the key is absent.
+pub fn remove<'a, V: Clone, H: Hasher>(
+    root: &'a Link<V>,
+    key: &Key,
+) -> Option<(Link<V>, &'a Node<V>)> {
+    let buf = &mut Vec::new();
+
+    let mut path: Vec<(&Arc<Node<V>>, Ordering)> = Vec::new();
+    let mut cur = root.as_ref();
+    let found = loop {
+        let n = cur?;
+        let dir = key.cmp(&n.key);
+        if dir == Ordering::Equal {
+            break n;
+        }
+        cur = match dir {
+            Ordering::Less => n.left.as_ref(),
+            _ => n.right.as_ref(),
+        };
+        path.push((n, dir));
+    };
+
+    let merged = merge::<V, H>(found.left.clone(), found.right.clone(), buf);
+    Some((rebuild_path::<V, H>(path, merged, buf), found))
+}
+
+/// Membership or non-existence proof for `key` against the tree under
+/// `root`; the same construction as `CartesianMerkleTree::generate_proof`.
+pub fn generate_proof<V>(root: Option<&Node<V>>, key: &Key) -> Proof {
+    let mut prefix: Vec<(Key, Hash, Hash)> = Vec::new();
+    let mut cur = root;
+    let mut last: Option<&Node<V>> = None;
+    let mut existence = false;
+
+    while let Some(n) = cur {
+        last = Some(n);
+        if &n.key == key {
+            existence = true;
+            break;
+        }
+        let (next, sibling) = if key < &n.key {
+            (n.left.as_deref(), n.right.as_deref())
+        } else {
+            (n.right.as_deref(), n.left.as_deref())
+        };
+        if next.is_none() {
+            // non-existence: the last traversed node is the witness
+            break;
+        }
+        // push (parent.e.k, parent.e.vh, sibling.mh)
+        prefix.push((
+            n.key,
+            n.value_hash,
+            sibling.map_or_else(empty_hash, |x| x.hash),
+        ));
+        cur = next;
+    }
+    // prefix is folded from the witness up to the root
+    prefix.reverse();
+
+    let suffix = last.map_or([empty_hash(), empty_hash()], sorted_children);
+    let non_ex_key = if existence { None } else { last.map(|n| n.key) };
+
+    // a witness with one child opens it, to show which side is empty
+    let child = match last {
+        Some(n) if !existence => match (n.left.as_deref(), n.right.as_deref()) {
+            (Some(c), None) | (None, Some(c)) => Some((c.key, c.value_hash, sorted_children(c))),
+            _ => None,
+        },
+        _ => None,
+    };
+
+    Proof {
+        prefix,
+        suffix,
+        value_hash: last.map_or_else(empty_hash, |n| n.value_hash),
+        existence,
+        nonexistence_key: non_ex_key,
+        nonexistence_child: child,
+    }
+}
+
+/// Child hashes of `node` in canonical order.
+fn sorted_children<V>(node: &Node<V>) -> [Hash; 2] {
+    let hash = |c: &Link<V>| c.as_ref().map_or_else(empty_hash, |x| x.hash);
+    let (left, right) = (hash(&node.left), hash(&node.right));
+    if left <= right {
+        [left, right]
+    } else {
+        [right, left]
+    }
+}
+
+/// Copies the nodes on `path` (root first) bottom-up, hanging `subtree`
+/// where the path ended.
+fn rebuild_path<V: Clone, H: Hasher>(
+    mut path: Vec<(&Arc<Node<V>>, Ordering)>,
+    mut subtree: Link<V>,
+    buf: &mut Vec<u8>,
+) -> Link<V> {
+    while let Some((n, dir)) = path.pop() {
+        subtree = Some(match dir {
+            Ordering::Less => n.with_children::<H>(subtree, n.right.clone(), buf),
+            _ => n.with_children::<H>(n.left.clone(), subtree, buf),
+        });
+    }
+    subtree
+}
+
+/// Splits `node` into the subtrees holding keys below and above `key`,
+/// copying only the nodes along the split path. A node already holding
+/// `key` is left out, and `found` is set.
+fn split<V: Clone, H: Hasher>(
+    node: Option<&Arc<Node<V>>>,
+    key: &Key,
+    found: &mut bool,
+    buf: &mut Vec<u8>,
+) -> (Link<V>, Link<V>) {
+    // nodes bound for the left result, which get a new right child, and for
+    // the right result, which get a new left child
+    let mut lower: Vec<&Arc<Node<V>>> = Vec::new();
+    let mut upper: Vec<&Arc<Node<V>>> = Vec::new();
+    let mut cur = node;
+    let (mut left, mut right) = (None, None);
+    while let Some(n) = cur {
+        match n.key.cmp(key) {
+            Ordering::Less => {
+                cur = n.right.as_ref();
+                lower.push(n);
+            }
+            Ordering::Greater => {
+                cur = n.left.as_ref();
+                upper.push(n);
+            }
+            Ordering::Equal => {
+                *found = true;
+                (left, right) = (n.left.clone(), n.right.clone());
+                break;
+            }
+        }
+    }
+
+    while let Some(n) = lower.pop() {
+        left = Some(n.with_children::<H>(n.left.clone(), left, buf));
+    }
+    while let Some(n) = upper.pop() {
+        right = Some(n.with_children::<H>(right, n.right.clone(), buf));
+    }
+    (left, right)
+}
+
+/// Joins two treaps where every key in `left` is below every key in
+/// `right`, copying only the nodes along the seam. The higher-priority root
+/// wins at each step, as when `heapify` sinks a removed node.
+fn merge<V: Clone, H: Hasher>(mut left: Link<V>, mut right: Link<V>, buf: &mut Vec<u8>) -> Link<V> {
+    // winners along the seam, each with the side that still needs merging
+    let mut seam: Vec<(Arc<Node<V>>, Ordering)> = Vec::new();
+    let mut merged = loop {
+        match (left, right) {
+            (Some(l), Some(r)) => {
+                if outranks(l.priority, &l.key, r.priority, &r.key) {
+                    left = l.right.clone();
+                    right = Some(r);
+                    seam.push((l, Ordering::Greater));
+                } else {
+                    right = r.left.clone();
+                    left = Some(l);
+                    seam.push((r, Ordering::Less));
+                }
+            }
+            (rest, None) | (None, rest) => break rest,
+        }
+    };
+
+    while let Some((n, side)) = seam.pop() {
+        merged = Some(match side {
+            Ordering::Less => n.with_children::<H>(merged, n.right.clone(), buf),
+            _ => n.with_children::<H>(n.left.clone(), merged, buf),
+        });
+    }
+    merged
+}
@@ -0,0 +1,321 @@
+//! module for proofs over a contiguous key range
+//!
+//! A `RangeProof` is a pruned tree, laid out like a `MultiProof`, that opens
+//! every node whose key interval meets `[start, end]`. That covers every
+//! entry in the range and the nodes just outside it, which act as boundary
+//! witnesses. Every other subtree is given only by its hash.
+//!
+//! Completeness rests on the key intervals of the hidden subtrees. The tree
+//! is a search tree, so a subtree hanging off a node with key `k` holds
+//! keys either all below or all above `k`, within the bounds its ancestors
+//! set. Node hashes store child hashes sorted, so the side isn't committed
+//! directly. The verifier reads it from the opened sibling's key instead.
+//! If no sibling is opened, a hidden subtree must clear the range on both
+//! sides. So when a node in the range has a single child, the prover opens
+//! that child too, as non-existence proofs do. A verified proof therefore
+//! hides no keys inside the range, and the entries it lists are all of them.
+//!
+//! A `SuccessorProof` is a range proof from a key to its successor, so it
+//! shows the key present or absent, the successor present, and nothing in
+//! between.
+use crate::multiproof::MultiProofItem;
+use crate::utils::{calculate_merkle_hash_into, hash_value};
+use crate::{
+    empty_hash, is_empty_subtree_hash, CartesianMerkleTreeWith, Hash, Hasher, Key, MultiProof,
+    TreeNode, Value,
+};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::{Bound, RangeBounds};
+
+/// Proof that `entries` are exactly the entries with keys in a range.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RangeProof {
+    /// Pruned tree covering the range and its boundaries.
+    pub tree: MultiProof,
+    /// Every entry in the range, in key order.
+    pub entries: Vec<(Key, Value)>,
+}
+
+/// Proof that `successor` is the smallest key above a queried key, built by
+/// `successor_proof` and checked with `verify_successor_proof`.
+///
+/// With no successor (the key is at or past the largest one), the range
+/// runs up to the largest possible key instead and must hold nothing but
+/// the queried key itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SuccessorProof {
+    /// Whether the queried key is itself in the tree.
+    pub present: bool,
+    /// The next key above the queried one, if any.
+    pub successor: Option<Key>,
+    /// Proof over `[key, successor]`, or `[key, MAX]` with no successor.
+    pub range: RangeProof,
+}
+
+impl SuccessorProof {
+    /// Value committed for the successor.
+    pub fn successor_value(&self) -> Option<&Value> {
+        let (key, value) = self.range.entries.last()?;
+        (Some(*key) == self.successor).then_some(value)
+    }
+}
+
+/// Largest possible key, closing the range when there is no successor.
+const MAX_KEY: Key = [0xff; 32];
+
+/// `range` as inclusive `[start, end]` bounds, inverted when it holds no
+/// key at all.
+fn inclusive<R: RangeBounds<Key>>(range: &R) -> (Key, Key) {
+    let start = match range.start_bound() {
+        Bound::Included(k) => Some(*k),
+        Bound::Excluded(k) => step(k, true),
+        Bound::Unbounded => Some([0; 32]),
+    };
+    let end = match range.end_bound() {
+        Bound::Included(k) => Some(*k),
+        Bound::Excluded(k) => step(k, false),
+        Bound::Unbounded => Some(MAX_KEY),
+    };
+    match (start, end) {
+        (Some(start), Some(end)) => (start, end),
+        _ => (MAX_KEY, [0; 32]),
+    }
+}
+
+/// The key just above (`up`) or below `key`, read as a big-endian
+/// integer, or `None` past either end.
+fn step(key: &Key, up: bool) -> Option<Key> {
+    let mut out = *key;
+    for b in out.iter_mut().rev() {
+        let (next, carry) = match up {
+            true => b.overflowing_add(1),
+            false => b.overflowing_sub(1),
+        };
+        *b = next;
+        if !carry {
+            return Some(out);
+        }
+    }
+    None
+}
+
+/// Exclusive key bounds of a subtree; `None` is unbounded.
+type Interval = (Option<Key>, Option<Key>);
+
+/// Whether some key strictly inside `interval` lies in `[start, end]`.
+fn meets((lo, hi): Interval, start: &Key, end: &Key) -> bool {
+    start <= end && lo.is_none_or(|lo| *end > lo) && hi.is_none_or(|hi| *start < hi)
+}
+
+impl<H: Hasher> CartesianMerkleTreeWith<H> {
+    /// Proof of every entry with a key in `[start, end]`, and that there are
+    /// no others. An inverted range (`start > end`) is empty, and its proof
+    /// is just the root hash.
+    pub fn range_proof(&self, start: &Key, end: &Key) -> RangeProof {
+        self.settle();
+        let mut items = Vec::new();
+        let mut entries = Vec::new();
+
+        let open_root = meets((None, None), start, end);
+        let mut stack: Vec<(Option<&TreeNode>, Interval, bool)> =
+            vec![(self.root.as_deref(), (None, None), open_root)];
+        while let Some((node, interval, open)) = stack.pop() {
+            let n = match node {
+                Some(n) if open => n,
+                n => {
+                    let hash = n.map_or_else(empty_hash, |n| *n.hash());
+                    items.push(MultiProofItem::Subtree(hash));
+                    continue;
+                }
+            };
+            items.push(MultiProofItem::Node {
+                key: n.key,
+                value_hash: n.value_hash,
+            });
+            if (start..=end).contains(&&n.key) {
+                entries.push((n.key, n.value.clone()));
+            }
+
+            let in_range = meets(interval, start, end);
+            let (lo, hi) = interval;
+            let left = (lo, Some(n.key));
+            let right = (Some(n.key), hi);
+            // an only child is opened so its side can be read off its key
+            let open_left = meets(left, start, end) || (in_range && n.right.is_none());
+            let open_right = meets(right, start, end) || (in_range && n.left.is_none());
+            stack.push((n.right.as_deref(), right, open_right));
+            stack.push((n.left.as_deref(), left, open_left));
+        }
+
+        entries.sort_unstable_by_key(|e| e.0);
+        RangeProof {
+            tree: MultiProof { items },
+            entries,
+        }
+    }
+
+    /// Proof that the smallest key above `key` is `successor`, or that there
+    /// is none, and of whether `key` itself is present; see
+    /// `SuccessorProof`.
+    pub fn successor_proof(&self, key: &Key) -> SuccessorProof {
+        let successor = self.successor(key).map(|(k, _)| *k);
+        let range = self.range_proof(key, &successor.unwrap_or(MAX_KEY));
+        SuccessorProof {
+            present: range.entries.first().is_some_and(|(k, _)| k == key),
+            successor,
+            range,
+        }
+    }
+
+    /// Checks a proof from `successor_proof` for `key` against `root_hash`:
+    /// the range from `key` to the claimed successor must verify and hold
+    /// just the successor, plus `key` itself if claimed present.
+    pub fn verify_successor_proof(proof: &SuccessorProof, key: &Key, root_hash: Hash) -> bool {
+        if proof.successor.is_some_and(|s| s <= *key) {
+            return false;
+        }
+        let end = proof.successor.unwrap_or(MAX_KEY);
+        let expected = proof
+            .present
+            .then_some(key)
+            .into_iter()
+            .chain(&proof.successor);
+        Self::verify_range_proof(&proof.range, key, &end, root_hash)
+            && proof.range.entries.iter().map(|(k, _)| k).eq(expected)
+    }
+
+    /// Checks that `proof.entries` are exactly the entries with keys in
+    /// `[start, end]` under `root_hash`: the pruned tree must fold to the
+    /// root, no hidden subtree may reach into the range, and the opened
+    /// nodes inside the range must match the entries and their values.
+    pub fn verify_range_proof(proof: &RangeProof, start: &Key, end: &Key, root_hash: Hash) -> bool {
+        Self::verify_range(&proof.tree, &proof.entries, start, end, root_hash)
+    }
+
+    /// Entries with keys in `range`, in key order, with the proof that they
+    /// are all of them; the verifiable `range`. Both come from one
+    /// traversal. The entries are moved out of the proof rather than sent
+    /// twice, so check the two together with `verify_range_with_proof`.
+    pub fn get_range_with_proof<R: RangeBounds<Key>>(
+        &self,
+        range: R,
+    ) -> (Vec<(Key, Value)>, RangeProof) {
+        let (start, end) = inclusive(&range);
+        let mut proof = self.range_proof(&start, &end);
+        (core::mem::take(&mut proof.entries), proof)
+    }
+
+    /// Checks that `entries` are exactly the entries with keys in `range`
+    /// under `root_hash`, against a proof from `get_range_with_proof`. Any
+    /// entries left in the proof itself are ignored.
+    pub fn verify_range_with_proof<R: RangeBounds<Key>>(
+        entries: &[(Key, Value)],
+        proof: &RangeProof,
+        range: R,
+        root_hash: Hash,
+    ) -> bool {
+        let (start, end) = inclusive(&range);
+        Self::verify_range(&proof.tree, entries, &start, &end, root_hash)
+    }
+
+    fn verify_range(
+        tree: &MultiProof,
+        entries: &[(Key, Value)],
+        start: &Key,
+        end: &Key,
+        root_hash: Hash,
+    ) -> bool {
+        let items = &tree.items;
+        if items.is_empty() {
+            return false;
+        }
+
+        // fold to the root, noting where each item's subtree ends
+        let buf = &mut Vec::new();
+        let mut ends = vec![0; items.len()];
+        let mut folded: Vec<(usize, Hash)> = Vec::new();
+        for (i, item) in items.iter().enumerate().rev() {
+            match item {
+                MultiProofItem::Subtree(h) => {
+                    ends[i] = i + 1;
+                    folded.push((i, *h));
+                }
+                MultiProofItem::Node { key, value_hash } => {
+                    let (Some((_, left)), Some((r, right))) = (folded.pop(), folded.pop()) else {
+                        return false;
+                    };
+                    ends[i] = ends[r];
+                    let hash =
+                        calculate_merkle_hash_into::<H, _>(buf, key, value_hash, &left, &right);
+                    folded.push((i, hash));
+                }
+            }
+        }
+        if folded != [(0, root_hash)] {
+            return false;
+        }
+
+        // walk down with each subtree's key interval
+        let mut found: Vec<(&Key, &Hash)> = Vec::new();
+        let mut stack: Vec<(usize, Interval)> = vec![(0, (None, None))];
+        while let Some((i, interval)) = stack.pop() {
+            let (key, value_hash) = match &items[i] {
+                MultiProofItem::Node { key, value_hash } => (key, value_hash),
+                MultiProofItem::Subtree(h) => {
+                    // only the root can get here; nothing else bounds it
+                    if !is_empty_subtree_hash(h) && meets(interval, start, end) {
+                        return false;
+                    }
+                    continue;
+                }
+            };
+            let (lo, hi) = interval;
+            if lo.is_some_and(|lo| *key <= lo) || hi.is_some_and(|hi| *key >= hi) {
+                return false;
+            }
+            if (start..=end).contains(&key) {
+                found.push((key, value_hash));
+            }
+
+            let left = (lo, Some(*key));
+            let right = (Some(*key), hi);
+            let (l, r) = (i + 1, ends[i + 1]);
+            // `Some(true)` when an opened child shows it is the left one
+            let side = |j: usize| match &items[j] {
+                MultiProofItem::Node { key: k, .. } => Some(k < key),
+                MultiProofItem::Subtree(_) => None,
+            };
+            let (l_side, r_side) = match (side(l), side(r)) {
+                (Some(a), Some(b)) if a == b => return false,
+                (a, b) => (a.or(b.map(|b| !b)), b.or(a.map(|a| !a))),
+            };
+
+            for (j, is_left) in [(l, l_side), (r, r_side)] {
+                match &items[j] {
+                    MultiProofItem::Node { .. } => {
+                        stack.push((j, if is_left == Some(true) { left } else { right }));
+                    }
+                    MultiProofItem::Subtree(h) if is_empty_subtree_hash(h) => {}
+                    MultiProofItem::Subtree(_) => {
+                        let hides_keys = match is_left {
+                            Some(true) => meets(left, start, end),
+                            Some(false) => meets(right, start, end),
+                            None => meets(left, start, end) || meets(right, start, end),
+                        };
+                        if hides_keys {
+                            return false;
+                        }
+                    }
+                }
+            }
+        }
+
+        found.sort_unstable();
+        found.len() == entries.len()
+            && found
+                .iter()
+                .zip(entries)
+                .all(|((k, vh), (key, value))| *k == key && **vh == hash_value::<H>(value))
+    }
+}
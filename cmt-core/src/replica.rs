@@ -0,0 +1,166 @@
+//! module for comparing replicated CMTs
+use crate::{CartesianMerkleTreeWith, Hasher, Key, TreeNode, Value};
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+
+/// Whether every tree has the same root hash.
+pub fn roots_agree<H: Hasher>(trees: &[&CartesianMerkleTreeWith<H>]) -> bool {
+    let mut roots = trees.iter().map(|t| t.root_hash());
+    match roots.next() {
+        Some(first) => roots.all(|r| r == first),
+        None => true,
+    }
+}
+
+enum Pending<'a> {
+    Subtree(&'a TreeNode),
+    Key(&'a TreeNode),
+}
+
+/// In-order walk that can hand out whole subtrees, so equal subtrees can be
+/// skipped without visiting their keys.
+struct Cursor<'a> {
+    stack: Vec<Pending<'a>>,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(root: Option<&'a TreeNode>) -> Self {
+        Self {
+            stack: root.map(Pending::Subtree).into_iter().collect(),
+        }
+    }
+
+    fn expand_top(&mut self) {
+        if let Some(Pending::Subtree(n)) = self.stack.pop() {
+            self.stack.extend(n.right.as_deref().map(Pending::Subtree));
+            self.stack.push(Pending::Key(n));
+            self.stack.extend(n.left.as_deref().map(Pending::Subtree));
+        }
+    }
+
+    fn next_key(&mut self) -> Option<Key> {
+        loop {
+            match self.stack.last()? {
+                Pending::Subtree(_) => self.expand_top(),
+                Pending::Key(n) => return Some(n.key),
+            }
+        }
+    }
+
+    /// Removes and returns the next node in key order.
+    fn next_node(&mut self) -> Option<&'a TreeNode> {
+        self.next_key()?;
+        match self.stack.pop() {
+            Some(Pending::Key(n)) => Some(n),
+            _ => unreachable!("next_key leaves a key on top"),
+        }
+    }
+}
+
+/// Entries that differ between two trees, as found by
+/// `CartesianMerkleTree::diff`. Each list is in key order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TreeDiff {
+    /// Entries only in the new tree.
+    pub added: Vec<(Key, Value)>,
+    /// Entries only in the old tree.
+    pub removed: Vec<(Key, Value)>,
+    /// Keys in both, with the old value and then the new one.
+    pub changed: Vec<(Key, Value, Value)>,
+}
+
+impl TreeDiff {
+    /// Whether the two trees hold the same entries.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+fn entry(n: &TreeNode) -> (Key, Value) {
+    (n.key, n.value.clone())
+}
+
+impl<H: Hasher> CartesianMerkleTreeWith<H> {
+    /// What changed going from this tree to `other`. Subtrees whose hashes
+    /// match at the same in-order position are skipped wholesale, as in
+    /// `first_divergence`, so two near-identical trees are diffed in
+    /// roughly O(d log n) for `d` differences rather than O(n).
+    pub fn diff(&self, other: &Self) -> TreeDiff {
+        self.settle();
+        other.settle();
+        let mut old = Cursor::new(self.root.as_deref());
+        let mut new = Cursor::new(other.root.as_deref());
+        let mut diff = TreeDiff::default();
+
+        loop {
+            match (old.stack.last(), new.stack.last()) {
+                (None, None) => return diff,
+                (Some(_), None) => diff.removed.extend(old.next_node().map(entry)),
+                (None, Some(_)) => diff.added.extend(new.next_node().map(entry)),
+                (Some(Pending::Subtree(x)), Some(Pending::Subtree(y))) => {
+                    if x.hash() == y.hash() {
+                        old.stack.pop();
+                        new.stack.pop();
+                    } else if x.subtree_size >= y.subtree_size {
+                        old.expand_top();
+                    } else {
+                        new.expand_top();
+                    }
+                }
+                (Some(Pending::Subtree(_)), Some(Pending::Key(_))) => old.expand_top(),
+                (Some(Pending::Key(_)), Some(Pending::Subtree(_))) => new.expand_top(),
+                (Some(Pending::Key(x)), Some(Pending::Key(y))) => match x.key.cmp(&y.key) {
+                    Ordering::Less => diff.removed.extend(old.next_node().map(entry)),
+                    Ordering::Greater => diff.added.extend(new.next_node().map(entry)),
+                    Ordering::Equal => {
+                        let (x, y) = (old.next_node().unwrap(), new.next_node().unwrap());
+                        if x.value_hash != y.value_hash {
+                            diff.changed.push((x.key, x.value.clone(), y.value.clone()));
+                        }
+                    }
+                },
+            }
+        }
+    }
+}
+
+/// Smallest key present in exactly one of the two trees, or `None` if they
+/// hold the same keys. Subtrees whose hashes match at the same in-order
+/// position are skipped wholesale, so near-identical trees are compared in
+/// roughly O(d log n) for `d` differences.
+pub fn first_divergence<H: Hasher>(
+    a: &CartesianMerkleTreeWith<H>,
+    b: &CartesianMerkleTreeWith<H>,
+) -> Option<Key> {
+    a.settle();
+    b.settle();
+    let mut a = Cursor::new(a.root.as_deref());
+    let mut b = Cursor::new(b.root.as_deref());
+
+    loop {
+        match (a.stack.last(), b.stack.last()) {
+            (None, None) => return None,
+            (Some(_), None) => return a.next_key(),
+            (None, Some(_)) => return b.next_key(),
+            (Some(Pending::Subtree(x)), Some(Pending::Subtree(y))) => {
+                if x.hash() == y.hash() {
+                    a.stack.pop();
+                    b.stack.pop();
+                } else if x.subtree_size >= y.subtree_size {
+                    a.expand_top();
+                } else {
+                    b.expand_top();
+                }
+            }
+            (Some(Pending::Subtree(_)), Some(Pending::Key(_))) => a.expand_top(),
+            (Some(Pending::Key(_)), Some(Pending::Subtree(_))) => b.expand_top(),
+            (Some(Pending::Key(x)), Some(Pending::Key(y))) => {
+                if x.key != y.key {
+                    return Some(x.key.min(y.key));
+                }
+                a.stack.pop();
+                b.stack.pop();
+            }
+        }
+    }
+}
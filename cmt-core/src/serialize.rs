@@ -0,0 +1,192 @@
+//! module for serde support, behind the `serde` feature
+//!
+//! The tree serializes as a flat sequence of node records in pre-order, the
+//! order `write_to` streams it in. Each record carries a node's key,
+//! priority, value, value hash and hash, and which of its children follow,
+//! so loading it back needs no re-insertion. Records don't nest, so neither
+//! side recurses however deep the tree is. Subtree sizes are not stored;
+//! they are rebuilt on load.
+use crate::{
+    empty_hash, utils, CartesianMerkleTreeWith, Hash, HashCell, Hasher, Key, Priority, TreeNode,
+    Value,
+};
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::vec::Vec;
+use core::fmt;
+use core::marker::PhantomData;
+use serde::de::{Error, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+const HAS_LEFT: u8 = 1;
+const HAS_RIGHT: u8 = 2;
+
+/// One node as it is serialized.
+#[derive(Serialize, Deserialize)]
+struct Record<V> {
+    key: Key,
+    priority: Priority,
+    value: V,
+    value_hash: Hash,
+    hash: Hash,
+    /// Bit 0 set when a left subtree follows, bit 1 for a right one.
+    children: u8,
+}
+
+/// The tree's nodes in pre-order, walked with an explicit stack.
+struct PreOrder<'a> {
+    stack: Vec<&'a TreeNode>,
+    remaining: usize,
+}
+
+impl<'a> Iterator for PreOrder<'a> {
+    type Item = Record<&'a Value>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let n = self.stack.pop()?;
+        self.remaining -= 1;
+        self.stack.extend(n.right.as_deref());
+        self.stack.extend(n.left.as_deref());
+        let mut children = 0;
+        if n.left.is_some() {
+            children |= HAS_LEFT;
+        }
+        if n.right.is_some() {
+            children |= HAS_RIGHT;
+        }
+        Some(Record {
+            key: n.key,
+            priority: n.priority,
+            value: &n.value,
+            value_hash: n.value_hash,
+            hash: *n.hash(),
+            children,
+        })
+    }
+
+    // exact, so formats that need a sequence's length up front can write it
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<H: Hasher> Serialize for CartesianMerkleTreeWith<H> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.settle();
+        serializer.collect_seq(PreOrder {
+            stack: self.root.as_deref().into_iter().collect(),
+            remaining: self.size,
+        })
+    }
+}
+
+/// Deserializes with `verify_on_load` set, so a tampered or corrupted
+/// encoding is rejected instead of yielding a tree whose root hash lies.
+impl<'de, H: Hasher> Deserialize<'de> for CartesianMerkleTreeWith<H> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Self::deserialize_tree(deserializer, true)
+    }
+}
+
+impl<H: Hasher> CartesianMerkleTreeWith<H> {
+    /// Loads a tree written by its `Serialize` impl. With `verify_on_load`
+    /// every value hash and node hash is recomputed and compared with the
+    /// stored one; without it the stored hashes are trusted, which skips
+    /// all hashing. The loaded tree uses the default priority function.
+    pub fn deserialize_tree<'de, D: Deserializer<'de>>(
+        deserializer: D,
+        verify_on_load: bool,
+    ) -> Result<Self, D::Error> {
+        deserializer.deserialize_seq(Records {
+            verify: verify_on_load,
+            hasher: PhantomData,
+        })
+    }
+}
+
+/// Rebuilds a tree from its records as they arrive.
+struct Records<H> {
+    verify: bool,
+    hasher: PhantomData<fn() -> H>,
+}
+
+impl<'de, H: Hasher> Visitor<'de> for Records<H> {
+    type Value = CartesianMerkleTreeWith<H>;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a sequence of tree node records")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut tree = CartesianMerkleTreeWith::new();
+        // the path from the root to the record read last, minus finished
+        // nodes, each with the children it still waits for; nodes tear
+        // their subtrees down iteratively, so bailing out with a deep
+        // partial tree is safe
+        let mut stack: Vec<(Box<TreeNode>, u8)> = Vec::new();
+        let mut buf = Vec::new();
+        while let Some(record) = seq.next_element::<Record<Value>>()? {
+            if tree.root.is_some() {
+                return Err(A::Error::custom("record after the end of the tree"));
+            }
+            if record.children & !(HAS_LEFT | HAS_RIGHT) != 0 {
+                return Err(A::Error::custom("invalid children flags"));
+            }
+            let node = Box::new(TreeNode {
+                key: record.key,
+                priority: record.priority,
+                value: record.value,
+                value_hash: record.value_hash,
+                hash: HashCell::from(record.hash),
+                subtree_size: 1,
+                left: None,
+                right: None,
+            });
+            stack.push((node, record.children));
+
+            // hand every finished node to its parent; its children are all
+            // in, so this is where its hashes can be checked
+            while let Some((_, 0)) = stack.last() {
+                let (mut done, _) = stack.pop().expect("just looked");
+                done.subtree_size =
+                    1 + utils::subtree_size(&done.left) + utils::subtree_size(&done.right);
+                if self.verify && !hashes_match::<H>(&done, &mut buf) {
+                    return Err(A::Error::custom(format!(
+                        "hash mismatch at key {}",
+                        hex::encode(done.key)
+                    )));
+                }
+                match stack.last_mut() {
+                    Some((parent, pending)) if *pending & HAS_LEFT != 0 => {
+                        parent.left = Some(done);
+                        *pending &= !HAS_LEFT;
+                    }
+                    Some((parent, pending)) => {
+                        parent.right = Some(done);
+                        *pending &= !HAS_RIGHT;
+                    }
+                    None => tree.root = Some(done),
+                }
+            }
+        }
+        if !stack.is_empty() {
+            return Err(A::Error::custom("records ended inside the tree"));
+        }
+        tree.size = utils::subtree_size(&tree.root);
+        Ok(tree)
+    }
+}
+
+/// Whether `node`'s stored value hash and node hash match a recompute from
+/// its value and its children's stored hashes.
+fn hashes_match<H: Hasher>(node: &TreeNode, buf: &mut Vec<u8>) -> bool {
+    let child_hash = |c: &Option<Box<TreeNode>>| c.as_ref().map_or_else(empty_hash, |n| *n.hash());
+    let expected = utils::calculate_merkle_hash_into::<H, _>(
+        buf,
+        &node.key,
+        &node.value_hash,
+        &child_hash(&node.left),
+        &child_hash(&node.right),
+    );
+    node.value_hash == utils::hash_value::<H>(&node.value) && *node.hash() == expected
+}
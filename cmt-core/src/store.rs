@@ -0,0 +1,504 @@
+//! module for CMTs whose nodes live in a pluggable `NodeStore`
+//!
+//! `CartesianMerkleTree` owns its nodes as boxes, so the whole tree has to
+//! fit in memory. A `StoredCMT` instead refers to children by `NodeId` and
+//! reads and writes nodes through a `NodeStore`, so the nodes can live in
+//! an arena, a file, an mmap'd region or a key-value database. Hashes are
+//! kept up to date on every write, so a store always holds a consistent
+//! tree that can be reopened from its root id.
+//!
+//! A node's value is stored apart from its links and hashes: walks read
+//! only the `StoredNode`, and the value is loaded once the walk reaches
+//! its target, so a lookup doesn't copy every value on the path.
+//!
+//! `MemoryStore` is the in-memory arena: nodes in a `Vec`, ids as indices.
+//! The stored tree builds the same shape and hashes as a
+//! `CartesianMerkleTree` over the same entries, and its proofs verify with
+//! `CartesianMerkleTree::verify_proof`. It supports inserts, removals and
+//! lookups. A removal hands the unlinked node's id back through
+//! `NodeStore::free`; `MemoryStore` reuses freed ids for later inserts, and
+//! a store is free to ignore the hook and let the slot leak.
+//!
+//! `CartesianMerkleTree` itself still owns boxed nodes. Moving it onto a
+//! `NodeStore`, so that the main tree can live outside memory too, is left
+//! as follow-up work; until then the two share hashing and proofs but not
+//! storage.
+use crate::utils::{calculate_merkle_hash_into, hash_value};
+use crate::{
+    empty_hash, find_priority, outranks, Hash, Hasher, Key, Priority, PriorityFn, Proof,
+    Sha256Hasher, Value,
+};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+use core::convert::Infallible;
+use core::marker::PhantomData;
+
+/// Handle to a node in a `NodeStore`.
+pub type NodeId = u32;
+
+/// A node as a `NodeStore` holds it, with children by id. The value is
+/// kept apart and read with `NodeStore::value`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StoredNode {
+    pub key: Key,
+    pub priority: Priority,
+    pub value_hash: Hash,
+    /// Merkle hash of the subtree rooted here, always current.
+    pub hash: Hash,
+    pub left: Option<NodeId>,
+    pub right: Option<NodeId>,
+}
+
+/// Backing storage for the nodes of a `StoredCMT`.
+pub trait NodeStore {
+    /// I/O or decoding failure; `Infallible` for in-memory stores.
+    type Error;
+
+    /// Reads the node stored under `id`, without its value.
+    fn get(&self, id: NodeId) -> Result<StoredNode, Self::Error>;
+    /// Reads the value of the node stored under `id`.
+    fn value(&self, id: NodeId) -> Result<Value, Self::Error>;
+    /// Overwrites the node stored under `id`, keeping its value.
+    fn put(&mut self, id: NodeId, node: StoredNode) -> Result<(), Self::Error>;
+    /// Overwrites the value of the node stored under `id`.
+    fn put_value(&mut self, id: NodeId, value: Value) -> Result<(), Self::Error>;
+    /// Stores a new node with `value` and returns its id.
+    fn alloc(&mut self, node: StoredNode, value: Value) -> Result<NodeId, Self::Error>;
+    /// Called once the tree no longer refers to `id`, so the store can
+    /// reclaim it or hand it out again from `alloc`. The default keeps the
+    /// node, for stores that never reclaim space.
+    fn free(&mut self, id: NodeId) -> Result<(), Self::Error> {
+        let _ = id;
+        Ok(())
+    }
+}
+
+/// In-memory arena store: nodes in a `Vec`, ids are indices, values in a
+/// parallel `Vec`. Freed ids are kept on a list and reused by the next
+/// allocations.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStore {
+    nodes: Vec<StoredNode>,
+    values: Vec<Value>,
+    free: Vec<NodeId>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of live nodes, not counting freed slots.
+    pub fn len(&self) -> usize {
+        self.nodes.len() - self.free.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Slots the arena has grown to, live or freed.
+    pub fn capacity(&self) -> usize {
+        self.nodes.len()
+    }
+}
+
+impl NodeStore for MemoryStore {
+    type Error = Infallible;
+
+    /// # Panics
+    ///
+    /// Panics if `id` was never allocated.
+    fn get(&self, id: NodeId) -> Result<StoredNode, Infallible> {
+        Ok(self.nodes[id as usize])
+    }
+
+    /// # Panics
+    ///
+    /// Panics if `id` was never allocated.
+    fn value(&self, id: NodeId) -> Result<Value, Infallible> {
+        Ok(self.values[id as usize].clone())
+    }
+
+    /// # Panics
+    ///
+    /// Panics if `id` was never allocated.
+    fn put(&mut self, id: NodeId, node: StoredNode) -> Result<(), Infallible> {
+        self.nodes[id as usize] = node;
+        Ok(())
+    }
+
+    /// # Panics
+    ///
+    /// Panics if `id` was never allocated.
+    fn put_value(&mut self, id: NodeId, value: Value) -> Result<(), Infallible> {
+        self.values[id as usize] = value;
+        Ok(())
+    }
+
+    /// # Panics
+    ///
+    /// Panics once the arena holds `u32::MAX` nodes.
+    fn alloc(&mut self, node: StoredNode, value: Value) -> Result<NodeId, Infallible> {
+        if let Some(id) = self.free.pop() {
+            self.nodes[id as usize] = node;
+            self.values[id as usize] = value;
+            return Ok(id);
+        }
+        let id = NodeId::try_from(self.nodes.len()).expect("arena is out of node ids");
+        self.nodes.push(node);
+        self.values.push(value);
+        Ok(id)
+    }
+
+    /// Clears the slot's value and queues `id` for reuse.
+    fn free(&mut self, id: NodeId) -> Result<(), Infallible> {
+        self.values[id as usize] = Value::new();
+        self.free.push(id);
+        Ok(())
+    }
+}
+
+/// A Cartesian Merkle Tree hashing with `H` whose nodes live in `S`.
+pub struct StoredCMTWith<S: NodeStore, H: Hasher> {
+    store: S,
+    root: Option<NodeId>,
+    len: usize,
+    priority_fn: PriorityFn,
+    hasher: PhantomData<fn() -> H>,
+}
+
+pub type StoredCMT<S = MemoryStore> = StoredCMTWith<S, Sha256Hasher>;
+
+impl<H: Hasher> Default for StoredCMTWith<MemoryStore, H> {
+    fn default() -> Self {
+        Self::new(MemoryStore::new())
+    }
+}
+
+impl<S: NodeStore, H: Hasher> StoredCMTWith<S, H> {
+    /// Empty tree writing its nodes to `store`, deriving priorities from
+    /// the first 16 bytes of `H(key)`.
+    pub fn new(store: S) -> Self {
+        Self::open(store, None, 0)
+    }
+
+    /// Tree already held in `store`, rooted at `root` with `len` entries,
+    /// e.g. as saved from `root_id` and `len` before a restart.
+    pub fn open(store: S, root: Option<NodeId>, len: usize) -> Self {
+        Self {
+            store,
+            root,
+            len,
+            priority_fn: Arc::new(find_priority::<H>),
+            hasher: PhantomData,
+        }
+    }
+
+    /// Id of the root node, for reopening the tree later.
+    pub fn root_id(&self) -> Option<NodeId> {
+        self.root
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    pub fn into_store(self) -> S {
+        self.store
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Hash of the root node, or `None` for an empty tree.
+    pub fn root_hash(&self) -> Result<Option<Hash>, S::Error> {
+        self.root.map(|id| Ok(self.store.get(id)?.hash)).transpose()
+    }
+
+    pub fn get(&self, key: &Key) -> Result<Option<Value>, S::Error> {
+        let mut cur = self.root;
+        while let Some(id) = cur {
+            let n = self.store.get(id)?;
+            cur = match key.cmp(&n.key) {
+                Ordering::Equal => return self.store.value(id).map(Some),
+                Ordering::Less => n.left,
+                Ordering::Greater => n.right,
+            };
+        }
+        Ok(None)
+    }
+
+    /// Stores `value` under `key`, rewriting the nodes on the search path
+    /// and, when the new node lands above existing ones, the split path
+    /// below it.
+    pub fn insert(&mut self, key: Key, value: Value) -> Result<(), S::Error> {
+        let priority = (self.priority_fn)(&key);
+        let buf = &mut Vec::new();
+
+        // walk down to where the new node belongs, remembering the path
+        let mut path: Vec<(NodeId, StoredNode, Ordering)> = Vec::new();
+        let mut cur = self.root;
+        let mut stop = None;
+        while let Some(id) = cur {
+            let n = self.store.get(id)?;
+            let dir = key.cmp(&n.key);
+            if outranks(priority, &key, n.priority, &n.key) || dir == Ordering::Equal {
+                stop = Some((id, n));
+                break;
+            }
+            cur = match dir {
+                Ordering::Less => n.left,
+                _ => n.right,
+            };
+            path.push((id, n, dir));
+        }
+
+        let subtree = match stop {
+            // same key, and the new priority doesn't lift it: overwrite
+            Some((id, mut n)) if n.key == key && priority <= n.priority => {
+                n.value_hash = hash_value::<H>(&value);
+                self.store.put_value(id, value)?;
+                self.write(id, n, buf)?;
+                id
+            }
+            rest => {
+                let mut replaced = false;
+                let (left, right) = self.split(rest.map(|(id, _)| id), &key, &mut replaced, buf)?;
+                if !replaced {
+                    self.len += 1;
+                }
+                let mut n = StoredNode {
+                    key,
+                    priority,
+                    value_hash: hash_value::<H>(&value),
+                    hash: empty_hash(),
+                    left,
+                    right,
+                };
+                n.hash = self.node_hash(&n, buf)?;
+                self.store.alloc(n, value)?
+            }
+        };
+
+        self.root = self.reattach(path, Some(subtree), buf)?;
+        Ok(())
+    }
+
+    /// Removes `key`, returning its value if it was present. The node's
+    /// children are joined in its place and its id is handed to
+    /// `NodeStore::free`.
+    pub fn remove(&mut self, key: &Key) -> Result<Option<Value>, S::Error> {
+        let buf = &mut Vec::new();
+        let mut path: Vec<(NodeId, StoredNode, Ordering)> = Vec::new();
+        let mut cur = self.root;
+        let (id, n) = loop {
+            let Some(id) = cur else {
+                return Ok(None);
+            };
+            let n = self.store.get(id)?;
+            let dir = key.cmp(&n.key);
+            if dir == Ordering::Equal {
+                break (id, n);
+            }
+            cur = match dir {
+                Ordering::Less => n.left,
+                _ => n.right,
+            };
+            path.push((id, n, dir));
+        };
+
+        let value = self.store.value(id)?;
+        let subtree = self.join(n.left, n.right, buf)?;
+        self.store.free(id)?;
+        self.len -= 1;
+        self.root = self.reattach(path, subtree, buf)?;
+        Ok(Some(value))
+    }
+
+    /// Joins two subtrees where every key in `lower` is below every key in
+    /// `upper`, lifting the higher-ranked top at each step down their
+    /// facing spines, and rewrites the nodes along that walk.
+    fn join(
+        &mut self,
+        mut lower: Option<NodeId>,
+        mut upper: Option<NodeId>,
+        buf: &mut Vec<u8>,
+    ) -> Result<Option<NodeId>, S::Error> {
+        let mut path: Vec<(NodeId, StoredNode, Ordering)> = Vec::new();
+        let subtree = loop {
+            let (l, u) = match (lower, upper) {
+                (Some(l), Some(u)) => (l, u),
+                (rest, None) | (None, rest) => break rest,
+            };
+            let (ln, un) = (self.store.get(l)?, self.store.get(u)?);
+            if outranks(ln.priority, &ln.key, un.priority, &un.key) {
+                lower = ln.right;
+                path.push((l, ln, Ordering::Greater));
+            } else {
+                upper = un.left;
+                path.push((u, un, Ordering::Less));
+            }
+        };
+        self.reattach(path, subtree, buf)
+    }
+
+    /// Hangs `subtree` back under the nodes of `path`, deepest first,
+    /// rewriting each with its new child.
+    fn reattach(
+        &mut self,
+        mut path: Vec<(NodeId, StoredNode, Ordering)>,
+        mut subtree: Option<NodeId>,
+        buf: &mut Vec<u8>,
+    ) -> Result<Option<NodeId>, S::Error> {
+        while let Some((id, mut n, dir)) = path.pop() {
+            match dir {
+                Ordering::Less => n.left = subtree,
+                _ => n.right = subtree,
+            }
+            self.write(id, n, buf)?;
+            subtree = Some(id);
+        }
+        Ok(subtree)
+    }
+
+    /// Splits the subtree at `node` into the subtrees holding keys below
+    /// and above `key`, rewriting the nodes along the split path. A node
+    /// already holding `key` is left out and freed, and `found` is set.
+    fn split(
+        &mut self,
+        node: Option<NodeId>,
+        key: &Key,
+        found: &mut bool,
+        buf: &mut Vec<u8>,
+    ) -> Result<(Option<NodeId>, Option<NodeId>), S::Error> {
+        // nodes bound for the left result, which get a new right child, and
+        // for the right result, which get a new left child
+        let mut lower: Vec<(NodeId, StoredNode)> = Vec::new();
+        let mut upper: Vec<(NodeId, StoredNode)> = Vec::new();
+        let mut cur = node;
+        let (mut left, mut right) = (None, None);
+        while let Some(id) = cur {
+            let n = self.store.get(id)?;
+            match n.key.cmp(key) {
+                Ordering::Less => {
+                    cur = n.right;
+                    lower.push((id, n));
+                }
+                Ordering::Greater => {
+                    cur = n.left;
+                    upper.push((id, n));
+                }
+                Ordering::Equal => {
+                    *found = true;
+                    (left, right) = (n.left, n.right);
+                    self.store.free(id)?;
+                    break;
+                }
+            }
+        }
+
+        while let Some((id, mut n)) = lower.pop() {
+            n.right = left;
+            self.write(id, n, buf)?;
+            left = Some(id);
+        }
+        while let Some((id, mut n)) = upper.pop() {
+            n.left = right;
+            self.write(id, n, buf)?;
+            right = Some(id);
+        }
+        Ok((left, right))
+    }
+
+    /// Rehashes `n` from its children and stores it under `id`.
+    fn write(&mut self, id: NodeId, mut n: StoredNode, buf: &mut Vec<u8>) -> Result<(), S::Error> {
+        n.hash = self.node_hash(&n, buf)?;
+        self.store.put(id, n)
+    }
+
+    fn node_hash(&self, n: &StoredNode, buf: &mut Vec<u8>) -> Result<Hash, S::Error> {
+        let [left, right] = self.child_hashes(n)?;
+        Ok(calculate_merkle_hash_into::<H, _>(
+            buf,
+            &n.key,
+            &n.value_hash,
+            &left,
+            &right,
+        ))
+    }
+
+    fn child_hashes(&self, n: &StoredNode) -> Result<[Hash; 2], S::Error> {
+        Ok([self.hash_of(n.left)?, self.hash_of(n.right)?])
+    }
+
+    fn hash_of(&self, id: Option<NodeId>) -> Result<Hash, S::Error> {
+        id.map_or(Ok(empty_hash()), |id| Ok(self.store.get(id)?.hash))
+    }
+
+    /// Child hashes of `n` in canonical order.
+    fn sorted_children(&self, n: &StoredNode) -> Result<[Hash; 2], S::Error> {
+        let mut children = self.child_hashes(n)?;
+        children.sort_unstable();
+        Ok(children)
+    }
+
+    /// Membership or non-existence proof for `key` against `root_hash`;
+    /// the same construction as `CartesianMerkleTree::generate_proof`.
+    pub fn generate_proof(&self, key: &Key) -> Result<Proof, S::Error> {
+        let mut prefix: Vec<(Key, Hash, Hash)> = Vec::new();
+        let mut cur = self.root;
+        let mut last: Option<StoredNode> = None;
+        let mut existence = false;
+
+        while let Some(id) = cur {
+            let n = self.store.get(id)?;
+            if &n.key == key {
+                existence = true;
+                last = Some(n);
+                break;
+            }
+            let (next, sibling) = if key < &n.key {
+                (n.left, n.right)
+            } else {
+                (n.right, n.left)
+            };
+            if next.is_some() {
+                prefix.push((n.key, n.value_hash, self.hash_of(sibling)?));
+            }
+            cur = next;
+            last = Some(n);
+        }
+        // prefix is folded from the witness up to the root
+        prefix.reverse();
+
+        let suffix = match &last {
+            Some(n) => self.sorted_children(n)?,
+            None => [empty_hash(), empty_hash()],
+        };
+
+        // a witness with one child opens it, to show which side is empty
+        let mut child = None;
+        if let Some(n) = last.as_ref().filter(|_| !existence) {
+            if let (Some(c), None) | (None, Some(c)) = (n.left, n.right) {
+                let c = self.store.get(c)?;
+                child = Some((c.key, c.value_hash, self.sorted_children(&c)?));
+            }
+        }
+
+        Ok(Proof {
+            prefix,
+            suffix,
+            value_hash: last.as_ref().map_or_else(empty_hash, |n| n.value_hash),
+            existence,
+            nonexistence_key: if existence { None } else { last.map(|n| n.key) },
+            nonexistence_child: child,
+        })
+    }
+}
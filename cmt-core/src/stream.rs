@@ -0,0 +1,204 @@
+//! module for streaming a whole tree to and from a byte stream
+//!
+//! `write_to` emits one record per node in pre-order and `read_from`
+//! rebuilds the tree record by record, so neither side ever buffers more
+//! than one node plus the current root-to-node path. Priorities are stored,
+//! and a treap's pre-order with its priorities fixes its shape, so loading
+//! does no rebalancing. Layout (all integers little-endian):
+//!
+//! ```text
+//! header : magic b"CMTS" | version u8 | node count u64
+//! record : length u32 (of the rest of the record)
+//!          | key [u8; 32] | priority i128
+//!          | children u8 (bit 0 = has left, bit 1 = has right)
+//!          | value bytes
+//! ```
+//!
+//! Node hashes are not stored; they are recomputed lazily after loading,
+//! so the loaded tree's root hash is checked rather than trusted.
+use crate::utils::hash_value;
+use crate::{outranks, CartesianMerkleTreeWith, HashCell, Hasher, Key, Priority, TreeNode};
+use std::io::{self, Read, Write};
+
+const MAGIC: &[u8; 4] = b"CMTS";
+const STREAM_VERSION: u8 = 1;
+const HAS_LEFT: u8 = 1;
+const HAS_RIGHT: u8 = 2;
+/// Record bytes before the value: key, priority and children flags.
+const RECORD_HEADER_LEN: usize = 32 + 16 + 1;
+
+/// A node read from the stream whose children are still to come.
+struct Frame {
+    node: Box<TreeNode>,
+    /// Exclusive key bounds set by the node's ancestors.
+    lo: Option<Key>,
+    hi: Option<Key>,
+    pending_left: bool,
+    pending_right: bool,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl<H: Hasher> CartesianMerkleTreeWith<H> {
+    /// Writes every node to `w` in pre-order as length-framed records; see
+    /// the module docs for the layout. Read it back with `read_from`.
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(MAGIC)?;
+        w.write_all(&[STREAM_VERSION])?;
+        w.write_all(&(self.size as u64).to_le_bytes())?;
+
+        let mut stack: Vec<&TreeNode> = self.root.as_deref().into_iter().collect();
+        while let Some(n) = stack.pop() {
+            let len = u32::try_from(RECORD_HEADER_LEN + n.value.len())
+                .map_err(|_| invalid("value too large for a record"))?;
+            let mut children = 0;
+            if n.left.is_some() {
+                children |= HAS_LEFT;
+            }
+            if n.right.is_some() {
+                children |= HAS_RIGHT;
+            }
+            w.write_all(&len.to_le_bytes())?;
+            w.write_all(&n.key)?;
+            w.write_all(&n.priority.to_le_bytes())?;
+            w.write_all(&[children])?;
+            w.write_all(&n.value)?;
+
+            stack.extend(n.right.as_deref());
+            stack.extend(n.left.as_deref());
+        }
+        Ok(())
+    }
+
+    /// Rebuilds a tree written by `write_to`, checking the search and heap
+    /// order of every node as it arrives. The tree keeps the stored
+    /// priorities but derives new ones as `new` does, so entries inserted
+    /// later sort in by the key hash.
+    pub fn read_from<R: Read>(r: &mut R) -> io::Result<Self> {
+        let mut magic = [0u8; 4];
+        r.read_exact(&mut magic)?;
+        let mut version = [0u8; 1];
+        r.read_exact(&mut version)?;
+        if &magic != MAGIC || version[0] != STREAM_VERSION {
+            return Err(invalid("not a CMT stream"));
+        }
+        let mut count = [0u8; 8];
+        r.read_exact(&mut count)?;
+        let count =
+            usize::try_from(u64::from_le_bytes(count)).map_err(|_| invalid("too many nodes"))?;
+
+        let mut tree = Self::new();
+        // the path from the root to the node read last, minus finished nodes
+        let mut stack: Vec<Frame> = Vec::new();
+        // on an error the partial subtrees left on it are dropped node by
+        // node, as `TreeNode` tears subtrees down iteratively
+        Self::load(r, count, &mut stack, &mut tree)?;
+        tree.size = count;
+        Ok(tree)
+    }
+
+    fn load<R: Read>(
+        r: &mut R,
+        count: usize,
+        stack: &mut Vec<Frame>,
+        tree: &mut Self,
+    ) -> io::Result<()> {
+        for index in 0..count {
+            let (node, children) = read_record::<H, R>(r)?;
+
+            let (lo, hi) = match stack.last() {
+                None if index == 0 => (None, None),
+                None => return Err(invalid("node after the end of the tree")),
+                Some(parent) => {
+                    if outranks(
+                        node.priority,
+                        &node.key,
+                        parent.node.priority,
+                        &parent.node.key,
+                    ) {
+                        return Err(invalid("node ranked above its parent"));
+                    }
+                    match parent.pending_left {
+                        true => (parent.lo, Some(parent.node.key)),
+                        false => (Some(parent.node.key), parent.hi),
+                    }
+                }
+            };
+            if lo.is_some_and(|lo| node.key <= lo) || hi.is_some_and(|hi| node.key >= hi) {
+                return Err(invalid("key out of search order"));
+            }
+            stack.push(Frame {
+                node,
+                lo,
+                hi,
+                pending_left: children & HAS_LEFT != 0,
+                pending_right: children & HAS_RIGHT != 0,
+            });
+
+            // hand every finished node to its parent
+            while let Some(top) = stack.last() {
+                if top.pending_left || top.pending_right {
+                    break;
+                }
+                let mut done = stack.pop().unwrap().node;
+                done.subtree_size = 1
+                    + done.left.as_ref().map_or(0, |c| c.subtree_size)
+                    + done.right.as_ref().map_or(0, |c| c.subtree_size);
+                match stack.last_mut() {
+                    Some(parent) if parent.pending_left => {
+                        parent.node.left = Some(done);
+                        parent.pending_left = false;
+                    }
+                    Some(parent) => {
+                        parent.node.right = Some(done);
+                        parent.pending_right = false;
+                    }
+                    None => tree.root = Some(done),
+                }
+            }
+        }
+        match stack.is_empty() {
+            true => Ok(()),
+            false => Err(invalid("stream ended inside the tree")),
+        }
+    }
+}
+
+fn read_record<H: Hasher, R: Read>(r: &mut R) -> io::Result<(Box<TreeNode>, u8)> {
+    let mut len = [0u8; 4];
+    r.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len) as usize;
+    let Some(value_len) = len.checked_sub(RECORD_HEADER_LEN) else {
+        return Err(invalid("record too short"));
+    };
+
+    let mut header = [0u8; RECORD_HEADER_LEN];
+    r.read_exact(&mut header)?;
+    let key: Key = header[..32].try_into().unwrap();
+    let priority = Priority::from_le_bytes(header[32..48].try_into().unwrap());
+    let children = header[48];
+    if children & !(HAS_LEFT | HAS_RIGHT) != 0 {
+        return Err(invalid("invalid children flags"));
+    }
+
+    // read through `take` so a bogus length can't force a huge allocation
+    let mut value = Vec::new();
+    r.by_ref().take(value_len as u64).read_to_end(&mut value)?;
+    if value.len() != value_len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+
+    let node = Box::new(TreeNode {
+        key,
+        priority,
+        value_hash: hash_value::<H>(&value),
+        value,
+        hash: HashCell::new(),
+        subtree_size: 1,
+        left: None,
+        right: None,
+    });
+    Ok((node, children))
+}
@@ -0,0 +1,209 @@
+//! module for trees keyed by any type with a byte encoding
+//!
+//! `CartesianMerkleTree` keys are fixed 32-byte arrays. A `TypedCMT` takes
+//! any `K: KeyEncode` instead and lays the key's encoding out as a tree key,
+//! so integers, strings and byte strings of any length can be used directly.
+//! Encodings are fixed (integers are big-endian, signed ones with the sign
+//! bit flipped), so the same key always lands on the same tree key and the
+//! root hash stays deterministic.
+//!
+//! An encoding of up to 31 bytes is stored as is, zero-padded, with its
+//! length in the last byte, so tree keys sort the way the encodings do:
+//! integers in numeric order, strings byte-wise. Ordered iteration and
+//! range proofs over `tree()` follow `K`. A longer encoding doesn't fit and
+//! is hashed instead, under a last byte no short key uses; such keys still
+//! work for lookups and proofs but land in hash order.
+use crate::{CartesianMerkleTreeWith, Hash, Hasher, Key, Proof, Sha256Hasher, Value};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+
+/// Longest encoding a tree key holds as is; the byte after it is the length.
+const INLINE_LEN: usize = 31;
+
+/// Last byte of a tree key holding the hash of a long encoding.
+const HASHED: u8 = 0xff;
+
+/// Deterministic byte encoding of a key, from which a `TypedCMT` derives
+/// the tree key. Encodings that compare in the order of the keys keep that
+/// order in the tree.
+pub trait KeyEncode {
+    type Bytes<'a>: AsRef<[u8]>
+    where
+        Self: 'a;
+
+    fn encode(&self) -> Self::Bytes<'_>;
+}
+
+impl KeyEncode for [u8] {
+    type Bytes<'a> = &'a [u8];
+
+    fn encode(&self) -> &[u8] {
+        self
+    }
+}
+
+impl<const N: usize> KeyEncode for [u8; N] {
+    type Bytes<'a> = &'a [u8];
+
+    fn encode(&self) -> &[u8] {
+        self
+    }
+}
+
+impl KeyEncode for Vec<u8> {
+    type Bytes<'a> = &'a [u8];
+
+    fn encode(&self) -> &[u8] {
+        self
+    }
+}
+
+/// UTF-8 bytes.
+impl KeyEncode for str {
+    type Bytes<'a> = &'a [u8];
+
+    fn encode(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+/// UTF-8 bytes.
+impl KeyEncode for String {
+    type Bytes<'a> = &'a [u8];
+
+    fn encode(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl<T: KeyEncode + ?Sized> KeyEncode for &T {
+    type Bytes<'a>
+        = T::Bytes<'a>
+    where
+        Self: 'a;
+
+    fn encode(&self) -> T::Bytes<'_> {
+        (**self).encode()
+    }
+}
+
+macro_rules! encode_unsigned {
+    ($($int:ty),*) => {$(
+        /// Big-endian bytes.
+        impl KeyEncode for $int {
+            type Bytes<'a> = [u8; core::mem::size_of::<$int>()];
+
+            fn encode(&self) -> Self::Bytes<'_> {
+                self.to_be_bytes()
+            }
+        }
+    )*};
+}
+
+macro_rules! encode_signed {
+    ($($int:ty),*) => {$(
+        /// Big-endian bytes with the sign bit flipped, so negatives sort
+        /// first.
+        impl KeyEncode for $int {
+            type Bytes<'a> = [u8; core::mem::size_of::<$int>()];
+
+            fn encode(&self) -> Self::Bytes<'_> {
+                (*self ^ <$int>::MIN).to_be_bytes()
+            }
+        }
+    )*};
+}
+
+encode_unsigned!(u8, u16, u32, u64, u128, usize);
+encode_signed!(i8, i16, i32, i64, i128, isize);
+
+/// A Cartesian Merkle Tree keyed by `K`, hashing with `H`; see the module
+/// docs. Most code uses the SHA-256 `TypedCMT` alias.
+pub struct TypedCMTWith<K: ?Sized, H: Hasher> {
+    tree: CartesianMerkleTreeWith<H>,
+    key_type: PhantomData<fn(&K)>,
+}
+
+pub type TypedCMT<K> = TypedCMTWith<K, Sha256Hasher>;
+
+/// Tree keyed by `u64`.
+pub type U64CMT = TypedCMT<u64>;
+
+impl<K: KeyEncode + ?Sized, H: Hasher> Default for TypedCMTWith<K, H> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: KeyEncode + ?Sized, H: Hasher> TypedCMTWith<K, H> {
+    pub fn new() -> Self {
+        Self {
+            tree: CartesianMerkleTreeWith::new(),
+            key_type: PhantomData,
+        }
+    }
+
+    /// Tree key `key` is stored under: its encoding padded out with its
+    /// length, or `H` of a longer encoding; see the module docs.
+    pub fn tree_key(key: &K) -> Key {
+        let encoded = key.encode();
+        let bytes = encoded.as_ref();
+        let mut tree_key = [0; 32];
+        if bytes.len() <= INLINE_LEN {
+            tree_key[..bytes.len()].copy_from_slice(bytes);
+            tree_key[INLINE_LEN] = bytes.len() as u8;
+        } else {
+            tree_key[..INLINE_LEN].copy_from_slice(&H::hash(bytes)[..INLINE_LEN]);
+            tree_key[INLINE_LEN] = HASHED;
+        }
+        tree_key
+    }
+
+    /// The underlying tree, keyed by `tree_key`.
+    pub fn tree(&self) -> &CartesianMerkleTreeWith<H> {
+        &self.tree
+    }
+
+    pub fn into_tree(self) -> CartesianMerkleTreeWith<H> {
+        self.tree
+    }
+
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    pub fn insert(&mut self, key: &K, value: Value) {
+        self.tree.insert(Self::tree_key(key), value);
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<Value> {
+        self.tree.remove(&Self::tree_key(key))
+    }
+
+    pub fn get(&self, key: &K) -> Option<&Value> {
+        self.tree.get(&Self::tree_key(key))
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.tree.contains_key(&Self::tree_key(key))
+    }
+
+    pub fn root_hash(&self) -> Option<Hash> {
+        self.tree.root_hash()
+    }
+
+    /// Membership or non-existence proof for `key` against `root_hash`.
+    pub fn generate_proof(&self, key: &K) -> Proof {
+        self.tree.generate_proof(&Self::tree_key(key))
+    }
+
+    /// Checks a proof from `generate_proof` for `key` against `root_hash`.
+    pub fn verify_proof(proof: Proof, key: &K, root_hash: Hash) -> bool {
+        CartesianMerkleTreeWith::<H>::verify_proof(proof, Self::tree_key(key), root_hash)
+    }
+}
@@ -1,68 +1,184 @@
 //! module to store the utility functions of CMT
-use crate::{Hash, TreeNode};
-use sha2::{Digest, Sha256};
+use crate::{
+    empty_hash, hash_bytes, is_empty_subtree_hash, Hash, Hasher, TreeNode, DIGEST_LEN,
+    HASH_ALGO_SHA256,
+};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::fmt;
 
-pub fn calculate_merkle_hash<K: AsRef<[u8]>>(
+/// Hash a node commits to for its value, cached on the node as
+/// `value_hash`.
+pub fn hash_value<H: Hasher>(value: &[u8]) -> Hash {
+    H::hash(value)
+}
+
+/// Domain tag for nodes whose children are both empty.
+pub const LEAF_TAG: u8 = 0x00;
+/// Domain tag for nodes with at least one child.
+pub const INTERNAL_TAG: u8 = 0x01;
+
+/// `H(tag || key || value_hash || min(left, right) || max(left, right))`,
+/// with every field after the tag prefixed by its length so that no two
+/// distinct inputs serialize to the same bytes. The tag keeps a leaf hash
+/// from ever being read as an internal node hash, or vice versa.
+pub fn calculate_merkle_hash<H: Hasher, K: AsRef<[u8]>>(
+    key: &K,
+    value_hash: &Hash,
+    left_child_hash: &Hash,
+    right_child_hash: &Hash,
+) -> Hash {
+    calculate_merkle_hash_into::<H, K>(
+        &mut Vec::new(),
+        key,
+        value_hash,
+        left_child_hash,
+        right_child_hash,
+    )
+}
+
+/// Same as `calculate_merkle_hash`, but serializes into a caller-provided
+/// scratch buffer so the insert/remove/rotation paths can reuse one
+/// allocation across every hash they recompute.
+pub fn calculate_merkle_hash_into<H: Hasher, K: AsRef<[u8]>>(
+    buf: &mut Vec<u8>,
     key: &K,
+    value_hash: &Hash,
     left_child_hash: &Hash,
     right_child_hash: &Hash,
 ) -> Hash {
-    let mut buf = Vec::new();
-    buf.extend_from_slice(key.as_ref());
-    if left_child_hash < right_child_hash {
-        buf.extend_from_slice(left_child_hash);
-        buf.extend_from_slice(right_child_hash);
+    let (lo, hi) = if left_child_hash < right_child_hash {
+        (left_child_hash, right_child_hash)
+    } else {
+        (right_child_hash, left_child_hash)
+    };
+    let tag = if is_empty_subtree_hash(lo) && is_empty_subtree_hash(hi) {
+        LEAF_TAG
+    } else {
+        INTERNAL_TAG
+    };
+    buf.clear();
+    buf.push(tag);
+    for field in [
+        key.as_ref(),
+        hash_bytes(value_hash),
+        hash_bytes(lo),
+        hash_bytes(hi),
+    ] {
+        buf.extend_from_slice(&(field.len() as u64).to_be_bytes());
+        buf.extend_from_slice(field);
+    }
+    H::hash(buf)
+}
+
+/// Domain tag for `combine_roots`, so a combined commitment can never be
+/// mistaken for a node hash.
+const COMBINE_ROOTS_TAG: &[u8] = b"cmt:combine-roots";
+
+/// Folds several root hashes (e.g. of shards) into one parent commitment,
+/// hashing with `H`. The roots are sorted first, so the result doesn't
+/// depend on input order, and each one is length-prefixed to keep the
+/// encoding unambiguous. The domain tag is followed by `H::ALGO_ID`, so two
+/// hashers over the same digest never share a commitment; SHA-256, whose
+/// commitments predate the tag, keeps the encoding without it.
+pub fn combine_roots<H: Hasher>(roots: &[Hash]) -> Hash {
+    let mut sorted: Vec<&Hash> = roots.iter().collect();
+    sorted.sort();
+
+    let mut buf = Vec::with_capacity(COMBINE_ROOTS_TAG.len() + 9 + sorted.len() * (8 + DIGEST_LEN));
+    buf.extend_from_slice(COMBINE_ROOTS_TAG);
+    if H::ALGO_ID != HASH_ALGO_SHA256 {
+        buf.push(H::ALGO_ID);
+    }
+    buf.extend_from_slice(&(sorted.len() as u64).to_be_bytes());
+    for root in sorted {
+        let root = hash_bytes(root);
+        buf.extend_from_slice(&(root.len() as u64).to_be_bytes());
+        buf.extend_from_slice(root);
+    }
+    H::hash(&buf)
+}
+
+/// Child hashes of a proof's witness node in the canonical order from the
+/// paper (`suffix[0] < suffix[1]`).
+pub fn proof_suffix(node: Option<&TreeNode>) -> [Hash; 2] {
+    let (left, right) = match node {
+        Some(n) => (
+            n.left.as_ref().map_or_else(empty_hash, |x| *x.hash()),
+            n.right.as_ref().map_or_else(empty_hash, |x| *x.hash()),
+        ),
+        None => (empty_hash(), empty_hash()),
+    };
+    if left <= right {
+        [left, right]
     } else {
-        buf.extend_from_slice(right_child_hash);
-        buf.extend_from_slice(left_child_hash);
+        [right, left]
     }
-    let mut hasher = Sha256::new();
-    hasher.update(&buf);
-    hasher.finalize().to_vec()
 }
 
-pub fn rotate_left(mut x: Box<TreeNode>) -> Box<TreeNode> {
-    let mut y = x.right.take().expect("rotate_left requires right child");
+pub fn subtree_size(node: &Option<Box<TreeNode>>) -> usize {
+    node.as_ref().map_or(0, |n| n.subtree_size)
+}
+
+/// Marks `node`'s hash stale and recomputes its subtree size.
+fn refresh(node: &mut TreeNode) {
+    node.hash.take();
+    node.subtree_size = 1 + subtree_size(&node.left) + subtree_size(&node.right);
+}
+
+/// Returned by a rotation when the child that would move up is missing.
+/// Hands the node back unchanged.
+#[derive(Debug)]
+pub struct RotateError {
+    pub node: Box<TreeNode>,
+}
+
+impl fmt::Display for RotateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "cannot rotate at key {}: the child to lift is missing",
+            hex::encode(self.node.key)
+        )
+    }
+}
+
+impl core::error::Error for RotateError {}
+
+/// Lifts `x`'s right child into its place. Fails, returning `x` as it was,
+/// if there is no right child. Hashes along the way are marked stale.
+pub fn rotate_left(mut x: Box<TreeNode>) -> Result<Box<TreeNode>, RotateError> {
+    let Some(mut y) = x.right.take() else {
+        return Err(RotateError { node: x });
+    };
 
     // move y.left into x.right
     x.right = y.left.take();
-
-    // recompute x.hash
-    let left_hash = x.left.as_ref().map(|n| n.hash.clone()).unwrap_or_default();
-    let right_hash = x.right.as_ref().map(|n| n.hash.clone()).unwrap_or_default();
-    x.hash = calculate_merkle_hash(&x.key, &left_hash, &right_hash);
+    refresh(&mut x);
 
     // put x as left child of y
     y.left = Some(x);
+    refresh(&mut y);
 
-    // recompute y.hash
-    let left_hash = y.left.as_ref().map(|n| n.hash.clone()).unwrap_or_default();
-    let right_hash = y.right.as_ref().map(|n| n.hash.clone()).unwrap_or_default();
-    y.hash = calculate_merkle_hash(&y.key, &left_hash, &right_hash);
-
-    y
+    Ok(y)
 }
 
-pub fn rotate_right(mut y: Box<TreeNode>) -> Box<TreeNode> {
-    let mut x = y.left.take().expect("rotate_right requires left child");
+/// Lifts `y`'s left child into its place. Fails, returning `y` as it was,
+/// if there is no left child. Hashes along the way are marked stale.
+pub fn rotate_right(mut y: Box<TreeNode>) -> Result<Box<TreeNode>, RotateError> {
+    let Some(mut x) = y.left.take() else {
+        return Err(RotateError { node: y });
+    };
 
     // move x.right into y.left
     y.left = x.right.take();
-
-    // recompute y.hash
-    let left_hash = y.left.as_ref().map(|n| n.hash.clone()).unwrap_or_default();
-    let right_hash = y.right.as_ref().map(|n| n.hash.clone()).unwrap_or_default();
-    y.hash = calculate_merkle_hash(&y.key, &left_hash, &right_hash);
+    refresh(&mut y);
 
     // put y as right child of x
     x.right = Some(y);
+    refresh(&mut x);
 
-    // recompute x.hash
-    let left_hash = x.left.as_ref().map(|n| n.hash.clone()).unwrap_or_default();
-    let right_hash = x.right.as_ref().map(|n| n.hash.clone()).unwrap_or_default();
-    x.hash = calculate_merkle_hash(&x.key, &left_hash, &right_hash);
-
-    x
+    Ok(x)
 }
 
 // Let CMT proof be a proof of membership of an element e in a tree T, represented as proof =
@@ -74,6 +190,9 @@ pub fn rotate_right(mut y: Box<TreeNode>) -> Box<TreeNode> {
 // • nonExistenceKey is used when e does not exist in the tree, and helps verify that e is absent.
 // The initial value of acc is computed as:
 // acc = H((existence?e.k : nonExistenceKey) ∥ proof.suffix[0] ∥ proof.suffix[1])
+// (this implementation also mixes in the hash of each node's value right
+// after its key, so proofs bind keys to values, and prepends a leaf/internal
+// domain tag)
 // ensuring that proof.suffix[0] < proof.suffix[1].
 // Then, acc is iteratively updated using values from prefix:
 // (
@@ -0,0 +1,106 @@
+//! module for checking a tree's internal invariants
+use crate::utils::{calculate_merkle_hash_into, hash_value};
+use crate::{empty_hash, outranks, CartesianMerkleTreeWith, Hasher, Key, TreeNode};
+use alloc::vec::Vec;
+use core::fmt;
+
+/// Invariant a node broke, as reported by `CartesianMerkleTree::validate`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    /// The key is out of order with respect to an ancestor.
+    KeyOrder,
+    /// The node outranks its parent: a higher priority, or an equal one
+    /// and a smaller key.
+    HeapOrder,
+    /// The cached value hash doesn't match the value.
+    ValueHash,
+    /// The stored node hash doesn't match its key, value hash and children.
+    NodeHash,
+    /// The cached subtree size doesn't match the children's.
+    SubtreeSize,
+}
+
+/// First node found breaking an invariant, and which one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ValidationError {
+    pub key: Key,
+    pub violation: Violation,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let what = match self.violation {
+            Violation::KeyOrder => "key out of search order",
+            Violation::HeapOrder => "ranked above its parent",
+            Violation::ValueHash => "stale value hash",
+            Violation::NodeHash => "stale node hash",
+            Violation::SubtreeSize => "wrong subtree size",
+        };
+        write!(f, "{what} at key {}", hex::encode(self.key))
+    }
+}
+
+impl core::error::Error for ValidationError {}
+
+impl<H: Hasher> CartesianMerkleTreeWith<H> {
+    /// Walks every node and checks the search order of keys, the heap order
+    /// of priorities, the cached value hashes and subtree sizes, and every
+    /// node hash against a fresh one. Stale hashes are settled first, so
+    /// this only catches hashes that are set and wrong. Runs in O(n).
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        self.settle();
+        let buf = &mut Vec::new();
+        // each node with the exclusive key bounds its ancestors set
+        let mut stack: Vec<(&TreeNode, Option<&Key>, Option<&Key>)> = self
+            .root
+            .as_deref()
+            .map(|n| (n, None, None))
+            .into_iter()
+            .collect();
+        while let Some((n, lo, hi)) = stack.pop() {
+            let fail = |violation| {
+                Err(ValidationError {
+                    key: n.key,
+                    violation,
+                })
+            };
+            if lo.is_some_and(|lo| n.key <= *lo) || hi.is_some_and(|hi| n.key >= *hi) {
+                return fail(Violation::KeyOrder);
+            }
+            let children = [n.left.as_deref(), n.right.as_deref()];
+            // the child is the node out of place
+            if let Some(c) = children
+                .iter()
+                .flatten()
+                .find(|c| outranks(c.priority, &c.key, n.priority, &n.key))
+            {
+                return Err(ValidationError {
+                    key: c.key,
+                    violation: Violation::HeapOrder,
+                });
+            }
+            if n.value_hash != hash_value::<H>(&n.value) {
+                return fail(Violation::ValueHash);
+            }
+            let size = |c: Option<&TreeNode>| c.map_or(0, |c| c.subtree_size);
+            if n.subtree_size != 1 + size(children[0]) + size(children[1]) {
+                return fail(Violation::SubtreeSize);
+            }
+            let hash = |c: Option<&TreeNode>| c.map_or_else(empty_hash, |c| *c.hash());
+            let expected = calculate_merkle_hash_into::<H, _>(
+                buf,
+                &n.key,
+                &n.value_hash,
+                &hash(children[0]),
+                &hash(children[1]),
+            );
+            if *n.hash() != expected {
+                return fail(Violation::NodeHash);
+            }
+
+            stack.extend(n.right.as_deref().map(|r| (r, Some(&n.key), hi)));
+            stack.extend(n.left.as_deref().map(|l| (l, lo, Some(&n.key))));
+        }
+        Ok(())
+    }
+}
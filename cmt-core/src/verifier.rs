@@ -0,0 +1,49 @@
+//! module for checking many proofs without allocating per proof
+//!
+//! `verify_proof` serializes every node it hashes into a scratch buffer it
+//! allocates for the call. A `ProofVerifier` keeps that buffer between
+//! calls, so once it has grown to fit a node, a run of verifications
+//! allocates nothing. Proofs are borrowed rather than consumed, so their
+//! storage stays with the caller as well.
+use crate::{CartesianMerkleTreeWith, Hash, Hasher, Key, Proof, Sha256Hasher, VerifyError};
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+
+/// Reusable proof checker for trees hashing with `H`; see the module docs.
+/// Most code uses the SHA-256 `ProofVerifier` alias.
+pub struct ProofVerifierWith<H: Hasher> {
+    buf: Vec<u8>,
+    hasher: PhantomData<fn() -> H>,
+}
+
+pub type ProofVerifier = ProofVerifierWith<Sha256Hasher>;
+
+impl<H: Hasher> Default for ProofVerifierWith<H> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<H: Hasher> ProofVerifierWith<H> {
+    pub fn new() -> Self {
+        Self {
+            buf: Vec::new(),
+            hasher: PhantomData,
+        }
+    }
+
+    /// Same check as `CartesianMerkleTree::verify_proof`.
+    pub fn verify(&mut self, proof: &Proof, key: &Key, root_hash: &Hash) -> bool {
+        self.verify_detailed(proof, key, root_hash).is_ok()
+    }
+
+    /// Same check as `CartesianMerkleTree::verify_proof_detailed`.
+    pub fn verify_detailed(
+        &mut self,
+        proof: &Proof,
+        key: &Key,
+        root_hash: &Hash,
+    ) -> Result<(), VerifyError> {
+        CartesianMerkleTreeWith::<H>::verify_into(&mut self.buf, proof, key, root_hash)
+    }
+}
@@ -0,0 +1,48 @@
+//! module for custom traversals over the nodes of a CMT
+use crate::TreeNode;
+use alloc::vec::Vec;
+
+/// Callbacks invoked by `CartesianMerkleTree::walk` for every node.
+///
+/// `visit_pre` fires before the node's left subtree is walked, `visit_in`
+/// between the left and right subtrees and `visit_post` after both. All
+/// callbacks default to no-ops so visitors only implement what they need.
+pub trait Visitor {
+    fn visit_pre(&mut self, _node: &TreeNode) {}
+    fn visit_in(&mut self, _node: &TreeNode) {}
+    fn visit_post(&mut self, _node: &TreeNode) {}
+}
+
+enum Stage {
+    Pre,
+    In,
+    Post,
+}
+
+/// Iterative depth-first walk, so deep (skewed) trees can't overflow the stack.
+pub fn walk<V: Visitor + ?Sized>(root: Option<&TreeNode>, visitor: &mut V) {
+    let mut stack: Vec<(&TreeNode, Stage)> = Vec::new();
+    if let Some(n) = root {
+        stack.push((n, Stage::Pre));
+    }
+
+    while let Some((n, stage)) = stack.pop() {
+        match stage {
+            Stage::Pre => {
+                visitor.visit_pre(n);
+                stack.push((n, Stage::In));
+                if let Some(left) = n.left.as_deref() {
+                    stack.push((left, Stage::Pre));
+                }
+            }
+            Stage::In => {
+                visitor.visit_in(n);
+                stack.push((n, Stage::Post));
+                if let Some(right) = n.right.as_deref() {
+                    stack.push((right, Stage::Pre));
+                }
+            }
+            Stage::Post => visitor.visit_post(n),
+        }
+    }
+}
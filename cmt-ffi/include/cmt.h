@@ -0,0 +1,46 @@
+/* C interface to cmt-core; see cmt-ffi/src/lib.rs for the ownership rules. */
+#ifndef CMT_H
+#define CMT_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define CMT_OK 0
+#define CMT_ERR_NULL (-1)
+#define CMT_ERR_DECODE (-2)
+#define CMT_ERR_PANIC (-3)
+
+typedef struct CmtTree CmtTree;
+typedef struct CmtProof CmtProof;
+
+/* Trees: create with cmt_new, release with cmt_free. */
+CmtTree *cmt_new(void);
+void cmt_free(CmtTree *tree);
+
+/* key: 32 bytes. The value is copied. */
+int32_t cmt_insert(CmtTree *tree, const uint8_t *key, const uint8_t *value, size_t value_len);
+
+/* Writes 32 bytes; all zeros for an empty tree. */
+int32_t cmt_root_hash(const CmtTree *tree, uint8_t *out);
+
+/* Proofs: create with cmt_generate_proof, release with cmt_free_proof.
+ * The data pointer stays valid until the proof is freed. */
+CmtProof *cmt_generate_proof(const CmtTree *tree, const uint8_t *key);
+const uint8_t *cmt_proof_data(const CmtProof *proof);
+size_t cmt_proof_len(const CmtProof *proof);
+void cmt_free_proof(CmtProof *proof);
+
+/* 1 if the proof holds for key under root (32 bytes each), 0 if not,
+ * or a negative CMT_ERR_* code. */
+int32_t cmt_verify_proof(const uint8_t *proof, size_t proof_len, const uint8_t *key,
+                         const uint8_t *root);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* CMT_H */
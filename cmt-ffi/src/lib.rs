@@ -0,0 +1,307 @@
+//! C ABI over `cmt-core`, declared in `include/cmt.h`.
+//!
+//! Ownership rules:
+//!
+//! - A `CmtTree` comes from `cmt_new` and must be released with exactly one
+//!   `cmt_free`.
+//! - A `CmtProof` comes from `cmt_generate_proof` and must be released with
+//!   exactly one `cmt_free_proof`. The pointer from `cmt_proof_data` is
+//!   valid until then.
+//! - Every other pointer is borrowed for the duration of the call only.
+//!   The library copies what it keeps, so the caller still owns key, value
+//!   and output buffers.
+//!
+//! Keys and roots are always 32 bytes. Functions return `CMT_OK` (or, for
+//! `cmt_verify_proof`, `1` / `0`) on success and a negative `CMT_ERR_*`
+//! code otherwise. Panics are caught at the boundary and reported as
+//! `CMT_ERR_PANIC`; this needs the default `panic = "unwind"`.
+use cmt_core::{empty_hash, CartesianMerkleTree, Hash, Key, Proof};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::{ptr, slice};
+
+pub const CMT_OK: i32 = 0;
+/// A required pointer was null.
+pub const CMT_ERR_NULL: i32 = -1;
+/// The proof bytes could not be decoded.
+pub const CMT_ERR_DECODE: i32 = -2;
+/// A panic was caught before it could cross the boundary.
+pub const CMT_ERR_PANIC: i32 = -3;
+
+/// Opaque tree handle.
+pub struct CmtTree {
+    inner: CartesianMerkleTree,
+}
+
+/// Opaque handle to an encoded proof (`Proof::to_bytes` format).
+pub struct CmtProof {
+    bytes: Box<[u8]>,
+}
+
+/// Runs `f`, turning a panic into `CMT_ERR_PANIC`.
+fn guard(f: impl FnOnce() -> i32) -> i32 {
+    catch_unwind(AssertUnwindSafe(f)).unwrap_or(CMT_ERR_PANIC)
+}
+
+/// Reads a 32-byte key or hash.
+///
+/// # Safety
+///
+/// `ptr` must be null or valid for reading 32 bytes.
+unsafe fn read32(ptr: *const u8) -> Option<[u8; 32]> {
+    (!ptr.is_null()).then(|| ptr.cast::<[u8; 32]>().read_unaligned())
+}
+
+/// Borrows `len` bytes at `ptr`; a null pointer is only allowed with a
+/// zero length.
+///
+/// # Safety
+///
+/// Unless null, `ptr` must be valid for reading `len` bytes.
+unsafe fn bytes<'a>(ptr: *const u8, len: usize) -> Option<&'a [u8]> {
+    match (ptr.is_null(), len) {
+        (true, 0) => Some(&[]),
+        (true, _) => None,
+        (false, _) => Some(slice::from_raw_parts(ptr, len)),
+    }
+}
+
+/// Creates an empty tree. Returns null only if a panic was caught.
+#[no_mangle]
+pub extern "C" fn cmt_new() -> *mut CmtTree {
+    catch_unwind(|| {
+        Box::into_raw(Box::new(CmtTree {
+            inner: CartesianMerkleTree::new(),
+        }))
+    })
+    .unwrap_or(ptr::null_mut())
+}
+
+/// Frees a tree. Null is ignored.
+///
+/// # Safety
+///
+/// `tree` must be null or a pointer from `cmt_new` not yet freed.
+#[no_mangle]
+pub unsafe extern "C" fn cmt_free(tree: *mut CmtTree) {
+    if !tree.is_null() {
+        let tree = Box::from_raw(tree);
+        let _ = catch_unwind(AssertUnwindSafe(move || drop(tree)));
+    }
+}
+
+/// Inserts or overwrites `key` with a copy of `value_len` bytes at
+/// `value_ptr`.
+///
+/// # Safety
+///
+/// `tree` must be a live tree, `key_ptr` must be valid for 32 bytes and
+/// `value_ptr` for `value_len` bytes (it may be null if `value_len` is 0).
+#[no_mangle]
+pub unsafe extern "C" fn cmt_insert(
+    tree: *mut CmtTree,
+    key_ptr: *const u8,
+    value_ptr: *const u8,
+    value_len: usize,
+) -> i32 {
+    let (Some(tree), Some(key), Some(value)) =
+        (tree.as_mut(), read32(key_ptr), bytes(value_ptr, value_len))
+    else {
+        return CMT_ERR_NULL;
+    };
+    guard(|| {
+        tree.inner.insert(key, value.to_vec());
+        CMT_OK
+    })
+}
+
+/// Writes the 32-byte root hash to `out_ptr`; all zeros for an empty tree.
+///
+/// # Safety
+///
+/// `tree` must be a live tree and `out_ptr` valid for writing 32 bytes.
+#[no_mangle]
+pub unsafe extern "C" fn cmt_root_hash(tree: *const CmtTree, out_ptr: *mut u8) -> i32 {
+    let Some(tree) = tree.as_ref() else {
+        return CMT_ERR_NULL;
+    };
+    if out_ptr.is_null() {
+        return CMT_ERR_NULL;
+    }
+    guard(|| {
+        let root: Hash = tree.inner.root_hash().unwrap_or_else(empty_hash);
+        out_ptr.cast::<Hash>().write_unaligned(root);
+        CMT_OK
+    })
+}
+
+/// Generates the encoded membership or non-existence proof for `key`.
+/// Returns null on a null argument or a caught panic. Free the result with
+/// `cmt_free_proof`.
+///
+/// # Safety
+///
+/// `tree` must be a live tree and `key_ptr` valid for 32 bytes.
+#[no_mangle]
+pub unsafe extern "C" fn cmt_generate_proof(
+    tree: *const CmtTree,
+    key_ptr: *const u8,
+) -> *mut CmtProof {
+    let (Some(tree), Some(key)) = (tree.as_ref(), read32(key_ptr)) else {
+        return ptr::null_mut();
+    };
+    catch_unwind(AssertUnwindSafe(|| {
+        let bytes = tree
+            .inner
+            .generate_proof(&key)
+            .to_bytes()
+            .into_boxed_slice();
+        Box::into_raw(Box::new(CmtProof { bytes }))
+    }))
+    .unwrap_or(ptr::null_mut())
+}
+
+/// Start of the proof's encoded bytes, valid until `cmt_free_proof`.
+///
+/// # Safety
+///
+/// `proof` must be null or a live proof.
+#[no_mangle]
+pub unsafe extern "C" fn cmt_proof_data(proof: *const CmtProof) -> *const u8 {
+    proof.as_ref().map_or(ptr::null(), |p| p.bytes.as_ptr())
+}
+
+/// Length of the proof's encoded bytes; 0 for null.
+///
+/// # Safety
+///
+/// `proof` must be null or a live proof.
+#[no_mangle]
+pub unsafe extern "C" fn cmt_proof_len(proof: *const CmtProof) -> usize {
+    proof.as_ref().map_or(0, |p| p.bytes.len())
+}
+
+/// Frees a proof. Null is ignored.
+///
+/// # Safety
+///
+/// `proof` must be null or a pointer from `cmt_generate_proof` not yet
+/// freed.
+#[no_mangle]
+pub unsafe extern "C" fn cmt_free_proof(proof: *mut CmtProof) {
+    if !proof.is_null() {
+        drop(Box::from_raw(proof));
+    }
+}
+
+/// Verifies `proof_len` encoded proof bytes for `key` against `root`.
+/// Returns 1 if the proof holds, 0 if it doesn't, or a negative error.
+/// Needs no tree.
+///
+/// # Safety
+///
+/// `proof_ptr` must be valid for `proof_len` bytes, and `key_ptr` and
+/// `root_ptr` for 32 bytes each.
+#[no_mangle]
+pub unsafe extern "C" fn cmt_verify_proof(
+    proof_ptr: *const u8,
+    proof_len: usize,
+    key_ptr: *const u8,
+    root_ptr: *const u8,
+) -> i32 {
+    let (Some(proof), Some(key), Some(root)) = (
+        bytes(proof_ptr, proof_len),
+        read32(key_ptr),
+        read32(root_ptr),
+    ) else {
+        return CMT_ERR_NULL;
+    };
+    guard(|| {
+        let Ok(proof) = Proof::from_bytes(proof) else {
+            return CMT_ERR_DECODE;
+        };
+        let key: Key = key;
+        CartesianMerkleTree::verify_proof(proof, key, root) as i32
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(i: u8) -> Key {
+        let mut key = [0u8; 32];
+        key[0] = i;
+        key
+    }
+
+    #[test]
+    fn round_trip_through_the_c_abi() {
+        unsafe {
+            let tree = cmt_new();
+            let mut root = [0xffu8; 32];
+            assert_eq!(cmt_root_hash(tree, root.as_mut_ptr()), CMT_OK);
+            assert_eq!(root, empty_hash());
+
+            for i in 0..20 {
+                let value = [i; 3];
+                let k = key(2 * i);
+                assert_eq!(
+                    cmt_insert(tree, k.as_ptr(), value.as_ptr(), value.len()),
+                    CMT_OK
+                );
+            }
+            assert_eq!(cmt_insert(tree, key(99).as_ptr(), ptr::null(), 0), CMT_OK);
+            assert_eq!(cmt_root_hash(tree, root.as_mut_ptr()), CMT_OK);
+            assert_eq!(Some(root), (*tree).inner.root_hash());
+
+            for k in [key(10), key(11), key(99)] {
+                let proof = cmt_generate_proof(tree, k.as_ptr());
+                assert!(!proof.is_null());
+                let (data, len) = (cmt_proof_data(proof), cmt_proof_len(proof));
+                assert_eq!(cmt_verify_proof(data, len, k.as_ptr(), root.as_ptr()), 1);
+                let other = key(12);
+                assert_eq!(
+                    cmt_verify_proof(data, len, other.as_ptr(), root.as_ptr()),
+                    0
+                );
+                assert_eq!(
+                    cmt_verify_proof(data, 3, k.as_ptr(), root.as_ptr()),
+                    CMT_ERR_DECODE
+                );
+                cmt_free_proof(proof);
+            }
+            cmt_free(tree);
+        }
+    }
+
+    #[test]
+    fn null_arguments_are_rejected() {
+        unsafe {
+            let k = key(1);
+            assert_eq!(
+                cmt_insert(ptr::null_mut(), k.as_ptr(), ptr::null(), 0),
+                CMT_ERR_NULL
+            );
+            let tree = cmt_new();
+            assert_eq!(cmt_insert(tree, ptr::null(), ptr::null(), 0), CMT_ERR_NULL);
+            assert_eq!(cmt_insert(tree, k.as_ptr(), ptr::null(), 4), CMT_ERR_NULL);
+            assert_eq!(cmt_root_hash(tree, ptr::null_mut()), CMT_ERR_NULL);
+            assert!(cmt_generate_proof(tree, ptr::null()).is_null());
+            assert!(cmt_proof_data(ptr::null()).is_null());
+            assert_eq!(cmt_proof_len(ptr::null()), 0);
+            assert_eq!(
+                cmt_verify_proof(ptr::null(), 0, k.as_ptr(), ptr::null()),
+                CMT_ERR_NULL
+            );
+            cmt_free_proof(ptr::null_mut());
+            cmt_free(tree);
+            cmt_free(ptr::null_mut());
+        }
+    }
+
+    #[test]
+    fn panics_stop_at_the_boundary() {
+        let code = guard(|| panic!("boom"));
+        assert_eq!(code, CMT_ERR_PANIC);
+    }
+}
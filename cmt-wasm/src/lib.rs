@@ -0,0 +1,185 @@
+//! wasm-bindgen bindings so light clients can verify CMT proofs in the
+//! browser. Proofs use the binary format of `Proof::to_bytes`; keys, roots
+//! and proofs can be passed as `Uint8Array`s or as hex strings. Only
+//! `cmt-core` is linked, without `std` features, threads or `rayon`, so the
+//! crate builds for `wasm32-unknown-unknown`.
+use cmt_core::{key_from_slice, CartesianMerkleTree, Hash, Proof, DIGEST_LEN};
+use wasm_bindgen::prelude::*;
+
+/// Outcome of a verification: `ok`, or the reason it failed in `error`.
+#[wasm_bindgen]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Verification {
+    ok: bool,
+    error: Option<String>,
+}
+
+#[wasm_bindgen]
+impl Verification {
+    #[wasm_bindgen(getter)]
+    pub fn ok(&self) -> bool {
+        self.ok
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn error(&self) -> Option<String> {
+        self.error.clone()
+    }
+}
+
+impl From<Result<(), String>> for Verification {
+    fn from(result: Result<(), String>) -> Self {
+        Self {
+            ok: result.is_ok(),
+            error: result.err(),
+        }
+    }
+}
+
+fn check(proof: &[u8], key: &[u8], root: &[u8]) -> Result<(), String> {
+    let proof = Proof::from_bytes(proof).map_err(|e| format!("bad proof: {e}"))?;
+    let key = key_from_slice(key).map_err(|e| format!("bad key: {e}"))?;
+    let root: Hash = root
+        .try_into()
+        .map_err(|_| format!("bad root: must be {DIGEST_LEN} bytes, got {}", root.len()))?;
+    CartesianMerkleTree::verify_proof_detailed(proof, key, root).map_err(|e| e.to_string())
+}
+
+fn decode(what: &str, input: &str) -> Result<Vec<u8>, String> {
+    hex::decode(input).map_err(|e| format!("bad {what} hex: {e}"))
+}
+
+/// Verifies an encoded membership or non-existence proof for `key` against
+/// `root`.
+#[wasm_bindgen(js_name = verifyProof)]
+pub fn verify_proof(proof: &[u8], key: &[u8], root: &[u8]) -> Verification {
+    check(proof, key, root).into()
+}
+
+/// `verifyProof` with every argument hex-encoded.
+#[wasm_bindgen(js_name = verifyProofHex)]
+pub fn verify_proof_hex(proof: &str, key: &str, root: &str) -> Verification {
+    (|| {
+        check(
+            &decode("proof", proof)?,
+            &decode("key", key)?,
+            &decode("root", root)?,
+        )
+    })()
+    .into()
+}
+
+/// A tree built in the browser, e.g. to recompute a root from known
+/// entries.
+#[wasm_bindgen]
+#[derive(Default)]
+pub struct Tree {
+    inner: CartesianMerkleTree,
+}
+
+#[wasm_bindgen]
+impl Tree {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<(), JsError> {
+        let key = key_from_slice(key)?;
+        self.inner.insert(key, value.to_vec());
+        Ok(())
+    }
+
+    /// Root hash; the empty hash (32 zero bytes) for an empty tree.
+    #[wasm_bindgen(js_name = rootHash)]
+    pub fn root_hash(&self) -> Vec<u8> {
+        self.inner
+            .root_hash()
+            .unwrap_or_else(cmt_core::empty_hash)
+            .to_vec()
+    }
+
+    /// Encoded proof for `key`, ready for `verifyProof`.
+    #[wasm_bindgen(js_name = generateProof)]
+    pub fn generate_proof(&self, key: &[u8]) -> Result<Vec<u8>, JsError> {
+        let key = key_from_slice(key)?;
+        Ok(self.inner.generate_proof(&key).to_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(target_arch = "wasm32")]
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    fn key(i: u8) -> [u8; 32] {
+        let mut key = [0u8; 32];
+        key[0] = i;
+        key
+    }
+
+    /// Root of the tree holding keys `2i` with value `[i]`, for `i` in
+    /// 0..8, and the encoded membership proof for key 6 (value `[3]`).
+    /// Produced by this crate; a change here means the proof format or
+    /// hashing changed.
+    const ROOT: &str = "87794ccef198b07da300b242ada7e524e0209dc56d6a32ae038190ed159acef5";
+    const PROOF: &str = concat!(
+        "03010120084fed08b978af4d7d196a7446a86b58009e636b611db16211b65a9aadff29c500000600",
+        "0000080000000000000000000000000000000000000000000000000000000000000020e52d9c508c",
+        "502347344d8c07ad91cbd6068afc75ff6292f062a09ca381c89e71000a0000000000000000000000",
+        "000000000000000000000000000000000000000020e77b9a9ae9e30b0dbdb6f510a264ef9de78150",
+        "1d7b6b92ae89eb059c5ab743db000c00000000000000000000000000000000000000000000000000",
+        "0000000000002067586e98fad27da0b9968bc039a1ef34c939b9b8e523a8bef89d478608c5ecf620",
+        "37044bb2e0a28acff89314cbc5b39e576e34d0fa3948f6412c2bac073c0b3a7f0400000000000000",
+        "00000000000000000000000000000000000000000000000020dbc1b4c900ffe48d575b5da5c63804",
+        "0125f65db0fe3e24494b76ea986457d98600020000000000000000000000000000000000000000000",
+        "0000000000000000000204bf5122f344554c53bde2ebb8cd2b7e3d1600ad631c385a5d7cce23c7785",
+        "459a000000000000000000000000000000000000000000000000000000000000000000206e340b9c",
+        "ffb37a989ca544e6bb780a2c78901d3fb33738768511a30617afa01d00",
+    );
+
+    fn ok() -> Verification {
+        Verification {
+            ok: true,
+            error: None,
+        }
+    }
+
+    // checked natively under `cargo test` and in a JS engine under
+    // `wasm-pack test --node`, so both builds agree on the vector
+    #[cfg_attr(not(target_arch = "wasm32"), test)]
+    #[cfg_attr(target_arch = "wasm32", wasm_bindgen_test)]
+    fn known_proof_vector_verifies() {
+        let key6 = hex::encode(key(6));
+        assert_eq!(verify_proof_hex(PROOF, &key6, ROOT), ok());
+        let bytes = |s| hex::decode(s).unwrap();
+        assert_eq!(verify_proof(&bytes(PROOF), &key(6), &bytes(ROOT)), ok());
+
+        let mut tree = Tree::new();
+        for i in 0..8 {
+            tree.insert(&key(2 * i), &[i]).unwrap();
+        }
+        assert_eq!(hex::encode(tree.root_hash()), ROOT);
+        assert_eq!(hex::encode(tree.generate_proof(&key(6)).unwrap()), PROOF);
+        let absent = tree.generate_proof(&key(7)).unwrap();
+        assert_eq!(verify_proof(&absent, &key(7), &tree.root_hash()), ok());
+    }
+
+    #[test]
+    fn failures_come_back_as_error_strings() {
+        let key6 = hex::encode(key(6));
+        let failed = |v: Verification| {
+            assert!(!v.ok());
+            v.error().unwrap()
+        };
+
+        let other_root = hex::encode([1u8; 32]);
+        assert!(failed(verify_proof_hex(PROOF, &key6, &other_root)).contains("instead of"));
+        assert!(failed(verify_proof_hex(PROOF, &hex::encode(key(8)), ROOT)).contains("instead of"));
+        assert!(failed(verify_proof_hex("zz", &key6, ROOT)).starts_with("bad proof hex"));
+        assert!(failed(verify_proof_hex(&PROOF[..20], &key6, ROOT)).starts_with("bad proof:"));
+        assert!(failed(verify_proof_hex(PROOF, "0011", ROOT)).starts_with("bad key:"));
+        assert!(failed(verify_proof_hex(PROOF, &key6, "0011")).starts_with("bad root:"));
+    }
+}